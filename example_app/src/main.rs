@@ -1,149 +1,225 @@
 use std::io::{self, ErrorKind};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
 // Use interprocess's Tokio integration for local sockets
 use interprocess::local_socket::{
     tokio::{prelude::*, Listener, Stream}, // Use Listener and Stream
-    GenericNamespaced, GenericFilePath, ToFsName, ToNsName, Name, ListenerOptions, // Import necessary types/traits
+    GenericNamespaced, Name, ListenerOptions, // Import necessary types/traits
 };
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::sync::mpsc; // Although not used for sending between tasks here, keep for consistency if needed later
-
-// --- Shared Message Structures (Copied from Broker for now) ---
-// IMPORTANT: In a real project, move these to a shared crate (e.g., `shared_types`)
-// to avoid duplication and ensure consistency.
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Message {
-    action: String,
-    task_id: String,
-    // Make task optional or use a different struct for simple pings
-    task: Option<Task>, // Example: Make task optional for ping
-    // Add other fields as needed for different message types
-    data: Option<serde_json::Value>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Task {
-    steps: Vec<Step>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type")]
-enum Step {
-    // Define steps if needed, or keep empty if only handling pings initially
-    #[serde(rename = "navigate")] Navigate { url: String },
-    // ... other steps
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-struct ExtensionResponse {
-    action: String, // e.g., "pong", "task_result"
-    task_id: String, // Echo task_id if available, else use placeholder
-    success: bool,
-    result: Option<serde_json::Value>,
-    #[serde(default)]
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
-}
-// --- End of Shared Message Structures ---
-
-// Constants
-const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024; // 10MB limit
-
-// --- IPC Endpoint Name (MUST match the Broker's) ---
-fn get_ipc_endpoint_name() -> io::Result<Name<'static> > {
-    let name = "com.yourcompany.projectagentis.broker.sock";
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinSet;
+
+use rzn_bridge_client::Handlers;
+
+// Message structs and framing are shared with the broker
+use shared_types::frame::{read_frame_detecting_limited, write_frame_as, Frame, FrameFlags, FramingMode};
+use shared_types::{
+    Action, Alert, AlertAction, AlertRule, AlertRules, BridgeConfig, BrokerStateChange, Browser, CommitDecision, CommitRequest, EndpointSpec, ExtensionConfig, ExtensionLog, ExtensionResponse, Hello, JsonError, JsonLimits, Message, TaskResult,
+    COMPRESSION_CAPABILITIES, E_NOT_PAIRED, E_REVOKED, Encoding, ExitReason, HealthPolicy, Heartbeat, LastExit, Locale, Overrides, PairRequest, Pairings, PairingStatus, Profile, Registration, Remediation, SelectorDegradation, SessionHealth, SHUTDOWN_ACTION,
+    MessageTooLarge,
+    Confirmed, ResidencyFilter, SealKey, SealedChannel, Side, is_sealed, may_be_unsealed,
+};
+
+// --- IPC Endpoints (MUST match the Broker's) ---
+/// Returns every endpoint to listen on for `profile`. Brokers resolve either the
+/// namespaced name or the filesystem path depending on how they were built, so
+/// both are bound when the platform supports them.
+fn get_ipc_endpoints(profile: &Profile) -> Vec<EndpointSpec> {
+    let name = profile.socket_name();
+    let mut endpoints = Vec::new();
     if GenericNamespaced::is_supported() {
-        name.to_ns_name::<GenericNamespaced>()
-            .map_err(|e| io::Error::new(ErrorKind::Other, e))
-    } else {
-        let path_str = format!("/tmp/{}", name);
-        // Ensure the path exists or handle creation if needed
-        // For simplicity, we assume /tmp exists. Use directories crate for robust paths.
-        String::from(path_str).to_fs_name::<GenericFilePath>()
-            .map_err(|e| io::Error::new(ErrorKind::Other, e))
+        endpoints.push(EndpointSpec::namespaced(&name));
+    }
+    // On Windows the filesystem endpoint is a named pipe path, which only
+    // matters as a fallback when namespaced names are unavailable
+    if cfg!(unix) || endpoints.is_empty() {
+        endpoints.push(EndpointSpec::filesystem(&name));
     }
+    endpoints
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    env_logger::init();
+fn main() -> io::Result<()> {
+    // The socket name and profile must match the broker's, or the two won't
+    // find each other. Both read the same flags, environment and config file.
+    let (overrides, _) = Overrides::from_args(std::env::args().skip(1));
+    overrides.install();
+    shared_types::logging::init("example_app");
     log::info!("Example App Server starting...");
+    // The same file tunes the runtime, which an app embedding the bridge would
+    // bring itself
+    BridgeConfig::load()?.runtime().block_on(serve())?
+}
 
-    // 1. Get the IPC endpoint name
-    let ipc_endpoint = get_ipc_endpoint_name()?;
-    log::info!("Attempting to listen on IPC endpoint: {:?}", ipc_endpoint);
-
-    // 2. Set up the listener options
-    let opts = ListenerOptions::new().name(ipc_endpoint.clone());
+async fn serve() -> io::Result<()> {
+    let profile = Profile::current()?;
+    log::info!("Serving profile {:?} for user {}", profile.name(), profile.user());
 
-    // 3. Create the listener
-    let listener = match opts.create_tokio() {
-        Ok(listener) => {
-            log::info!("Server listening on {:?}", ipc_endpoint);
-            listener
+    // 1. Create a listener for every endpoint (namespaced and/or filesystem)
+    let mut listeners = Vec::new();
+    for spec in get_ipc_endpoints(&profile) {
+        let endpoint = spec.to_name()?;
+        log::info!("Attempting to listen on IPC endpoint: {:?}", endpoint);
+        match create_listener(endpoint.clone(), spec.socket_file()) {
+            Ok(listener) => {
+                log::info!("Server listening on {:?}", endpoint);
+                listeners.push(listener);
+            }
+            // Keep serving on the other endpoints if one can't be bound
+            Err(e) => log::error!("Failed to listen on {:?}: {}", endpoint, e),
         }
-        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+    }
+    if listeners.is_empty() {
+        log::error!("No IPC endpoint could be bound. Exiting.");
+        return Err(io::Error::new(ErrorKind::AddrNotAvailable, "no IPC endpoint could be bound"));
+    }
+
+    // 2. Funnel connections from all listeners into a single accept loop
+    let (conn_tx, mut conn_rx) = mpsc::channel::<Stream>(16);
+    for listener in listeners {
+        tokio::spawn(accept_connections(listener, conn_tx.clone()));
+    }
+    drop(conn_tx);
+
+    // With RZN_REQUIRE_PAIRING=1, extensions have to be paired with "pair <code>" first
+    let pairing = pairing_from_env();
+
+    // "pause"/"resume" typed on the console switch automation off and on in every session
+    let (pause_tx, pause_rx) = watch::channel::<Option<String>>(None);
+    tokio::spawn(read_console_commands(pause_tx, pairing.clone()));
+
+    // SIGTERM/SIGINT end every session with a shutdown notice instead of mid-frame
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+
+    // 3. Handle connections as they arrive
+    let mut sessions = JoinSet::new();
+    let mut next_session_id: u64 = 1;
+    loop {
+        let stream = tokio::select! {
+            stream = conn_rx.recv() => match stream {
+                Some(stream) => stream,
+                None => return Ok(()),
+            },
+            signal = &mut signal => {
+                log::info!("Received {}, shutting down.", signal?);
+                break;
+            }
+        };
+        // Each broker connection is one session (used e.g. as the extension log target)
+        let session_id = next_session_id;
+        next_session_id += 1;
+        log::info!("Broker connected! (session {})", session_id);
+        // Spawn a task to handle this connection
+        let pause = pause_rx.clone();
+        let shutdown = shutdown_rx.clone();
+        let pairing = pairing.clone();
+        sessions.spawn(async move {
+            if let Err(e) = handle_connection(stream, session_id, pause, shutdown, pairing.clone()).await {
+                log::error!("Error handling connection: {}", e);
+            }
+            if let Some(pairing) = pairing {
+                pairing.store.lock().unwrap_or_else(|e| e.into_inner()).cancel(session_id);
+            }
+            log::info!("Broker disconnected.");
+        });
+    }
+
+    // Stop accepting, then let every session finish what it has read and say goodbye
+    drop(conn_rx);
+    shutdown_tx.send_replace(true);
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, async { while sessions.join_next().await.is_some() {} }).await;
+    if drained.is_err() {
+        log::warn!("{} session(s) still busy after {:?}, exiting anyway.", sessions.len(), SHUTDOWN_TIMEOUT);
+    }
+    log::info!("Example App Server stopped.");
+    // The console reader's blocking stdin read can't be cancelled and would
+    // keep the runtime from shutting down
+    std::process::exit(0);
+}
+
+/// Longest wait for the sessions to close on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves with the name of the first shutdown signal received.
+#[cfg(unix)]
+async fn shutdown_signal() -> io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = interrupt.recv() => Ok("SIGINT"),
+    }
+}
+
+/// Resolves with the name of the first console control event received.
+#[cfg(windows)]
+async fn shutdown_signal() -> io::Result<&'static str> {
+    use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close};
+
+    let mut ctrl_c = ctrl_c()?;
+    let mut ctrl_break = ctrl_break()?;
+    let mut ctrl_close = ctrl_close()?;
+    tokio::select! {
+        _ = ctrl_c.recv() => Ok("CTRL_C"),
+        _ = ctrl_break.recv() => Ok("CTRL_BREAK"),
+        _ = ctrl_close.recv() => Ok("CTRL_CLOSE"),
+    }
+}
+
+/// Creates a listener, removing a stale socket file left behind by a crash if needed.
+fn create_listener(endpoint: Name<'static>, socket_path: Option<&Path>) -> io::Result<Listener> {
+    let opts = ListenerOptions::new().name(endpoint.clone());
+    match opts.create_tokio() {
+        Ok(listener) => Ok(listener),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
             // Handle case where the socket file/pipe exists (e.g., from a previous crash)
             log::error!(
                 "IPC endpoint {:?} already in use. Attempting to clean up...",
-                ipc_endpoint
+                endpoint
             );
-            // On Unix, try removing the socket file. This is potentially racy.
-            #[cfg(unix)]
-            {
-                // For filesystem-based sockets on Unix, try to remove the file
-                // Create a path using the same logic as in get_ipc_endpoint_name
-                let socket_name = "com.yourcompany.projectagentis.broker.sock";
-                let path_str = format!("/tmp/{}", socket_name);
-                let path = std::path::Path::new(&path_str);
-                
-                if path.exists() {
-                    match std::fs::remove_file(path) {
-                        Ok(_) => {
-                            log::info!("Removed stale socket file: {:?}", path);
-                            // Try creating the listener again with new options
-                            let new_opts = ListenerOptions::new().name(ipc_endpoint.clone());
-                            new_opts.create_tokio()?
-                        }
-                        Err(remove_err) => {
-                            log::error!("Failed to remove stale socket file {:?}: {}", path, remove_err);
-                            return Err(e);
-                        }
-                    }
-                } else {
-                    log::error!("Socket file expected but not found at: {:?}", path);
-                    return Err(e);
-                }
+            // Only filesystem sockets leave a file behind. This is potentially racy.
+            let Some(path) = socket_path else {
+                log::error!("IPC endpoint {:?} already in use. Please ensure no other instance is running.", endpoint);
+                return Err(e);
+            };
+            if !path.exists() {
+                log::error!("Socket file expected but not found at: {:?}", path);
+                return Err(e);
             }
-            // On Windows, named pipes usually clean up better, but retrying might still be needed.
-            #[cfg(not(unix))]
-            {
-                 log::error!("IPC endpoint {:?} already in use. Please ensure no other instance is running.", ipc_endpoint);
-                 return Err(e);
+            match std::fs::remove_file(path) {
+                Ok(_) => {
+                    log::info!("Removed stale socket file: {:?}", path);
+                    // Try creating the listener again with new options
+                    ListenerOptions::new().name(endpoint).create_tokio()
+                }
+                Err(remove_err) => {
+                    log::error!("Failed to remove stale socket file {:?}: {}", path, remove_err);
+                    Err(e)
+                }
             }
         }
         Err(e) => {
             log::error!("Failed to create IPC listener: {}", e);
-            return Err(e);
+            Err(e)
         }
-    };
+    }
+}
 
-    // 4. Accept connections in a loop
+/// Accepts connections on one listener and hands them to the main accept loop.
+async fn accept_connections(listener: Listener, conn_tx: mpsc::Sender<Stream>) {
     loop {
         match listener.accept().await {
             Ok(stream) => {
-                log::info!("Broker connected!");
-                // Spawn a task to handle this connection
-                tokio::spawn(async move {
-                    if let Err(e) = handle_connection(stream).await {
-                        log::error!("Error handling connection: {}", e);
-                    }
-                    log::info!("Broker disconnected.");
-                });
+                if conn_tx.send(stream).await.is_err() {
+                    break; // Accept loop is gone, stop listening
+                }
             }
             Err(e) => {
                 log::error!("Failed to accept connection: {}", e);
@@ -156,46 +232,339 @@ async fn main() -> io::Result<()> {
 }
 
 /// Handles a single connection from the broker
-async fn handle_connection(stream: Stream) -> io::Result<()> {
+async fn handle_connection(
+    stream: Stream,
+    session_id: u64,
+    mut pause: watch::Receiver<Option<String>>,
+    mut shutdown: watch::Receiver<bool>,
+    pairing: Option<Arc<Pairing>>,
+) -> io::Result<()> {
     // Split the stream for reading and writing
     // Use tokio::io::split as the broker does, for consistency
-    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (reader, mut writer) = tokio::io::split(stream);
+    // Frames are read in their own task so the pause switch doesn't have to wait for the broker
+    let (frame_tx, mut frames) = mpsc::channel(16);
+    tokio::spawn(read_frames(reader, frame_tx, BridgeConfig::load_or_default().message_limits().to_app));
+    // Framing is detected from the broker's first frame (old brokers use bare lengths)
+    let mut framing: Option<FramingMode> = None;
+    // The current pause state is pushed with the configuration
+    pause.borrow_and_update();
+    let mut pause_seq: u64 = 0;
+    // Settings pushed to the extension once the framing is known
+    let config = extension_config();
+    let mut configure_seq: u64 = 0;
+    // Guards against pathological payloads before deserializing
+    let limits = JsonLimits::from_env();
+    // Checked against every task result
+    let alerts = alert_rules();
+    // Sensitive data is redacted from results before they are logged or exported
+    let residency = ResidencyFilter::from_env();
+    // Answers requests that aren't handled above; unknown actions get a structured error
+    let handlers = action_handlers();
+    // Set once the app is shutting down: frames already read are still handled
+    let mut closing = false;
+    // Once the broker has sent a heartbeat, a connection silent for longer is dead
+    let reap_after = Heartbeat::from_env().map(|heartbeat| heartbeat.reap_after());
+    let mut heartbeats = false;
+    let mut last_heard = tokio::time::Instant::now();
+    // Probes the broker and acts on thresholds from RZN_HEALTH_POLICY
+    let mut health = HealthPolicy::from_env().map(HealthMonitor::new);
+    // Nothing but the pairing is done for an extension that has to be paired
+    let mut paired = pairing.is_none();
+    let mut confirmed = pairing.as_ref().map(|pairing| pairing.confirmed.subscribe());
+    // The session is closed if the pairing behind its token is revoked
+    let mut paired_token: Option<String> = None;
+    // Payloads to and from an extension that agreed on a seal key are sealed
+    let mut seal: Option<SealedChannel> = None;
+    let mut revoked = pairing.as_ref().map(|pairing| pairing.revoked.subscribe());
 
     loop {
-        // Read message from broker
-        match read_message_bytes(&mut reader, "ExampleAppRead").await {
-            Ok(Some(message_bytes)) => {
+        // Read message from broker, or pass on a flip of the pause switch
+        let was_detected = framing.is_some();
+        let read = tokio::select! {
+            next = frames.recv() => match next {
+                Some((read, detected)) => {
+                    framing = detected;
+                    last_heard = tokio::time::Instant::now();
+                    read
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep_until(last_heard + reap_after.unwrap_or_default()), if heartbeats && reap_after.is_some() => {
+                log::warn!("Session {}: Nothing from the broker in {:?}, closing the dead connection.",
+                           session_id, reap_after.unwrap_or_default());
+                break;
+            }
+            Ok(()) = pause.changed(), if was_detected && paired => {
+                pause_seq += 1;
+                let paused = pause.borrow_and_update().clone();
+                let mode = framing.unwrap_or(FramingMode::Header);
+                if let Err(e) = push_pause_state(&mut writer, mode, paused, session_id, pause_seq).await {
+                    log::error!("Failed to push pause state to extension: {}", e);
+                    break;
+                }
+                continue;
+            }
+            confirmation = next_confirmed_pairing(&mut confirmed, session_id), if was_detected && !paired => {
+                paired = true;
+                seal = pairing.as_ref().and_then(|pairing| pairing.seal_key(&confirmation.token)).map(|key| SealedChannel::new(key, Side::MainApp));
+                paired_token = Some(confirmation.token.clone());
+                let mode = framing.unwrap_or(FramingMode::Header);
+                let status = confirmation.status();
+                if let Err(e) = answer_pair(&mut writer, mode, 0, &format!("pair-{}", session_id), &status).await {
+                    log::error!("Failed to send the pairing token to extension: {}", e);
+                    break;
+                }
+                configure_seq += 1;
+                pause_seq += 1;
+                let paused = pause.borrow().clone();
+                if let Err(e) = push_settings(&mut writer, mode, &config, paused, session_id, configure_seq, pause_seq).await {
+                    log::error!("Failed to push settings to extension: {}", e);
+                    break;
+                }
+                continue;
+            }
+            id = next_revocation(&mut revoked), if paired_token.is_some() => {
+                let ours = match (&pairing, &paired_token) {
+                    (Some(pairing), Some(token)) => pairing.is_identity(token, &id),
+                    _ => false,
+                };
+                if !ours {
+                    continue;
+                }
+                log::warn!("Session {}: Closing the connection, its pairing {} was revoked.", session_id, id);
+                let mode = framing.unwrap_or(FramingMode::Header);
+                if let Err(e) = reject_revoked(&mut writer, mode, 0, &format!("revoked-{}", session_id), &id).await {
+                    log::error!("Failed to tell the extension its pairing was revoked: {}", e);
+                }
+                break;
+            }
+            () = next_health_check(&mut health), if was_detected && !closing => {
+                let Some(monitor) = health.as_mut() else { continue };
+                let depth = frames.max_capacity() - frames.capacity();
+                let mode = framing.unwrap_or(FramingMode::Header);
+                match monitor.check(&mut writer, mode, depth, session_id).await {
+                    Ok(true) => continue,
+                    Ok(false) => {
+                        log::warn!("Session {}: Closing the connection so the broker reconnects.", session_id);
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to probe broker health: {}", e);
+                        break;
+                    }
+                }
+            }
+            Ok(()) = shutdown.changed(), if !closing => {
+                closing = true;
+                frames.close();
+                continue;
+            }
+        };
+        match read {
+            Ok(Some(frame)) => {
+                if !was_detected && framing == Some(FramingMode::Legacy) {
+                    log::warn!("Deprecated: broker uses legacy bare-length IPC framing. Please upgrade the broker.");
+                }
+                // Reply on the same logical channel the request arrived on
+                let channel_id = frame.header.channel_id;
+                let mode = framing.unwrap_or(FramingMode::Header);
+                // Frames in a binary encoding (RZN_ENCODING) are handled as JSON like the rest
+                // here; a Main App after the savings decodes them with `Frame::deserialize`
+                let payload = match frame.encoding() {
+                    Encoding::Json => frame.payload,
+                    encoding => match encoding.transcode(&frame.payload, Encoding::Json) {
+                        Ok(json) => json,
+                        Err(e) => {
+                            log::error!("Session {}: Dropping {:?} message: {}", session_id, encoding, e);
+                            continue;
+                        }
+                    },
+                };
+                let message_bytes = if is_sealed(&payload) {
+                    match seal.as_mut().map(|channel| (channel.open(&payload), channel.replays_rejected())) {
+                        Some((Ok(opened), _)) => opened,
+                        Some((Err(e), replays)) => {
+                            log::error!("Session {}: Dropping sealed message ({} replays rejected so far): {}", session_id, replays, e);
+                            continue;
+                        }
+                        None => {
+                            log::error!("Session {}: Dropping sealed message, no seal key was agreed with the extension.", session_id);
+                            continue;
+                        }
+                    }
+                } else if seal.is_some() && !may_be_unsealed(&payload) {
+                    // Only the broker's own messages can't be sealed
+                    log::error!("Session {}: Dropping unsealed message, the extension agreed to seal them.", session_id);
+                    continue;
+                } else {
+                    payload
+                };
+
+                // Push the settings on connect, as soon as we know how to frame them
+                let pushed_now = !was_detected && paired;
+                if pushed_now {
+                    configure_seq += 1;
+                    pause_seq += 1;
+                    let paused = pause.borrow().clone();
+                    if let Err(e) = push_settings(&mut writer, mode, &config, paused, session_id, configure_seq, pause_seq).await {
+                        log::error!("Failed to push settings to extension: {}", e);
+                        break;
+                    }
+                }
                 if message_bytes.is_empty() {
                     log::warn!("Received empty message from broker.");
                     continue;
                 }
 
                 // Attempt to deserialize the message (e.g., into the generic Message struct)
-                match serde_json::from_slice::<Message>(&message_bytes) {
+                match limits.from_slice::<Message>(&message_bytes) {
                     Ok(received_msg) => {
+                        match received_msg.action {
+                            // The broker introduces itself on connect
+                            Action::Hello => {
+                                if let Err(e) = answer_hello(&mut writer, mode, channel_id, &received_msg).await {
+                                    log::error!("Failed to answer hello: {}", e);
+                                    break;
+                                }
+                                continue;
+                            }
+                            // Heartbeats are answered quietly
+                            Action::Ping if Heartbeat::is_heartbeat(&received_msg.task_id) => {
+                                heartbeats = true;
+                                if let Err(e) = answer_heartbeat(&mut writer, mode, channel_id, &received_msg.task_id).await {
+                                    log::error!("Failed to answer heartbeat: {}", e);
+                                    break;
+                                }
+                                continue;
+                            }
+                            // The extension pairs, or shows it is paired, on connect
+                            Action::Pair => {
+                                let (status, newly_paired) = match pair_extension(pairing.as_deref(), &received_msg, session_id, paired) {
+                                    Ok(answer) => answer,
+                                    Err(id) => {
+                                        log::warn!("Session {}: Refusing the extension, its pairing {} was revoked.", session_id, id);
+                                        if let Err(e) = reject_revoked(&mut writer, mode, channel_id, &received_msg.task_id, &id).await {
+                                            log::error!("Failed to answer pair: {}", e);
+                                        }
+                                        break;
+                                    }
+                                };
+                                if let PairingStatus::Paired { token, .. } = &status {
+                                    seal = pairing.as_ref().and_then(|pairing| pairing.seal_key(token)).map(|key| SealedChannel::new(key, Side::MainApp));
+                                    paired_token = Some(token.clone());
+                                }
+                                if let Err(e) = answer_pair(&mut writer, mode, channel_id, &received_msg.task_id, &status).await {
+                                    log::error!("Failed to answer pair: {}", e);
+                                    break;
+                                }
+                                if newly_paired {
+                                    paired = true;
+                                    configure_seq += 1;
+                                    pause_seq += 1;
+                                    let paused = pause.borrow().clone();
+                                    if let Err(e) = push_settings(&mut writer, mode, &config, paused, session_id, configure_seq, pause_seq).await {
+                                        log::error!("Failed to push settings to extension: {}", e);
+                                        break;
+                                    }
+                                }
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        // The broker's own messages are fine; the extension's wait for the pairing
+                        if !paired && !matches!(received_msg.action, Action::StatsResult | Action::SelectorDegraded | Action::BrokerState | Action::Shutdown | Action::MessageTooLarge) {
+                            if received_msg.action != Action::Log {
+                                if let Err(e) = reject_unpaired(&mut writer, mode, channel_id, &received_msg).await {
+                                    log::error!("Failed to answer unpaired extension: {}", e);
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
+                        match received_msg.action {
+                            // Extension log records are routed into our logger, not answered
+                            Action::Log => {
+                                forward_extension_log(&received_msg, session_id);
+                                continue;
+                            }
+                            // The extension asks for its settings when it (re)connects
+                            Action::ConfigureRequest => {
+                                if !pushed_now {
+                                    configure_seq += 1;
+                                    if let Err(e) = push_configure(&mut writer, mode, channel_id, &config, session_id, configure_seq).await {
+                                        log::error!("Failed to push configuration to extension: {}", e);
+                                        break;
+                                    }
+                                }
+                                continue;
+                            }
+                            // Destructive steps wait for our go-ahead
+                            Action::CommitRequest => {
+                                if let Err(e) = answer_commit_request(&mut writer, mode, channel_id, &received_msg).await {
+                                    log::error!("Failed to answer commit request: {}", e);
+                                    break;
+                                }
+                                continue;
+                            }
+                            // Results of tasks run by the extension are logged, not answered
+                            Action::TaskResult => {
+                                let succeeded = log_task_result(&message_bytes, &alerts, residency.as_ref());
+                                if let Some(monitor) = health.as_mut() {
+                                    monitor.record_result(succeeded);
+                                }
+                                continue;
+                            }
+                            // Answers to our health probes
+                            Action::StatsResult => {
+                                if let Some(monitor) = health.as_mut() {
+                                    monitor.probe_answered(&received_msg.task_id);
+                                }
+                                continue;
+                            }
+                            // The broker warns about selectors that stopped working on a site
+                            Action::SelectorDegraded => {
+                                log_selector_degradation(&message_bytes);
+                                continue;
+                            }
+                            // The broker tells us when it is about to go away
+                            Action::BrokerState => {
+                                log_broker_state(&message_bytes, session_id);
+                                continue;
+                            }
+                            // One of our messages was over the broker's limit and never reached the extension
+                            Action::MessageTooLarge => {
+                                match serde_json::from_slice::<ExtensionResponse>(&message_bytes) {
+                                    Ok(error) => log::error!("Broker dropped our message for task {}: {}",
+                                                             error.task_id, error.error.unwrap_or_default()),
+                                    Err(e) => log::error!("Malformed message_too_large: {}", e),
+                                }
+                                continue;
+                            }
+                            Action::ConfigureAck => {
+                                match serde_json::from_slice::<ExtensionResponse>(&message_bytes) {
+                                    Ok(ack) if ack.success => log::info!("Extension applied configuration ({}): {}",
+                                                                         ack.task_id, ack.result.unwrap_or_default()),
+                                    Ok(ack) => log::warn!("Extension rejected configuration ({}): {}",
+                                                          ack.task_id, ack.error.unwrap_or_default()),
+                                    Err(e) => log::error!("Malformed configure_ack: {}", e),
+                                }
+                                continue;
+                            }
+                            _ => {}
+                        }
+
                         log::info!("Received message: {:?}", received_msg);
 
-                        // --- Simple Echo/Pong Logic ---
-                        let response_action = match received_msg.action.as_str() {
-                            "ping" => "pong".to_string(),
-                            "perform_task" => "task_result".to_string(), // Acknowledge task receipt
-                            _ => "unknown_action_response".to_string(),
-                        };
-
-                        // Create a simple response
-                        let response = ExtensionResponse {
-                            action: response_action,
-                            task_id: received_msg.task_id.clone(), // Echo task_id
-                            success: true, // Assume success for this simple test
-                            result: Some(serde_json::json!({ "echo": received_msg })), // Echo back received data
-                            error: None,
-                        };
-
-                        // Serialize the response
-                        match serde_json::to_vec(&response) {
+                        // Everything else is answered by the handler registered for its action
+                        let response = handlers.dispatch(received_msg).await;
+
+                        // Serialize the response, sealed for an extension that agreed on a key
+                        let serialized = serde_json::to_vec(&response).map_err(io::Error::other);
+                        match serialized.and_then(|bytes| match &mut seal { Some(channel) => channel.seal(&bytes), None => Ok(bytes) }) {
                             Ok(response_bytes) => {
                                 // Send response back to broker
-                                if let Err(e) = write_message_bytes(&mut writer, &response_bytes, "ExampleAppWrite").await {
+                                if let Err(e) = write_frame_as(&mut writer, mode, FrameFlags::NONE, channel_id, &response_bytes, "ExampleAppWrite").await {
                                     log::error!("Failed to send response to broker: {}", e);
                                     break; // Stop handling this connection on write error
                                 }
@@ -206,10 +575,12 @@ async fn handle_connection(stream: Stream) -> io::Result<()> {
                                 // Decide if we should send an error back or just log
                             }
                         }
-                        // --- End Simple Echo/Pong Logic ---
 
                     }
-                    Err(e) => {
+                    Err(JsonError::Limit(e)) => {
+                        log::error!("Rejected message from broker ({} bytes): {}", message_bytes.len(), e);
+                    }
+                    Err(JsonError::Parse(e)) => {
                         log::error!("Failed to deserialize message: {}. Raw bytes: {:?}", e, message_bytes);
                         // Optionally send an error response back
                     }
@@ -221,77 +592,769 @@ async fn handle_connection(stream: Stream) -> io::Result<()> {
                 break;
             }
             Err(e) => {
+                // An oversized message was skipped; tell the sender and read on
+                if let Some(too_large) = MessageTooLarge::of(&e) {
+                    let bytes = serde_json::to_vec(&ExtensionResponse::message_too_large(too_large)).unwrap_or_default();
+                    let mode = framing.unwrap_or(FramingMode::Header);
+                    if write_frame_as(&mut writer, mode, FrameFlags::NONE, 0, &bytes, "ExampleAppWrite").await.is_ok() {
+                        continue;
+                    }
+                }
                 // Error reading from broker
                 log::error!("Error reading from broker: {}", e);
                 break;
             }
         }
     }
+    if closing {
+        push_shutdown(&mut writer, framing.unwrap_or(FramingMode::Header), session_id).await?;
+    }
     Ok(())
 }
 
+/// The handlers of the requests the session doesn't handle itself: `ping`
+/// gets a `pong`, and a `perform_task` is acknowledged with a `task_result`.
+/// Both echo the request back.
+fn action_handlers() -> Handlers {
+    let mut handlers = Handlers::default();
+    handlers
+        .on_action(Action::Ping, |message: Message| async move { Ok(echo(Action::Pong, message)) })
+        .on_action(Action::PerformTask, |message: Message| async move { Ok(echo(Action::TaskResult, message)) });
+    handlers
+}
 
-// --- Helper Functions (Copied from Broker) ---
-// IMPORTANT: Move these to a shared crate.
+/// Answers `message` with `action`, echoing it back as the result.
+fn echo(action: Action, message: Message) -> ExtensionResponse {
+    ExtensionResponse {
+        action,
+        task_id: message.task_id.clone(),
+        success: true, // Assume success for this simple test
+        result: Some(serde_json::json!({ "echo": message })),
+        error: None,
+    }
+}
 
-/// Reads a message prefixed with a 4-byte little-endian length.
-async fn read_message_bytes<R: AsyncRead + Unpin>(
-    reader: &mut R,
-    log_prefix: &str,
-) -> io::Result<Option<Vec<u8>>> {
-    let mut len_bytes = [0u8; 4];
-    match reader.read_exact(&mut len_bytes).await {
-        Ok(_) => {}
-        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-            log::debug!("{}: Connection closed cleanly while reading length.", log_prefix);
-            return Ok(None);
+/// Reads frames of up to `max_len` bytes from the broker, along with the
+/// framing detected so far, until the connection ends.
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    frame_tx: mpsc::Sender<(io::Result<Option<Frame>>, Option<FramingMode>)>,
+    max_len: usize,
+) {
+    let mut framing = None;
+    loop {
+        let read = read_frame_detecting_limited(&mut reader, &mut framing, max_len, "ExampleAppRead").await;
+        // Oversized frames are skipped, so reading can go on after them
+        let done = match &read {
+            Ok(read) => read.is_none(),
+            Err(e) => MessageTooLarge::of(e).is_none(),
+        };
+        if frame_tx.send((read, framing)).await.is_err() || done {
+            break;
         }
+    }
+}
+
+/// Reads `pause [reason]`, `resume`, `pair <code>`, `pairings`,
+/// `revoke <id>` and `broker [repair]` commands from the console.
+async fn read_console_commands(pause: watch::Sender<Option<String>>, pairing: Option<Arc<Pairing>>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        let (command, reason) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "pause" => {
+                let reason = match reason.trim() {
+                    "" => "paused from the example app console",
+                    reason => reason,
+                };
+                log::warn!("Pausing all automation: {}", reason);
+                pause.send_replace(Some(reason.to_string()));
+            }
+            "resume" => {
+                log::info!("Resuming all automation.");
+                pause.send_replace(None);
+            }
+            "pair" | "pairings" | "revoke" => match &pairing {
+                Some(pairing) if command == "pair" => pairing.confirm(reason.trim()),
+                Some(pairing) if command == "revoke" => pairing.revoke(reason.trim()),
+                Some(pairing) => pairing.list(),
+                None => log::warn!("Pairing: Not required; set RZN_REQUIRE_PAIRING=1 to require it."),
+            },
+            "broker" => check_broker_installation(reason.trim() == "repair"),
+            "" => {}
+            other => log::warn!("Unknown console command {:?} (try \"pause [reason]\", \"resume\", \"pair <code>\", \"pairings\", \"revoke <id>\" or \"broker [repair]\")", other),
+        }
+    }
+    // Keep the switch alive once stdin closes, e.g. when the broker launched us
+    pause.closed().await;
+}
+
+/// Logs whether each browser would launch the expected broker. With `repair`,
+/// registers it again where it doesn't and opens the browser's extensions
+/// page, as a Main App would when the user clicks "Repair".
+fn check_broker_installation(repair: bool) {
+    let registration = match Registration::from_env() {
+        Ok(registration) => registration,
         Err(e) => {
-            log::error!("{}: Error reading message length: {}", log_prefix, e);
-            return Err(e);
+            log::error!("Install: Could not determine the broker path: {}", e);
+            return;
+        }
+    };
+    for browser in Browser::ALL {
+        let status = registration.verify(browser);
+        if status.is_registered() || !browser.is_installed() {
+            log::info!("Install: {}: {}", browser, status);
+            continue;
+        }
+        log::warn!("Install: {}: {}", browser, status);
+        if !repair {
+            continue;
+        }
+        if !registration.covers(browser) {
+            log::warn!("Install: Not repairing {}: no extension ID in RZN_EXTENSION_IDS or RZN_FIREFOX_ID.", browser);
+            continue;
+        }
+        match registration.register(browser) {
+            Ok(path) => log::info!("Install: Registered the broker with {} ({}); restart the browser to use it.", browser, path.display()),
+            Err(e) => {
+                log::error!("Install: Could not register the broker with {}: {}", browser, e);
+                continue;
+            }
+        }
+        if let Err(e) = browser.open_extensions_page() {
+            log::warn!("Install: Could not open {}: {}", browser.extensions_url(), e);
         }
     }
+    // The broker records why it last stopped, so a failed start shows up here
+    match LastExit::default_path().map(|path| LastExit::read(&path)) {
+        Some(Ok(Some(last))) if last.reason != ExitReason::Ok => {
+            log::warn!("Install: The broker last exited with code {} ({:?}): {}", last.code, last.reason, last.message);
+            for detail in &last.details {
+                log::warn!("Install:   [{}] {}", detail.code, detail.message);
+            }
+        }
+        Some(Ok(Some(last))) => log::info!("Install: The broker last exited cleanly (pid {}).", last.pid),
+        Some(Ok(None)) | None => {}
+        Some(Err(e)) => log::warn!("Install: Could not read the broker's last exit: {}", e),
+    }
+}
 
-    let len = u32::from_le_bytes(len_bytes) as usize;
-    if len > MAX_MESSAGE_SIZE {
-        let err_msg = format!("Message length {} exceeds limit {}", len, MAX_MESSAGE_SIZE);
-        log::error!("{}: {}", log_prefix, err_msg);
-        return Err(io::Error::new(ErrorKind::InvalidData, err_msg));
+/// Extension pairing state shared by the sessions and the console.
+struct Pairing {
+    store: Mutex<Pairings>,
+    /// Each confirmed pairing, for the session it belongs to.
+    confirmed: broadcast::Sender<Confirmed>,
+    /// ID of each revoked pairing, so sessions using it are closed.
+    revoked: broadcast::Sender<String>,
+}
+
+impl Pairing {
+    /// Confirms the code an extension shows and hands its session the token.
+    fn confirm(&self, code: &str) {
+        let confirmed = self.store.lock().unwrap_or_else(|e| e.into_inner()).confirm_pairing(code);
+        match confirmed {
+            Ok(Some(confirmed)) => {
+                log::info!("Pairing: Paired the extension in session {}{}.", confirmed.session,
+                           if confirmed.sealed { ", payloads are sealed" } else { "" });
+                let _ = self.confirmed.send(confirmed);
+            }
+            Ok(None) => log::warn!("Pairing: No extension is waiting with code {:?}; codes expire after 5 minutes.", code),
+            Err(e) => log::error!("Pairing: Could not save the pairing: {}", e),
+        }
     }
-    if len == 0 {
-        log::warn!("{}: Received message length 0.", log_prefix);
-        return Ok(Some(Vec::new()));
+
+    /// Logs the paired extensions.
+    fn list(&self) {
+        let store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        if store.list().is_empty() {
+            log::info!("Pairing: No extension is paired.");
+        }
+        for identity in store.list() {
+            log::info!("Pairing: {} extension {} paired at {} ms{}", identity.id,
+                       identity.extension_id.as_deref().unwrap_or("(unknown)"), identity.paired_at_ms,
+                       identity.revoked_at_ms.map(|at| format!(", revoked at {} ms", at)).unwrap_or_default());
+        }
     }
 
-    let mut buffer = vec![0u8; len];
-    match reader.read_exact(&mut buffer).await {
-        Ok(_) => Ok(Some(buffer)),
-        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
-            log::error!("{}: Connection closed unexpectedly while reading message body (expected {} bytes).", log_prefix, len);
-            Err(e)
+    /// Revokes the pairing `id` and closes the sessions using it.
+    fn revoke(&self, id: &str) {
+        let revoked = self.store.lock().unwrap_or_else(|e| e.into_inner()).revoke(id);
+        match revoked {
+            Ok(true) => {
+                let _ = self.revoked.send(id.to_string());
+            }
+            Ok(false) => log::warn!("Pairing: No pairing {:?} to revoke (see \"pairings\").", id),
+            Err(e) => log::error!("Pairing: Could not save the revocation: {}", e),
+        }
+    }
+
+    /// The seal key of the pairing behind `token`, if one was agreed.
+    fn seal_key(&self, token: &str) -> Option<SealKey> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).seal_key(token).unwrap_or_else(|e| {
+            log::error!("Pairing: Could not read the seal keys: {}", e);
+            None
+        })
+    }
+
+    /// Whether `token` belongs to the pairing `id`.
+    fn is_identity(&self, token: &str, id: &str) -> bool {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).identity(token).is_some_and(|identity| identity.id == id)
+    }
+}
+
+/// The pairing state if `RZN_REQUIRE_PAIRING=1`, with tokens kept in
+/// [`Pairings::default_path`] (in memory only if there is none or it can't
+/// be read). With `RZN_SEALED=1` new pairings agree on a seal key.
+fn pairing_from_env() -> Option<Arc<Pairing>> {
+    if std::env::var("RZN_REQUIRE_PAIRING").as_deref() != Ok("1") {
+        return None;
+    }
+    let mut store = match Pairings::default_path().map(Pairings::load) {
+        Some(Ok(store)) => store,
+        Some(Err(e)) => {
+            log::error!("Pairing: Could not read the pairings, keeping new ones in memory: {}", e);
+            Pairings::default()
+        }
+        None => Pairings::default(),
+    };
+    if std::env::var("RZN_SEALED").as_deref() == Ok("1") {
+        store.set_sealing(true);
+        log::info!("Pairing: Payloads of newly paired extensions are sealed; the broker relays ciphertext.");
+    }
+    log::info!("Pairing: Extensions have to be paired before they are served.");
+    Some(Arc::new(Pairing { store: Mutex::new(store), confirmed: broadcast::channel(16).0, revoked: broadcast::channel(16).0 }))
+}
+
+/// Resolves with the next pairing confirmed for `session_id`.
+async fn next_confirmed_pairing(confirmed: &mut Option<broadcast::Receiver<Confirmed>>, session_id: u64) -> Confirmed {
+    let Some(confirmed) = confirmed else { return std::future::pending().await };
+    loop {
+        match confirmed.recv().await {
+            Ok(confirmed) if confirmed.session == session_id => return confirmed,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+/// Resolves with the ID of the next revoked pairing.
+async fn next_revocation(revoked: &mut Option<broadcast::Receiver<String>>) -> String {
+    let Some(revoked) = revoked else { return std::future::pending().await };
+    loop {
+        match revoked.recv().await {
+            Ok(id) => return id,
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+/// Answers a `pair`: paired if pairing isn't required or the token is known,
+/// otherwise a new code for the extension to show. The flag tells whether
+/// the extension just became paired. A revoked token is refused, and its
+/// pairing's ID returned as the error.
+fn pair_extension(pairing: Option<&Pairing>, message: &Message, session_id: u64, paired: bool) -> Result<(PairingStatus, bool), String> {
+    let Some(pairing) = pairing else { return Ok((PairingStatus::NotRequired, false)) };
+    let request: PairRequest = message.data.clone().and_then(|data| serde_json::from_value(data).ok()).unwrap_or_default();
+    let mut store = pairing.store.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(token) = request.token.clone() {
+        match store.identity(&token) {
+            Some(identity) if identity.is_revoked() => {
+                store.record_refusal(identity, "main_app");
+                return Err(identity.id.clone());
+            }
+            Some(_) => {
+                log::info!("Pairing: Extension in session {} is paired.", session_id);
+                let sealed = store.seal_key(&token).is_ok_and(|key| key.is_some());
+                return Ok((PairingStatus::Paired { token, sealed }, !paired));
+            }
+            None => {}
+        }
+    }
+    match store.request(session_id, request) {
+        Ok(status @ PairingStatus::KeyOffered { .. }) => {
+            log::info!("Pairing: An unpaired extension connected (session {}), offering it a seal key.", session_id);
+            Ok((status, false))
+        }
+        Ok(status) => {
+            // The code is only shown by the extension, so whoever types it here has seen that extension
+            log::warn!("Pairing: An unpaired extension connected (session {}). Enter the code it shows with \"pair <code>\".", session_id);
+            Ok((status, false))
         }
         Err(e) => {
-            log::error!("{}: Error reading message body: {}", log_prefix, e);
-            Err(e)
+            log::error!("Pairing: Could not create a pairing code: {}", e);
+            Ok((PairingStatus::Pending { code: String::new() }, false))
+        }
+    }
+}
+
+/// Sends the extension a `pair_result`.
+async fn answer_pair<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    channel_id: u16,
+    task_id: &str,
+    status: &PairingStatus,
+) -> io::Result<()> {
+    let response = ExtensionResponse {
+        action: Action::PairResult,
+        task_id: task_id.to_string(),
+        success: true,
+        result: Some(serde_json::to_value(status).map_err(io::Error::other)?),
+        error: None,
+    };
+    let bytes = serde_json::to_vec(&response).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await
+}
+
+/// Refuses a message from an extension that isn't paired yet.
+async fn reject_unpaired<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    channel_id: u16,
+    message: &Message,
+) -> io::Result<()> {
+    log::warn!("Pairing: Refusing {} ({}) from an unpaired extension.", message.action, message.task_id);
+    let response = ExtensionResponse::bridge_error(&message.task_id, E_NOT_PAIRED, "This browser is not paired with the application yet")
+        .with_details(serde_json::json!({ "action": message.action }));
+    let bytes = serde_json::to_vec(&response).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await
+}
+
+/// Refuses an extension whose pairing `id` was revoked.
+async fn reject_revoked<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    channel_id: u16,
+    task_id: &str,
+    id: &str,
+) -> io::Result<()> {
+    let message = format!("The pairing of this browser ({}) was revoked", id);
+    let response = ExtensionResponse::bridge_error(task_id, E_REVOKED, &message).with_details(serde_json::json!({ "id": id }));
+    let bytes = serde_json::to_vec(&response).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await
+}
+
+/// Pushes the configuration, and the pause switch if it is on, to an
+/// extension that was just connected or paired.
+async fn push_settings<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    config: &ExtensionConfig,
+    paused: Option<String>,
+    session_id: u64,
+    configure_seq: u64,
+    pause_seq: u64,
+) -> io::Result<()> {
+    push_configure(writer, mode, 0, config, session_id, configure_seq).await?;
+    if paused.is_some() {
+        push_pause_state(writer, mode, paused, session_id, pause_seq).await?;
+    }
+    Ok(())
+}
+
+/// Broadcasts the pause switch to the extension: `pause_all` with the reason,
+/// or `resume_all`.
+async fn push_pause_state<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    paused: Option<String>,
+    session_id: u64,
+    seq: u64,
+) -> io::Result<()> {
+    let task_id = format!("pause-{}-{}", session_id, seq);
+    let message = match paused {
+        Some(reason) => Message::pause_all(task_id, Some(reason)),
+        None => Message::resume_all(task_id),
+    };
+    let bytes = serde_json::to_vec(&message).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, 0, &bytes, "ExampleAppWrite").await?;
+    log::info!("Pushed {} to extension", message.action);
+    Ok(())
+}
+
+/// Answers a heartbeat `ping` from the broker with a bare `pong`.
+async fn answer_heartbeat<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    channel_id: u16,
+    task_id: &str,
+) -> io::Result<()> {
+    let pong = ExtensionResponse { action: Action::Pong, task_id: task_id.to_string(), success: true, result: None, error: None };
+    let bytes = serde_json::to_vec(&pong).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await?;
+    log::debug!("Answered heartbeat {}", task_id);
+    Ok(())
+}
+
+/// Checks one session against a [`HealthPolicy`]: probes the broker with a
+/// `bridge_stats` it answers itself, counts task results, and performs the
+/// policy's remediations when a threshold is crossed.
+struct HealthMonitor {
+    policy: HealthPolicy,
+    interval: tokio::time::Interval,
+    /// Measurements since the last check.
+    window: SessionHealth,
+    /// Task ID of the unanswered probe, if any.
+    probe: Option<String>,
+    seq: u64,
+}
+
+impl HealthMonitor {
+    fn new(policy: HealthPolicy) -> Self {
+        let period = Duration::from_millis(policy.interval_ms);
+        let interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        HealthMonitor { policy, interval, window: SessionHealth::default(), probe: None, seq: 0 }
+    }
+
+    fn record_result(&mut self, succeeded: bool) {
+        if succeeded {
+            self.window.succeeded += 1;
+        } else {
+            self.window.failed += 1;
+        }
+    }
+
+    fn probe_answered(&mut self, task_id: &str) {
+        if self.probe.as_deref() == Some(task_id) {
+            self.probe = None;
+            self.window.probe_answered = Some(true);
+        }
+    }
+
+    /// Judges the measurements since the last check, then sends the next
+    /// probe. `Ok(false)` if the session should be closed.
+    async fn check<W: tokio::io::AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        mode: FramingMode,
+        queue_depth: usize,
+        session_id: u64,
+    ) -> io::Result<bool> {
+        if self.probe.take().is_some() {
+            self.window.probe_answered = Some(false);
+        }
+        self.window.queue_depth = queue_depth;
+        let issues = self.policy.check(&std::mem::take(&mut self.window));
+        let mut keep = true;
+        if !issues.is_empty() {
+            let summary = issues.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+            log::warn!("Session {} is unhealthy: {}", session_id, summary);
+            for remediation in &self.policy.remediations {
+                match remediation {
+                    Remediation::Reconnect => keep = false,
+                    Remediation::Alert { actions } => {
+                        let values = [("session".to_string(), session_id.into()), ("issues".to_string(), summary.clone().into())];
+                        let alert = Alert { rule: "health".to_string(), actions: actions.clone(), values: values.into_iter().collect() };
+                        raise_alert(&format!("health-{}", session_id), alert);
+                    }
+                }
+            }
+        }
+        if keep {
+            self.seq += 1;
+            let task_id = format!("health-{}-{}", session_id, self.seq);
+            let probe = Message { action: Action::Stats, task_id: task_id.clone(), task: None, data: None, ttl_ms: None, msg_id: None };
+            let bytes = serde_json::to_vec(&probe).map_err(io::Error::other)?;
+            write_frame_as(writer, mode, FrameFlags::NONE, 0, &bytes, "ExampleAppWrite").await?;
+            self.probe = Some(task_id);
+        }
+        Ok(keep)
+    }
+}
+
+/// Resolves when the next health check is due; never without a monitor.
+async fn next_health_check(health: &mut Option<HealthMonitor>) {
+    match health {
+        Some(monitor) => {
+            monitor.interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Tells the broker, and through it the extension, that the app is going away.
+async fn push_shutdown<W: tokio::io::AsyncWrite + Unpin>(writer: &mut W, mode: FramingMode, session_id: u64) -> io::Result<()> {
+    let message = Message::shutdown(format!("shutdown-{}", session_id), Some("example app stopped".to_string()));
+    let bytes = serde_json::to_vec(&message).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, 0, &bytes, "ExampleAppWrite").await?;
+    log::info!("Pushed {} to session {}", SHUTDOWN_ACTION, session_id);
+    Ok(())
+}
+
+/// Alert rules from `RZN_ALERT_RULES`, a JSON array of [`AlertRule`]s
+/// (e.g. `[{"name": "cheap", "condition": "price < 100", "actions": [{"type": "event"}]}]`).
+fn alert_rules() -> AlertRules {
+    let Ok(json) = std::env::var("RZN_ALERT_RULES") else {
+        return AlertRules::default();
+    };
+    let rules = serde_json::from_str::<Vec<AlertRule>>(&json)
+        .map_err(|e| e.to_string())
+        .and_then(|rules| AlertRules::new(rules).map_err(|e| e.to_string()));
+    match rules {
+        Ok(rules) => rules,
+        Err(e) => {
+            log::error!("Ignoring invalid RZN_ALERT_RULES: {}", e);
+            AlertRules::default()
+        }
+    }
+}
+
+/// Performs an alert's actions. A real app would show desktop notifications
+/// and publish events through its own UI; here they are logged.
+fn raise_alert(task_id: &str, alert: Alert) {
+    for action in &alert.actions {
+        match action {
+            AlertAction::Event { name } => {
+                log::info!(target: "alerts", "Event {} for task {}: {:?}",
+                           name.as_deref().unwrap_or(&alert.rule), task_id, alert.values);
+            }
+            AlertAction::Notify { title, message } => {
+                log::warn!(target: "alerts", "Notification: {}: {}", title.as_deref().unwrap_or(&alert.rule), message);
+            }
+            AlertAction::Webhook { url } => {
+                let url = url.clone();
+                let body = serde_json::json!({ "task_id": task_id, "alert": alert });
+                tokio::spawn(async move {
+                    if let Err(e) = post_webhook(&url, &body).await {
+                        log::error!(target: "alerts", "Webhook {} failed: {}", url, e);
+                    }
+                });
+            }
         }
     }
 }
 
-/// Writes a message prefixed with a 4-byte little-endian length.
-async fn write_message_bytes<W: AsyncWrite + Unpin>(
+/// POSTs `body` as JSON to a plain `http://` URL.
+async fn post_webhook(url: &str, body: &serde_json::Value) -> io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "only http:// webhooks are supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let body = serde_json::to_vec(body).map_err(io::Error::other)?;
+    let mut stream = tokio::net::TcpStream::connect(address).await?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path, authority, body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let status = String::from_utf8_lossy(&response).lines().next().unwrap_or_default().to_string();
+    if status.split_whitespace().nth(1).is_some_and(|code| code.starts_with('2')) {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("unexpected response {:?}", status)))
+    }
+}
+
+/// Settings pushed to the extension. `RZN_EXTENSION_CONFIG` may hold a JSON
+/// [`ExtensionConfig`] to override the defaults.
+fn extension_config() -> ExtensionConfig {
+    if let Ok(json) = std::env::var("RZN_EXTENSION_CONFIG") {
+        match serde_json::from_str(&json) {
+            Ok(config) => return config,
+            Err(e) => log::error!("Ignoring invalid RZN_EXTENSION_CONFIG: {}", e),
+        }
+    }
+    ExtensionConfig {
+        step_delay_ms: Some(250),
+        default_timeout_ms: Some(5000),
+        ..Default::default()
+    }
+}
+
+/// Sends a `configure` message to the extension. The extension answers with a
+/// `configure_ack` carrying the settings it applied.
+async fn push_configure<W: tokio::io::AsyncWrite + Unpin>(
     writer: &mut W,
-    message_bytes: &[u8],
-    log_prefix: &str,
+    mode: FramingMode,
+    channel_id: u16,
+    config: &ExtensionConfig,
+    session_id: u64,
+    seq: u64,
 ) -> io::Result<()> {
-    let len = message_bytes.len();
-    if len > MAX_MESSAGE_SIZE {
-         let err_msg = format!("Attempted to send message larger than limit: {} bytes", len);
-         log::error!("{}: {}", log_prefix, err_msg);
-        return Err(io::Error::new(ErrorKind::InvalidInput, err_msg));
+    let message = Message {
+        action: Action::Configure,
+        task_id: format!("configure-{}-{}", session_id, seq),
+        task: None,
+        data: Some(serde_json::to_value(config).map_err(io::Error::other)?),
+        ttl_ms: None,
+        msg_id: None,
+    };
+    let bytes = serde_json::to_vec(&message).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await?;
+    log::info!("Pushed configuration to extension: {:?}", config);
+    Ok(())
+}
+
+/// Human-in-the-loop decision for a destructive step: `Ok` commits, `Err`
+/// aborts with a reason. `RZN_COMMIT_POLICY=abort` rejects every such step.
+fn decide_commit(task_id: &str, request: &CommitRequest) -> Result<(), String> {
+    log::warn!("Task {} wants to run destructive step {}: {:?}", task_id, request.step_index, request.step);
+    match std::env::var("RZN_COMMIT_POLICY").as_deref() {
+        Ok("abort") => Err("destructive steps are disabled by RZN_COMMIT_POLICY".to_string()),
+        _ => Ok(()),
     }
+}
 
-    writer.write_all(&(len as u32).to_le_bytes()).await?;
-    writer.write_all(message_bytes).await?;
-    writer.flush().await?;
+/// Replies to a `commit_request` with `commit` or `abort`.
+async fn answer_commit_request<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    channel_id: u16,
+    message: &Message,
+) -> io::Result<()> {
+    let request = message.data.clone().map(serde_json::from_value::<CommitRequest>);
+    let (action, decision) = match request {
+        Some(Ok(request)) => match decide_commit(&message.task_id, &request) {
+            Ok(()) => (Action::Commit, CommitDecision { step_index: request.step_index, reason: None }),
+            Err(reason) => (Action::Abort, CommitDecision { step_index: request.step_index, reason: Some(reason) }),
+        },
+        // Can't tell what would run, so don't let it
+        _ => {
+            log::error!("Malformed commit_request for task {}, aborting.", message.task_id);
+            (Action::Abort, CommitDecision { step_index: 0, reason: Some("malformed commit_request".to_string()) })
+        }
+    };
+    let reply = Message {
+        action: action.clone(),
+        task_id: message.task_id.clone(),
+        task: None,
+        data: Some(serde_json::to_value(&decision).map_err(io::Error::other)?),
+        ttl_ms: None,
+        msg_id: None,
+    };
+    let bytes = serde_json::to_vec(&reply).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await?;
+    log::info!("Answered commit request of task {} with {}", message.task_id, action);
     Ok(())
 }
+
+/// Answers the broker's `hello` with our own, refusing brokers that speak
+/// another major protocol version.
+async fn answer_hello<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    channel_id: u16,
+    message: &Message,
+) -> io::Result<()> {
+    // Our frame reader decompresses, so the broker may compress what it sends,
+    // and RZN_ENCODING asks it for a binary encoding
+    let mut capabilities = [&["configure", "commit", "alerts"], COMPRESSION_CAPABILITIES].concat();
+    capabilities.extend(Encoding::from_env().capability());
+    let ours = Hello::new(concat!("example_app ", env!("CARGO_PKG_VERSION")), &capabilities);
+    let error = match message.data.clone().map(serde_json::from_value::<Hello>) {
+        Some(Ok(broker)) => match ours.check_compatible(&broker) {
+            Ok(()) => {
+                log::info!("Broker {} (protocol {}, capabilities {:?})",
+                           broker.software, broker.protocol_version, broker.capabilities);
+                None
+            }
+            Err(mismatch) => Some(format!("broker speaks {}", mismatch)),
+        },
+        _ => Some("malformed hello".to_string()),
+    };
+    if let Some(error) = &error {
+        log::error!("Refusing broker: {}", error);
+    }
+    let reply = ExtensionResponse {
+        action: Action::HelloAck,
+        task_id: message.task_id.clone(),
+        success: error.is_none(),
+        result: Some(serde_json::to_value(&ours).map_err(io::Error::other)?),
+        error,
+    };
+    let bytes = serde_json::to_vec(&reply).map_err(io::Error::other)?;
+    write_frame_as(writer, mode, FrameFlags::NONE, channel_id, &bytes, "ExampleAppWrite").await
+}
+
+/// Logs a summary of a `task_result`, flagging results cut to fit the task's
+/// budget, and raises the alerts it matches. Sensitive data is redacted first,
+/// so none of it reaches the log or an alert. Returns whether the task succeeded.
+fn log_task_result(message_bytes: &[u8], alerts: &AlertRules, residency: Option<&ResidencyFilter>) -> bool {
+    let mut response = match serde_json::from_slice::<ExtensionResponse>(message_bytes) {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("Malformed task_result: {}", e);
+            return false;
+        }
+    };
+    if let Some(filter) = residency {
+        let mut counts = response.result.as_mut().map(|result| filter.redact(result)).unwrap_or_default();
+        if let Some(error) = response.error.as_mut() {
+            *error = filter.redact_str(error, &mut counts);
+        }
+        if !counts.is_empty() {
+            log::warn!("Residency: Redacted {:?} from the result of task {}.", counts, response.task_id);
+        }
+    }
+    let succeeded = response.success;
+    let result = response.result.clone().map(serde_json::from_value::<TaskResult>);
+    match result {
+        Some(Ok(result)) => {
+            log::info!("Task {} finished (success: {}, steps: {}, {} bytes)",
+                       response.task_id, response.success, result.steps.len(), result.encoded_len());
+            if result.result_truncated {
+                log::warn!("Task {} result was truncated to fit its max_result_bytes.", response.task_id);
+            }
+            for alert in alerts.evaluate(&result, &Locale::default()) {
+                raise_alert(&response.task_id, alert);
+            }
+        }
+        _ => log::info!("Task {} finished (success: {}, error: {})",
+                        response.task_id, response.success, response.error.unwrap_or_default()),
+    }
+    succeeded
+}
+
+/// Logs a `selector_degraded` report from the broker.
+fn log_selector_degradation(message_bytes: &[u8]) {
+    let report = serde_json::from_slice::<ExtensionResponse>(message_bytes)
+        .ok()
+        .and_then(|response| response.result)
+        .and_then(|result| serde_json::from_value::<SelectorDegradation>(result).ok());
+    match report {
+        Some(report) => log::warn!(
+            "Selector {} ({} step {}) stopped working on {} after {} successful runs (last success at {} ms): {}",
+            report.selector, report.step_type, report.step_index, report.origin, report.successes,
+            report.last_success_ms, report.error.unwrap_or_default()
+        ),
+        None => log::error!("Malformed selector_degraded report"),
+    }
+}
+
+/// Logs a `broker_state` notification from the broker.
+fn log_broker_state(message_bytes: &[u8], session_id: u64) {
+    let change = serde_json::from_slice::<ExtensionResponse>(message_bytes)
+        .ok()
+        .and_then(|response| response.result)
+        .and_then(|result| serde_json::from_value::<BrokerStateChange>(result).ok());
+    match change {
+        Some(change) => log::info!("Broker on session {} is now {:?} (was {:?})", session_id, change.state, change.previous),
+        None => log::error!("Malformed broker_state notification"),
+    }
+}
+
+/// Routes a `log` message from the extension into this app's logger,
+/// under the `extension::session-<id>` target.
+fn forward_extension_log(message: &Message, session_id: u64) {
+    let target = format!("extension::session-{}", session_id);
+    let record = message.data.clone().map(serde_json::from_value::<ExtensionLog>);
+    match record {
+        Some(Ok(record)) => {
+            log::log!(target: &target, record.level.into(), "[{}] (task_id: {}) {}",
+                      record.scope, message.task_id, record.message);
+        }
+        Some(Err(e)) => log::warn!("Received malformed extension log record: {}", e),
+        None => log::warn!("Received extension log message without data."),
+    }
+}
@@ -0,0 +1,197 @@
+//! End-to-end run of the bridge in Firefox.
+//!
+//! `firefox_example install` registers the broker built next to it for the
+//! fixture extension in `extension/` (add-on ID [`FIREFOX_ID`]). Run without
+//! arguments, it serves the fixture pages on a local port, waits for the
+//! broker Firefox starts for the extension and pairs with it: type the code
+//! the extension logs in its console. Then it scrapes the page through the
+//! bridge, the heading and every item of `/scrape`, and exits with status 0
+//! if they match, so a run shows the Firefox path works from the host
+//! manifest to a task result. See README.md for the steps.
+//!
+//! Settings, from the environment:
+//! * `RZN_EXAMPLE_PORT`: port of the fixture pages (default: any free one);
+//! * `RZN_BROKER_PATH` and `RZN_FIREFOX_ID`: the broker and add-on ID to
+//!   register, as for `example_app`.
+
+use std::io::{self, ErrorKind};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::watch;
+
+use rzn_bridge_client::{BridgeClient, BridgeServer, ClientOptions, FixtureServer};
+use shared_types::{Action, Browser, ExtensionResponse, Message, PairRequest, Pairings, PairingStatus, Registration, SealKey, Step, Task, PAIR_ACTION};
+
+/// Add-on ID in the fixture extension's manifest.
+const FIREFOX_ID: &str = "rzn-bridge-example@rzn.dev";
+/// What the extension scrapes from the fixture server's `/scrape`.
+const HEADING: &str = "Rzn:Browser Bridge in Firefox";
+const ITEMS: [&str; 3] = ["Alpha", "Beta", "Gamma"];
+/// Session the pairing codes are handed out for; there is one connection.
+const SESSION: u64 = 1;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    shared_types::logging::init("firefox_example");
+    let outcome = match std::env::args().nth(1).as_deref() {
+        Some("install") => install(),
+        None => run().await,
+        Some(other) => Err(io::Error::new(ErrorKind::InvalidInput, format!("unknown command {:?} (try \"install\")", other))),
+    };
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The broker next to this executable, for the fixture extension unless
+/// `RZN_FIREFOX_ID` names another.
+fn registration() -> io::Result<Registration> {
+    let mut registration = Registration::from_env()?;
+    registration.firefox_id.get_or_insert_with(|| FIREFOX_ID.to_string());
+    Ok(registration)
+}
+
+fn install() -> io::Result<()> {
+    let registration = registration()?;
+    let path = registration.register(Browser::Firefox)?;
+    println!("Registered {} for {} in {}", registration.broker.display(), FIREFOX_ID, path.display());
+    println!("Restart Firefox if it is running, then load the extension (see README.md).");
+    Ok(())
+}
+
+async fn run() -> io::Result<()> {
+    let status = registration()?.verify(Browser::Firefox);
+    if !status.is_registered() {
+        log::warn!("The broker isn't registered for Firefox ({}); run \"firefox_example install\" first.", status);
+    }
+
+    let port = std::env::var("RZN_EXAMPLE_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(0);
+    let fixtures = FixtureServer::bind(("127.0.0.1", port)).await?;
+    let url = fixtures.url("/scrape");
+    println!("Serving the page at {}", url);
+
+    let pairing = Arc::new(Pairing::load());
+    let mut server = BridgeServer::bind_current(ClientOptions { software: concat!("firefox_example ", env!("CARGO_PKG_VERSION")).to_string(), ..ClientOptions::default() })?;
+    let handler = pairing.clone();
+    server.on_action(PAIR_ACTION, move |message: Message| {
+        let pairing = handler.clone();
+        async move { Ok(pairing.answer(message)) }
+    });
+    println!("Waiting for the extension; start Firefox with it loaded (\"npm start\" in extension/)");
+    let (client, _events) = server.accept().await?;
+    pairing.wait(&client).await?;
+
+    let steps: Vec<Step> = serde_json::from_value(serde_json::json!([
+        { "type": "navigate", "url": url },
+        { "type": "extract", "selector": "#heading", "target": "text", "variable_name": "heading", "trim": true },
+        { "type": "extract", "selector": "li.item", "target": "text", "variable_name": "items", "all": true, "trim": true },
+    ]))
+    .map_err(io::Error::other)?;
+    let result = client.send_task(Task { steps, ..Task::default() }).await.map_err(io::Error::other)?;
+    let scraped = |name: &str| result.steps.iter().find_map(|step| step.data.as_ref()?.get(name).cloned()).unwrap_or_default();
+    let (heading, items) = (scraped("heading"), scraped("items"));
+    println!("Scraped heading {} and items {}", heading, items);
+    if heading != HEADING || items != serde_json::json!(ITEMS) {
+        return Err(io::Error::other(format!("expected heading {:?} and items {:?}", HEADING, ITEMS)));
+    }
+    println!("Firefox works end to end.");
+    Ok(())
+}
+
+/// The pairings, kept where `example_app` keeps them, and the token the
+/// extension is paired with, once it is.
+struct Pairing {
+    store: Mutex<Pairings>,
+    paired: watch::Sender<Option<String>>,
+}
+
+impl Pairing {
+    fn load() -> Self {
+        let store = match Pairings::default_path().map(Pairings::load) {
+            Some(Ok(store)) => store,
+            Some(Err(e)) => {
+                log::error!("Pairing: Could not read the pairings, keeping new ones in memory: {}", e);
+                Pairings::default()
+            }
+            None => Pairings::default(),
+        };
+        Pairing { store: Mutex::new(store), paired: watch::channel(None).0 }
+    }
+
+    /// Answers a `pair`: paired for a known token, else a code for the
+    /// extension to show.
+    fn answer(&self, message: Message) -> ExtensionResponse {
+        let request: PairRequest = message.data.clone().and_then(|data| serde_json::from_value(data).ok()).unwrap_or_default();
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let status = match request.token.clone().filter(|token| store.is_paired(token)) {
+            Some(token) => {
+                log::info!("Pairing: The extension is paired.");
+                let sealed = store.seal_key(&token).is_ok_and(|key| key.is_some());
+                self.paired.send_replace(Some(token.clone()));
+                PairingStatus::Paired { token, sealed }
+            }
+            None => match store.request(SESSION, request) {
+                Ok(status) => {
+                    println!("Enter the pairing code the extension logs in its console:");
+                    status
+                }
+                Err(e) => {
+                    log::error!("Pairing: Could not create a pairing code: {}", e);
+                    PairingStatus::Pending { code: String::new() }
+                }
+            },
+        };
+        pair_result(&message.task_id, &status)
+    }
+
+    /// Waits until the extension is paired, confirming the codes typed in
+    /// meanwhile and sending it its token, then seals with the pairing's key
+    /// if it agreed on one.
+    async fn wait(&self, client: &BridgeClient) -> io::Result<()> {
+        let mut paired = self.paired.subscribe();
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            tokio::select! {
+                token = paired.wait_for(Option::is_some) => {
+                    let token = token.map_err(io::Error::other)?.clone().unwrap_or_default();
+                    client.seal_with(self.seal_key(&token));
+                    return Ok(());
+                }
+                line = lines.next_line() => {
+                    let Some(code) = line? else {
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof, "stdin closed before the extension was paired"));
+                    };
+                    let confirmed = self.store.lock().unwrap_or_else(|e| e.into_inner()).confirm_pairing(code.trim())?;
+                    let Some(confirmed) = confirmed else {
+                        println!("No extension shows {:?}; codes expire after 5 minutes.", code.trim());
+                        continue;
+                    };
+                    client.respond(&pair_result(&format!("pair-{}", SESSION), &confirmed.status())).await.map_err(io::Error::other)?;
+                    log::info!("Pairing: Paired the extension{}.", if confirmed.sealed { ", payloads are sealed" } else { "" });
+                    client.seal_with(self.seal_key(&confirmed.token));
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// The seal key of the pairing behind `token`, if one was agreed.
+    fn seal_key(&self, token: &str) -> Option<SealKey> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner()).seal_key(token).unwrap_or_else(|e| {
+            log::error!("Pairing: Could not read the seal keys: {}", e);
+            None
+        })
+    }
+}
+
+fn pair_result(task_id: &str, status: &PairingStatus) -> ExtensionResponse {
+    let result = serde_json::to_value(status).unwrap_or(Value::Null);
+    ExtensionResponse { action: Action::PairResult, task_id: task_id.to_string(), success: true, result: Some(result), error: None }
+}
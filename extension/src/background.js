@@ -6,6 +6,32 @@ let port = null;
 let isTestRunning = false; // Add this flag to prevent multiple simultaneous tests
 let initialConnectionAttempted = false; // Track if we've already tried to connect
 let reconnectAttempts = 0; // Count reconnection attempts
+let bridgeState = null; // Broker's Main App connection state ("dormant" | "active" | "reconnecting" | "shutdown"), if reported
+let brokerHello = null; // The broker's hello_ack result (protocol version, software, capabilities)
+let brokerState = null; // Broker lifecycle state from "broker_state" (e.g. "ipc_connected", "ipc_lost"), if reported
+let pairingCode = null; // Code to enter in the host application while pairing is pending
+let pairingSettled = false; // Whether the host answered our "pair" on this connection: paired, or no pairing needed
+
+// Protocol version spoken by this extension (shared_types PROTOCOL_VERSION)
+const PROTOCOL_VERSION = "1.0";
+const CAPABILITIES = ["regex", "value_type", "handles", "shadow_dom", "commit", "configure", "log_forwarding", "pause", "chunking", "sealed", "cancel", "step_events", "msg_id", "select", "keyboard", "scroll", "resolve_selectors", "hover", "drag_and_drop"];
+
+// Settings pushed by the host via "configure" (see applyConfig)
+const DEFAULT_CONFIG = {
+    step_delay_ms: 0,          // Pause between task steps, to be polite to sites
+    default_timeout_ms: 5000,  // Element wait timeout when a step doesn't set one
+    features: {
+        forward_logs: true,    // Forward bridgeLog records to the host
+        step_events: true      // Report each step's progress (see reportStep)
+    }
+};
+let extensionConfig = structuredClone(DEFAULT_CONFIG);
+
+// Whether the Main App behind the broker can run tasks right now. Brokers that
+// don't report their lifecycle are assumed online while the port is open.
+function isBackendOnline() {
+    return port !== null && (brokerState === null || brokerState === "ipc_connected");
+}
 
 // --- Function to send a simple test message ---
 function sendSimplePing() {
@@ -35,6 +61,504 @@ function sendSimplePing() {
 }
 // --- End of simple test message function ---
 
+// --- Structured log forwarding to the host ---
+// Logs to the console and, when connected, forwards the record to the Main App
+// so extension-side errors end up in the host's logs.
+// level: "error" | "warn" | "info" | "debug" | "trace"
+function bridgeLog(level, scope, message, taskId = null) {
+    const consoleFn = { error: console.error, warn: console.warn, debug: console.debug, trace: console.debug }[level] || console.log;
+    consoleFn(`[${scope}]${taskId ? ` (task ${taskId})` : ""} ${message}`);
+    if (!port || !extensionConfig.features.forward_logs) {
+        return; // Console only while disconnected or when forwarding is off
+    }
+    postToHost({
+        action: "log",
+        task_id: taskId || "",
+        data: { level, scope, message: String(message) }
+    }).catch(error => console.error("Error forwarding log record:", error));
+}
+// --- End of structured log forwarding ---
+
+// --- Step progress ---
+// Tells the host how a task's steps are getting on: step_started, step_progress
+// and step_completed, keyed by task_id and step_index (see shared_types'
+// StepStarted, StepProgress and StepCompleted). Awaited, so they reach the host
+// in order and ahead of the task_result.
+async function reportStep(action, taskId, data) {
+    if (!port || !extensionConfig.features.step_events) {
+        return;
+    }
+    try {
+        await postToHost({ action, task_id: taskId, data });
+    } catch (error) {
+        console.error(`Task ${taskId}: Error reporting ${action}:`, error);
+    }
+}
+// --- End of step progress ---
+
+// --- Duplicate deliveries ---
+// Every message from the host carries a msg_id (see shared_types::delivery). The
+// broker drops messages sent again under an ID it has seen; this catches those
+// that reach a new broker, e.g. one started after the native host went away.
+const MAX_DELIVERED_IDS = 1000;
+const deliveredIds = new Set(); // In arrival order, oldest first
+
+function isFirstDelivery(msgId) {
+    if (deliveredIds.has(msgId)) {
+        return false;
+    }
+    deliveredIds.add(msgId);
+    if (deliveredIds.size > MAX_DELIVERED_IDS) {
+        deliveredIds.delete(deliveredIds.values().next().value);
+    }
+    return true;
+}
+// --- End of duplicate deliveries ---
+
+// --- Host configuration ---
+// Merges the fields present in a "configure" message into the current settings
+// and acknowledges with the full config now in effect.
+function applyConfig(message) {
+    const update = message.data || {};
+    try {
+        for (const key of ["step_delay_ms", "default_timeout_ms"]) {
+            if (update[key] === undefined || update[key] === null) continue;
+            if (!Number.isInteger(update[key]) || update[key] < 0) {
+                throw new Error(`${key} must be a non-negative integer`);
+            }
+        }
+        extensionConfig = {
+            ...extensionConfig,
+            ...(update.step_delay_ms != null && { step_delay_ms: update.step_delay_ms }),
+            ...(update.default_timeout_ms != null && { default_timeout_ms: update.default_timeout_ms }),
+            features: { ...extensionConfig.features, ...(update.features || {}) }
+        };
+        console.log("Applied host configuration:", extensionConfig);
+        port?.postMessage({
+            action: "configure_ack",
+            task_id: message.task_id,
+            success: true,
+            result: extensionConfig
+        });
+    } catch (error) {
+        bridgeLog("warn", "applyConfig", `Rejected configuration: ${error.message}`, message.task_id);
+        port?.postMessage({
+            action: "configure_ack",
+            task_id: message.task_id,
+            success: false,
+            result: extensionConfig,
+            error: error.message
+        });
+    }
+}
+// --- End of host configuration ---
+
+// --- Chunked transfer ---
+// Messages over CHUNK_THRESHOLD characters go to the host as chunk_start,
+// chunk_data pieces and chunk_end (see shared_types::chunk); the broker sends
+// large messages to us the same way and reassembleChunk puts them together.
+const CHUNK_THRESHOLD = 1024 * 1024;
+const CHUNK_TEXT_LEN = 128 * 1024;
+const CHUNK_ACTIONS = new Set(["chunk_start", "chunk_data", "chunk_end"]);
+const incomingTransfers = new Map(); // message_id -> { start, pieces }
+let outgoingTransfers = 0;
+
+// Sends message to the host, sealed if we agreed on a key with it and in pieces if it is large
+async function postToHost(message) {
+    if (sealKey) {
+        message = await seal(message);
+    }
+    const text = JSON.stringify(message);
+    if (text.length <= CHUNK_THRESHOLD) {
+        port?.postMessage(message);
+        return;
+    }
+    const pieces = [];
+    for (let start = 0; start < text.length;) {
+        let end = Math.min(start + CHUNK_TEXT_LEN, text.length);
+        // Don't split a surrogate pair
+        if (end < text.length && /[\uD800-\uDBFF]/.test(text[end - 1])) {
+            end--;
+        }
+        pieces.push(text.slice(start, end));
+        start = end;
+    }
+    const messageId = `chunk-${Date.now()}-${++outgoingTransfers}`;
+    const taskId = message.task_id;
+    const totalLen = new TextEncoder().encode(text).length;
+    console.log(`Sending ${totalLen} byte ${message.action} in ${pieces.length} chunks`);
+    port?.postMessage({ action: "chunk_start", task_id: taskId, data: { message_id: messageId, total_len: totalLen, chunks: pieces.length, action: message.action } });
+    pieces.forEach((data, seq) => {
+        port?.postMessage({ action: "chunk_data", task_id: taskId, data: { message_id: messageId, seq, data } });
+    });
+    port?.postMessage({ action: "chunk_end", task_id: taskId, data: { message_id: messageId, chunks: pieces.length } });
+}
+
+// Takes a chunk_* message from the host; returns the whole message once its
+// chunk_end arrives, otherwise null
+function reassembleChunk(message) {
+    const { message_id: messageId } = message.data || {};
+    if (message.action === "chunk_start") {
+        incomingTransfers.set(messageId, { start: message.data, pieces: [] });
+        return null;
+    }
+    const transfer = incomingTransfers.get(messageId);
+    if (!transfer) {
+        console.error(`Chunk for unknown transfer ${messageId}`);
+        return null;
+    }
+    if (message.action === "chunk_data") {
+        if (message.data.seq !== transfer.pieces.length) {
+            console.error(`Transfer ${messageId}: expected chunk ${transfer.pieces.length}, got ${message.data.seq}`);
+            incomingTransfers.delete(messageId);
+            return null;
+        }
+        transfer.pieces.push(message.data.data);
+        return null;
+    }
+    incomingTransfers.delete(messageId);
+    if (transfer.pieces.length !== transfer.start.chunks) {
+        console.error(`Transfer ${messageId}: got ${transfer.pieces.length} of ${transfer.start.chunks} chunks`);
+        return null;
+    }
+    try {
+        return JSON.parse(transfer.pieces.join(""));
+    } catch (error) {
+        console.error(`Transfer ${messageId}: ${error.message}`);
+        return null;
+    }
+}
+// --- End of chunked transfer ---
+
+// --- Sealed payloads ---
+// A host that seals (see shared_types::sealed) agrees on an AES-GCM key with us while pairing,
+// with ECDH P-256 keys bound to the code the user confirms (see shared_types::pairing): our "pair"
+// commits to our key, the host offers its own, we reveal ours and show the code derived from both.
+// Sealed messages keep their task_id in the clear; the broker in between only relays ciphertext.
+const SEAL_INFO = new TextEncoder().encode("rzn-browser-bridge sealed v1");
+const PAIRING_CODE_INFO = new TextEncoder().encode("rzn-browser-bridge pairing code v1");
+const REPLAY_WINDOW_US = 120 * 1000 * 1000; // shared_types::sealed::REPLAY_WINDOW
+let sealKey = null;         // AES-GCM key shared with the host, while it seals
+let lastSealedSeq = 0;      // Sequence number of the last message we sealed
+let openedSeqs = new Set(); // Sequence numbers of the host's messages opened within the replay window
+let sealedReplaysRejected = 0; // Sealed messages from the host dropped as replays
+let pairingExchange = null; // { keyPair, publicKey, sealKey, code } while a pairing is pending
+let openedInOrder = Promise.resolve(); // Sealed messages from the host, being opened
+let sealKeyLoaded = false; // Whether the stored key was loaded on connect; host messages wait for it
+let waitingForSealKey = Promise.resolve(); // Host messages that came before it was
+// What may still come unsealed once we seal (shared_types::sealed::UNSEALED_ACTIONS); the broker
+// can forge these, so they only ever log or fail, never answer a task
+const UNSEALED_ACTIONS = new Set([
+    "hello", "hello_ack", "ping", "pong", "bridge_error", "ack", "broker_state", "bridge_state", "shutdown", "message_too_large",
+    "dead_letter", "bridge_stats", "bridge_stats_result", "bridge_history", "bridge_history_result", "selector_degraded",
+    "bridge_selftest_result", "pair", "pair_result",
+]);
+
+function toBase64(buffer) {
+    const bytes = new Uint8Array(buffer);
+    let binary = "";
+    for (let i = 0; i < bytes.length; i += 0x8000) {
+        binary += String.fromCharCode(...bytes.subarray(i, i + 0x8000));
+    }
+    return btoa(binary);
+}
+
+function fromBase64(text) {
+    return Uint8Array.from(atob(text), c => c.charCodeAt(0));
+}
+
+// Derives the shared key from the host's public key (ECDH, then HKDF-SHA256)
+async function agreeSealKey(hostPublicKey) {
+    const theirs = await crypto.subtle.importKey("raw", hostPublicKey, { name: "ECDH", namedCurve: "P-256" }, false, []);
+    const secret = await crypto.subtle.deriveBits({ name: "ECDH", public: theirs }, pairingExchange.keyPair.privateKey, 256);
+    const hkdfKey = await crypto.subtle.importKey("raw", secret, "HKDF", false, ["deriveKey"]);
+    return crypto.subtle.deriveKey(
+        { name: "HKDF", hash: "SHA-256", salt: new Uint8Array(), info: SEAL_INFO },
+        hkdfKey, { name: "AES-GCM", length: 256 }, true, ["encrypt", "decrypt"]);
+}
+
+// What a sealed message binds besides its text: who sealed it, its sequence number and task
+// (shared_types::sealed), so the broker can't move it to another task or send it back to us
+function sealedAssociatedData(sealedBy, seq, taskId) {
+    return new TextEncoder().encode(`${sealedBy}:${seq}:${taskId}`);
+}
+
+async function seal(message) {
+    const taskId = message.task_id ?? "N/A";
+    // Microseconds since the epoch, so the numbers keep growing across restarts
+    lastSealedSeq = Math.max(Date.now() * 1000, lastSealedSeq + 1);
+    const seq = lastSealedSeq;
+    const nonce = crypto.getRandomValues(new Uint8Array(12));
+    const plaintext = new TextEncoder().encode(JSON.stringify(message));
+    const ciphertext = await crypto.subtle.encrypt(
+        { name: "AES-GCM", iv: nonce, additionalData: sealedAssociatedData("extension", seq, taskId) }, sealKey, plaintext);
+    return { action: "sealed", task_id: taskId, data: { seq, nonce: toBase64(nonce), ciphertext: toBase64(ciphertext) } };
+}
+
+// The message a "sealed" one wraps, or null if it can't be opened or is a replay: opened before,
+// or sealed further than the replay window from now
+async function openSealed(message) {
+    if (!sealKey) {
+        console.error(`Sealed message for task ${message.task_id}, but no key was agreed with the host`);
+        return null;
+    }
+    try {
+        const plaintext = await crypto.subtle.decrypt(
+            { name: "AES-GCM", iv: fromBase64(message.data.nonce), additionalData: sealedAssociatedData("main_app", message.data.seq, message.task_id) },
+            sealKey, fromBase64(message.data.ciphertext));
+        const seq = message.data.seq;
+        const now = Date.now() * 1000;
+        if (Math.abs(seq - now) > REPLAY_WINDOW_US || openedSeqs.has(seq)) {
+            sealedReplaysRejected += 1;
+            console.error(`Sealed message for task ${message.task_id} is a replay (seq ${seq}, ${sealedReplaysRejected} rejected so far)`);
+            return null;
+        }
+        openedSeqs.add(seq);
+        // Older ones are rejected for their age alone
+        openedSeqs = new Set([...openedSeqs].filter(opened => opened >= now - REPLAY_WINDOW_US));
+        return JSON.parse(new TextDecoder().decode(plaintext));
+    } catch (error) {
+        console.error(`Sealed message for task ${message.task_id} could not be opened:`, error);
+        return null;
+    }
+}
+
+// The code shown while pairing, from our public key and the host's (shared_types::pairing)
+async function pairingCodeFor(extensionKey, hostKey) {
+    const hash = await crypto.subtle.digest("SHA-256", new Uint8Array([...PAIRING_CODE_INFO, ...extensionKey, ...hostKey]));
+    const digits = String(new DataView(hash).getUint32(0) % 1000000).padStart(6, "0");
+    return `${digits.slice(0, 3)}-${digits.slice(3)}`;
+}
+
+// Keeps the key of a pairing that seals. A key we have is only forgotten for a sealed
+// pair_result, which only the host can send, or by unpair(): the broker can't stop us sealing.
+async function updateSealKey(status, storedSealKey, wasSealed) {
+    if (status.sealed && pairingExchange?.sealKey) {
+        sealKey = pairingExchange.sealKey;
+        await chrome.storage.local.set({ sealKey: toBase64(await crypto.subtle.exportKey("raw", sealKey)) });
+        console.log("Payloads to and from the host application are sealed");
+    } else if (storedSealKey && (status.sealed || !wasSealed)) {
+        if (!status.sealed) {
+            console.error("Pairing: Still sealing, an unsealed pair_result can't turn it off (see unpair())");
+        }
+        sealKey = await importSealKey(storedSealKey);
+    } else {
+        sealKey = null;
+        await chrome.storage.local.remove("sealKey");
+    }
+    pairingExchange = null;
+}
+
+// The key updateSealKey stored, in base64
+function importSealKey(storedSealKey) {
+    return crypto.subtle.importKey("raw", fromBase64(storedSealKey), "AES-GCM", false, ["encrypt", "decrypt"]);
+}
+
+// Forgets our pairing and its seal key, e.g. to pair with another host application. Run it
+// from the service worker's console; the next connection pairs anew.
+async function unpair() {
+    await chrome.storage.local.remove(["pairingToken", "sealKey"]);
+    sealKey = null;
+    pairingExchange = null;
+    pairingCode = null;
+    console.warn("Pairing: Forgot the pairing and its seal key, reconnect to pair again");
+}
+// --- End of sealed payloads ---
+
+// --- Two-phase commit for destructive steps ---
+// Steps flagged `destructive: true` only run once the host replies "commit".
+const COMMIT_TIMEOUT_MS = 120000; // No answer counts as abort
+const pendingCommits = new Map(); // "taskId:stepIndex" -> resolve({ commit, reason })
+
+function requestCommit(taskId, stepIndex, step) {
+    if (!port) {
+        return Promise.resolve({ commit: false, reason: "native host disconnected" });
+    }
+    const key = `${taskId}:${stepIndex}`;
+    return new Promise(resolve => {
+        const timer = setTimeout(() => {
+            pendingCommits.delete(key);
+            resolve({ commit: false, reason: `no commit decision within ${COMMIT_TIMEOUT_MS}ms` });
+        }, COMMIT_TIMEOUT_MS);
+        pendingCommits.set(key, (decision) => { clearTimeout(timer); resolve(decision); });
+        postToHost({
+            action: "commit_request",
+            task_id: taskId,
+            data: { step_index: stepIndex, step }
+        }).catch(error => console.error("Error sending commit request:", error));
+    });
+}
+
+// Handles a "commit" or "abort" reply from the host
+function resolveCommit(message) {
+    const key = `${message.task_id}:${message.data?.step_index}`;
+    const resolve = pendingCommits.get(key);
+    if (!resolve) {
+        console.warn("Commit decision for unknown or expired request:", message);
+        return;
+    }
+    pendingCommits.delete(key);
+    resolve({ commit: message.action === "commit", reason: message.data?.reason || null });
+}
+// --- End of two-phase commit ---
+
+// --- Pause-all switch ---
+// The host's "pause_all" stops every running task before its next step until "resume_all".
+// The pause outlives a broker restart; only the host lifts it.
+let automationPaused = false;
+let resumeWaiters = []; // resolve() of tasks waiting for "resume_all"
+
+function setPaused(message) {
+    automationPaused = message.action === "pause_all";
+    if (automationPaused) {
+        bridgeLog("warn", "pause", `Automation paused: ${message.data?.reason || "no reason given"}`, message.task_id);
+    } else {
+        bridgeLog("info", "pause", `Automation resumed, ${resumeWaiters.length} task(s) continue`, message.task_id);
+        resumeWaiters.forEach(resolve => resolve());
+        resumeWaiters = [];
+    }
+}
+
+function waitWhilePaused() {
+    return automationPaused ? new Promise(resolve => resumeWaiters.push(resolve)) : Promise.resolve();
+}
+// --- End of pause-all switch ---
+
+// --- Task cancellation ---
+// The host's "cancel_task" stops a running task before its next step, also while it waits
+// for "resume_all" or a commit decision. The step already running finishes first.
+const runningTasks = new Map(); // taskId -> { reason, cancelled: Promise resolving with the reason, cancel(reason) }
+
+function trackTask(taskId) {
+    let resolve;
+    const task = { reason: null, cancelled: new Promise(r => { resolve = r; }) };
+    task.cancel = (reason) => { task.reason = reason; resolve(reason); };
+    runningTasks.set(taskId, task);
+    return task;
+}
+
+// Handles a "cancel_task" from the host and acknowledges it with "task_cancelled"
+function cancelTask(message) {
+    const task = runningTasks.get(message.task_id);
+    if (task && task.reason === null) {
+        const reason = message.data?.reason || "cancelled by host";
+        bridgeLog("warn", "cancelTask", `Cancelling task: ${reason}`, message.task_id);
+        task.cancel(reason);
+        // A pending commit decision counts as abort
+        for (const [key, resolve] of pendingCommits) {
+            if (key.startsWith(`${message.task_id}:`)) {
+                pendingCommits.delete(key);
+                resolve({ commit: false, reason });
+            }
+        }
+    }
+    postToHost({
+        action: "task_cancelled",
+        task_id: message.task_id,
+        success: true,
+        result: { cancelled: !!task }
+    }).catch(error => console.error(`Task ${message.task_id}: Cannot acknowledge cancellation:`, error));
+}
+
+function throwIfCancelled(task) {
+    if (task.reason !== null) {
+        throw stepError(`Cancelled: ${task.reason}`, "cancelled");
+    }
+}
+// --- End of task cancellation ---
+
+// --- Pairing with the host application ---
+// A host that requires pairing answers our "pair" with a one-time code to show the user,
+// who enters it in the host application. The token we then get proves this browser on later connections.
+// A new pairing commits to an ECDH public key, for sealed payloads if the host seals.
+async function requestPairing(pairingToken) {
+    const data = { extension_id: chrome.runtime.id, ...(pairingToken ? { token: pairingToken } : {}) };
+    if (!pairingToken) {
+        const keyPair = await crypto.subtle.generateKey({ name: "ECDH", namedCurve: "P-256" }, false, ["deriveBits"]);
+        const publicKey = new Uint8Array(await crypto.subtle.exportKey("raw", keyPair.publicKey));
+        pairingExchange = { keyPair, publicKey, sealKey: null, code: null };
+        data.key_commitment = toBase64(await crypto.subtle.digest("SHA-256", publicKey));
+    }
+    port?.postMessage({ action: "pair", task_id: `pair-${Date.now()}`, data });
+}
+
+// Answers the host's key with ours and shows the code derived from both
+async function revealPairingKey(hostPublicKey) {
+    if (!pairingExchange || pairingExchange.code) {
+        console.warn("Pairing: Ignoring a key offer we didn't ask for");
+        return;
+    }
+    const hostKey = fromBase64(hostPublicKey);
+    pairingExchange.sealKey = await agreeSealKey(hostKey);
+    pairingExchange.code = await pairingCodeFor(pairingExchange.publicKey, hostKey);
+    pairingCode = pairingExchange.code;
+    port?.postMessage({
+        action: "pair",
+        task_id: `pair-${Date.now()}`,
+        data: { extension_id: chrome.runtime.id, public_key: toBase64(pairingExchange.publicKey) }
+    });
+    console.warn(`Pairing: enter the code ${pairingCode} in the host application to allow this browser`);
+}
+
+// An incompatible broker answers with a bridge_error (E_PROTOCOL_VERSION), and so does one
+// that finds our pairing revoked by the host (E_REVOKED)
+function sayHello(pairingToken) {
+    port?.postMessage({
+        action: "hello",
+        task_id: `hello-${Date.now()}`,
+        data: {
+            protocol_version: PROTOCOL_VERSION,
+            software: `rzn_extension ${chrome.runtime.getManifest().version}`,
+            capabilities: CAPABILITIES,
+            ...(pairingToken ? { pairing_token: pairingToken } : {})
+        }
+    });
+}
+
+function handlePairResult(message, wasSealed) {
+    const status = message.result || {};
+    if (status.state === "key_offered") {
+        revealPairingKey(status.public_key).catch(error => console.error("Agreeing on a seal key failed:", error));
+    } else if (status.state === "pending" && pairingExchange?.code) {
+        // The host derived its code from the keys it got; another code means they were swapped on the way
+        if (status.code !== pairingExchange.code) {
+            console.error("Pairing: The host derived another code from our keys; they were changed on the way, not pairing");
+            pairingCode = null;
+            pairingExchange = null;
+        }
+    } else if (status.state === "pending") {
+        pairingCode = status.code;
+        console.warn(`Pairing: enter the code ${pairingCode} in the host application to allow this browser`);
+    } else if (status.state === "paired") {
+        pairingCode = null;
+        pairingSettled = true;
+        chrome.storage.local.set({ pairingToken: status.token });
+        console.log("Paired with the host application");
+        chrome.storage.local.get("sealKey")
+            .then(({ sealKey: storedSealKey }) => updateSealKey(status, storedSealKey, wasSealed))
+            .catch(error => console.error("Agreeing on a seal key failed:", error));
+    } else {
+        pairingCode = null;
+        pairingSettled = status.state === "not_required";
+    }
+}
+// --- End of pairing ---
+
+// --- Bridge self-test (answered by the broker itself) ---
+function runBridgeSelfTest() {
+    if (!port) {
+        console.error("Cannot run self-test: Native host not connected.");
+        return;
+    }
+    const selfTestMessage = {
+        action: "bridge_selftest",
+        task_id: `selftest-${Date.now()}`
+    };
+    console.log("Running bridge self-test:", selfTestMessage);
+    port.postMessage(selfTestMessage);
+}
+// --- End of bridge self-test ---
+
 function connectToNative() {
     // Prevent repeated connection attempts during startup
     if (initialConnectionAttempted && port) {
@@ -61,13 +585,108 @@ function connectToNative() {
         port = chrome.runtime.connectNative(HOST_NAME);
         reconnectAttempts = 0; // Reset attempts on successful connection start
 
-        port.onMessage.addListener((message) => {
+        port.onMessage.addListener(function onHostMessage(message, _port, wasSealed = false) {
+            // Nothing is handled before the stored seal key is loaded; what came meanwhile follows in order
+            if (!sealKeyLoaded) {
+                waitingForSealKey = waitingForSealKey.then(() => sealKeyLoaded && onHostMessage(message, _port, wasSealed));
+                return;
+            }
+            // Large messages arrive in pieces and are handled once whole
+            if (CHUNK_ACTIONS.has(message.action)) {
+                message = reassembleChunk(message);
+                if (!message) {
+                    return;
+                }
+            }
+            // A message delivered before already ran
+            if (message.msg_id && !isFirstDelivery(message.msg_id)) {
+                bridgeLog("warn", "onHostMessage", `Ignoring ${message.action} ${message.msg_id}, it was delivered before`, message.task_id);
+                return;
+            }
+            // Sealed messages are handled once opened, in the order they came
+            if (message.action === "sealed") {
+                openedInOrder = openedInOrder
+                    .then(() => openSealed(message))
+                    .then(opened => opened && onHostMessage(opened, port, true));
+                return;
+            }
+            // Once we seal, and until the host answered our pairing, only the broker's own messages
+            // and the pairing may come unsealed
+            if (!wasSealed && !UNSEALED_ACTIONS.has(message.action) && (sealKey || !pairingSettled)) {
+                console.error(sealKey
+                    ? `Dropping unsealed ${message.action} for task ${message.task_id}, the host agreed to seal its messages`
+                    : `Dropping ${message.action} for task ${message.task_id}, the host hasn't answered our pairing yet`);
+                return;
+            }
+
             // --- Updated Message Handling ---
             console.log("<<< Received message from native host:", message);
 
             if (message.action === "pong") {
                 console.log("Received PONG response:", message);
                 // Handle the pong response (e.g., update UI, confirm connection)
+            } else if (message.action === "bridge_selftest_result") {
+                // Report covers framing, IPC connectivity and latency
+                if (message.success) {
+                    console.log("Bridge self-test passed:", message.result);
+                } else {
+                    console.error("Bridge self-test failed:", message.error, message.result);
+                }
+            } else if (message.action === "bridge_error") {
+                // Structured error from the broker (e.g. failed startup checks)
+                console.error(`Bridge error ${message.result?.code}:`, message.error, message.result);
+                if (message.task_id?.startsWith("pair-") && message.result?.code !== "E_REVOKED") {
+                    // A host that doesn't pair doesn't know "pair" either
+                    pairingSettled = true;
+                }
+                if (message.result?.code === "E_REVOKED") {
+                    // The host banned this browser; the token stays so it keeps being refused
+                    pairingCode = null;
+                    bridgeState = "revoked";
+                }
+            } else if (message.action === "message_too_large") {
+                // One of our messages (e.g. a big task_result) was over the limit and never reached the host
+                console.error(`Message for task ${message.task_id} dropped as too large:`, message.error, message.result);
+            } else if (message.action === "pair_result") {
+                handlePairResult(message, wasSealed);
+            } else if (message.action === "hello_ack") {
+                brokerHello = message.result || null;
+                if (message.success) {
+                    console.log("Broker handshake:", brokerHello);
+                } else {
+                    console.error("Broker handshake failed:", message.error, brokerHello);
+                }
+            } else if (message.action === "bridge_state") {
+                // A lazy broker starts "dormant" and connects to the Main App on the first real message;
+                // "reconnecting" means the Main App went away and the broker is holding messages for it
+                bridgeState = message.result?.state || null;
+                console.log("Bridge state:", bridgeState);
+            } else if (message.action === "dead_letter") {
+                // A message we sent while the Main App was away expired in the broker's spill and was never delivered
+                console.warn(`Message (${message.result?.action || "unknown"}) for task ${message.task_id} expired after ${message.result?.held_ms} ms:`, message.error);
+            } else if (message.action === "broker_state") {
+                // Lifecycle of the broker's Main App connection; anything but "ipc_connected" means tasks can't run
+                brokerState = message.result?.state || null;
+                if (isBackendOnline()) {
+                    console.log("Backend online:", brokerState);
+                } else {
+                    console.warn(`Backend offline (${brokerState}, was ${message.result?.previous || "unknown"})`);
+                }
+            } else if (message.action === "shutdown") {
+                // The broker (task_id "broker") or the Main App is exiting cleanly; nothing more will arrive from it
+                bridgeState = "shutdown";
+                console.warn(`Native host shutting down (${message.task_id}):`, message.data?.reason || "(no reason given)");
+            } else if (message.action === "pause_all" || message.action === "resume_all") {
+                setPaused(message);
+            } else if (message.action === "commit" || message.action === "abort") {
+                resolveCommit(message);
+            } else if (message.action === "cancel_task") {
+                cancelTask(message);
+            } else if (message.action === "configure") {
+                // Settings from the host, pushed on connect or at runtime
+                applyConfig(message);
+            } else if (message.action === "resolve_selectors") {
+                resolveSelectorsPreview(message);
             } else if (message.action === "perform_task") {
                 console.log("Received 'perform_task' action with task_id:", message.task_id);
                 handleTask(message); // Pass to the existing task handler
@@ -87,6 +706,17 @@ function connectToNative() {
             const lastError = chrome.runtime.lastError;
             console.error("Native host disconnected.", lastError ? lastError.message : "(No error message)");
             port = null;
+            incomingTransfers.clear();
+            bridgeState = null;
+            brokerState = null;
+            brokerHello = null;
+            pairingCode = null;
+            pairingSettled = false;
+            // Paused destructive steps can't be committed anymore
+            for (const resolve of pendingCommits.values()) {
+                resolve({ commit: false, reason: "native host disconnected" });
+            }
+            pendingCommits.clear();
             initialConnectionAttempted = false; // Allow future connection attempts
 
             // Optional: Schedule a delayed reconnection attempt
@@ -95,6 +725,22 @@ function connectToNative() {
 
         console.log("Native messaging port connection initiated.");
 
+        // Load our seal key, introduce ourselves, show the host this browser is paired (or start
+        // pairing it), then ask the host for our settings (it also pushes them on its own)
+        sealKeyLoaded = false;
+        const stored = chrome.storage.local.get(["pairingToken", "sealKey"]);
+        waitingForSealKey = stored
+            .then(async ({ sealKey: storedSealKey }) => {
+                sealKey = storedSealKey ? await importSealKey(storedSealKey) : null;
+                sealKeyLoaded = true;
+            })
+            .catch(error => console.error("Loading the seal key failed, nothing the host sends is handled:", error));
+        stored.then(async ({ pairingToken }) => {
+            sayHello(pairingToken);
+            await requestPairing(pairingToken);
+            port?.postMessage({ action: "configure_request", task_id: `configure-request-${Date.now()}` });
+        }).catch(error => console.error("Introducing ourselves failed:", error));
+
     } catch (error) {
         console.error("Error connecting to native host:", error);
         port = null;
@@ -182,7 +828,7 @@ async function injectAndScrape(tabId, config) {
             items: scrapedItems
         }];
     } catch (error) {
-        console.error("Scraping error:", error);
+        bridgeLog("error", "injectAndScrape", `Scraping error: ${error.message || String(error)}`);
         throw error;
     }
 }
@@ -192,26 +838,48 @@ async function handleTask(message) {
     const taskId = message.task_id;
     let currentTabId = null; // Initialize tab ID for this task
     const results = [];
+    const tracked = trackTask(taskId);
 
     try {
         console.log(`Handling task ${taskId}:`, message.task);
 
-        for (const step of message.task.steps) {
+        for (const [stepIndex, step] of message.task.steps.entries()) {
             let stepResult = {
                 type: step.type,
                 success: false,
                 data: null,
                 error: null
             };
+            let recording = false; // Whether the page records this step's mutations
 
             try {
+                throwIfCancelled(tracked);
+                await reportStep("step_started", taskId, { step_index: stepIndex, type: step.type });
+                if (automationPaused) {
+                    console.log(`Task ${taskId}, Step ${step.type}: Paused, waiting for resume...`);
+                    await reportStep("step_progress", taskId, { step_index: stepIndex, message: "paused, waiting for resume" });
+                    await Promise.race([waitWhilePaused(), tracked.cancelled]);
+                    throwIfCancelled(tracked);
+                }
                 console.log(`Task ${taskId}, Step ${step.type}: Starting...`);
 
+                // Destructive steps pause until the host commits them
+                if (step.destructive) {
+                    console.log(`Task ${taskId}, Step ${step.type}: Destructive, waiting for commit...`);
+                    await reportStep("step_progress", taskId, { step_index: stepIndex, message: "waiting for commit" });
+                    const decision = await requestCommit(taskId, stepIndex, step);
+                    throwIfCancelled(tracked);
+                    if (!decision.commit) {
+                        throw stepError(`Aborted before destructive step: ${decision.reason || "rejected by host"}`, "aborted");
+                    }
+                }
+
                 if (step.type === 'navigate') {
                     // Handle navigation directly using chrome.tabs API
                     console.log(`Task ${taskId}, Step navigate: Navigating to:`, step.url);
                     const tab = await chrome.tabs.create({ url: step.url, active: true });
                     currentTabId = tab.id; // Store the new tab ID
+                    await reportStep("step_progress", taskId, { step_index: stepIndex, message: "waiting for the page to load" });
                     await waitForTabLoad(currentTabId); // Wait for the tab to load
                     console.log(`Task ${taskId}, Step navigate: Navigation complete for tab ${currentTabId}`);
                     stepResult.success = true;
@@ -219,25 +887,33 @@ async function handleTask(message) {
                 } else if (currentTabId) {
                     // For all other step types, execute in the content script of the current tab
                     console.log(`Task ${taskId}, Step ${step.type}: Executing in content script for tab ${currentTabId}`);
+                    if (message.task.record_mutations) {
+                        await chrome.scripting.executeScript({ target: { tabId: currentTabId }, func: startMutationRecording });
+                        recording = true;
+                    }
                     const stepExecutionResult = await chrome.scripting.executeScript({
                         target: { tabId: currentTabId },
                         func: contentScriptExecutor, // The function defined below handleTask
-                        args: [step] // Pass the current step object
+                        args: [step, extensionConfig.default_timeout_ms] // Pass the current step object
                     });
 
                     // Process result from content script
                     if (stepExecutionResult && stepExecutionResult[0] && stepExecutionResult[0].result) {
                         const result = stepExecutionResult[0].result;
                         if (result.error) {
-                            throw new Error(result.error); // Throw error if content script reported one
+                            throw stepError(result.error, result.error_kind); // Throw error if content script reported one
                         }
                         stepResult.data = result.data; // Store extracted data if any
+                        if (step.type === 'screenshot') {
+                            stepResult.data = await captureScreenshot(currentTabId, step, result.data);
+                        }
                         stepResult.success = true;
                         console.log(`Task ${taskId}, Step ${step.type}: Execution successful. Data:`, result.data);
 
                         // Handle navigation potentially triggered by CLICK
                         if (step.type === 'click' && step.wait_for_nav) {
                             console.log(`Task ${taskId}, Step click: Waiting for navigation after click...`);
+                            await reportStep("step_progress", taskId, { step_index: stepIndex, message: "waiting for navigation" });
                             await waitForTabLoad(currentTabId); // Wait for page load after click
                             console.log(`Task ${taskId}, Step click: Navigation complete.`);
                         }
@@ -252,12 +928,24 @@ async function handleTask(message) {
                 }
 
             } catch (error) {
-                console.error(`Task ${taskId}, Step ${step.type}: Error -`, error);
+                bridgeLog("error", "handleTask", `Step ${step.type} failed: ${error.message || String(error)}`, taskId);
                 stepResult.error = error.message || String(error);
+                stepResult.error_kind = error.kind || "other"; // See shared_types::StepErrorKind
                 stepResult.success = false; // Ensure success is false on error
             }
 
+            if (recording) {
+                stepResult.mutations = await stopMutationRecording(currentTabId);
+            }
             results.push(stepResult);
+            // A screenshot's image goes to the host once, with the task_result
+            const completed = step.type === 'screenshot' && stepResult.data ? { ...stepResult, data: { ...stepResult.data, image: null } } : stepResult;
+            await reportStep("step_completed", taskId, { step_index: stepIndex, result: completed });
+
+            // Politeness delay between steps, as configured by the host
+            if (stepResult.success && extensionConfig.step_delay_ms > 0) {
+                await new Promise(resolve => setTimeout(resolve, extensionConfig.step_delay_ms));
+            }
 
             // If a step failed, stop processing further steps for this task
             if (!stepResult.success) {
@@ -266,14 +954,23 @@ async function handleTask(message) {
             }
         }
 
+        // Keep the result within the budget the task declared, if any
+        const taskResult = { steps: results, result_truncated: false };
+        if (message.task.max_result_bytes) {
+            fitResultToBudget(taskResult, message.task.max_result_bytes);
+            if (taskResult.result_truncated) {
+                bridgeLog("warn", "handleTask", `Result truncated to fit max_result_bytes (${message.task.max_result_bytes})`, taskId);
+            }
+        }
+
         // Send final result back to native host
         console.log(`Task ${taskId}: Completed. Sending results back to native host.`);
         if (port) {
-            port.postMessage({
+            await postToHost({
                 action: "task_result", // Send task_result *to* the native host
                 task_id: taskId,
                 success: results.every(r => r.success),
-                result: { steps: results },
+                result: taskResult,
                 error: results.find(r => !r.success)?.error || null
             });
         } else {
@@ -282,19 +979,20 @@ async function handleTask(message) {
 
     } catch (error) {
         // Catch errors from the overall task handling logic (e.g., initial setup)
-        console.error(`Task ${taskId}: Unhandled error during task execution -`, error);
+        bridgeLog("error", "handleTask", `Unhandled error during task execution: ${error.message || String(error)}`, taskId);
          if (port) {
-            port.postMessage({
+            postToHost({
                 action: "task_result",
                 task_id: taskId,
                 success: false,
                 result: null,
                 error: error.message || String(error)
-            });
+            }).catch(sendError => console.error(`Task ${taskId}: Cannot send error result:`, sendError));
         } else {
              console.error(`Task ${taskId}: Cannot send error result, native host disconnected.`);
         }
     } finally {
+         runningTasks.delete(taskId);
          // Optional: Close the tab? Maybe only if we created it?
          // if (currentTabId) {
          //    chrome.tabs.remove(currentTabId).catch(e => console.log("Error closing tab:", e));
@@ -302,6 +1000,106 @@ async function handleTask(message) {
     }
 }
 
+// Cuts step data until the serialized result fits maxBytes (same rules as the
+// broker's TaskResult::truncate_to): arrays lose trailing items, other data is
+// dropped, largest step first. Sets result_truncated if anything was cut.
+function fitResultToBudget(taskResult, maxBytes) {
+    const size = (value) => new TextEncoder().encode(JSON.stringify(value ?? null)).length;
+    while (size(taskResult) > maxBytes) {
+        let largest = null;
+        for (const step of taskResult.steps) {
+            if (step.data != null && (!largest || size(step.data) > size(largest.data))) {
+                largest = step;
+            }
+        }
+        if (!largest) {
+            break; // Nothing left to cut
+        }
+        const excess = size(taskResult) - maxBytes;
+        if (Array.isArray(largest.data) && largest.data.length > 1) {
+            let freed = 0;
+            while (freed < excess && largest.data.length > 1) {
+                freed += size(largest.data.pop()) + 1;
+            }
+        } else {
+            largest.data = null;
+        }
+        taskResult.result_truncated = true;
+    }
+    return taskResult;
+}
+
+// --- Screenshots ---
+// captureVisibleTab pictures what the tab shows, so an element or page larger than the viewport
+// is captured a viewport at a time and the pieces are drawn onto one canvas (fixed headers show
+// up in every piece). The canvas is encoded in the step's format and sent as base64 in the
+// step's data (see shared_types::Screenshot); a large image reaches the host in chunks.
+const CAPTURE_INTERVAL_MS = 550; // Chrome allows two captures a second
+const MAX_CANVAS_SIDE = 16384; // Device pixels; longer pages are clipped
+let lastCaptureAt = 0;
+
+async function captureVisible(windowId) {
+    const wait = lastCaptureAt + CAPTURE_INTERVAL_MS - Date.now();
+    if (wait > 0) {
+        await new Promise(resolve => setTimeout(resolve, wait));
+    }
+    lastCaptureAt = Date.now();
+    const dataUrl = await chrome.tabs.captureVisibleTab(windowId, { format: "png" });
+    return createImageBitmap(await (await fetch(dataUrl)).blob());
+}
+
+// Scrolls the tab as close to (x, y) as it goes and returns where it ended up
+async function scrollTab(tabId, x, y) {
+    const [{ result }] = await chrome.scripting.executeScript({
+        target: { tabId },
+        func: (x, y) => { window.scrollTo(x, y); return { x: window.scrollX, y: window.scrollY }; },
+        args: [x, y]
+    });
+    return result;
+}
+
+// Captures the region the content script measured for a screenshot step and returns the step's data
+async function captureScreenshot(tabId, step, { rect, viewport, scroll, dpr }) {
+    const tab = await chrome.tabs.update(tabId, { active: true }); // Only the active tab can be captured
+    const format = step.format === "jpeg" ? "jpeg" : "png";
+    const width = Math.max(1, Math.min(Math.ceil(rect.width), Math.floor(MAX_CANVAS_SIDE / dpr)));
+    const height = Math.max(1, Math.min(Math.ceil(rect.height), Math.floor(MAX_CANVAS_SIDE / dpr)));
+    const clipped = width < Math.ceil(rect.width) || height < Math.ceil(rect.height);
+    // A region already in view takes one capture; anything else one per viewport-sized tile
+    const inView = rect.x >= scroll.x && rect.y >= scroll.y
+        && rect.x + width <= scroll.x + viewport.width && rect.y + height <= scroll.y + viewport.height;
+    const tiles = inView ? [null] : [];
+    for (let y = rect.y; !inView && y < rect.y + height; y += viewport.height) {
+        for (let x = rect.x; x < rect.x + width; x += viewport.width) {
+            tiles.push({ x, y });
+        }
+    }
+    let canvas = null;
+    try {
+        for (const tile of tiles) {
+            const at = tile ? await scrollTab(tabId, tile.x, tile.y) : scroll;
+            const bitmap = await captureVisible(tab.windowId);
+            const scale = bitmap.width / viewport.width; // Device pixels per CSS pixel
+            canvas ||= new OffscreenCanvas(Math.round(width * scale), Math.round(height * scale));
+            canvas.getContext("2d").drawImage(bitmap, (at.x - rect.x) * scale, (at.y - rect.y) * scale);
+            bitmap.close();
+        }
+    } finally {
+        if (!inView) {
+            await scrollTab(tabId, scroll.x, scroll.y).catch(() => {});
+        }
+    }
+    const quality = format === "jpeg" ? { quality: (step.quality ?? 90) / 100 } : {};
+    const blob = await canvas.convertToBlob({ type: `image/${format}`, ...quality });
+    return { format, width: canvas.width, height: canvas.height, image: toBase64(await blob.arrayBuffer()), clipped };
+}
+// --- End of screenshots ---
+
+// Error carrying a StepErrorKind ("timeout", "coercion", "aborted", "other")
+function stepError(message, kind = "other") {
+    return Object.assign(new Error(message), { kind });
+}
+
 // Helper function to wait for tab load (Example implementation)
 function waitForTabLoad(tabId, timeout = 30000) {
     return new Promise((resolve, reject) => {
@@ -327,6 +1125,35 @@ function waitForTabLoad(tabId, timeout = 30000) {
     });
 }
 
+// --- Selector preview ---
+// Answers a "resolve_selectors" with what each selector matches on the page
+// (see shared_types::ResolveSelectors), loaded in a background tab that is
+// closed again. Nothing on the page is clicked or filled.
+async function resolveSelectorsPreview(message) {
+    const request = message.data || {};
+    let tabId = null;
+    try {
+        const tab = await chrome.tabs.create({ url: request.url, active: false });
+        tabId = tab.id;
+        await waitForTabLoad(tabId);
+        const [execution] = await chrome.scripting.executeScript({
+            target: { tabId },
+            func: contentScriptExecutor,
+            args: [{ type: "resolve_selectors", selectors: request.selectors || [], samples: request.samples ?? 3 }, extensionConfig.default_timeout_ms]
+        });
+        const outcome = execution?.result;
+        if (!outcome || outcome.error) throw new Error(outcome?.error || "Content script execution failed or returned no result.");
+        await postToHost({ action: "resolve_selectors_result", task_id: message.task_id, success: true, result: outcome.data, error: null });
+    } catch (error) {
+        bridgeLog("warn", "resolveSelectorsPreview", `Selector preview of ${request.url} failed: ${error.message || String(error)}`, message.task_id);
+        await postToHost({ action: "resolve_selectors_result", task_id: message.task_id, success: false, result: null, error: error.message || String(error) })
+            .catch(sendError => console.error(`Preview ${message.task_id}: Cannot send error result:`, sendError));
+    } finally {
+        if (tabId !== null) chrome.tabs.remove(tabId).catch(() => {});
+    }
+}
+// --- End of selector preview ---
+
 console.log("Extension ID:", chrome.runtime.id);
 
 chrome.runtime.getPlatformInfo(function(info) {
@@ -346,31 +1173,343 @@ chrome.runtime.onInstalled.addListener(details => {
 });
 
 // This function is injected and executed in the target page's context
-async function contentScriptExecutor(step) {
+// --- DOM mutation summaries ---
+// For tasks with record_mutations, each step's changes to the page are counted
+// while it runs and briefly after (see shared_types::MutationSummary), so a
+// click that changed nothing shows up as such.
+const MUTATION_SETTLE_MS = 1000; // Longest wait for changes after a step
+const MUTATION_QUIET_MS = 100; // No changes for this long ends the wait early
+
+// Runs in the page; the recorder stays there between scripts
+function startMutationRecording() {
+    const NOTABLE = /^(class|hidden|disabled|open|checked|selected|value|src|href|aria-.*)$/;
+    const MAX_NOTABLE = 20;
+    globalThis.__rznMutations?.observer.disconnect();
+    const recorder = { summary: { added: 0, removed: 0, attributes: 0, text: 0, notable: [] }, lastChange: Date.now() };
+    const describe = (el) => el.tagName.toLowerCase() + (el.id ? `#${el.id}` : "") + Array.from(el.classList).slice(0, 2).map(c => `.${c}`).join("");
+    recorder.tally = (records) => {
+        const summary = recorder.summary;
+        for (const record of records) {
+            summary.added += record.addedNodes.length;
+            summary.removed += record.removedNodes.length;
+            if (record.type === 'characterData') summary.text += 1;
+            if (record.type !== 'attributes') continue;
+            summary.attributes += 1;
+            if (NOTABLE.test(record.attributeName) && summary.notable.length < MAX_NOTABLE) {
+                const newValue = record.target.getAttribute(record.attributeName);
+                if (newValue !== record.oldValue) {
+                    summary.notable.push({ element: describe(record.target), attribute: record.attributeName, old_value: record.oldValue, new_value: newValue });
+                }
+            }
+        }
+        if (records.length > 0) recorder.lastChange = Date.now();
+    };
+    recorder.observer = new MutationObserver(recorder.tally);
+    recorder.observer.observe(document, { childList: true, subtree: true, attributes: true, attributeOldValue: true, characterData: true });
+    globalThis.__rznMutations = recorder;
+}
+
+// Runs in the page: waits for the changes to settle and hands back the summary
+async function finishMutationRecording(settleMs, quietMs) {
+    const recorder = globalThis.__rznMutations;
+    if (!recorder) return null; // The step left the page
+    const deadline = Date.now() + settleMs;
+    while (Date.now() < deadline && Date.now() - recorder.lastChange < quietMs) {
+        await new Promise(resolve => setTimeout(resolve, quietMs / 2));
+    }
+    recorder.tally(recorder.observer.takeRecords());
+    recorder.observer.disconnect();
+    delete globalThis.__rznMutations;
+    return recorder.summary;
+}
+
+async function stopMutationRecording(tabId) {
+    try {
+        const [finished] = await chrome.scripting.executeScript({ target: { tabId }, func: finishMutationRecording, args: [MUTATION_SETTLE_MS, MUTATION_QUIET_MS] });
+        return finished?.result ?? null;
+    } catch (error) {
+        console.warn(`Could not read the mutations of tab ${tabId}:`, error);
+        return null;
+    }
+}
+// --- End of DOM mutation summaries ---
+
+async function contentScriptExecutor(step, defaultTimeout = 5000) {
+    // Helper: selector resolution (see shared_types::selector). Plain strings are CSS,
+    // where ">>>" descends into open shadow roots (e.g. "my-app >>> button.save").
+    // Resolvers search under `root`: the document, or an element handle from a "locate" step.
+    function querySelectorAllDeep(selector, root) {
+        const segments = selector.split(">>>").map(s => s.trim());
+        let roots = [root];
+        for (const [i, segment] of segments.entries()) {
+            const found = roots.flatMap(r => Array.from(r.querySelectorAll(segment)));
+            if (i === segments.length - 1) return found;
+            roots = found.map(el => el.shadowRoot).filter(Boolean); // Closed shadow roots are null
+        }
+        return [];
+    }
+    const IMPLICIT_ROLES = {
+        button: "button, input[type=button], input[type=submit], input[type=reset]",
+        link: "a[href], area[href]",
+        textbox: "input:not([type]), input[type=text], input[type=email], input[type=search], input[type=tel], input[type=url], textarea",
+        checkbox: "input[type=checkbox]",
+        radio: "input[type=radio]",
+        combobox: "select",
+        heading: "h1, h2, h3, h4, h5, h6",
+        img: "img[alt]",
+        list: "ul, ol",
+        listitem: "li"
+    };
+    function accessibleName(element) {
+        const labelledBy = element.getAttribute("aria-labelledby");
+        if (labelledBy) {
+            return labelledBy.split(/\s+/).map(id => document.getElementById(id)?.textContent || "").join(" ").trim();
+        }
+        return (element.getAttribute("aria-label") || element.labels?.[0]?.textContent
+            || element.getAttribute("alt") || element.textContent || element.value || "").trim();
+    }
+    function resolveSelectorAll(selector, root = document) {
+        if (typeof selector === "string") return querySelectorAllDeep(selector, root);
+        if (selector.css !== undefined) return querySelectorAllDeep(selector.css, root);
+        if (selector.xpath !== undefined) {
+            const result = document.evaluate(selector.xpath, root, null, XPathResult.ORDERED_NODE_SNAPSHOT_TYPE, null);
+            return Array.from({ length: result.snapshotLength }, (_, i) => result.snapshotItem(i));
+        }
+        if (selector.text !== undefined) {
+            const matches = (el) => {
+                const text = (el.innerText ?? el.textContent ?? "").trim();
+                return selector.exact ? text === selector.text : text.includes(selector.text);
+            };
+            const scope = root === document ? document.body : root;
+            const found = Array.from(scope.querySelectorAll("*")).filter(matches);
+            // Innermost matches only, so "Buy" finds the button rather than <body>.
+            // In document order a match's matching descendants directly follow it.
+            return found.filter((el, i) => !(found[i + 1] && el.contains(found[i + 1])));
+        }
+        if (selector.role !== undefined) {
+            const css = [`[role="${selector.role}"]`, IMPLICIT_ROLES[selector.role]].filter(Boolean).join(", ");
+            return Array.from(root.querySelectorAll(css)).filter(el => {
+                const explicitRole = el.getAttribute("role");
+                if (explicitRole && explicitRole !== selector.role) return false;
+                return selector.name === undefined || selector.name === null || accessibleName(el) === selector.name;
+            });
+        }
+        throw new Error(`Unsupported selector: ${JSON.stringify(selector)}`);
+    }
+    function resolveSelector(selector, root = document, index = 0) {
+        return resolveSelectorAll(selector, root)[index] || null;
+    }
+    function describeSelector(selector) {
+        return typeof selector === "string" ? selector : JSON.stringify(selector);
+    }
+    // Element handles set by "locate" steps; they live as long as the page does
+    const handles = (globalThis.__rznHandles ||= {});
+    function scopeRoot(handleName) {
+        if (!handleName) return document;
+        const handle = handles[handleName];
+        if (!handle || !handle.isConnected) {
+            throw new Error(`Handle "${handleName}" is not located on this page`);
+        }
+        return handle;
+    }
     // Helper: Wait for selector function (basic polling)
-    function waitForElement(selector, timeout, state = 'attached') {
+    function waitForElement(selector, timeout, state = 'attached', root = document, index = 0) {
         return new Promise((resolve, reject) => {
             const startTime = Date.now();
             const interval = setInterval(() => {
-                const element = document.querySelector(selector);
+                const element = resolveSelector(selector, root, index);
                 let conditionMet = false;
                 if (state === 'attached') { conditionMet = !!element; }
                 else if (state === 'visible') { conditionMet = !!element && (element.offsetWidth > 0 || element.offsetHeight > 0 || element.getClientRects().length > 0); }
                 else if (state === 'hidden') { conditionMet = !element || (element.offsetWidth === 0 && element.offsetHeight === 0); }
 
                 if (conditionMet) { clearInterval(interval); resolve(element); }
-                else if (Date.now() - startTime > timeout) { clearInterval(interval); reject(new Error(`Timeout waiting for selector "${selector}" (state: ${state}) after ${timeout}ms`)); }
+                else if (Date.now() - startTime > timeout) { clearInterval(interval); reject(Object.assign(new Error(`Timeout waiting for selector ${describeSelector(selector)} (state: ${state}) after ${timeout}ms`), { kind: 'timeout' })); }
             }, 100);
         });
+    }
+    // Helper: reduce an extracted string to a regex match (null when it doesn't match)
+    function applyRegex(value, pattern, group) {
+        if (!pattern || value === null || value === undefined) return value;
+        if (typeof value === 'object') {
+            return Object.fromEntries(Object.entries(value).map(([k, v]) => [k, applyRegex(v, pattern, group)]));
+        }
+        const match = String(value).match(new RegExp(pattern));
+        if (!match) return null;
+        const index = group ?? (match.length > 1 ? 1 : 0);
+        return match[index] ?? null;
+    }
+    // Helper: coerce an extracted string to the step's value_type (see shared_types::ValueType)
+    function coerceValue(value, valueType) {
+        if (!valueType || value === null || value === undefined) return value;
+        if (typeof value === 'object') {
+            // Attribute maps: coerce each attribute
+            return Object.fromEntries(Object.entries(value).map(([k, v]) => [k, coerceValue(v, valueType)]));
+        }
+        const fail = (what) => { throw Object.assign(new Error(`Cannot coerce ${JSON.stringify(value)} to ${what}`), { kind: 'coercion' }); };
+        const text = String(value).trim();
+        if (valueType === 'string') return text;
+        if (valueType === 'number') {
+            const cleaned = text.replace(/[^\d.eE+-]/g, '');
+            const number = cleaned === '' ? NaN : Number(cleaned);
+            return Number.isFinite(number) ? number : fail('number');
+        }
+        if (valueType === 'boolean') {
+            const lower = text.toLowerCase();
+            if (['true', 'yes', 'on', '1'].includes(lower)) return true;
+            if (['false', 'no', 'off', '0'].includes(lower)) return false;
+            return fail('boolean');
+        }
+        if (valueType.date !== undefined) {
+            const format = valueType.date.format;
+            if (!format) {
+                const parsed = new Date(text);
+                return isNaN(parsed) ? fail('date') : parsed.toISOString();
+            }
+            // Turn the format into a regex with one group per token
+            const tokens = [];
+            const pattern = format.replace(/[.*+?^${}()|[\]\\]/g, '\\$&').replace(/YYYY|MM|DD|HH|mm|ss/g, token => {
+                tokens.push(token);
+                return token === 'YYYY' ? '(\\d{4})' : '(\\d{1,2})';
+            });
+            const match = text.match(new RegExp(`^${pattern}$`));
+            if (!match) return fail(`date (${format})`);
+            const parts = { YYYY: 0, MM: 1, DD: 1, HH: 0, mm: 0, ss: 0 };
+            tokens.forEach((token, i) => { parts[token] = Number(match[i + 1]); });
+            const date = new Date(Date.UTC(parts.YYYY, parts.MM - 1, parts.DD, parts.HH, parts.mm, parts.ss));
+            if (date.getUTCMonth() !== parts.MM - 1 || date.getUTCDate() !== parts.DD) return fail(`date (${format})`);
+            return date.toISOString();
+        }
+        return fail(JSON.stringify(valueType));
     }
      function dispatchInputEvents(element) {
          element.dispatchEvent(new Event('input', { bubbles: true, cancelable: true }));
          element.dispatchEvent(new Event('change', { bubbles: true, cancelable: true }));
      }
 
+    // Helper: pointer and mouse events at a point (see the hover and drag_and_drop steps)
+    function pointerAt(element) {
+        const box = element.getBoundingClientRect();
+        return { x: box.left + box.width / 2, y: box.top + box.height / 2 };
+    }
+    function dispatchPointer(element, type, at) {
+        const init = { bubbles: !type.endsWith('enter'), cancelable: true, composed: true, clientX: at.x, clientY: at.y, view: window, buttons: type.endsWith('down') || (type.endsWith('move') && dragging) ? 1 : 0 };
+        if (type.endsWith('down')) dragging = true;
+        if (type.endsWith('up')) dragging = false;
+        const event = type.startsWith('pointer') ? new PointerEvent(type, { ...init, pointerId: 1, pointerType: 'mouse', isPrimary: true }) : new MouseEvent(type, init);
+        element.dispatchEvent(event);
+    }
+    let dragging = false; // Whether a mouse button is down, for the buttons of moves
+
+    // --- Keyboard ---
+    // Chords come as "Ctrl+Shift+Tab" (see shared_types::keys); the host has
+    // checked the names already
+    function parseChord(chord) {
+        let key = chord, modifiers = [];
+        if (chord !== '+' && chord.endsWith('++')) {
+            key = '+';
+            modifiers = chord.slice(0, -2).split('+');
+        } else if (chord !== '+' && chord.includes('+')) {
+            const at = chord.lastIndexOf('+');
+            key = chord.slice(at + 1).trim();
+            modifiers = chord.slice(0, at).split('+');
+        }
+        modifiers = modifiers.map(m => m.trim().toLowerCase());
+        if (key === 'Space') key = ' ';
+        return {
+            key,
+            ctrlKey: modifiers.some(m => m === 'ctrl' || m === 'control'),
+            altKey: modifiers.some(m => m === 'alt' || m === 'option'),
+            shiftKey: modifiers.includes('shift'),
+            metaKey: modifiers.some(m => m === 'meta' || m === 'cmd' || m === 'command')
+        };
+    }
+
+    function keyCode(key) {
+        if (/^[a-z]$/i.test(key)) return `Key${key.toUpperCase()}`;
+        if (/^[0-9]$/.test(key)) return `Digit${key}`;
+        if (key === ' ') return 'Space';
+        return key.length === 1 ? '' : key;
+    }
+
+    function isEditable(element) {
+        return element instanceof HTMLTextAreaElement
+            || (element instanceof HTMLInputElement && !['checkbox', 'radio', 'button', 'submit', 'reset', 'file', 'image', 'range', 'color'].includes(element.type))
+            || element?.isContentEditable;
+    }
+
+    // Replaces the selection of an input or textarea with text (or, if text
+    // is empty, deletes it or the character on the side of deleteBackward)
+    function editText(element, text, deleteBackward) {
+        if (element.isContentEditable) {
+            document.execCommand(text ? 'insertText' : deleteBackward ? 'delete' : 'forwardDelete', false, text);
+            return;
+        }
+        if (element.selectionStart === null) {
+            // Email and number inputs have no selection: edit at the end
+            element.value = text ? element.value + text : deleteBackward ? element.value.slice(0, -1) : element.value;
+        } else {
+            let start = element.selectionStart, end = element.selectionEnd;
+            if (!text && start === end) {
+                if (deleteBackward) start = Math.max(0, start - 1);
+                else end = Math.min(element.value.length, end + 1);
+            }
+            element.setRangeText(text, start, end, 'end');
+        }
+        element.dispatchEvent(new InputEvent('input', { bubbles: true, inputType: text ? 'insertText' : 'deleteContent', data: text || null }));
+    }
+
+    function moveFocus(backward) {
+        const focusable = Array.from(document.querySelectorAll('a[href], button, input, select, textarea, [tabindex]'))
+            .filter(el => !el.disabled && el.tabIndex >= 0 && el.getClientRects().length > 0);
+        if (focusable.length === 0) return;
+        const at = focusable.indexOf(document.activeElement);
+        const next = at === -1 ? (backward ? focusable.length - 1 : 0) : (at + (backward ? -1 : 1) + focusable.length) % focusable.length;
+        focusable[next].focus();
+    }
+
+    // Synthetic key events don't make the browser act on them, so after an
+    // uncancelled keydown the usual effect is carried out here
+    function pressChord(chord) {
+        const target = document.activeElement || document.body;
+        const init = { ...chord, code: keyCode(chord.key), bubbles: true, cancelable: true, composed: true };
+        const proceed = target.dispatchEvent(new KeyboardEvent('keydown', init));
+        const shortcut = chord.ctrlKey || chord.metaKey || chord.altKey;
+        if (proceed && chord.key.length === 1 && !shortcut) {
+            target.dispatchEvent(new KeyboardEvent('keypress', init));
+            if (isEditable(target)) editText(target, chord.key);
+        } else if (proceed && shortcut && chord.key.toLowerCase() === 'a' && !chord.altKey) {
+            if (typeof target.select === 'function') target.select();
+            else document.execCommand('selectAll');
+        } else if (proceed && (chord.key === 'Backspace' || chord.key === 'Delete') && isEditable(target)) {
+            editText(target, '', chord.key === 'Backspace');
+        } else if (proceed && chord.key === 'Tab') {
+            moveFocus(chord.shiftKey);
+        } else if (proceed && chord.key === 'Enter' && !shortcut) {
+            if (target instanceof HTMLTextAreaElement || target.isContentEditable) editText(target, '\n');
+            else if (target instanceof HTMLInputElement && target.form) target.form.requestSubmit();
+            else if (target instanceof HTMLButtonElement || target instanceof HTMLAnchorElement) target.click();
+        }
+        target.dispatchEvent(new KeyboardEvent('keyup', init));
+    }
+    // --- End of keyboard ---
+
     try {
         switch (step.type) {
             case 'navigate': return { data: null };
+            case 'resolve_selectors': {
+                // Not a task step: a "resolve_selectors" preview (see resolveSelectorsPreview)
+                const matches = step.selectors.map(selector => {
+                    try {
+                        const found = resolveSelectorAll(selector);
+                        const samples = found.slice(0, step.samples).map(el => (el.innerText ?? el.textContent ?? "").trim().slice(0, 200));
+                        return { selector, count: found.length, samples };
+                    } catch (error) {
+                        return { selector, count: 0, samples: [], error: error.message || String(error) };
+                    }
+                });
+                return { data: { url: location.href, matches } };
+            }
             case 'scrape': {
                  const items = [];
                  document.querySelectorAll(step.config.item_selector).forEach(element => {
@@ -388,43 +1527,219 @@ async function contentScriptExecutor(step) {
                  return { data: items };
              }
             case 'click': {
-                const element = await waitForElement(step.selector, step.timeout || 5000, 'visible');
-                if (!element) throw new Error(`Element not found or not visible for click: ${step.selector}`);
+                const element = await waitForElement(step.selector, step.timeout || defaultTimeout, 'visible', scopeRoot(step.within));
+                if (!element) throw new Error(`Element not found or not visible for click: ${describeSelector(step.selector)}`);
                 element.click();
                 return { data: null };
             }
             case 'fill': {
-                const element = await waitForElement(step.selector, 5000, 'visible');
-                if (!element) throw new Error(`Element not found for fill: ${step.selector}`);
+                const element = await waitForElement(step.selector, defaultTimeout, 'visible', scopeRoot(step.within));
+                if (!element) throw new Error(`Element not found for fill: ${describeSelector(step.selector)}`);
                 element.value = step.value;
                  if (step.dispatch_events && step.dispatch_events.length > 0) { dispatchInputEvents(element); }
                 return { data: null };
             }
+            case 'select': {
+                const element = await waitForElement(step.selector, defaultTimeout, 'visible', scopeRoot(step.within));
+                if (!element) throw new Error(`Element not found for select: ${describeSelector(step.selector)}`);
+                if (element.tagName !== 'SELECT') throw new Error(`Not a <select>: ${describeSelector(step.selector)}`);
+                const options = Array.from(element.options);
+                let option;
+                if (step.value !== undefined) option = options.find(o => o.value === step.value);
+                else if (step.label !== undefined) option = options.find(o => o.text.trim() === step.label.trim());
+                else option = options[step.index];
+                const wanted = step.value !== undefined ? `value ${JSON.stringify(step.value)}` : step.label !== undefined ? `label ${JSON.stringify(step.label)}` : `index ${step.index}`;
+                if (!option) throw new Error(`No option with ${wanted} in ${describeSelector(step.selector)}`);
+                if (option.disabled) throw new Error(`Option with ${wanted} is disabled in ${describeSelector(step.selector)}`);
+                element.value = option.value;
+                option.selected = true;
+                // Frameworks listen for these rather than watching the value
+                dispatchInputEvents(element);
+                return { data: { value: option.value, label: option.text.trim(), index: option.index } };
+            }
+            case 'keyboard': {
+                if (step.selector) {
+                    const element = await waitForElement(step.selector, defaultTimeout, 'visible', scopeRoot(step.within));
+                    if (!element) throw new Error(`Element not found for keyboard: ${describeSelector(step.selector)}`);
+                    element.focus();
+                }
+                for (const chord of step.keys) {
+                    pressChord(parseChord(chord));
+                    if (step.delay_ms) await new Promise(resolve => setTimeout(resolve, step.delay_ms));
+                }
+                return { data: null };
+            }
+            case 'scroll': {
+                const behavior = step.behavior || 'instant';
+                const scroller = document.scrollingElement || document.documentElement;
+                // Smooth scrolls take a while; scrollend says when they're done
+                const settled = () => behavior !== 'smooth' ? Promise.resolve() : new Promise(resolve => {
+                    const timer = setTimeout(resolve, 1000);
+                    window.addEventListener('scrollend', () => { clearTimeout(timer); resolve(); }, { once: true });
+                });
+                const scrollOnce = async () => {
+                    const target = step.target;
+                    const done = settled();
+                    if (target === 'top') window.scrollTo({ top: 0, left: 0, behavior });
+                    else if (target === 'bottom') window.scrollTo({ top: scroller.scrollHeight, left: window.scrollX, behavior });
+                    else if (target.pixels) window.scrollBy({ left: target.pixels.x || 0, top: target.pixels.y || 0, behavior });
+                    else {
+                        const element = await waitForElement(target.selector, defaultTimeout, 'attached');
+                        if (!element) throw new Error(`Element not found for scroll: ${describeSelector(target.selector)}`);
+                        element.scrollIntoView({ block: 'center', inline: 'nearest', behavior });
+                    }
+                    await done;
+                };
+                const findUntil = () => step.until ? resolveSelector(step.until) : null;
+
+                let iterations = 0, found = false;
+                if (!step.until) {
+                    await scrollOnce();
+                    iterations = 1;
+                } else {
+                    const maxIterations = step.max_iterations || 10;
+                    found = findUntil() !== null;
+                    while (!found && iterations < maxIterations) {
+                        const height = scroller.scrollHeight;
+                        await scrollOnce();
+                        iterations += 1;
+                        // Give the page time to load more, up to the step timeout
+                        const deadline = Date.now() + defaultTimeout;
+                        while (!(found = findUntil() !== null) && scroller.scrollHeight === height && Date.now() < deadline) {
+                            await new Promise(resolve => setTimeout(resolve, 100));
+                        }
+                        if (!found && scroller.scrollHeight === height) break; // Nothing more to load
+                    }
+                }
+                return { data: { x: window.scrollX, y: window.scrollY, iterations, ...(step.until ? { found } : {}) } };
+            }
+            case 'hover': {
+                const element = await waitForElement(step.selector, defaultTimeout, 'visible', scopeRoot(step.within));
+                if (!element) throw new Error(`Element not found for hover: ${describeSelector(step.selector)}`);
+                element.scrollIntoView({ block: 'nearest', inline: 'nearest' });
+                // Synthetic events run the page's handlers but don't turn on CSS :hover
+                const at = pointerAt(element);
+                for (const type of ['pointerover', 'pointerenter', 'mouseover', 'mouseenter', 'pointermove', 'mousemove']) {
+                    dispatchPointer(element, type, at);
+                }
+                return { data: null };
+            }
+            case 'drag_and_drop': {
+                const root = scopeRoot(step.within);
+                const source = await waitForElement(step.source_selector, defaultTimeout, 'visible', root);
+                if (!source) throw new Error(`Element not found for drag_and_drop: ${describeSelector(step.source_selector)}`);
+                const target = await waitForElement(step.target_selector, defaultTimeout, 'visible', root);
+                if (!target) throw new Error(`Element not found for drag_and_drop: ${describeSelector(step.target_selector)}`);
+                source.scrollIntoView({ block: 'nearest', inline: 'nearest' });
+                const from = pointerAt(source);
+                // The target may be off screen, and the page may move once the drag starts,
+                // so it is scrolled into view after the press and measured again before each event
+                if (source.draggable) {
+                    // HTML5 drag and drop, with one DataTransfer throughout like a real drag
+                    const dataTransfer = new DataTransfer();
+                    const drag = (element, type, at) => element.dispatchEvent(new DragEvent(type, { bubbles: true, cancelable: true, composed: true, clientX: at.x, clientY: at.y, dataTransfer }));
+                    drag(source, 'dragstart', from);
+                    target.scrollIntoView({ block: 'nearest', inline: 'nearest' });
+                    drag(target, 'dragenter', pointerAt(target));
+                    drag(target, 'dragover', pointerAt(target));
+                    drag(target, 'drop', pointerAt(target));
+                    drag(source, 'dragend', pointerAt(target));
+                } else {
+                    // Libraries that implement dragging themselves follow the pointer, in steps
+                    dispatchPointer(source, 'pointerdown', from);
+                    dispatchPointer(source, 'mousedown', from);
+                    target.scrollIntoView({ block: 'nearest', inline: 'nearest' });
+                    // Where the press was, now that the page may have scrolled
+                    const start = pointerAt(source);
+                    const moves = 5;
+                    for (let i = 1; i <= moves; i++) {
+                        const to = pointerAt(target);
+                        const at = { x: start.x + (to.x - start.x) * i / moves, y: start.y + (to.y - start.y) * i / moves };
+                        const over = document.elementFromPoint(at.x, at.y) || target;
+                        dispatchPointer(over, 'pointermove', at);
+                        dispatchPointer(over, 'mousemove', at);
+                        await new Promise(resolve => setTimeout(resolve, 16));
+                    }
+                    const to = pointerAt(target);
+                    dispatchPointer(target, 'pointerup', to);
+                    dispatchPointer(target, 'mouseup', to);
+                }
+                return { data: null };
+            }
             case 'wait_for_selector': {
-                await waitForElement(step.selector, step.timeout, step.state || 'attached');
+                await waitForElement(step.selector, step.timeout, step.state || 'attached', scopeRoot(step.within));
+                return { data: null };
+            }
+            case 'locate': {
+                // Later steps with `within: handle_name` search under this element
+                const root = scopeRoot(step.within);
+                const element = await waitForElement(step.selector, defaultTimeout, 'attached', root, step.index || 0);
+                handles[step.handle_name] = element;
                 return { data: null };
             }
+            case 'screenshot': {
+                // Only measures what to capture, in page coordinates; the background script takes the picture
+                const viewport = { width: window.innerWidth, height: window.innerHeight };
+                let rect = { x: window.scrollX, y: window.scrollY, ...viewport };
+                if (step.selector) {
+                    const element = await waitForElement(step.selector, defaultTimeout, 'visible');
+                    element.scrollIntoView({ block: 'nearest', inline: 'nearest' });
+                    const box = element.getBoundingClientRect();
+                    rect = { x: box.left + window.scrollX, y: box.top + window.scrollY, width: box.width, height: box.height };
+                } else if (step.full_page) {
+                    const root = document.documentElement;
+                    rect = { x: 0, y: 0, width: Math.max(root.scrollWidth, viewport.width), height: Math.max(root.scrollHeight, viewport.height) };
+                }
+                return { data: { rect, viewport, scroll: { x: window.scrollX, y: window.scrollY }, dpr: window.devicePixelRatio || 1 } };
+            }
             case 'wait_for_timeout': {
                 await new Promise(resolve => setTimeout(resolve, step.timeout));
                 return { data: null };
             }
             case 'extract': {
-                const element = await waitForElement(step.selector, 5000);
-                if (!element) throw new Error(`Element not found for extract: ${step.selector}`);
-                let value = null;
-                switch (step.target) {
-                    case 'text': value = element.innerText; break;
-                    case 'html': value = element.innerHTML; break;
-                    case 'attribute':
-                        if (!step.attribute_name) throw new Error("Missing attribute_name for extract target 'attribute'");
-                        value = element.getAttribute(step.attribute_name); break;
-                    default: throw new Error(`Unknown extract target: ${step.target}`);
-                }
+                const root = scopeRoot(step.within);
+                const element = await waitForElement(step.selector, defaultTimeout, 'attached', root);
+                if (!element) throw new Error(`Element not found for extract: ${describeSelector(step.selector)}`);
+                const extractValue = (el) => {
+                    let value = null;
+                    switch (step.target) {
+                        case 'text': value = el.innerText; break;
+                        case 'html': value = el.innerHTML; break;
+                        case 'attribute':
+                            if (!step.attribute_name) throw new Error("Missing attribute_name for extract target 'attribute'");
+                            value = el.getAttribute(step.attribute_name); break;
+                        case 'attributes': {
+                            // All attributes, or just the requested ones (null when missing)
+                            const names = step.attribute_names || el.getAttributeNames();
+                            value = Object.fromEntries(names.map(name => {
+                                const attr = el.getAttribute(name);
+                                return [name, step.trim && typeof attr === 'string' ? attr.trim() : attr];
+                            }));
+                            break;
+                        }
+                        default: throw new Error(`Unknown extract target: ${step.target}`);
+                    }
+                    value = step.trim && typeof value === 'string' ? value.trim() : value;
+                    value = applyRegex(value, step.regex, step.group);
+                    return coerceValue(value, step.value_type);
+                };
                  const extractedData = {};
-                 extractedData[step.variable_name] = value;
+                 if (step.all) {
+                     // Every match, in document order
+                     let values = resolveSelectorAll(step.selector, root).map(extractValue);
+                     if (step.dedup) {
+                         // Compare by JSON so attribute maps dedup too
+                         const seen = new Set();
+                         values = values.filter(v => { const key = JSON.stringify(v); return !seen.has(key) && seen.add(key); });
+                     }
+                     if (step.limit) { values = values.slice(0, step.limit); }
+                     extractedData[step.variable_name] = values;
+                 } else {
+                     extractedData[step.variable_name] = extractValue(element);
+                 }
                  return { data: extractedData };
             }
             default: throw new Error(`Unsupported step type in content script: ${step.type}`);
         }
-    } catch (error) { return { error: error.message || String(error) }; }
+    } catch (error) { return { error: error.message || String(error), error_kind: error.kind || 'other' }; }
 }
@@ -0,0 +1,1228 @@
+//! One broker connection, seen from the Main App.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+
+use rzn_protocol::{Correlator, Expect, Handshake, Verdict};
+use shared_types::frame::{read_frame_limited, write_frame, FrameFlags};
+use shared_types::{
+    is_sealed, may_be_unsealed, msg_id, Ack, Action, BridgeConfig, BrokerState, BrokerStateChange, CommitRequest, ConnectionState, ConnectionStateChange, Encoding, ExtensionLog, ExtensionResponse, Heartbeat, Hello, Message, MessageTooLarge, ProcessedKeys, ResolveSelectors,
+    ResolvedSelectors, SealKey, SealedChannel, Side, StepCompleted, StepProgress, StepStarted, Task, TaskCancelled, TaskResult, ACK_CAPABILITY, AT_LEAST_ONCE_CAPABILITY, COMPRESSION_CAPABILITIES,
+    RESOLVE_SELECTORS_RESULT_ACTION, STEP_COMPLETED_ACTION, STEP_PROGRESS_ACTION, STEP_STARTED_ACTION, TASK_CANCELLED_ACTION,
+    TASK_RESULT_ACTION, TASK_TIMEOUT_ERROR,
+};
+
+use crate::error::ClientError;
+#[cfg(feature = "tokio")]
+use crate::executor::Tokio;
+use crate::executor::{BoxFuture, Executor};
+use crate::handler::Handlers;
+use crate::replay::Recorder;
+
+/// How long [`BridgeClient::send_task`] waits for a result by default.
+pub const DEFAULT_TASK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long [`TaskHandle::cancel`] waits for the `task_cancelled`.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long [`BridgeClient::deliver`] waits for the broker's `ack`.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings of the clients a [`BridgeServer`](crate::BridgeServer) hands out.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// Name and version in our `hello_ack`.
+    pub software: String,
+    /// Wait for a task's result in [`BridgeClient::send_task`].
+    pub task_timeout: Duration,
+    /// Encoding to ask the broker for; frames in any encoding are read.
+    pub encoding: Encoding,
+    /// Largest message accepted from the broker.
+    pub max_message_size: usize,
+    /// File to append every message read from the broker to, for
+    /// [`replay`](crate::replay).
+    pub record_to: Option<PathBuf>,
+    /// File of the idempotency keys of the messages already processed (see
+    /// [`ProcessedKeys`]). When set, the client asks the broker for
+    /// at-least-once delivery, acknowledges every message from the
+    /// extension and skips those it processed before.
+    pub processed_keys: Option<PathBuf>,
+    /// Key agreed with the extension while pairing (see
+    /// [`shared_types::sealed`]). When set, everything for the extension is
+    /// sent sealed, sealed messages are opened before anything else sees
+    /// them, and unsealed ones other than the broker's own are dropped.
+    /// Change it on a running client with [`BridgeClient::seal_with`].
+    pub seal_key: Option<SealKey>,
+}
+
+impl Default for ClientOptions {
+    /// [`DEFAULT_TASK_TIMEOUT`], JSON, and the message limit from the bridge
+    /// config.
+    fn default() -> Self {
+        ClientOptions {
+            software: concat!("rzn_bridge_client ", env!("CARGO_PKG_VERSION")).to_string(),
+            task_timeout: DEFAULT_TASK_TIMEOUT,
+            encoding: Encoding::Json,
+            max_message_size: BridgeConfig::load_or_default().message_limits().to_app,
+            record_to: None,
+            processed_keys: None,
+            seal_key: None,
+        }
+    }
+}
+
+/// Something the bridge sent that isn't the answer to a task.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The broker introduced itself; our `hello_ack` is already sent.
+    Hello(Hello),
+    BrokerState(BrokerStateChange),
+    /// This connection changed state; see [`BridgeClient::state`].
+    ConnectionState(ConnectionStateChange),
+    /// A log record forwarded by the extension.
+    Log { task_id: String, log: ExtensionLog },
+    /// A destructive step waits for `commit` or `abort`, sent with
+    /// [`BridgeClient::send`].
+    CommitRequest { task_id: String, request: CommitRequest },
+    /// Progress of a task whose [`TaskHandle`] doesn't take its
+    /// [`steps`](TaskHandle::steps) (anymore).
+    Step { task_id: String, event: StepEvent },
+    /// Any other message, including results of tasks no longer waited for.
+    /// Requests go to the [`Handlers`] instead once any are registered.
+    Other(Value),
+}
+
+/// The unsolicited messages of one connection, in the order they came.
+/// Read them or drop this: an unread stream holds up task results once full.
+pub struct Events(mpsc::Receiver<Event>);
+
+impl Events {
+    /// The next event, or `None` once the broker disconnected.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.0.recv().await
+    }
+}
+
+/// How a step of a task is getting on, as reported by the extension.
+#[derive(Debug, Clone)]
+pub enum StepEvent {
+    Started(StepStarted),
+    Progress(StepProgress),
+    /// The step's result, which the task's result has too.
+    Completed(StepCompleted),
+}
+
+impl StepEvent {
+    /// Index of the step in the task.
+    pub fn step_index(&self) -> usize {
+        match self {
+            StepEvent::Started(started) => started.step_index,
+            StepEvent::Progress(progress) => progress.step_index,
+            StepEvent::Completed(completed) => completed.step_index,
+        }
+    }
+
+    /// The event of a `step_*` message, `None` for any other message.
+    fn of(value: &Value) -> Option<Self> {
+        let data = value.get("data").cloned().unwrap_or_default();
+        match value.get("action").and_then(Value::as_str).unwrap_or_default() {
+            STEP_STARTED_ACTION => serde_json::from_value(data).ok().map(StepEvent::Started),
+            STEP_PROGRESS_ACTION => serde_json::from_value(data).ok().map(StepEvent::Progress),
+            STEP_COMPLETED_ACTION => serde_json::from_value(data).ok().map(StepEvent::Completed),
+            _ => None,
+        }
+    }
+}
+
+/// The [`StepEvent`]s of one task, in the order the steps ran. Ends after
+/// the last one, once the task's result came in.
+pub struct StepEvents(mpsc::UnboundedReceiver<StepEvent>);
+
+impl StepEvents {
+    /// The next event, or `None` once the task finished.
+    pub async fn next(&mut self) -> Option<StepEvent> {
+        self.0.recv().await
+    }
+}
+
+impl futures_core::Stream for StepEvents {
+    type Item = StepEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StepEvent>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Tasks waiting for their result, cancellations for their acknowledgment
+/// and deliveries for their `ack`.
+type Answers = Arc<Mutex<Correlator<oneshot::Sender<ExtensionResponse>>>>;
+
+/// Where the step events of running tasks go, by task ID.
+type Steps = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<StepEvent>>>>;
+
+/// Where the connection is; it starts out `handshaking`.
+type State = Arc<Mutex<ConnectionState>>;
+
+/// The key messages to and from the extension are sealed with, if any.
+type Seal = Arc<Mutex<Option<SealedChannel>>>;
+
+/// Everything waiting for an answer from the bridge.
+#[derive(Clone, Default)]
+struct Waiters {
+    answers: Answers,
+    steps: Steps,
+}
+
+/// A broker connection. Cheap to share: every method takes `&self`, so tasks
+/// can be sent from several places at once.
+pub struct BridgeClient {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    waiters: Waiters,
+    next_id: AtomicU64,
+    /// Start of the message IDs this client assigns, so they don't repeat
+    /// another client's.
+    msg_id_prefix: String,
+    task_timeout: Duration,
+    executor: Arc<dyn Executor>,
+    state: State,
+    seal: Seal,
+}
+
+impl BridgeClient {
+    /// Serves a broker connection, e.g. an accepted stream split in two.
+    /// Must be called within a tokio runtime.
+    #[cfg(feature = "tokio")]
+    pub fn new<R, W>(reader: R, writer: W, options: ClientOptions) -> (BridgeClient, Events)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        BridgeClient::with_handlers(reader, writer, options, Arc::new(Handlers::default()))
+    }
+
+    /// Same as [`new`](Self::new), answering requests from the bridge with
+    /// `handlers`: the actions they have a handler for, and, once any handler
+    /// is registered, every other message that isn't an answer or an
+    /// [`Event`] of its own.
+    #[cfg(feature = "tokio")]
+    pub fn with_handlers<R, W>(reader: R, writer: W, options: ClientOptions, handlers: Arc<Handlers>) -> (BridgeClient, Events)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        BridgeClient::start(reader, writer, options, handlers, Arc::new(Tokio))
+    }
+
+    /// Same as [`with_handlers`](Self::with_handlers) over [`futures_io`]
+    /// streams, running on `executor`, for apps on a runtime other than
+    /// tokio.
+    pub fn with_executor<R, W>(
+        reader: R,
+        writer: W,
+        options: ClientOptions,
+        handlers: Arc<Handlers>,
+        executor: Arc<dyn Executor>,
+    ) -> (BridgeClient, Events)
+    where
+        R: futures_io::AsyncRead + Unpin + Send + 'static,
+        W: futures_io::AsyncWrite + Unpin + Send + 'static,
+    {
+        BridgeClient::start(reader.compat(), writer.compat_write(), options, handlers, executor)
+    }
+
+    fn start<R, W>(reader: R, writer: W, options: ClientOptions, handlers: Arc<Handlers>, executor: Arc<dyn Executor>) -> (BridgeClient, Events)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(32);
+        let (event_tx, event_rx) = mpsc::channel(64);
+        let waiters = Waiters::default();
+        let task_timeout = options.task_timeout;
+        let state = Arc::new(Mutex::new(ConnectionState::Handshaking));
+        let seal = Arc::new(Mutex::new(options.seal_key.clone().map(|key| SealedChannel::new(key, Side::MainApp))));
+        let requests = Requests { handlers, executor: executor.clone(), state: state.clone() };
+        executor.spawn(Box::pin(write_frames(writer, outgoing_rx, seal.clone())));
+        executor.spawn(Box::pin(read_frames(reader, outgoing_tx.clone(), event_tx, waiters.clone(), requests, seal.clone(), options)));
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos());
+        let msg_id_prefix = format!("app.{:x}.{:x}", std::process::id(), started);
+        let client = BridgeClient { outgoing: outgoing_tx, waiters, next_id: AtomicU64::new(1), msg_id_prefix, task_timeout, executor, state, seal };
+        (client, Events(event_rx))
+    }
+
+    /// Runs `task` in the browser and waits for its result, for the task's
+    /// [`timeout_ms`](Task::timeout_ms), else the
+    /// [`ClientOptions::task_timeout`], at most. A task that runs out of time
+    /// is cancelled.
+    pub async fn send_task(&self, task: Task) -> Result<TaskResult, ClientError> {
+        self.start_task(task).await?.await
+    }
+
+    /// Same as [`send_task`](Self::send_task) with its own timeout. The task
+    /// is sent with the timeout as its TTL, so the bridge drops it rather than
+    /// start it late.
+    pub async fn send_task_within(&self, task: Task, timeout: Duration) -> Result<TaskResult, ClientError> {
+        self.start_task_within(task, timeout).await?.await
+    }
+
+    /// Sends `task` and returns without waiting for its result. Await the
+    /// [`TaskHandle`] for the result, within the same timeout as
+    /// [`send_task`](Self::send_task), or [`cancel`](TaskHandle::cancel) it.
+    pub async fn start_task(&self, task: Task) -> Result<TaskHandle, ClientError> {
+        let timeout = task.timeout_ms.map_or(self.task_timeout, Duration::from_millis);
+        self.start_task_within(task, timeout).await
+    }
+
+    /// Same as [`start_task`](Self::start_task) with its own timeout, which
+    /// is also the task's TTL.
+    pub async fn start_task_within(&self, task: Task, timeout: Duration) -> Result<TaskHandle, ClientError> {
+        let task_id = format!("task-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.waiters.answers.lock().unwrap().expect(Expect::Result, task_id.clone(), tx);
+        let (steps_tx, steps_rx) = mpsc::unbounded_channel();
+        self.waiters.steps.lock().unwrap().insert(task_id.clone(), steps_tx);
+        // Whether answered, timed out or dropped, the task isn't waited for anymore
+        let handle = TaskHandle {
+            task_id: task_id.clone(),
+            result: rx,
+            steps: Some(steps_rx),
+            deadline: self.executor.sleep(timeout),
+            timeout,
+            outgoing: self.outgoing.clone(),
+            waiters: self.waiters.clone(),
+            executor: self.executor.clone(),
+        };
+        let message = Message {
+            action: Action::PerformTask,
+            task_id,
+            task: Some(task),
+            data: None,
+            ttl_ms: Some(timeout.as_millis() as u64),
+            msg_id: None,
+        };
+        self.send(&message).await?;
+        Ok(handle)
+    }
+
+    /// Loads `request.url` in the browser and reports what each of the
+    /// selectors matches there (how many elements and the text of the first
+    /// few), without running any step. Waits for the
+    /// [`ClientOptions::task_timeout`] at most.
+    pub async fn resolve_selectors(&self, request: &ResolveSelectors) -> Result<ResolvedSelectors, ClientError> {
+        let task_id = format!("preview-{}", self.next_id.fetch_add(1, Ordering::Relaxed));
+        let (tx, rx) = oneshot::channel();
+        self.waiters.answers.lock().unwrap().expect(Expect::Preview, task_id.clone(), tx);
+        let _waiting = Waiting { answers: &self.waiters.answers, expect: Expect::Preview, id: &task_id };
+        let mut message = Message::resolve_selectors(task_id.as_str(), request);
+        message.ttl_ms = Some(self.task_timeout.as_millis() as u64);
+        self.send(&message).await?;
+        wait_answer(rx, self.executor.sleep(self.task_timeout), self.task_timeout).await.and_then(resolved_selectors)
+    }
+
+    /// Sends any message, e.g. a `commit` or `configure`, without waiting
+    /// for an answer.
+    pub async fn send(&self, message: &Message) -> Result<(), ClientError> {
+        send(&self.outgoing, message).await
+    }
+
+    /// Sends an answer the extension didn't just ask for, e.g. the
+    /// `pair_result` that tells it a pairing was confirmed.
+    pub async fn respond(&self, response: &ExtensionResponse) -> Result<(), ClientError> {
+        let bytes = serde_json::to_vec(response).map_err(io::Error::other)?;
+        self.outgoing.send(bytes).await.map_err(|_| ClientError::Disconnected)
+    }
+
+    /// Sends `message` and waits for the broker's [`Ack`]. A message without
+    /// a `msg_id` is given one; set it to the [`Ack::msg_id`] of an earlier
+    /// delivery to send that message again, e.g. over a new connection after
+    /// the old one broke before the `ack` came. A message the broker already
+    /// took is acknowledged as a [`duplicate`](Ack::duplicate) and doesn't
+    /// reach the extension twice.
+    pub async fn deliver(&self, message: &Message) -> Result<Ack, ClientError> {
+        let mut message = message.clone();
+        let msg_id = message
+            .msg_id
+            .get_or_insert_with(|| format!("{}-{}", self.msg_id_prefix, self.next_id.fetch_add(1, Ordering::Relaxed)))
+            .clone();
+        let (tx, rx) = oneshot::channel();
+        self.waiters.answers.lock().unwrap().expect(Expect::Ack, msg_id.clone(), tx);
+        let _waiting = Waiting { answers: &self.waiters.answers, expect: Expect::Ack, id: &msg_id };
+        self.send(&message).await?;
+        wait_answer(rx, self.executor.sleep(ACK_TIMEOUT), ACK_TIMEOUT).await.and_then(ack)
+    }
+
+    /// Whether the broker is still connected.
+    pub fn is_connected(&self) -> bool {
+        !self.outgoing.is_closed()
+    }
+
+    /// Where the connection is: `handshaking` until the broker's hello is
+    /// answered, `ready`, `draining` once the broker said it is going away,
+    /// and `closed` once it disconnected. Each change is also an
+    /// [`Event::ConnectionState`].
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Seals from now on with `key`, e.g. once a pairing that agreed on one
+    /// is confirmed, or stops sealing with `None`. Messages already queued
+    /// are sent as this says when their turn comes.
+    pub fn seal_with(&self, key: Option<SealKey>) {
+        *self.seal.lock().unwrap() = key.map(|key| SealedChannel::new(key, Side::MainApp));
+    }
+
+    /// How many sealed messages were dropped as replays since the current
+    /// seal key was set (see [`SealedChannel::replays_rejected`]).
+    pub fn replays_rejected(&self) -> u64 {
+        self.seal.lock().unwrap().as_ref().map_or(0, SealedChannel::replays_rejected)
+    }
+}
+
+/// A task sent with [`BridgeClient::start_task`]. Resolves to the task's
+/// result like [`BridgeClient::send_task`]; dropping it stops waiting, but
+/// not the task.
+pub struct TaskHandle {
+    task_id: String,
+    result: oneshot::Receiver<ExtensionResponse>,
+    steps: Option<mpsc::UnboundedReceiver<StepEvent>>,
+    deadline: BoxFuture<()>,
+    timeout: Duration,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    waiters: Waiters,
+    executor: Arc<dyn Executor>,
+}
+
+impl TaskHandle {
+    pub fn task_id(&self) -> &str {
+        &self.task_id
+    }
+
+    /// The progress of the task's steps, from its first step on, to follow
+    /// while awaiting the handle. Only the first call gets them: later ones
+    /// get a stream that has ended, and the events of a task whose stream
+    /// was dropped are [`Event::Step`]s.
+    pub fn steps(&mut self) -> StepEvents {
+        match self.steps.take() {
+            Some(steps) => StepEvents(steps),
+            None => StepEvents(mpsc::unbounded_channel().1),
+        }
+    }
+
+    /// Asks the extension to stop the task before its next step and waits
+    /// for the acknowledgment. `Ok(false)` if the task wasn't running
+    /// anymore. A cancelled task still resolves, failed at the step it
+    /// stopped before.
+    pub async fn cancel(&self, reason: Option<String>) -> Result<bool, ClientError> {
+        let (tx, rx) = oneshot::channel();
+        self.waiters.answers.lock().unwrap().expect(Expect::Cancellation, self.task_id.clone(), tx);
+        let _waiting = Waiting { answers: &self.waiters.answers, expect: Expect::Cancellation, id: &self.task_id };
+        send(&self.outgoing, &Message::cancel_task(self.task_id.as_str(), reason)).await?;
+        wait_answer(rx, self.executor.sleep(CANCEL_TIMEOUT), CANCEL_TIMEOUT).await.and_then(task_cancelled)
+    }
+}
+
+impl Future for TaskHandle {
+    type Output = Result<TaskResult, ClientError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(answer) = Pin::new(&mut self.result).poll(cx) {
+            return Poll::Ready(answer.map_err(|_| ClientError::Disconnected).and_then(task_result));
+        }
+        let timeout = self.timeout;
+        ready!(self.deadline.as_mut().poll(cx));
+        // Nobody waits for the task anymore, so the extension needn't finish it
+        let cancel = Message::cancel_task(self.task_id.as_str(), Some(TASK_TIMEOUT_ERROR.to_string()));
+        let sent = serde_json::to_vec(&cancel).map_err(|e| e.to_string()).and_then(|bytes| self.outgoing.try_send(bytes).map_err(|e| e.to_string()));
+        if let Err(e) = sent {
+            log::warn!("BridgeClient: Could not cancel task {} after its timeout: {}", self.task_id, e);
+        }
+        Poll::Ready(Err(ClientError::Timeout(timeout)))
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        self.waiters.answers.lock().unwrap().forget(Expect::Result, &self.task_id);
+        self.waiters.steps.lock().unwrap().remove(&self.task_id);
+    }
+}
+
+/// Stops waiting for a cancellation (or a delivery) when its caller stops
+/// waiting.
+struct Waiting<'a> {
+    answers: &'a Answers,
+    expect: Expect,
+    id: &'a str,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.answers.lock().unwrap().forget(self.expect, self.id);
+    }
+}
+
+async fn send(outgoing: &mpsc::Sender<Vec<u8>>, message: &Message) -> Result<(), ClientError> {
+    let bytes = serde_json::to_vec(message).map_err(io::Error::other)?;
+    outgoing.send(bytes).await.map_err(|_| ClientError::Disconnected)
+}
+
+/// Waits for the answer on `rx` until `deadline`, which ends after `timeout`.
+async fn wait_answer(mut rx: oneshot::Receiver<ExtensionResponse>, mut deadline: BoxFuture<()>, timeout: Duration) -> Result<ExtensionResponse, ClientError> {
+    let answer = std::future::poll_fn(|cx| match Pin::new(&mut rx).poll(cx) {
+        Poll::Ready(answer) => Poll::Ready(Some(answer)),
+        Poll::Pending => deadline.as_mut().poll(cx).map(|()| None),
+    });
+    match answer.await {
+        Some(Ok(response)) => Ok(response),
+        Some(Err(_)) => Err(ClientError::Disconnected),
+        None => Err(ClientError::Timeout(timeout)),
+    }
+}
+
+/// Whether the task was cancelled, from the answer to a `cancel_task`.
+fn task_cancelled(response: ExtensionResponse) -> Result<bool, ClientError> {
+    if response.action != TASK_CANCELLED_ACTION {
+        let code = response.result.as_ref().and_then(|result| result.get("code")).and_then(|code| code.as_str()).map(String::from);
+        return Err(ClientError::Rejected { action: response.action, code, error: response.error });
+    }
+    let acknowledgment = response.result.map(serde_json::from_value::<TaskCancelled>).transpose().map_err(|e| ClientError::Malformed(e.to_string()))?;
+    Ok(acknowledgment.is_some_and(|acknowledgment| acknowledgment.cancelled))
+}
+
+/// What the selectors matched, from the answer to a `resolve_selectors`.
+fn resolved_selectors(response: ExtensionResponse) -> Result<ResolvedSelectors, ClientError> {
+    if response.action != RESOLVE_SELECTORS_RESULT_ACTION || !response.success {
+        let code = response.result.as_ref().and_then(|result| result.get("code")).and_then(|code| code.as_str()).map(String::from);
+        return Err(ClientError::Rejected { action: response.action, code, error: response.error });
+    }
+    let result = response.result.ok_or_else(|| ClientError::Malformed("resolve_selectors_result without a result".to_string()))?;
+    serde_json::from_value(result).map_err(|e| ClientError::Malformed(e.to_string()))
+}
+
+/// The broker's acknowledgment of a delivered message.
+fn ack(response: ExtensionResponse) -> Result<Ack, ClientError> {
+    let result = response.result.ok_or_else(|| ClientError::Malformed("ack without a result".to_string()))?;
+    serde_json::from_value(result).map_err(|e| ClientError::Malformed(e.to_string()))
+}
+
+/// The outcome of a task from the answer to it.
+fn task_result(response: ExtensionResponse) -> Result<TaskResult, ClientError> {
+    if response.action != TASK_RESULT_ACTION {
+        let code = response.result.as_ref().and_then(|result| result.get("code")).and_then(|code| code.as_str()).map(String::from);
+        return Err(ClientError::Rejected { action: response.action, code, error: response.error });
+    }
+    let result = response
+        .result
+        .map(serde_json::from_value::<TaskResult>)
+        .transpose()
+        .map_err(|e| ClientError::Malformed(e.to_string()));
+    match (response.success, result) {
+        (true, Ok(Some(result))) => Ok(result),
+        (true, Ok(None)) => Err(ClientError::Malformed("task_result without a result".to_string())),
+        (true, Err(e)) => Err(e),
+        (false, result) => Err(ClientError::TaskFailed { error: response.error, result: result.ok().flatten() }),
+    }
+}
+
+/// Writes queued messages to the broker until the client is dropped or the
+/// connection fails, sealing those for the extension once a key is set.
+async fn write_frames<W: AsyncWrite + Unpin>(mut writer: W, mut outgoing: mpsc::Receiver<Vec<u8>>, seal: Seal) {
+    while let Some(bytes) = outgoing.recv().await {
+        let sealed = match seal.lock().unwrap().as_mut().filter(|_| !may_be_unsealed(&bytes)) {
+            Some(channel) => channel.seal(&bytes).map(Some),
+            None => Ok(None),
+        };
+        let bytes = match sealed {
+            Ok(sealed) => sealed.unwrap_or(bytes),
+            Err(e) => {
+                log::error!("BridgeClient: Dropping message that could not be sealed: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = write_frame(&mut writer, FrameFlags::NONE, 0, &bytes, "BridgeClient").await {
+            log::error!("BridgeClient: Error writing to broker: {}", e);
+            break;
+        }
+    }
+}
+
+/// Where requests from the bridge go: to the handlers, each run on its own.
+/// Also where the state of the connection is kept.
+struct Requests {
+    handlers: Arc<Handlers>,
+    executor: Arc<dyn Executor>,
+    state: State,
+}
+
+/// Reads from the broker until it disconnects: answers the handshake and
+/// heartbeats, hands results and step events to the tasks waiting for them,
+/// requests to the handlers and everything else to the event stream.
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: R,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    events: mpsc::Sender<Event>,
+    waiters: Waiters,
+    requests: Requests,
+    seal: Seal,
+    options: ClientOptions,
+) {
+    let Requests { handlers, executor, state } = requests;
+    let Waiters { answers, steps } = waiters;
+    let mut recorder = options.record_to.as_deref().and_then(|path| match Recorder::create(path) {
+        Ok(recorder) => Some(recorder),
+        Err(e) => {
+            log::warn!("BridgeClient: Not recording to {}: {}", path.display(), e);
+            None
+        }
+    });
+    let mut processed = options.processed_keys.as_deref().and_then(|path| match ProcessedKeys::open(path) {
+        Ok(processed) => Some(processed),
+        Err(e) => {
+            log::warn!("BridgeClient: Not asking for at-least-once delivery, {} can't be read: {}", path.display(), e);
+            None
+        }
+    });
+    loop {
+        let frame = match read_frame_limited(&mut reader, options.max_message_size, "BridgeClient").await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                // An oversized message was skipped; tell the sender and read on
+                if let Some(too_large) = MessageTooLarge::of(&e) {
+                    if let Ok(bytes) = serde_json::to_vec(&ExtensionResponse::message_too_large(too_large)) {
+                        let _ = outgoing.send(bytes).await;
+                    }
+                    continue;
+                }
+                log::error!("BridgeClient: Error reading from broker: {}", e);
+                break;
+            }
+        };
+        let value: Value = match frame.deserialize() {
+            Ok(value) => value,
+            Err(e) => {
+                log::warn!("BridgeClient: Dropping message that can't be decoded: {}", e);
+                continue;
+            }
+        };
+        let Some(value) = unseal(value, &seal) else {
+            continue;
+        };
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record(&value);
+        }
+        let action = Action::of(&value).unwrap_or_else(|| Action::Unknown(String::new()));
+        let task_id = value.get("task_id").and_then(Value::as_str).unwrap_or_default().to_string();
+
+        if is_going_away(&action, &value) {
+            advance(&state, ConnectionState::Draining, &events).await;
+        }
+        match action {
+            Action::Hello => {
+                let Some((hello, agreed, ack)) = answer_hello(&value, &task_id, &options, processed.is_some()) else {
+                    log::warn!("BridgeClient: Ignoring malformed hello.");
+                    continue;
+                };
+                if outgoing.send(ack).await.is_err() {
+                    break;
+                }
+                let _ = events.send(Event::Hello(hello)).await;
+                if agreed {
+                    advance(&state, ConnectionState::Ready, &events).await;
+                }
+                continue;
+            }
+            Action::Ping if Heartbeat::is_heartbeat(&task_id) => {
+                let pong = ExtensionResponse { action: Action::Pong, task_id, success: true, result: None, error: None };
+                if outgoing.send(serde_json::to_vec(&pong).unwrap_or_default()).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        // Under at-least-once delivery each of the extension's messages carries a key to acknowledge
+        let delivered = msg_id(&value).filter(|_| processed.is_some()).map(str::to_string);
+        if let Some(key) = delivered.as_ref().filter(|key| processed.as_ref().is_some_and(|processed| processed.contains(key))) {
+            log::info!("BridgeClient: Skipping {} {} of task {}, it was processed before.", action, key, task_id);
+            let ack = Message::ack(task_id.clone(), key.clone(), true);
+            if outgoing.send(serde_json::to_vec(&ack).unwrap_or_default()).await.is_err() {
+                break;
+            }
+            continue;
+        }
+        'handed_on: {
+            if let Some(step) = StepEvent::of(&value) {
+                let unread = match steps.lock().unwrap().get(&task_id) {
+                    Some(tx) => tx.send(step).err().map(|unread| unread.0),
+                    None => Some(step),
+                };
+                if let Some(step) = unread {
+                    // Nobody follows the task's steps (anymore), so they are events
+                    steps.lock().unwrap().remove(&task_id);
+                    let _ = events.send(Event::Step { task_id: task_id.clone(), event: step }).await;
+                }
+                break 'handed_on;
+            }
+            if Expect::answered_by(&value).is_some_and(|(expects, _)| expects.contains(&Expect::Result)) {
+                // The last step event came before, so the task's stream ends
+                steps.lock().unwrap().remove(&task_id);
+            }
+            let waiter = answers.lock().unwrap().answer(&value);
+            if let Some(waiter) = waiter {
+                match serde_json::from_value::<ExtensionResponse>(value) {
+                    Ok(response) => {
+                        let _ = waiter.send(response);
+                    }
+                    // Dropping the waiter fails the task
+                    Err(e) => log::error!("BridgeClient: Malformed {} for task {}: {}", action, task_id, e),
+                }
+                break 'handed_on;
+            }
+            // Acks nobody waits for are of messages sent without `deliver`
+            if action == Action::Ack {
+                break 'handed_on;
+            }
+            if handlers.handles(&action) || (!handlers.is_empty() && is_request(&action, &value)) {
+                let request = match serde_json::from_value::<Message>(value) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        log::warn!("BridgeClient: Not handling malformed {}: {}", action, e);
+                        break 'handed_on;
+                    }
+                };
+                // Handlers run on their own so a slow one doesn't hold up results
+                let (handlers, outgoing) = (handlers.clone(), outgoing.clone());
+                executor.spawn(Box::pin(async move {
+                    let response = handlers.dispatch(request).await;
+                    if let Ok(bytes) = serde_json::to_vec(&response) {
+                        let _ = outgoing.send(bytes).await;
+                    }
+                }));
+                break 'handed_on;
+            }
+            let _ = events.send(event(&action, value)).await;
+        }
+        // Only once the message was taken, and its key kept, is it acknowledged
+        if let Some((processed, key)) = processed.as_mut().zip(delivered) {
+            if let Err(e) = processed.insert(&key) {
+                log::error!("BridgeClient: Not acknowledging {}, it could not be recorded in {}: {}", key, processed.path().display(), e);
+                continue;
+            }
+            let ack = Message::ack(task_id, key, false);
+            if outgoing.send(serde_json::to_vec(&ack).unwrap_or_default()).await.is_err() {
+                break;
+            }
+        }
+    }
+    // Tasks and cancellations still waiting fail as their senders go
+    answers.lock().unwrap().clear();
+    steps.lock().unwrap().clear();
+    advance(&state, ConnectionState::Closed, &events).await;
+}
+
+/// The message `value` wraps if it is sealed, `value` itself if it may come
+/// unsealed. `None`, and logged, for a message that must be dropped.
+fn unseal(value: Value, seal: &Seal) -> Option<Value> {
+    let bytes = serde_json::to_vec(&value).ok()?;
+    let mut seal = seal.lock().unwrap();
+    if !is_sealed(&bytes) {
+        if seal.is_some() && !may_be_unsealed(&bytes) {
+            // Only the broker's own messages can't be sealed
+            log::error!("BridgeClient: Dropping unsealed message, the extension agreed to seal them.");
+            return None;
+        }
+        return Some(value);
+    }
+    let Some(channel) = seal.as_mut() else {
+        log::error!("BridgeClient: Dropping sealed message, no seal key is set (see ClientOptions::seal_key).");
+        return None;
+    };
+    match channel.open(&bytes).and_then(|opened| serde_json::from_slice(&opened).map_err(io::Error::other)) {
+        Ok(opened) => Some(opened),
+        Err(e) => {
+            log::error!("BridgeClient: Dropping sealed message: {}", e);
+            None
+        }
+    }
+}
+
+/// Moves the connection to `next`, if it may go there, and tells the event
+/// stream.
+async fn advance(state: &State, next: ConnectionState, events: &mpsc::Sender<Event>) {
+    let change = state.lock().unwrap().advance(next);
+    if let Some(change) = change {
+        log::info!("BridgeClient: Connection {:?} -> {:?}", change.previous, change.state);
+        let _ = events.send(Event::ConnectionState(change)).await;
+    }
+}
+
+/// Whether `message` says the broker is about to go away.
+fn is_going_away(action: &Action, message: &Value) -> bool {
+    match action {
+        Action::Shutdown => true,
+        Action::BrokerState => message
+            .get("result")
+            .and_then(|result| serde_json::from_value::<BrokerStateChange>(result.clone()).ok())
+            .is_some_and(|change| matches!(change.state, BrokerState::Draining | BrokerState::ShuttingDown)),
+        _ => false,
+    }
+}
+
+/// The broker's hello, whether we agreed with it and our answer to it,
+/// refusing a broker of another major protocol version. `None` for a
+/// malformed hello.
+fn answer_hello(message: &Value, task_id: &str, options: &ClientOptions, at_least_once: bool) -> Option<(Hello, bool, Vec<u8>)> {
+    // Frames are read decompressed and decoded, so the broker may send any of these
+    let mut capabilities = COMPRESSION_CAPABILITIES.to_vec();
+    capabilities.extend(options.encoding.capability());
+    capabilities.push(ACK_CAPABILITY);
+    if at_least_once {
+        capabilities.push(AT_LEAST_ONCE_CAPABILITY);
+    }
+    let mut handshake = Handshake::new(Hello::new(options.software.clone(), &capabilities));
+    let (broker, error) = match handshake.on_hello(message) {
+        Verdict::Agreed(broker) => {
+            log::info!("BridgeClient: Broker {} (protocol {}) connected.", broker.software, broker.protocol_version);
+            (broker, None)
+        }
+        Verdict::Incompatible(broker, mismatch) => {
+            let error = format!("broker speaks {}", mismatch);
+            log::error!("BridgeClient: Refusing broker {}: {}", broker.software, error);
+            (broker, Some(error))
+        }
+        Verdict::Rejected(..) | Verdict::Malformed => return None,
+    };
+    let ack = handshake.hello_ack(task_id, error.as_deref());
+    Some((broker, error.is_none(), serde_json::to_vec(&ack).unwrap_or_default()))
+}
+
+/// Types an unsolicited message, falling back to [`Event::Other`].
+fn event(action: &Action, value: Value) -> Event {
+    let field = |name: &str| value.get(name).cloned().unwrap_or_default();
+    let task_id = value.get("task_id").and_then(Value::as_str).unwrap_or_default().to_string();
+    let typed = match action {
+        Action::BrokerState => serde_json::from_value(field("result")).ok().map(Event::BrokerState),
+        Action::Log => serde_json::from_value(field("data")).ok().map(|log| Event::Log { task_id, log }),
+        Action::CommitRequest => serde_json::from_value(field("data")).ok().map(|request| Event::CommitRequest { task_id, request }),
+        _ => None,
+    };
+    typed.unwrap_or(Event::Other(value))
+}
+
+/// Whether a message without a handler of its own goes to the unknown-action
+/// handler: anything but an answer (those carry `success`) or a typed
+/// [`Event`].
+fn is_request(action: &Action, value: &Value) -> bool {
+    let event = matches!(action, Action::BrokerState | Action::Log | Action::CommitRequest | Action::StepStarted | Action::StepProgress | Action::StepCompleted);
+    value.get("success").is_none() && !event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::frame::read_frame;
+    use shared_types::{ImageFormat, KeyExchange, Screenshot, Step, StepErrorKind, ACK_ACTION, SEALED_ACTION, BRIDGE_ERROR_ACTION, HEARTBEAT_TASK_PREFIX, CANCEL_TASK_ACTION, E_UNKNOWN_ACTION, HELLO_ACK_ACTION, PERFORM_TASK_ACTION, PROTOCOL_VERSION};
+    use tokio::io::{duplex, split, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    /// A client and the broker's ends of its connection.
+    fn connect(options: ClientOptions) -> (BridgeClient, Events, ReadHalf<DuplexStream>, WriteHalf<DuplexStream>) {
+        let (client_side, broker_side) = duplex(64 * 1024);
+        let (reader, writer) = split(client_side);
+        let (client, events) = BridgeClient::new(reader, writer, options);
+        let (broker_reader, broker_writer) = split(broker_side);
+        (client, events, broker_reader, broker_writer)
+    }
+
+    fn options() -> ClientOptions {
+        ClientOptions {
+            software: "test app".to_string(),
+            task_timeout: Duration::from_secs(5),
+            encoding: Encoding::Json,
+            max_message_size: 1024 * 1024,
+            record_to: None,
+            processed_keys: None,
+            seal_key: None,
+        }
+    }
+
+    async fn next_json(reader: &mut ReadHalf<DuplexStream>) -> Value {
+        let frame = read_frame(reader, "test").await.unwrap().unwrap();
+        serde_json::from_slice(&frame.payload).unwrap()
+    }
+
+    async fn send_json(writer: &mut WriteHalf<DuplexStream>, value: Value) {
+        write_frame(writer, FrameFlags::NONE, 0, &serde_json::to_vec(&value).unwrap(), "test").await.unwrap();
+    }
+
+    fn task() -> Task {
+        Task { steps: vec![Step::Navigate { url: "https://example.com".to_string(), destructive: None }], ..Task::default() }
+    }
+
+    #[tokio::test]
+    async fn answers_the_handshake_and_heartbeats() {
+        let (client, mut events, mut reader, mut writer) = connect(ClientOptions { encoding: Encoding::MessagePack, ..options() });
+        let hello = Hello::new("rzn_broker test", &["heartbeat"]);
+        send_json(&mut writer, serde_json::json!({ "action": "hello", "task_id": "broker-hello", "data": hello })).await;
+        let ack = next_json(&mut reader).await;
+        assert_eq!((ack["action"].as_str(), ack["success"].as_bool()), (Some(HELLO_ACK_ACTION), Some(true)));
+        assert_eq!(ack["result"]["protocol_version"], PROTOCOL_VERSION);
+        assert!(ack["result"]["capabilities"].as_array().unwrap().contains(&"encoding:msgpack".into()));
+        assert!(matches!(events.next().await, Some(Event::Hello(hello)) if hello.software == "rzn_broker test"));
+        let ready = ConnectionStateChange { state: ConnectionState::Ready, previous: ConnectionState::Handshaking };
+        assert!(matches!(events.next().await, Some(Event::ConnectionState(change)) if change == ready));
+        assert_eq!(client.state(), ConnectionState::Ready);
+
+        let ping = Heartbeat::task_id(3);
+        send_json(&mut writer, serde_json::json!({ "action": "ping", "task_id": ping })).await;
+        let pong = next_json(&mut reader).await;
+        assert_eq!((pong["action"].as_str(), pong["task_id"].as_str()), (Some("pong"), Some(ping.as_str())));
+    }
+
+    #[tokio::test]
+    async fn correlates_results_with_their_tasks() {
+        let (client, mut events, mut reader, mut writer) = connect(options());
+        let client = Arc::new(client);
+        let first = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_task(task()).await }
+        });
+        let first_id = next_json(&mut reader).await["task_id"].as_str().unwrap().to_string();
+        let second = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_task(task()).await }
+        });
+        let sent = next_json(&mut reader).await;
+        assert_eq!((sent["action"].as_str(), sent["ttl_ms"].as_u64()), (Some(PERFORM_TASK_ACTION), Some(5000)));
+        let second_id = sent["task_id"].as_str().unwrap().to_string();
+
+        // Answered out of order, with a log record in between
+        send_json(&mut writer, serde_json::json!({
+            "action": "task_result", "task_id": second_id, "success": false, "error": "Timeout",
+            "result": { "steps": [{ "type": "navigate", "success": false, "error": "Timeout" }] },
+        })).await;
+        send_json(&mut writer, serde_json::json!({
+            "action": "log", "task_id": first_id, "data": { "level": "info", "scope": "handleTask", "message": "navigating" },
+        })).await;
+        send_json(&mut writer, serde_json::json!({
+            "action": "task_result", "task_id": first_id, "success": true,
+            "result": { "steps": [{ "type": "navigate", "success": true }] },
+        })).await;
+
+        let first = first.await.unwrap().unwrap();
+        assert!(first.steps[0].success);
+        match second.await.unwrap() {
+            Err(ClientError::TaskFailed { error, result }) => {
+                assert_eq!(error.as_deref(), Some("Timeout"));
+                assert_eq!(result.unwrap().steps.len(), 1);
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert!(matches!(events.next().await, Some(Event::Log { task_id, log }) if task_id == first_id && log.message == "navigating"));
+    }
+
+    #[tokio::test]
+    async fn seals_and_opens_messages_for_the_extension() {
+        let (app, extension) = (KeyExchange::new().unwrap(), KeyExchange::new().unwrap());
+        let key = app.agree(&extension.public_key()).unwrap();
+        let mut sealed = SealedChannel::new(key.clone(), Side::Extension);
+        let (client, mut events, mut reader, mut writer) = connect(ClientOptions { seal_key: Some(key), ..options() });
+        let client = Arc::new(client);
+        let running = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_task(task()).await }
+        });
+        let sent = next_json(&mut reader).await;
+        assert_eq!(sent["action"], SEALED_ACTION);
+        let opened: Value = serde_json::from_slice(&sealed.open(&serde_json::to_vec(&sent).unwrap()).unwrap()).unwrap();
+        assert_eq!(opened["action"], PERFORM_TASK_ACTION);
+
+        // Heartbeats stay readable to the broker
+        send_json(&mut writer, serde_json::json!({ "action": "ping", "task_id": Heartbeat::task_id(1) })).await;
+        assert_eq!(next_json(&mut reader).await["action"], "pong");
+
+        // An unsealed result is dropped, the sealed one answers the task, and a replayed log is dropped
+        let result = serde_json::json!({
+            "action": "task_result", "task_id": sent["task_id"], "success": true,
+            "result": { "steps": [{ "type": "navigate", "success": true }] },
+        });
+        send_json(&mut writer, result.clone()).await;
+        let log = serde_json::json!({ "action": "log", "task_id": sent["task_id"], "data": { "level": "info", "scope": "handleTask", "message": "sealed" } });
+        let log: Value = serde_json::from_slice(&sealed.seal(&serde_json::to_vec(&log).unwrap()).unwrap()).unwrap();
+        send_json(&mut writer, log.clone()).await;
+        send_json(&mut writer, log).await;
+        send_json(&mut writer, serde_json::from_slice(&sealed.seal(&serde_json::to_vec(&result).unwrap()).unwrap()).unwrap()).await;
+        assert!(running.await.unwrap().unwrap().steps[0].success);
+        assert!(matches!(events.next().await, Some(Event::Log { log, .. }) if log.message == "sealed"));
+        assert_eq!(client.replays_rejected(), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_sealed_messages_without_a_key() {
+        let (_client, mut events, _reader, mut writer) = connect(options());
+        let key = KeyExchange::new().unwrap().agree(&KeyExchange::new().unwrap().public_key()).unwrap();
+        let log = serde_json::json!({ "action": "log", "task_id": "t1", "data": { "level": "info", "scope": "handleTask", "message": "sealed" } });
+        let sealed = SealedChannel::new(key, Side::Extension).seal(&serde_json::to_vec(&log).unwrap()).unwrap();
+        send_json(&mut writer, serde_json::from_slice(&sealed).unwrap()).await;
+        send_json(&mut writer, log).await;
+        assert!(matches!(events.next().await, Some(Event::Log { log, .. }) if log.message == "sealed"));
+    }
+
+    #[tokio::test]
+    async fn hands_back_screenshots_as_bytes() {
+        let (client, _events, mut reader, mut writer) = connect(options());
+        let mut task = task();
+        task.steps.push(Step::Screenshot { selector: None, full_page: true, format: ImageFormat::Jpeg, quality: Some(80) });
+        let running = tokio::spawn(async move { client.send_task(task).await });
+        let sent = next_json(&mut reader).await;
+        assert_eq!(sent["task"]["steps"][1], serde_json::json!({ "type": "screenshot", "full_page": true, "format": "jpeg", "quality": 80 }));
+
+        let image = vec![0xff, 0xd8, 0xff, 0x00, 0x10];
+        let screenshot = Screenshot { format: ImageFormat::Jpeg, width: 2, height: 1, image: image.clone(), clipped: false };
+        send_json(&mut writer, serde_json::json!({
+            "action": "task_result", "task_id": sent["task_id"], "success": true,
+            "result": { "steps": [{ "type": "navigate", "success": true }, { "type": "screenshot", "success": true, "data": screenshot }] },
+        })).await;
+        let result = running.await.unwrap().unwrap();
+        assert_eq!(result.steps[1].data.as_ref().unwrap()["image"], "/9j/ABA=");
+        assert_eq!(result.steps[1].screenshot().map(|screenshot| screenshot.image), Some(image));
+        assert!(result.steps[0].screenshot().is_none());
+    }
+
+    #[tokio::test]
+    async fn previews_what_selectors_match() {
+        let (client, _events, mut reader, mut writer) = connect(options());
+        let request = ResolveSelectors { url: "http://127.0.0.1/scrape".to_string(), selectors: vec!["li.item".into(), "#missing".into()], samples: Some(2) };
+        let preview = tokio::spawn(async move { client.resolve_selectors(&request).await });
+        let sent = next_json(&mut reader).await;
+        assert_eq!(sent["action"], "resolve_selectors");
+        assert_eq!(sent["data"]["selectors"], serde_json::json!(["li.item", "#missing"]));
+
+        send_json(&mut writer, serde_json::json!({
+            "action": "resolve_selectors_result", "task_id": sent["task_id"], "success": true,
+            "result": { "url": "http://127.0.0.1/scrape", "matches": [
+                { "selector": "li.item", "count": 3, "samples": ["Alpha", "Beta"] },
+                { "selector": "#missing", "count": 0 },
+            ] },
+        })).await;
+        let resolved = preview.await.unwrap().unwrap();
+        assert_eq!((resolved.matches[0].count, resolved.matches[0].samples.as_slice()), (3, ["Alpha".to_string(), "Beta".to_string()].as_slice()));
+        assert_eq!(resolved.matches[1].count, 0);
+    }
+
+    #[tokio::test]
+    async fn fails_tasks_that_time_out_or_lose_the_broker() {
+        let (client, mut events, mut reader, writer) = connect(options());
+        let timed = Task { timeout_ms: Some(50), ..task() };
+        assert!(matches!(client.send_task(timed).await, Err(ClientError::Timeout(t)) if t == Duration::from_millis(50)));
+        let late_id = next_json(&mut reader).await["task_id"].as_str().unwrap().to_string();
+        // The extension is told to stop it
+        let cancel = next_json(&mut reader).await;
+        assert_eq!((cancel["action"].as_str(), cancel["task_id"].as_str()), (Some(CANCEL_TASK_ACTION), Some(late_id.as_str())));
+        assert_eq!(cancel["data"]["reason"], TASK_TIMEOUT_ERROR);
+
+        // A result nobody waits for anymore is an event
+        let mut writer = writer;
+        send_json(&mut writer, serde_json::json!({ "action": "task_result", "task_id": late_id, "success": true, "result": { "steps": [] } })).await;
+        assert!(matches!(events.next().await, Some(Event::Other(value)) if value["task_id"] == late_id.as_str()));
+
+        let waiting = client.send_task(task());
+        let disconnect = async {
+            next_json(&mut reader).await;
+            writer.shutdown().await.unwrap();
+        };
+        let (outcome, ()) = tokio::join!(waiting, disconnect);
+        assert!(matches!(outcome, Err(ClientError::Disconnected)));
+        assert!(matches!(events.next().await, Some(Event::ConnectionState(change)) if change.state == ConnectionState::Closed));
+        assert!(events.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn cancels_a_running_task() {
+        let (client, _events, mut reader, mut writer) = connect(options());
+        let handle = client.start_task(task()).await.unwrap();
+        let task_id = next_json(&mut reader).await["task_id"].as_str().unwrap().to_string();
+        assert_eq!(handle.task_id(), task_id);
+
+        let extension = async {
+            let cancel = next_json(&mut reader).await;
+            assert_eq!((cancel["action"].as_str(), cancel["data"]["reason"].as_str()), (Some(CANCEL_TASK_ACTION), Some("stopped by user")));
+            send_json(&mut writer, serde_json::to_value(ExtensionResponse::task_cancelled(task_id.as_str(), true)).unwrap()).await;
+            send_json(&mut writer, serde_json::json!({
+                "action": "task_result", "task_id": task_id, "success": false, "error": "Cancelled: stopped by user",
+                "result": { "steps": [{ "type": "navigate", "success": false, "error": "Cancelled: stopped by user", "error_kind": "cancelled" }] },
+            })).await;
+        };
+        let (cancelled, ()) = tokio::join!(handle.cancel(Some("stopped by user".to_string())), extension);
+        assert!(cancelled.unwrap());
+        match handle.await {
+            Err(ClientError::TaskFailed { result, .. }) => assert_eq!(result.unwrap().steps[0].error_kind, Some(StepErrorKind::Cancelled)),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_the_progress_of_steps() {
+        let (client, mut events, mut reader, mut writer) = connect(options());
+        let mut handle = client.start_task(task()).await.unwrap();
+        let mut steps = handle.steps();
+        let task_id = next_json(&mut reader).await["task_id"].as_str().unwrap().to_string();
+
+        for (action, data) in [
+            (STEP_STARTED_ACTION, serde_json::json!({ "step_index": 0, "type": "navigate" })),
+            (STEP_PROGRESS_ACTION, serde_json::json!({ "step_index": 0, "message": "loading", "fraction": 0.5 })),
+            (STEP_COMPLETED_ACTION, serde_json::json!({ "step_index": 0, "result": { "type": "navigate", "success": true } })),
+        ] {
+            send_json(&mut writer, serde_json::json!({ "action": action, "task_id": task_id, "data": data })).await;
+        }
+        send_json(&mut writer, serde_json::json!({
+            "action": "task_result", "task_id": task_id, "success": true,
+            "result": { "steps": [{ "type": "navigate", "success": true }] },
+        })).await;
+
+        assert!(matches!(steps.next().await, Some(StepEvent::Started(started)) if started.step_type == "navigate"));
+        assert!(matches!(steps.next().await, Some(StepEvent::Progress(progress)) if progress.fraction == Some(0.5)));
+        assert!(matches!(steps.next().await, Some(StepEvent::Completed(completed)) if completed.result.success));
+        assert!(steps.next().await.is_none());
+        assert_eq!(handle.await.unwrap().steps.len(), 1);
+        // Only the first call gets the steps
+        let mut second = client.start_task(task()).await.unwrap();
+        second.steps();
+        assert!(second.steps().next().await.is_none());
+
+        // Steps of a task nobody follows are events
+        send_json(&mut writer, serde_json::json!({ "action": STEP_STARTED_ACTION, "task_id": "gone", "data": { "step_index": 2, "type": "click" } })).await;
+        assert!(matches!(events.next().await, Some(Event::Step { task_id, event }) if task_id == "gone" && event.step_index() == 2));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_ack_of_a_delivery() {
+        let (client, mut events, mut reader, mut writer) = connect(options());
+        let commit = Message { action: Action::Commit, task_id: "t-1".to_string(), task: None, data: None, ttl_ms: None, msg_id: None };
+        let broker = async {
+            let sent = next_json(&mut reader).await;
+            assert!(sent["msg_id"].as_str().unwrap().starts_with("app."));
+            // Acks of other messages are dropped
+            send_json(&mut writer, serde_json::to_value(ExtensionResponse::ack("t-0", "other", false)).unwrap()).await;
+            send_json(&mut writer, serde_json::to_value(ExtensionResponse::ack("t-1", sent["msg_id"].as_str().unwrap(), false)).unwrap()).await;
+        };
+        let (delivered, ()) = tokio::join!(client.deliver(&commit), broker);
+        let delivered = delivered.unwrap();
+        assert!(!delivered.duplicate);
+
+        // Sent again under the same ID, as after a reconnect
+        let again = Message { msg_id: Some(delivered.msg_id.clone()), ..commit };
+        let broker = async {
+            assert_eq!(next_json(&mut reader).await["msg_id"], delivered.msg_id.as_str());
+            send_json(&mut writer, serde_json::to_value(ExtensionResponse::ack("t-1", delivered.msg_id.as_str(), true)).unwrap()).await;
+        };
+        let (duplicate, ()) = tokio::join!(client.deliver(&again), broker);
+        assert!(duplicate.unwrap().duplicate);
+        send_json(&mut writer, serde_json::json!({ "action": "log", "task_id": "t-1", "data": { "level": "info", "scope": "test", "message": "after" } })).await;
+        assert!(matches!(events.next().await, Some(Event::Log { .. })));
+    }
+
+    #[tokio::test]
+    async fn acknowledges_each_message_once_under_at_least_once_delivery() {
+        let path = std::env::temp_dir().join(format!("rzn-client-keys-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (_client, mut events, mut reader, mut writer) = connect(ClientOptions { processed_keys: Some(path.clone()), ..options() });
+        send_json(&mut writer, serde_json::json!({ "action": "hello", "task_id": "broker-hello", "data": Hello::new("rzn_broker test", &[]) })).await;
+        let ack = next_json(&mut reader).await;
+        assert!(ack["result"]["capabilities"].as_array().unwrap().contains(&AT_LEAST_ONCE_CAPABILITY.into()));
+
+        let log = |msg_id: &str| serde_json::json!({ "msg_id": msg_id, "action": "log", "task_id": "t-1", "data": { "level": "info", "scope": "test", "message": "copy" } });
+        for duplicate in [false, true] {
+            send_json(&mut writer, log("b.e-1")).await;
+            let ack = next_json(&mut reader).await;
+            assert_eq!((ack["action"].as_str(), ack["data"]["msg_id"].as_str()), (Some(ACK_ACTION), Some("b.e-1")));
+            assert_eq!(ack["data"]["duplicate"], duplicate);
+            // Acknowledged once handed on; the second copy is skipped
+            let logs = std::iter::from_fn(|| events.0.try_recv().ok()).filter(|event| matches!(event, Event::Log { .. })).count();
+            assert_eq!(logs, usize::from(!duplicate));
+        }
+        assert!(ProcessedKeys::open(&path).unwrap().contains("b.e-1"));
+
+        // A key that can't be recorded isn't acknowledged, so the broker delivers it again
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        send_json(&mut writer, log("b.e-2")).await;
+        send_json(&mut writer, serde_json::json!({ "action": "ping", "task_id": format!("{}1", HEARTBEAT_TASK_PREFIX) })).await;
+        assert_eq!(next_json(&mut reader).await["action"], "pong");
+        let _ = std::fs::remove_dir(&path);
+    }
+
+    #[tokio::test]
+    async fn answers_requests_with_the_handlers() {
+        let mut handlers = Handlers::default();
+        handlers.on_action("whoami", |message: Message| async move {
+            Ok(ExtensionResponse { action: "whoami_result".into(), task_id: message.task_id, success: true, result: Some("test app".into()), error: None })
+        });
+        let (client_side, broker_side) = duplex(64 * 1024);
+        let (reader, writer) = split(client_side);
+        let (_client, mut events) = BridgeClient::with_handlers(reader, writer, options(), Arc::new(handlers));
+        let (mut reader, mut writer) = split(broker_side);
+
+        send_json(&mut writer, serde_json::json!({ "action": "whoami", "task_id": "w-1" })).await;
+        let answer = next_json(&mut reader).await;
+        assert_eq!((answer["action"].as_str(), answer["task_id"].as_str()), (Some("whoami_result"), Some("w-1")));
+
+        // Other requests get the unknown-action error; answers and typed events still are events
+        send_json(&mut writer, serde_json::json!({ "action": "teleport", "task_id": "t-1" })).await;
+        let unknown = next_json(&mut reader).await;
+        assert_eq!((unknown["action"].as_str(), unknown["result"]["code"].as_str()), (Some(BRIDGE_ERROR_ACTION), Some(E_UNKNOWN_ACTION)));
+        send_json(&mut writer, serde_json::json!({ "action": "configure_ack", "task_id": "c-1", "success": true })).await;
+        assert!(matches!(events.next().await, Some(Event::Other(value)) if value["task_id"] == "c-1"));
+    }
+
+    /// Tokio under another name, counting what it is given to run.
+    struct Counting(Arc<AtomicU64>);
+
+    impl Executor for Counting {
+        fn spawn(&self, future: BoxFuture<()>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Tokio.spawn(future);
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<()> {
+            Tokio.sleep(duration)
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_on_any_executor() {
+        let (client_side, broker_side) = duplex(64 * 1024);
+        let (reader, writer) = split(client_side);
+        let spawned = Arc::new(AtomicU64::new(0));
+        let executor = Arc::new(Counting(spawned.clone()));
+        let (client, _events) = BridgeClient::with_executor(reader.compat(), writer.compat_write(), options(), Arc::default(), executor);
+        let (mut reader, mut writer) = split(broker_side);
+
+        let timed_out = Task { timeout_ms: Some(20), ..task() };
+        assert!(matches!(client.send_task(timed_out).await, Err(ClientError::Timeout(_))));
+        let waiting = client.send_task(task());
+        let extension = async {
+            let late_id = next_json(&mut reader).await["task_id"].clone();
+            assert_eq!(next_json(&mut reader).await["task_id"], late_id);
+            let task_id = next_json(&mut reader).await["task_id"].clone();
+            send_json(&mut writer, serde_json::json!({ "action": "task_result", "task_id": task_id, "success": true, "result": { "steps": [] } })).await;
+        };
+        let (result, ()) = tokio::join!(waiting, extension);
+        assert!(result.unwrap().steps.is_empty());
+        // The reader and the writer
+        assert_eq!(spawned.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,31 @@
+use std::io;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use shared_types::{Action, TaskResult};
+
+/// Why a message or task didn't go through.
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// The broker went away before the answer came.
+    #[error("broker disconnected")]
+    Disconnected,
+    /// No `task_result` within the task's timeout.
+    #[error("no result within {0:?}")]
+    Timeout(Duration),
+    /// The task ran, or was refused by the broker's validation, and failed.
+    /// `result` has the steps it got through, if the extension sent them.
+    #[error("task failed: {}", .error.as_deref().unwrap_or("no reason given"))]
+    TaskFailed { error: Option<String>, result: Option<TaskResult> },
+    /// The bridge answered with `action` instead of a result, e.g.
+    /// `message_too_large` or `bridge_error`. `code` is the error code in the
+    /// answer's `result`, if it has one, such as `E_UNKNOWN_ACTION`.
+    #[error("{action}: {}", .error.as_deref().unwrap_or("no reason given"))]
+    Rejected { action: Action, code: Option<String>, error: Option<String> },
+    /// The answer couldn't be read.
+    #[error("malformed answer: {0}")]
+    Malformed(String),
+}
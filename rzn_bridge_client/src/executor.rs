@@ -0,0 +1,59 @@
+//! The async runtime a client runs on.
+//!
+//! A client spawns its reader, its writer and the handlers of requests, and
+//! times out what it waits for. Nothing else needs a runtime: it talks over
+//! [`futures_io`] (or tokio) streams, and its channels work on any executor.
+//! With the default `tokio` feature [`Tokio`] does both. An app on another
+//! runtime implements [`Executor`] for it and connects with
+//! [`BridgeClient::with_executor`](crate::BridgeClient::with_executor), e.g.
+//! on smol:
+//!
+//! ```ignore
+//! use rzn_bridge_client::{BoxFuture, Executor};
+//!
+//! struct Smol;
+//!
+//! impl Executor for Smol {
+//!     fn spawn(&self, future: BoxFuture<()>) {
+//!         smol::spawn(future).detach();
+//!     }
+//!
+//!     fn sleep(&self, duration: Duration) -> BoxFuture<()> {
+//!         Box::pin(async move {
+//!             smol::Timer::after(duration).await;
+//!         })
+//!     }
+//! }
+//! ```
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// A boxed future, as spawned and returned by an [`Executor`].
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Runs a client's background tasks and timers.
+pub trait Executor: Send + Sync + 'static {
+    /// Runs `future` to completion in the background.
+    fn spawn(&self, future: BoxFuture<()>);
+
+    /// Resolves once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<()>;
+}
+
+/// The tokio runtime the caller runs in.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Executor for Tokio {
+    fn spawn(&self, future: BoxFuture<()>) {
+        tokio::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
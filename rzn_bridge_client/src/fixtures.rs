@@ -0,0 +1,174 @@
+//! Fixture pages on a local port, for examples and tests that drive a real
+//! browser.
+//!
+//! A [`FixtureServer`] serves the same pages on every run, so what a task
+//! scrapes, fills in or downloads doesn't depend on the live internet. The
+//! pages are in the crate's `fixtures/` directory and built into it. Only
+//! with the `fixtures` feature.
+
+use std::io;
+use std::net::SocketAddr;
+
+use axum::extract::Query;
+use axum::http::header;
+use axum::response::{Html, IntoResponse};
+use axum::routing::get;
+use axum::Router;
+use serde::Deserialize;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::oneshot;
+
+/// Items the infinite scroll page loads per page, and how many pages there are.
+const ITEMS_PER_PAGE: u32 = 10;
+const ITEM_PAGES: u32 = 5;
+/// The file behind the download page's link.
+const REPORT_CSV: &str = "id,name,total\n1,Alpha,10\n2,Beta,20\n3,Gamma,30\n";
+
+/// Serves the fixture pages until dropped:
+/// * `/`: links to the others;
+/// * `/scrape`: a heading (`#heading`) and a list (`li.item`: Alpha, Beta,
+///   Gamma);
+/// * `/form`: text inputs, a dropdown, a textarea and a checkbox, submitted
+///   to `/form/submitted`, which lists the fields it got (`dt`/`dd`);
+/// * `/infinite-scroll`: `li.item`s "Item 1" to "Item 50", loaded ten at a
+///   time from `/infinite-scroll/items?page=N` as the page is scrolled;
+/// * `/iframe`: a page embedding `/iframe/inner` in `#frame`;
+/// * `/download`: a link (`#report`) to `/download/report.csv`, sent as an
+///   attachment.
+pub struct FixtureServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl FixtureServer {
+    /// Serves the pages on a free port of 127.0.0.1. Must be called within a
+    /// tokio runtime.
+    pub async fn start() -> io::Result<Self> {
+        Self::bind(("127.0.0.1", 0)).await
+    }
+
+    /// Serves the pages on `addr`.
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let serve = axum::serve(listener, router()).with_graceful_shutdown(async move {
+                let _ = stopped.await;
+            });
+            if let Err(e) = serve.await {
+                log::error!("Fixtures: The server stopped: {}", e);
+            }
+        });
+        log::info!("Fixtures: Serving the pages at http://{}/", addr);
+        Ok(FixtureServer { addr, shutdown: Some(shutdown) })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL of `path` on this server, e.g. `url("/form")`.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}/{}", self.addr, path.trim_start_matches('/'))
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+fn router() -> Router {
+    Router::new()
+        .route("/", get(Html(include_str!("../fixtures/index.html"))))
+        .route("/scrape", get(Html(include_str!("../fixtures/scrape.html"))))
+        .route("/form", get(Html(include_str!("../fixtures/form.html"))))
+        .route("/form/submitted", get(form_submitted))
+        .route("/infinite-scroll", get(Html(include_str!("../fixtures/infinite-scroll.html"))))
+        .route("/infinite-scroll/items", get(scroll_items))
+        .route("/iframe", get(Html(include_str!("../fixtures/iframe.html"))))
+        .route("/iframe/inner", get(Html(include_str!("../fixtures/iframe-inner.html"))))
+        .route("/download", get(Html(include_str!("../fixtures/download.html"))))
+        .route("/download/report.csv", get(report))
+}
+
+/// Lists the submitted fields, in the order the form sent them.
+async fn form_submitted(Query(fields): Query<Vec<(String, String)>>) -> Html<String> {
+    let fields: String = fields.iter().map(|(name, value)| format!("\n        <dt>{}</dt><dd>{}</dd>", escape(name), escape(value))).collect();
+    Html(format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n    <meta charset=\"utf-8\">\n    <title>Submitted</title>\n</head>\n<body>\n    <h1 id=\"submitted\">Submitted</h1>\n    <dl id=\"fields\">{}\n    </dl>\n</body>\n</html>\n",
+        fields
+    ))
+}
+
+#[derive(Deserialize)]
+struct ItemsQuery {
+    #[serde(default)]
+    page: u32,
+}
+
+/// A page of items for the infinite scroll page, empty past the last one.
+async fn scroll_items(Query(query): Query<ItemsQuery>) -> impl IntoResponse {
+    let items: Vec<String> = match query.page {
+        page if page < ITEM_PAGES => (1..=ITEMS_PER_PAGE).map(|n| format!("Item {}", page * ITEMS_PER_PAGE + n)).collect(),
+        _ => Vec::new(),
+    };
+    ([(header::CONTENT_TYPE, "application/json")], serde_json::to_string(&items).unwrap_or_default())
+}
+
+async fn report() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/csv; charset=utf-8"), (header::CONTENT_DISPOSITION, "attachment; filename=\"report.csv\"")], REPORT_CSV)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// The status line, headers and body of a GET of `path`.
+    async fn get(server: &FixtureServer, path: &str) -> String {
+        let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, server.addr());
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_the_fixture_pages() {
+        let server = FixtureServer::start().await.unwrap();
+        assert_eq!(server.url("/form"), format!("http://{}/form", server.addr()));
+        for (path, expected) in [("/", "href=\"/form\""), ("/scrape", "class=\"item\""), ("/iframe", "src=\"/iframe/inner\""), ("/iframe/inner", "inner-button")] {
+            let response = get(&server, path).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", path, response);
+            assert!(response.contains(expected), "{}: {}", path, response);
+        }
+        assert!(get(&server, "/missing").await.starts_with("HTTP/1.1 404"));
+
+        let submitted = get(&server, "/form/submitted?name=Ada+%3Cb%3E&topic=bug").await;
+        assert!(submitted.contains("<dt>name</dt><dd>Ada &lt;b&gt;</dd>"));
+        assert!(submitted.contains("<dt>topic</dt><dd>bug</dd>"));
+
+        assert!(get(&server, "/infinite-scroll/items?page=4").await.ends_with("\"Item 41\",\"Item 42\",\"Item 43\",\"Item 44\",\"Item 45\",\"Item 46\",\"Item 47\",\"Item 48\",\"Item 49\",\"Item 50\"]"));
+        assert!(get(&server, "/infinite-scroll/items?page=5").await.ends_with("[]"));
+
+        let report = get(&server, "/download/report.csv").await.to_lowercase();
+        assert!(report.contains("content-disposition: attachment; filename=\"report.csv\""));
+        assert!(report.ends_with(&REPORT_CSV.to_lowercase()));
+
+        let addr = server.addr();
+        drop(server);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
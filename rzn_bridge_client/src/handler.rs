@@ -0,0 +1,182 @@
+//! Answering the requests the bridge sends the Main App.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use shared_types::{Action, ExtensionResponse, Message, E_UNKNOWN_ACTION};
+
+/// What a [`Handler`] resolves to.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<ExtensionResponse, ActionError>> + Send>>;
+
+/// Answers the messages of one action. Implemented by async closures taking
+/// the [`Message`], so most handlers are registered as
+/// `|message: Message| async move { ... }`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, message: Message) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Message) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<ExtensionResponse, ActionError>> + Send + 'static,
+{
+    fn call(&self, message: Message) -> HandlerFuture {
+        Box::pin(self(message))
+    }
+}
+
+/// A request that couldn't be answered, sent back as a `bridge_error` with
+/// `code` in its `result`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionError {
+    pub code: String,
+    pub message: String,
+    /// Merged into the `result` of the `bridge_error` next to the `code`.
+    pub details: Option<Value>,
+}
+
+impl ActionError {
+    pub fn new(code: impl Into<String>, message: impl Into<String>) -> Self {
+        ActionError { code: code.into(), message: message.into(), details: None }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// The error of the default handler, code [`E_UNKNOWN_ACTION`].
+    pub fn unknown_action(action: &str) -> Self {
+        ActionError::new(E_UNKNOWN_ACTION, format!("No handler for action {:?}", action))
+    }
+
+    /// The `bridge_error` answering `request`, with the request's `action`
+    /// and the details in its `result`.
+    pub fn into_response(self, request: &Message) -> ExtensionResponse {
+        let response = ExtensionResponse::bridge_error(&request.task_id, &self.code, &self.message)
+            .with_details(serde_json::json!({ "action": request.action }));
+        match self.details {
+            Some(details) => response.with_details(details),
+            None => response,
+        }
+    }
+}
+
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ActionError {}
+
+/// The handlers of a Main App, by action, and the one for actions without a
+/// handler. Unless replaced with [`Handlers::on_unknown_action`], that one
+/// answers with an [`E_UNKNOWN_ACTION`] error.
+#[derive(Clone, Default)]
+pub struct Handlers {
+    by_action: HashMap<Action, Arc<dyn Handler>>,
+    unknown: Option<Arc<dyn Handler>>,
+}
+
+impl Handlers {
+    /// Answers `action` with `handler`, replacing an earlier one. Takes an
+    /// [`Action`] or its name on the wire.
+    pub fn on_action(&mut self, action: impl Into<Action>, handler: impl Handler) -> &mut Self {
+        self.by_action.insert(action.into(), Arc::new(handler));
+        self
+    }
+
+    /// Answers the actions without a handler with `handler`.
+    pub fn on_unknown_action(&mut self, handler: impl Handler) -> &mut Self {
+        self.unknown = Some(Arc::new(handler));
+        self
+    }
+
+    /// Whether `action` has a handler of its own.
+    pub fn handles(&self, action: &Action) -> bool {
+        self.by_action.contains_key(action)
+    }
+
+    /// Whether no handler was registered at all.
+    pub fn is_empty(&self) -> bool {
+        self.by_action.is_empty() && self.unknown.is_none()
+    }
+
+    /// Runs the handler of `message`'s action and returns its answer, or
+    /// the structured error it failed with.
+    pub async fn dispatch(&self, message: Message) -> ExtensionResponse {
+        let handler = self.by_action.get(&message.action).or(self.unknown.as_ref()).cloned();
+        let Some(handler) = handler else {
+            return ActionError::unknown_action(message.action.as_str()).into_response(&message);
+        };
+        // The task, if any, is the handler's; the error only needs the envelope
+        let request = Message { action: message.action.clone(), task_id: message.task_id.clone(), task: None, data: None, ttl_ms: None, msg_id: None };
+        match handler.call(message).await {
+            Ok(response) => response,
+            Err(e) => {
+                log::warn!("Handlers: {} ({}) failed: {}", request.action, request.task_id, e);
+                e.into_response(&request)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handlers")
+            .field("actions", &self.by_action.keys().map(Action::as_str).collect::<Vec<_>>())
+            .field("unknown", &self.unknown.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::{Action, BRIDGE_ERROR_ACTION};
+
+    fn request(action: &str) -> Message {
+        Message { action: action.into(), task_id: "t-1".to_string(), task: None, data: None, ttl_ms: None, msg_id: None }
+    }
+
+    #[tokio::test]
+    async fn dispatches_by_action() {
+        let mut handlers = Handlers::default();
+        handlers
+            .on_action(Action::Ping, |message: Message| async move {
+                Ok(ExtensionResponse { action: Action::Pong, task_id: message.task_id, success: true, result: None, error: None })
+            })
+            .on_action("fail", |_: Message| async { Err(ActionError::new("E_BUSY", "try later").with_details(serde_json::json!({ "retry_ms": 10 }))) });
+
+        assert!(handlers.handles(&Action::Ping) && handlers.handles(&Action::Unknown("fail".to_string())));
+        let pong = handlers.dispatch(request("ping")).await;
+        assert_eq!((pong.action.as_str(), pong.task_id.as_str(), pong.success), ("pong", "t-1", true));
+
+        let failed = handlers.dispatch(request("fail")).await;
+        assert_eq!((failed.action.as_str(), failed.success), (BRIDGE_ERROR_ACTION, false));
+        assert_eq!(
+            failed.result,
+            Some(serde_json::json!({ "code": "E_BUSY", "message": "try later", "task_id": "t-1", "action": "fail", "retry_ms": 10 }))
+        );
+        assert_eq!(failed.error.as_deref(), Some("[E_BUSY] try later"));
+    }
+
+    #[tokio::test]
+    async fn answers_unknown_actions_with_a_structured_error() {
+        let mut handlers = Handlers::default();
+        let unknown = handlers.dispatch(request("teleport")).await;
+        let result = unknown.result.unwrap();
+        assert_eq!((result["code"].as_str(), result["action"].as_str()), (Some(E_UNKNOWN_ACTION), Some("teleport")));
+        assert_eq!(unknown.task_id, "t-1");
+
+        handlers.on_unknown_action(|message: Message| async move { Err(ActionError::new("E_NOPE", message.action.to_string())) });
+        let replaced = handlers.dispatch(request("teleport")).await;
+        assert_eq!(replaced.error.as_deref(), Some("[E_NOPE] teleport"));
+    }
+}
@@ -0,0 +1,159 @@
+//! Driving a client from a test, without a broker or an extension.
+//!
+//! An [`Injector`] is the broker's end of an in-process connection. A test
+//! injects what the bridge would send, such as a `hello`, requests for the
+//! app's handlers, logs and task results, and reads the client's answers back.
+//! It can also drop the connection the way a broker going away would. Apps
+//! get one from [`BridgeServer::inject`](crate::BridgeServer::inject) to test
+//! the handlers they register, without spawning a broker. Only with the
+//! `testing` feature.
+
+use std::io;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::sync::mpsc;
+
+use shared_types::frame::{read_frame, write_frame, FrameFlags};
+use shared_types::{Hello, HELLO_ACTION, HELLO_ACK_ACTION};
+
+use crate::client::{BridgeClient, ClientOptions, Events};
+use crate::handler::Handlers;
+
+/// Software a [`hello`](Injector::hello) is said to come from.
+const SOFTWARE: &str = "rzn_bridge_client injector";
+
+/// The broker's end of a client's connection, for a test to send fabricated
+/// messages through and see what the client writes.
+pub struct Injector {
+    writer: Option<WriteHalf<DuplexStream>>,
+    sent: mpsc::UnboundedReceiver<Value>,
+    next_id: u64,
+}
+
+impl Injector {
+    /// A client answering with `handlers`, and the broker's end of its
+    /// connection. Must be called within a tokio runtime.
+    pub fn connect(options: ClientOptions, handlers: Arc<Handlers>) -> (BridgeClient, Events, Injector) {
+        let (client_side, broker_side) = tokio::io::duplex(options.max_message_size.saturating_add(64 * 1024));
+        let (reader, writer) = tokio::io::split(client_side);
+        let (client, events) = BridgeClient::with_handlers(reader, writer, options, handlers);
+        let (mut broker_reader, broker_writer) = tokio::io::split(broker_side);
+        // Read on their own, so `next_sent` can be given up on halfway
+        let (sent_tx, sent) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(Some(frame)) = read_frame(&mut broker_reader, "Injector").await {
+                let value = serde_json::from_slice(&frame.payload).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&frame.payload).into_owned()));
+                if sent_tx.send(value).is_err() {
+                    break;
+                }
+            }
+        });
+        (client, events, Injector { writer: Some(broker_writer), sent, next_id: 1 })
+    }
+
+    /// Sends the client `message` as if the bridge had, e.g. a `Message`, an
+    /// `ExtensionResponse` or any JSON value.
+    pub async fn inject(&mut self, message: &impl Serialize) -> io::Result<()> {
+        let writer = self.writer.as_mut().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "the injector disconnected"))?;
+        let bytes = serde_json::to_vec(message).map_err(io::Error::other)?;
+        write_frame(writer, FrameFlags::NONE, 0, &bytes, "Injector").await
+    }
+
+    /// Says hello with `capabilities`, as a broker opening the connection,
+    /// and returns the client's answer. Messages the client sent before the
+    /// answer are skipped.
+    pub async fn hello(&mut self, capabilities: &[&str]) -> io::Result<Value> {
+        let task_id = format!("injected-hello-{}", self.next_id);
+        self.next_id += 1;
+        let hello = serde_json::json!({ "action": HELLO_ACTION, "task_id": task_id, "data": Hello::new(SOFTWARE, capabilities) });
+        self.inject(&hello).await?;
+        while let Some(sent) = self.next_sent().await {
+            if sent["action"] == HELLO_ACK_ACTION && sent["task_id"] == task_id.as_str() {
+                return Ok(sent);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the client went away before answering the hello"))
+    }
+
+    /// The next message the client wrote, e.g. a task, or a handler's or
+    /// the handshake's answer. `None` once the client is gone and everything
+    /// it wrote has been read.
+    pub async fn next_sent(&mut self) -> Option<Value> {
+        self.sent.recv().await
+    }
+
+    /// A message the client already wrote, without waiting for one.
+    pub fn try_next_sent(&mut self) -> Option<Value> {
+        self.sent.try_recv().ok()
+    }
+
+    /// Closes the connection as a broker going away would: the client reads
+    /// the end of it, fails the tasks still waiting and ends up `closed`.
+    /// What it wrote before can still be read with
+    /// [`next_sent`](Self::next_sent).
+    pub async fn disconnect(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.shutdown().await;
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.writer.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use shared_types::{ConnectionState, Encoding, ExtensionResponse, Message, Step, Task, PERFORM_TASK_ACTION};
+
+    use crate::client::Event;
+    use crate::error::ClientError;
+
+    fn options() -> ClientOptions {
+        ClientOptions {
+            software: "test app".to_string(),
+            task_timeout: Duration::from_secs(5),
+            encoding: Encoding::Json,
+            max_message_size: 1024 * 1024,
+            record_to: None,
+            processed_keys: None,
+            seal_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn drives_the_handlers_and_drops_the_connection() {
+        let mut handlers = Handlers::default();
+        handlers.on_action("whoami", |message: Message| async move {
+            Ok(ExtensionResponse { action: "whoami_result".into(), task_id: message.task_id, success: true, result: Some("app".into()), error: None })
+        });
+        let (client, mut events, mut injector) = Injector::connect(options(), Arc::new(handlers));
+
+        let ack = injector.hello(&[]).await.unwrap();
+        assert_eq!(ack["success"], true);
+        while !matches!(events.next().await, Some(Event::ConnectionState(change)) if change.state == ConnectionState::Ready) {}
+
+        injector.inject(&serde_json::json!({ "action": "whoami", "task_id": "w-1" })).await.unwrap();
+        let answer = injector.next_sent().await.unwrap();
+        assert_eq!((answer["action"].as_str(), answer["result"].as_str()), (Some("whoami_result"), Some("app")));
+
+        // A task in flight fails when the connection drops
+        let task = Task { steps: vec![Step::Navigate { url: "https://example.com".to_string(), destructive: None }], ..Task::default() };
+        let handle = client.start_task(task).await.unwrap();
+        assert_eq!(injector.next_sent().await.unwrap()["action"], PERFORM_TASK_ACTION);
+        injector.disconnect().await;
+        assert!(matches!(handle.await, Err(ClientError::Disconnected)));
+        assert!(injector.inject(&serde_json::json!({ "action": "log" })).await.is_err());
+        let mut closed = false;
+        while let Some(event) = events.next().await {
+            closed |= matches!(event, Event::ConnectionState(change) if change.state == ConnectionState::Closed);
+        }
+        assert!(closed);
+    }
+}
@@ -0,0 +1,68 @@
+//! Main App side of the Rzn:Browser Bridge.
+//!
+//! A Main App listens with a [`BridgeServer`] and gets a [`BridgeClient`] for
+//! every broker that connects. The client runs tasks in the browser with
+//! [`BridgeClient::send_task`], which resolves once the extension's
+//! `task_result` for that task comes back (or its timeout runs out), and
+//! hands everything the bridge sends unasked to the [`Events`] stream. The
+//! handshake, heartbeats and frames in any negotiated encoding or compression
+//! are taken care of, so an app never touches raw frames or action strings.
+//! Requests the bridge sends the app are answered by the handlers registered
+//! with [`BridgeServer::on_action`]; see [`Handlers`]. A connection recorded
+//! with [`ClientOptions::record_to`] can be replayed against them in-process
+//! and in virtual time with [`replay::replay`]. With the `testing` feature,
+//! an `Injector` stands in for the broker so tests can send a client
+//! fabricated messages and drop its connection. With the `fixtures` feature,
+//! a `FixtureServer` serves deterministic pages (forms, infinite scroll,
+//! iframes, downloads) on a local port for tasks to run against.
+//!
+//! Clients run on tokio with the default `tokio` feature. Apps on another
+//! runtime turn it off and connect with [`BridgeClient::with_executor`],
+//! giving it an [`Executor`] for theirs.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), rzn_bridge_client::ClientError> {
+//! use rzn_bridge_client::{BridgeServer, ClientOptions, Event};
+//! use shared_types::{Step, Task};
+//!
+//! let server = BridgeServer::bind_current(ClientOptions::default())?;
+//! let (client, mut events) = server.accept().await?;
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.next().await {
+//!         if let Event::Log { task_id, log } = event {
+//!             println!("[{}] {}", task_id, log.message);
+//!         }
+//!     }
+//! });
+//! let steps = vec![Step::Navigate { url: "https://example.com".to_string(), destructive: None }];
+//! let task = Task { steps, ..Task::default() };
+//! let result = client.send_task(task).await?;
+//! println!("{} steps ran", result.steps.len());
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod error;
+mod executor;
+#[cfg(feature = "fixtures")]
+mod fixtures;
+mod handler;
+#[cfg(feature = "testing")]
+mod inject;
+pub mod replay;
+#[cfg(feature = "tokio")]
+mod server;
+
+pub use client::{BridgeClient, ClientOptions, Event, Events, StepEvent, StepEvents, TaskHandle, DEFAULT_TASK_TIMEOUT};
+pub use error::ClientError;
+#[cfg(feature = "tokio")]
+pub use executor::Tokio;
+pub use executor::{BoxFuture, Executor};
+#[cfg(feature = "fixtures")]
+pub use fixtures::FixtureServer;
+pub use handler::{ActionError, Handler, HandlerFuture, Handlers};
+#[cfg(feature = "testing")]
+pub use inject::Injector;
+#[cfg(feature = "tokio")]
+pub use server::BridgeServer;
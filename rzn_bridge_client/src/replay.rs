@@ -0,0 +1,260 @@
+//! Recording what the bridge sends a Main App, and replaying it in-process.
+//!
+//! A client with [`ClientOptions::record_to`](crate::ClientOptions::record_to) set appends every message it
+//! reads to that file, one JSON line each, with the milliseconds since the
+//! connection opened. [`replay`] (with the `tokio` feature) feeds such a recording to a client with the
+//! app's [`Handlers`] at the recorded times and returns everything that
+//! happened in order: the recorded messages, the client's answers and the
+//! events it raised. Run on a runtime with paused time (e.g.
+//! `#[tokio::test(start_paused = true)]`), the waits are virtual, so a long
+//! session replays instantly and the same way every time, which makes it
+//! easy to follow the dispatch decisions behind an ordering bug.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+
+#[cfg(feature = "tokio")]
+use shared_types::frame::{read_frame, write_frame, FrameFlags};
+
+#[cfg(feature = "tokio")]
+use crate::client::{BridgeClient, ClientOptions};
+#[cfg(feature = "tokio")]
+use crate::handler::Handlers;
+
+/// How long a replay waits for the client to go quiet after the last
+/// recorded message.
+#[cfg(feature = "tokio")]
+pub const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// One message read from the broker, as recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedMessage {
+    /// Milliseconds since the connection opened.
+    pub at_ms: u64,
+    pub message: Value,
+}
+
+/// The messages of one connection, in the order they were read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub messages: Vec<RecordedMessage>,
+}
+
+impl Recording {
+    /// Reads a recording written with [`ClientOptions::record_to`](crate::ClientOptions::record_to).
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Recording::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a recording from JSON lines, skipping blank ones.
+    pub fn read(reader: impl BufRead) -> io::Result<Self> {
+        let mut messages = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            messages.push(serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+        }
+        Ok(Recording { messages })
+    }
+}
+
+/// Appends the messages a client reads to its recording.
+pub(crate) struct Recorder {
+    file: File,
+    started: Instant,
+}
+
+impl Recorder {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder { file, started: Instant::now() })
+    }
+
+    pub(crate) fn record(&mut self, message: &Value) {
+        let recorded = RecordedMessage { at_ms: self.started.elapsed().as_millis() as u64, message: message.clone() };
+        let written = serde_json::to_vec(&recorded)
+            .map_err(io::Error::other)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file.write_all(&line)
+            });
+        if let Err(e) = written {
+            log::warn!("BridgeClient: Could not record message: {}", e);
+        }
+    }
+}
+
+/// What happened during a replay.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayStep {
+    /// A recorded message was fed to the client.
+    Received(Value),
+    /// The client answered, e.g. a `hello_ack` or a handler's response.
+    Sent(Value),
+    /// The client raised an event, as its `Debug` output.
+    Event(String),
+}
+
+/// A [`ReplayStep`] and when it happened, in milliseconds since the replay
+/// started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayedStep {
+    pub at_ms: u64,
+    pub step: ReplayStep,
+}
+
+/// Feeds `recording` to a client answering with `handlers`, each message at
+/// its recorded time, and returns the steps in the order they happened. The
+/// replay ends [`SETTLE_TIME`] after the last thing that happened.
+#[cfg(feature = "tokio")]
+pub async fn replay(recording: &Recording, handlers: Arc<Handlers>, options: ClientOptions) -> Vec<ReplayedStep> {
+    let (client_side, broker_side) = tokio::io::duplex(options.max_message_size.saturating_add(64 * 1024));
+    let (reader, writer) = tokio::io::split(client_side);
+    // Recording a replay would only record the recording again, and what was recorded was already opened
+    let options = ClientOptions { record_to: None, processed_keys: None, seal_key: None, ..options };
+    let (_client, mut events) = BridgeClient::with_handlers(reader, writer, options, handlers);
+    let (mut broker_reader, mut broker_writer) = tokio::io::split(broker_side);
+    // Read on their own, as a frame read can't be interrupted halfway
+    let (sent_tx, mut sent) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(Some(frame)) = read_frame(&mut broker_reader, "Replay").await {
+            let value = serde_json::from_slice(&frame.payload).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&frame.payload).into_owned()));
+            if sent_tx.send(value).is_err() {
+                break;
+            }
+        }
+    });
+
+    let started = Instant::now();
+    let at_ms = || started.elapsed().as_millis() as u64;
+    let mut steps = Vec::new();
+    let mut recorded = recording.messages.iter().peekable();
+    let mut quiet_since = started;
+    loop {
+        let next_due = recorded.peek().map(|next| started + Duration::from_millis(next.at_ms));
+        tokio::select! {
+            // The client's reactions to a message come before the next message
+            biased;
+            sent = sent.recv() => match sent {
+                Some(value) => {
+                    log::debug!("Replay: {} ms: sent {}", at_ms(), value);
+                    steps.push(ReplayedStep { at_ms: at_ms(), step: ReplayStep::Sent(value) });
+                    quiet_since = Instant::now();
+                }
+                None => break,
+            },
+            Some(event) = events.next() => {
+                log::debug!("Replay: {} ms: event {:?}", at_ms(), event);
+                steps.push(ReplayedStep { at_ms: at_ms(), step: ReplayStep::Event(format!("{:?}", event)) });
+                quiet_since = Instant::now();
+            }
+            () = tokio::time::sleep_until(next_due.unwrap_or(started)), if next_due.is_some() => {
+                let Some(next) = recorded.next() else { continue };
+                let bytes = serde_json::to_vec(&next.message).unwrap_or_default();
+                if write_frame(&mut broker_writer, FrameFlags::NONE, 0, &bytes, "Replay").await.is_err() {
+                    break;
+                }
+                log::debug!("Replay: {} ms: received {}", at_ms(), next.message);
+                steps.push(ReplayedStep { at_ms: at_ms(), step: ReplayStep::Received(next.message.clone()) });
+                quiet_since = Instant::now();
+            }
+            () = tokio::time::sleep_until(quiet_since + SETTLE_TIME), if next_due.is_none() => break,
+        }
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::{Encoding, ExtensionResponse, Hello, Message};
+
+    fn options(record_to: Option<std::path::PathBuf>) -> ClientOptions {
+        ClientOptions {
+            software: "test app".to_string(),
+            task_timeout: Duration::from_secs(5),
+            encoding: Encoding::Json,
+            max_message_size: 1024 * 1024,
+            record_to,
+            processed_keys: None,
+            seal_key: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_a_recording_in_virtual_time() {
+        let hello = serde_json::to_value(Hello::new("rzn_broker test", &[])).unwrap();
+        let recording = Recording::read(
+            format!(
+                "{}\n\n{}\n{}\n",
+                serde_json::json!({ "at_ms": 0, "message": { "action": "hello", "task_id": "broker-hello", "data": hello } }),
+                serde_json::json!({ "at_ms": 60_000, "message": { "action": "whoami", "task_id": "w-1" } }),
+                serde_json::json!({ "at_ms": 60_500, "message": { "action": "log", "task_id": "w-1", "data": { "level": "info", "scope": "test", "message": "hi" } } }),
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let mut handlers = Handlers::default();
+        handlers.on_action("whoami", |message: Message| async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(ExtensionResponse { action: "whoami_result".into(), task_id: message.task_id, success: true, result: None, error: None })
+        });
+
+        let real = std::time::Instant::now();
+        let steps = replay(&recording, Arc::new(handlers), options(None)).await;
+        assert!(real.elapsed() < Duration::from_secs(10));
+
+        let mut timeline: Vec<(u64, &str, &str)> = steps
+            .iter()
+            .map(|step| match &step.step {
+                ReplayStep::Received(value) => (step.at_ms, "received", value["action"].as_str().unwrap_or_default()),
+                ReplayStep::Sent(value) => (step.at_ms, "sent", value["action"].as_str().unwrap_or_default()),
+                ReplayStep::Event(event) => (step.at_ms, "event", event.split(['(', ' ']).next().unwrap_or_default()),
+            })
+            .collect();
+        // The hello_ack and the Hello and ConnectionState events race each other
+        timeline[1..4].sort();
+        assert_eq!(
+            timeline,
+            vec![
+                (0, "received", "hello"),
+                (0, "event", "ConnectionState"),
+                (0, "event", "Hello"),
+                (0, "sent", "hello_ack"),
+                (60_000, "received", "whoami"),
+                (60_100, "sent", "whoami_result"),
+                (60_500, "received", "log"),
+                (60_500, "event", "Log"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn records_what_the_client_reads() {
+        let path = std::env::temp_dir().join(format!("rzn-recording-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (client_side, broker_side) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(client_side);
+        let (_client, mut events) = BridgeClient::new(reader, writer, options(Some(path.clone())));
+        let (_broker_reader, mut broker_writer) = tokio::io::split(broker_side);
+        let log = serde_json::json!({ "action": "log", "task_id": "t-1", "data": { "level": "info", "scope": "test", "message": "hi" } });
+        write_frame(&mut broker_writer, FrameFlags::NONE, 0, &serde_json::to_vec(&log).unwrap(), "test").await.unwrap();
+        assert!(events.next().await.is_some());
+
+        let recording = Recording::load(&path).unwrap();
+        assert_eq!(recording.messages.len(), 1);
+        assert_eq!(recording.messages[0].message, log);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
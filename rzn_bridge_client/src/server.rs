@@ -0,0 +1,79 @@
+//! The Main App's listener that brokers connect to.
+
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+
+use interprocess::local_socket::tokio::{prelude::*, Listener};
+use interprocess::local_socket::ListenerOptions;
+
+use shared_types::{Action, EndpointSpec, Profile};
+
+use crate::client::{BridgeClient, ClientOptions, Events};
+use crate::handler::{Handler, Handlers};
+#[cfg(feature = "testing")]
+use crate::inject::Injector;
+
+/// Listens for brokers and serves each one with a [`BridgeClient`],
+/// answering requests from the bridge with the handlers registered with
+/// [`on_action`](Self::on_action).
+pub struct BridgeServer {
+    listener: Listener,
+    options: ClientOptions,
+    handlers: Arc<Handlers>,
+}
+
+impl BridgeServer {
+    /// Listens where the brokers of the current profile connect (see
+    /// [`Profile::current`]).
+    pub fn bind_current(options: ClientOptions) -> io::Result<Self> {
+        BridgeServer::bind(&Profile::current()?.endpoint(), options)
+    }
+
+    /// Listens on `endpoint`, removing a socket file left behind by a crash.
+    pub fn bind(endpoint: &EndpointSpec, options: ClientOptions) -> io::Result<Self> {
+        let name = endpoint.to_name()?;
+        let listener = match ListenerOptions::new().name(name.clone()).create_tokio() {
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                let Some(path) = endpoint.socket_file().filter(|path| path.exists()) else {
+                    return Err(e);
+                };
+                log::warn!("BridgeServer: Removing stale socket file {}", path.display());
+                std::fs::remove_file(path)?;
+                ListenerOptions::new().name(name).create_tokio()?
+            }
+            listener => listener?,
+        };
+        Ok(BridgeServer { listener, options, handlers: Arc::default() })
+    }
+
+    /// Answers `action` with `handler` on the connections accepted from now
+    /// on, e.g.
+    /// `server.on_action("perform_task", |message: Message| async move { ... })`.
+    /// Requests without a handler then get an `E_UNKNOWN_ACTION` error,
+    /// unless [`on_unknown_action`](Self::on_unknown_action) says otherwise.
+    pub fn on_action(&mut self, action: impl Into<Action>, handler: impl Handler) -> &mut Self {
+        Arc::make_mut(&mut self.handlers).on_action(action, handler);
+        self
+    }
+
+    /// Answers requests without a handler of their own with `handler`.
+    pub fn on_unknown_action(&mut self, handler: impl Handler) -> &mut Self {
+        Arc::make_mut(&mut self.handlers).on_unknown_action(handler);
+        self
+    }
+
+    /// Waits for the next broker to connect.
+    pub async fn accept(&self) -> io::Result<(BridgeClient, Events)> {
+        let stream = self.listener.accept().await?;
+        let (reader, writer) = tokio::io::split(stream);
+        Ok(BridgeClient::with_handlers(reader, writer, self.options.clone(), self.handlers.clone()))
+    }
+
+    /// A client served like the ones [`accept`](Self::accept) returns, with
+    /// this server's options and handlers, connected to an [`Injector`]
+    /// instead of a broker.
+    #[cfg(feature = "testing")]
+    pub fn inject(&self) -> (BridgeClient, Events, Injector) {
+        Injector::connect(self.options.clone(), self.handlers.clone())
+    }
+}
@@ -0,0 +1,196 @@
+//! Startup environment sanity checks.
+//!
+//! "The broker just exits" is hard to diagnose from the browser side, so every
+//! failed check carries a stable code that shows up in the log and, for fatal
+//! problems, in a `bridge_error` message sent to the extension. The first
+//! fatal failure also decides the broker's exit code ([`CheckFailure::exit_reason`]).
+
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+
+use shared_types::frame::write_message_bytes;
+use shared_types::{Action, BridgeConfig, ExitDetail, ExitReason, ExtensionResponse, Profile};
+
+use shared_types::install::{manifest_broker_path, Browser};
+pub use shared_types::install::HOST_NAME;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Logged, the broker keeps running.
+    Warning,
+    /// The broker cannot work and exits.
+    Fatal,
+}
+
+/// A failed startup check.
+#[derive(Debug, Clone)]
+pub struct CheckFailure {
+    pub code: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl CheckFailure {
+    fn new(code: &'static str, severity: Severity, message: impl Into<String>) -> Self {
+        CheckFailure { code, severity, message: message.into() }
+    }
+
+    /// What the broker reports when it exits over this failure.
+    pub fn exit_reason(&self) -> ExitReason {
+        match self.code {
+            "E_INVALID_CONFIG" | "E_INVALID_PROFILE" => ExitReason::Config,
+            "E_SOCKET_DIR_NOT_WRITABLE" => ExitReason::IpcUnreachable,
+            code if code.starts_with("W_MANIFEST_") => ExitReason::ManifestMismatch,
+            _ => ExitReason::Failure,
+        }
+    }
+
+    /// The failure as a detail of the broker's `last_exit.json`.
+    pub fn exit_detail(&self) -> ExitDetail {
+        ExitDetail { code: self.code.to_string(), message: self.message.clone() }
+    }
+}
+
+/// Runs all startup checks and returns the failures (empty if all passed).
+pub fn run_startup_checks() -> Vec<CheckFailure> {
+    [check_stdin_is_pipe(), check_manifest_path(), check_config(), check_profile(), check_socket_dir_writable()]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Logs each failure with its code at the matching level.
+pub fn log_failures(failures: &[CheckFailure]) {
+    for failure in failures {
+        match failure.severity {
+            Severity::Warning => log::warn!("[{}] {}", failure.code, failure.message),
+            Severity::Fatal => log::error!("[{}] {}", failure.code, failure.message),
+        }
+    }
+}
+
+/// Sends the fatal failures to the extension as a `bridge_error` message.
+/// Skipped when stdout is a terminal, since nobody would decode the frame.
+pub async fn report_to_extension(failures: &[CheckFailure]) -> io::Result<()> {
+    if io::stdout().is_terminal() {
+        return Ok(());
+    }
+    let fatal: Vec<&CheckFailure> = failures.iter().filter(|f| f.severity == Severity::Fatal).collect();
+    let Some(first) = fatal.first() else {
+        return Ok(());
+    };
+
+    let response = ExtensionResponse {
+        action: Action::BridgeError,
+        task_id: "startup".to_string(),
+        success: false,
+        result: Some(serde_json::json!({
+            "code": first.code,
+            "failures": fatal
+                .iter()
+                .map(|f| serde_json::json!({ "code": f.code, "message": f.message }))
+                .collect::<Vec<_>>(),
+        })),
+        error: Some(format!("[{}] {}", first.code, first.message)),
+    };
+    let bytes = serde_json::to_vec(&response).map_err(io::Error::other)?;
+    write_message_bytes(&mut tokio::io::stdout(), &bytes, "StartupCheck").await
+}
+
+// --- Individual Checks ---
+
+/// The browser talks to us over a pipe. A TTY means we were started by hand.
+fn check_stdin_is_pipe() -> Option<CheckFailure> {
+    io::stdin().is_terminal().then(|| {
+        CheckFailure::new(
+            "E_STDIN_IS_TTY",
+            Severity::Fatal,
+            "stdin is a terminal. The broker is meant to be launched by the browser via native messaging.",
+        )
+    })
+}
+
+/// The manifest the browser uses should point at this executable. Problems
+/// are warnings unless `RZN_STRICT_MANIFEST=1`, which makes them fatal.
+fn check_manifest_path() -> Option<CheckFailure> {
+    let strict = std::env::var("RZN_STRICT_MANIFEST").is_ok_and(|v| v == "1");
+    let severity = if strict { Severity::Fatal } else { Severity::Warning };
+    let current_exe = match std::env::current_exe().and_then(fs::canonicalize) {
+        Ok(path) => path,
+        Err(e) => {
+            return Some(CheckFailure::new(
+                "W_EXE_PATH_UNKNOWN",
+                Severity::Warning,
+                format!("Could not determine the broker's own path: {}", e),
+            ));
+        }
+    };
+
+    let manifests: Vec<PathBuf> = Browser::ALL
+        .into_iter()
+        .filter_map(Browser::manifest_path)
+        .filter(|path| path.exists())
+        .collect();
+    if manifests.is_empty() {
+        return Some(CheckFailure::new(
+            "W_MANIFEST_NOT_FOUND",
+            severity,
+            format!("No native messaging host manifest named {}.json found. Run `rzn_broker install` to install it.", HOST_NAME),
+        ));
+    }
+
+    for manifest in &manifests {
+        match manifest_broker_path(manifest) {
+            Ok(path) if path == current_exe => return None,
+            Ok(_) => {}
+            Err(e) => {
+                return Some(CheckFailure::new(
+                    "W_MANIFEST_UNREADABLE",
+                    severity,
+                    format!("Could not read host manifest {}: {}", manifest.display(), e),
+                ));
+            }
+        }
+    }
+    Some(CheckFailure::new(
+        "W_MANIFEST_PATH_MISMATCH",
+        severity,
+        format!(
+            "Host manifest(s) {:?} do not point at this executable ({}). The browser may be launching a different broker.",
+            manifests,
+            current_exe.display()
+        ),
+    ))
+}
+
+/// The bridge config file, if any, has to be readable and valid.
+fn check_config() -> Option<CheckFailure> {
+    BridgeConfig::load()
+        .err()
+        .map(|e| CheckFailure::new("E_INVALID_CONFIG", Severity::Fatal, e.to_string()))
+}
+
+/// The profile has to be usable in the endpoint name.
+fn check_profile() -> Option<CheckFailure> {
+    Profile::current()
+        .err()
+        .map(|e| CheckFailure::new("E_INVALID_PROFILE", Severity::Fatal, e.to_string()))
+}
+
+/// With a filesystem socket, the socket directory has to be writable.
+fn check_socket_dir_writable() -> Option<CheckFailure> {
+    let dir = rzn_broker_core::socket_directory()?;
+    let probe = dir.join(format!(".{}.{}.probe", HOST_NAME, std::process::id()));
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            None
+        }
+        Err(e) => Some(CheckFailure::new(
+            "E_SOCKET_DIR_NOT_WRITABLE",
+            Severity::Fatal,
+            format!("Socket directory {} is not writable: {}", dir.display(), e),
+        )),
+    }
+}
@@ -0,0 +1,97 @@
+//! `rzn_broker install` / `uninstall`: registering the broker as a native
+//! messaging host from the command line (see [`shared_types::install`]).
+
+use std::fs;
+use std::io;
+
+use shared_types::install::{self, Browser, Registration};
+
+const USAGE: &str = "\
+Usage:
+  rzn_broker install --extension-id <id> [--firefox-id <id>] [--browser <name>]...
+  rzn_broker uninstall [--browser <name>]...
+
+<name> is chrome, chromium, edge or firefox. Without --browser, install writes
+a manifest for every browser found and uninstall removes them all.
+--extension-id may be repeated to allow several Chromium extensions.";
+
+/// Runs `install` or `uninstall` with the arguments following the subcommand.
+pub fn run(subcommand: &str, args: &[String]) -> io::Result<()> {
+    let mut browsers = Vec::new();
+    let mut extension_ids = Vec::new();
+    let mut firefox_id = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+        let mut value = || inline.clone().or_else(|| args.next().cloned()).ok_or_else(|| usage_error(&format!("{} needs a value", flag)));
+        match flag {
+            "--browser" => {
+                let name = value()?;
+                browsers.push(Browser::parse(&name).ok_or_else(|| usage_error(&format!("unknown browser {:?}", name)))?);
+            }
+            "--extension-id" => extension_ids.push(value()?),
+            "--firefox-id" => firefox_id = Some(value()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => return Err(usage_error(&format!("unexpected argument {:?}", arg))),
+        }
+    }
+
+    match subcommand {
+        "install" => install(browsers, &extension_ids, firefox_id.as_deref()),
+        _ => uninstall(if browsers.is_empty() { Browser::ALL.to_vec() } else { browsers }),
+    }
+}
+
+fn install(browsers: Vec<Browser>, extension_ids: &[String], firefox_id: Option<&str>) -> io::Result<()> {
+    let broker = std::env::current_exe().and_then(fs::canonicalize)?;
+    let registration = Registration { broker, extension_ids: extension_ids.to_vec(), firefox_id: firefox_id.map(str::to_string) };
+    let explicit = !browsers.is_empty();
+    let browsers = if explicit {
+        browsers
+    } else {
+        let found: Vec<Browser> = Browser::ALL.into_iter().filter(|b| b.is_installed()).collect();
+        if found.is_empty() {
+            return Err(usage_error("no supported browser found; pick one with --browser"));
+        }
+        found
+    };
+
+    let mut installed = 0;
+    for browser in browsers {
+        if !registration.covers(browser) {
+            let flag = if browser == Browser::Firefox { "--firefox-id" } else { "--extension-id" };
+            if explicit {
+                return Err(usage_error(&format!("{} needs {}", browser.name(), flag)));
+            }
+            println!("Skipping {}: no {} given.", browser.name(), flag);
+            continue;
+        }
+        let path = registration.register(browser)?;
+        println!("Installed {} host manifest: {}", browser.name(), path.display());
+        installed += 1;
+    }
+    if installed == 0 {
+        return Err(usage_error("nothing installed"));
+    }
+    println!("Restart the browser so it picks up the manifest.");
+    Ok(())
+}
+
+fn uninstall(browsers: Vec<Browser>) -> io::Result<()> {
+    for browser in browsers {
+        if let Some(path) = install::unregister(browser)? {
+            println!("Removed {} host manifest: {}", browser.name(), path.display());
+        }
+    }
+    Ok(())
+}
+
+fn usage_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n\n{}", message, USAGE))
+}
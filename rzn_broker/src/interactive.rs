@@ -0,0 +1,189 @@
+//! Human-friendly mode used when the broker is started from a terminal.
+//!
+//! Instead of silently waiting for native messaging frames on stdin, the broker
+//! prints diagnostics and lets the user type JSON messages. Each line is framed
+//! exactly like the browser would frame it and pushed through the normal relay,
+//! so the whole path to the Main App is exercised.
+
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use shared_types::frame::{read_message_bytes, write_message_bytes};
+use shared_types::{BridgeConfig, Profile};
+
+use crate::checks::{self, Severity};
+
+const HELP: &str = "\
+Commands:
+  {\"action\": ...}   send a JSON message as if it came from the extension
+  :ping            send a ping to the Main App
+  :doctor          re-run the startup checks and the bridge self-test
+  :stats [tag]     show task outcomes per site, optionally for one task tag
+  :help            show this help
+  :quit            exit";
+
+/// Runs the interactive troubleshooting session until `:quit` or EOF.
+pub async fn run() -> io::Result<()> {
+    println!("rzn_broker {} - interactive troubleshooting mode", env!("CARGO_PKG_VERSION"));
+    println!("stdin is a terminal, so the broker was not launched by a browser.");
+    println!();
+    print_diagnostics();
+
+    let endpoint = rzn_broker_core::get_ipc_endpoint_name()?;
+    println!("Connecting to the Main App at {:?}...", endpoint);
+    let ipc_stream = match rzn_broker_core::connect_to_main_app(&endpoint).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Could not connect to the Main App: {}", e);
+            println!("Start it first (e.g. RUST_LOG=info ./target/release/example_app) and try again.");
+            return Ok(());
+        }
+    };
+    println!("Connected. {}", HELP);
+    let (ipc_reader, ipc_writer) = tokio::io::split(ipc_stream);
+
+    // In-memory pipes standing in for the browser's stdin/stdout
+    let (mut to_relay, relay_input) = tokio::io::duplex(64 * 1024);
+    let (relay_output, mut from_relay) = tokio::io::duplex(64 * 1024);
+
+    let relay = tokio::spawn(rzn_broker_core::relay(
+        relay_input,
+        relay_output,
+        ipc_reader,
+        ipc_writer,
+        rzn_broker_core::Hooks::default(),
+    ));
+
+    // Print everything the relay would send to the extension
+    let printer = tokio::spawn(async move {
+        while let Ok(Some(bytes)) = read_message_bytes(&mut from_relay, "Interactive").await {
+            match serde_json::from_slice::<serde_json::Value>(&bytes) {
+                Ok(value) => println!("<< {}", serde_json::to_string_pretty(&value).unwrap_or_default()),
+                Err(_) => println!("<< (non-JSON, {} bytes)", bytes.len()),
+            }
+        }
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut counter = 0u32;
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        counter += 1;
+        let message = match line {
+            "" => continue,
+            ":quit" | ":q" => break,
+            ":help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            ":stats" => {
+                print_origin_stats(None);
+                continue;
+            }
+            _ if line.starts_with(":stats ") => {
+                print_origin_stats(Some(line[":stats ".len()..].trim()));
+                continue;
+            }
+            ":ping" => serde_json::json!({ "action": "ping", "task_id": format!("interactive-{}", counter) }),
+            ":doctor" => {
+                print_diagnostics();
+                serde_json::json!({ "action": rzn_broker_core::SELFTEST_ACTION, "task_id": format!("interactive-{}", counter) })
+            }
+            _ => match serde_json::from_str::<serde_json::Value>(line) {
+                Ok(value) => value,
+                Err(e) => {
+                    println!("Not valid JSON ({}). Type :help for commands.", e);
+                    continue;
+                }
+            },
+        };
+        println!(">> {}", message);
+        if let Err(e) = write_message_bytes(&mut to_relay, message.to_string().as_bytes(), "Interactive").await {
+            println!("Relay stopped: {}", e);
+            break;
+        }
+    }
+
+    // Closing our end looks like the browser closing stdin
+    drop(to_relay);
+    let _ = relay.await;
+    printer.abort();
+    println!("Bye.");
+    Ok(())
+}
+
+/// Prints the success rate, duration and failure codes of the tasks run so
+/// far (or only those tagged `tag`), by site.
+fn print_origin_stats(tag: Option<&str>) {
+    let stats = match tag {
+        Some(tag) => rzn_broker_core::tagged_origin_stats(tag),
+        None => rzn_broker_core::origin_stats(),
+    };
+    if stats.is_empty() {
+        println!("No finished tasks yet.");
+    }
+    for origin in stats {
+        let codes: Vec<String> = origin.failure_codes.iter().map(|(code, count)| format!("{} x{}", code, count)).collect();
+        println!("  {}: {:.0}% of {} succeeded, avg {} ms{}", origin.origin, origin.success_rate() * 100.0,
+                 origin.succeeded + origin.failed, origin.average_duration_ms,
+                 if codes.is_empty() { String::new() } else { format!(", failures: {}", codes.join(", ")) });
+    }
+}
+
+/// Prints the startup check results and the relevant paths.
+fn print_diagnostics() {
+    println!("Diagnostics:");
+    match std::env::current_exe() {
+        Ok(path) => println!("  executable:       {}", path.display()),
+        Err(e) => println!("  executable:       unknown ({})", e),
+    }
+    match BridgeConfig::path() {
+        Some(path) if path.exists() => println!("  config file:      {}", path.display()),
+        Some(path) => println!("  config file:      none ({} not found)", path.display()),
+        None => println!("  config file:      none"),
+    }
+    match Profile::current() {
+        Ok(profile) => {
+            println!("  endpoint:         {}", profile.socket_name());
+            println!("  profile:          {} (user {})", profile.name(), profile.user());
+            match profile.list_running() {
+                Ok(running) if running.is_empty() => println!("  running profiles: none"),
+                Ok(running) => println!("  running profiles: {}", running.join(", ")),
+                Err(e) => println!("  running profiles: unknown ({})", e),
+            }
+        }
+        Err(e) => println!("  profile:          {}", e),
+    }
+    match rzn_broker_core::socket_directory() {
+        Some(dir) => println!("  socket directory: {}", dir.display()),
+        None => println!("  socket directory: none (namespaced endpoint)"),
+    }
+    let metrics = rzn_broker_core::metrics();
+    println!("  expired messages: {} to Main App, {} to extension",
+             metrics.expired_to_host, metrics.expired_to_extension);
+    println!("  rejected by JSON limits: {}", metrics.rejected_by_json_limits);
+    println!("  truncated task results: {}", metrics.truncated_results);
+    println!("  dropped while reconnecting: {}", metrics.dropped_while_disconnected);
+    println!("  heartbeat timeouts: {}", metrics.heartbeat_timeouts);
+    println!("  skipped as too large: {}", metrics.too_large);
+    let limits = BridgeConfig::load_or_default().message_limits();
+    println!("  message limits: {} bytes to Main App, {} bytes to extension", limits.to_app, limits.to_extension);
+
+    // The TTY check is expected to fail here, so it is not reported
+    let failures: Vec<_> = checks::run_startup_checks()
+        .into_iter()
+        .filter(|f| f.code != "E_STDIN_IS_TTY")
+        .collect();
+    if failures.is_empty() {
+        println!("  startup checks:   all passed");
+    }
+    for failure in &failures {
+        let level = if failure.severity == Severity::Fatal { "ERROR" } else { "WARN " };
+        println!("  {} [{}] {}", level, failure.code, failure.message);
+    }
+    if failures.iter().any(|f| f.code.starts_with("W_MANIFEST")) {
+        println!("  hint: run ./setup.sh to (re)install the native messaging host manifest.");
+    }
+    println!();
+}
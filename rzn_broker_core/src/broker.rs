@@ -0,0 +1,643 @@
+//! Configurable entry point for embedding the relay.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use rzn_broker_core::Broker;
+//!
+//! Broker::builder()
+//!     .endpoint_name("com.example.myapp.sock")
+//!     .max_message_size(1024 * 1024)
+//!     .build()
+//!     .run_stdio()
+//!     .await
+//! # }
+//! ```
+//!
+//! The async methods run on the caller's runtime. A host without one lets the
+//! broker own it with [`Broker::run_stdio_blocking`], built from the
+//! [`runtime`](BrokerBuilder::runtime) options.
+//!
+//! [`Broker::relay`] takes any pair of streams on each side, so the relay can
+//! be exercised with in-memory pipes such as `tokio::io::duplex`. Only
+//! [`Broker::serve`] and [`Broker::run_stdio`] reconnect to the Main App, as
+//! they know how to open a new connection.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use interprocess::local_socket::tokio::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+
+use shared_types::{BridgeConfig, Compression, EndpointSpec, FlushPolicy, Heartbeat, JsonLimits, MessageLimits, Profile, RuntimeOptions};
+
+use crate::hooks::{Hooks, RelayHook};
+use crate::ipc::connect_endpoint;
+use crate::launch::LaunchConfig;
+use crate::lazy::serve_lazy;
+use crate::notifier::Notifier;
+use crate::peers::run_peers;
+use crate::reconnect::{run_sessions, Connection, ReconnectPolicy};
+use crate::relay::{relay_via, relay_with, FrameCompression, RelayConfig};
+
+/// A configured broker. Cheap to clone.
+#[derive(Clone)]
+pub struct Broker {
+    config: RelayConfig,
+    endpoint: Option<EndpointSpec>,
+    launch: Option<LaunchConfig>,
+    reconnect: Option<ReconnectPolicy>,
+    /// Main Apps connected besides the primary one.
+    peers: Vec<EndpointSpec>,
+    lazy: bool,
+    runtime: RuntimeOptions,
+}
+
+/// Builder of a [`Broker`]. Unset options keep the defaults of [`run_stdio`](crate::run_stdio).
+pub struct BrokerBuilder {
+    config: RelayConfig,
+    hooks: Vec<Arc<dyn RelayHook>>,
+    endpoint: Option<EndpointSpec>,
+    launch: Option<LaunchConfig>,
+    reconnect: Option<ReconnectPolicy>,
+    /// Main Apps connected besides the primary one.
+    peers: Vec<EndpointSpec>,
+    lazy: bool,
+    runtime: RuntimeOptions,
+}
+
+impl Broker {
+    /// Starts a builder with the defaults: the current profile's endpoint, no
+    /// hooks or notifications, JSON limits, heartbeat, flush policy,
+    /// compression, inspection and task timeouts from the environment, the message limits from the bridge
+    /// config ([`BridgeConfig::message_limits`]), the Main App launch and
+    /// reconnect settings from the environment ([`LaunchConfig::from_env`], [`ReconnectPolicy::from_env`]),
+    /// the peers listed in `RZN_PEER_PROFILES`, graceful shutdown on signals
+    /// and the runtime options of the bridge config ([`BridgeConfig::runtime`]).
+    pub fn builder() -> BrokerBuilder {
+        let bridge_config = BridgeConfig::load_or_default();
+        BrokerBuilder {
+            config: RelayConfig {
+                handle_signals: true,
+                limits: bridge_config.message_limits(),
+                ..RelayConfig::new(Hooks::default())
+            },
+            hooks: Vec::new(),
+            endpoint: None,
+            launch: LaunchConfig::from_env(),
+            reconnect: ReconnectPolicy::from_env(),
+            peers: peers_from_env(),
+            lazy: false,
+            runtime: bridge_config.runtime(),
+        }
+    }
+
+    /// Runs as a native messaging host on stdin/stdout until either side disconnects.
+    pub async fn run_stdio(&self) -> io::Result<()> {
+        let native_reader = BufReader::new(tokio::io::stdin());
+        let native_writer = BufWriter::new(tokio::io::stdout());
+        self.serve(native_reader, native_writer).await
+    }
+
+    /// [`run_stdio`](Self::run_stdio) on a runtime of its own, for hosts that
+    /// don't run tokio. Blocks until the relay ends.
+    pub fn run_stdio_blocking(&self) -> io::Result<()> {
+        let runtime = self.runtime.build()?;
+        let result = runtime.block_on(self.run_stdio());
+        // The relay has drained by now, but tokio's blocking read of stdin
+        // can't be cancelled and would hold up a waiting shutdown
+        runtime.shutdown_background();
+        result
+    }
+
+    /// Connects to the Main App (right away, or on demand if lazy) and relays
+    /// between it and the given extension-side streams.
+    pub async fn serve<NR, NW>(&self, native_reader: NR, native_writer: NW) -> io::Result<()>
+    where
+        NR: AsyncRead + Unpin + Send + 'static,
+        NW: AsyncWrite + Unpin + Send + 'static,
+    {
+        if self.lazy {
+            return serve_lazy(self, native_reader, native_writer).await;
+        }
+        // Exits the broker if the Main App isn't running and can't be launched
+        let connection = self.connect().await?;
+        self.relay_connected(native_reader, native_writer, connection).await;
+        Ok(())
+    }
+
+    /// Relays between an extension-side pair and a Main App-side pair of
+    /// streams. Returns once either side disconnects.
+    pub async fn relay<NR, NW, IR, IW>(&self, native_reader: NR, native_writer: NW, ipc_reader: IR, ipc_writer: IW)
+    where
+        NR: AsyncRead + Unpin + Send + 'static,
+        NW: AsyncWrite + Unpin + Send + 'static,
+        IR: AsyncRead + Unpin + Send + 'static,
+        IW: AsyncWrite + Unpin + Send + 'static,
+    {
+        relay_with(self.config.clone(), native_reader, native_writer, ipc_reader, ipc_writer).await
+    }
+
+    /// Relays over an established Main App connection, reconnecting when it
+    /// drops if a reconnect policy is set. Additional peers are connected
+    /// here; one that isn't up is retried like a lost connection.
+    pub(crate) async fn relay_connected<NR, NW>(&self, native_reader: NR, native_writer: NW, connection: Connection<Stream>)
+    where
+        NR: AsyncRead + Unpin + Send + 'static,
+        NW: AsyncWrite + Unpin + Send + 'static,
+    {
+        let connect = connector(self.endpoint.clone(), self.launch.clone());
+        if !self.peers.is_empty() {
+            let mut connections = vec![(Some(connection), connect)];
+            for (index, endpoint) in self.peers.iter().enumerate() {
+                let mut connect = connector(Some(endpoint.clone()), None);
+                let connection = connect()
+                    .await
+                    .inspect_err(|e| log::warn!("Peers: Main App {} is not up: {}", index + 1, e))
+                    .ok();
+                connections.push((connection, connect));
+            }
+            let policy = self.reconnect.clone();
+            return relay_via(self.config.clone(), native_reader, native_writer, |links| {
+                run_peers(policy, connections, links)
+            })
+            .await;
+        }
+        let Some(policy) = self.reconnect.clone() else {
+            let (ipc_stream, _main_app) = connection;
+            let (ipc_reader, ipc_writer) = tokio::io::split(ipc_stream);
+            return self.relay(native_reader, native_writer, ipc_reader, ipc_writer).await;
+        };
+        relay_via(self.config.clone(), native_reader, native_writer, |links| {
+            run_sessions(policy, Some(connection), connect, links)
+        })
+        .await
+    }
+
+    pub(crate) fn notifier(&self) -> &Arc<dyn Notifier> {
+        &self.config.notifier
+    }
+
+    /// Connects to the configured Main App endpoint, launching the Main App if
+    /// configured. A non-detached Main App is killed when the child is dropped.
+    pub(crate) async fn connect(&self) -> io::Result<Connection<Stream>> {
+        connect_endpoint(self.endpoint.as_ref(), self.launch.as_ref(), true).await
+    }
+}
+
+/// A connection attempt made by [`connector`].
+type Connecting = Pin<Box<dyn Future<Output = io::Result<Connection<Stream>>> + Send>>;
+
+/// Opens a connection to `endpoint` with a single attempt (launching the
+/// Main App if `launch` is set), as often as it is called.
+fn connector(
+    endpoint: Option<EndpointSpec>,
+    launch: Option<LaunchConfig>,
+) -> impl FnMut() -> Connecting + Send + 'static {
+    move || {
+        let endpoint = endpoint.clone();
+        let launch = launch.clone();
+        Box::pin(async move { connect_endpoint(endpoint.as_ref(), launch.as_ref(), false).await })
+    }
+}
+
+impl BrokerBuilder {
+    /// Connects to `endpoint` instead of the current profile's endpoint.
+    pub fn endpoint(mut self, endpoint: EndpointSpec) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Connects to the endpoint `name` (namespaced where supported, else a
+    /// socket file in the runtime directory).
+    pub fn endpoint_name(self, name: &str) -> Self {
+        self.endpoint(EndpointSpec::resolve(name))
+    }
+
+    /// Rejects messages larger than `bytes` from either side.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.config.limits = MessageLimits::uniform(bytes);
+        self
+    }
+
+    /// Rejects messages larger than the limit for their direction. A rejected
+    /// message is answered with a `message_too_large` to its sender.
+    pub fn message_limits(mut self, limits: MessageLimits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// Replaces the JSON limits read from the environment.
+    pub fn json_limits(mut self, limits: JsonLimits) -> Self {
+        self.config.json_limits = limits;
+        self
+    }
+
+    /// Appends a hook; hooks run in the order they were added.
+    pub fn hook(mut self, hook: impl RelayHook + 'static) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Appends every hook of `hooks`.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks.extend(hooks.iter().cloned());
+        self
+    }
+
+    /// Reports task failures, approval requests and extension disconnects to
+    /// `notifier`.
+    pub fn notifier(mut self, notifier: impl Notifier + 'static) -> Self {
+        self.config.notifier = Arc::new(notifier);
+        self
+    }
+
+    /// Starts the Main App when it isn't listening, or never with `None`.
+    pub fn launch(mut self, launch: Option<LaunchConfig>) -> Self {
+        self.launch = launch;
+        self
+    }
+
+    /// Reconnects to the Main App with `policy` when it goes away, or ends the
+    /// relay with `None`.
+    pub fn reconnect(mut self, policy: Option<ReconnectPolicy>) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Pings every Main App connection as `heartbeat` says and drops one that
+    /// stops answering, or never pings with `None`.
+    pub fn heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.config.heartbeat = heartbeat;
+        self
+    }
+
+    /// Flushes writes to the extension and the Main App as `policy` says.
+    /// Bursts of small messages need fewer writes with
+    /// [`FlushPolicy::Coalesced`] or [`FlushPolicy::OnIdle`].
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.config.flush_policy = policy;
+        self
+    }
+
+    /// Compresses frames to the Main App over the threshold of `compression`
+    /// if the Main App's `hello_ack` lists its codec, or never with `None`.
+    pub fn compression(mut self, compression: Option<Compression>) -> Self {
+        self.config.compression = FrameCompression::new(compression);
+        self
+    }
+
+    /// Parses every relayed message to check it and log it in full. Off by
+    /// default: messages are logged by `action` and `task_id`, which are read
+    /// without parsing the payload.
+    pub fn inspect(mut self, inspect: bool) -> Self {
+        self.config.inspect = inspect;
+        self
+    }
+
+    /// Fails tasks with a `timeout_ms` that run past it, answering the Main
+    /// App with a `task_result` with error `timeout` and sending the
+    /// extension a `cancel_task`. Off by default, as `rzn_bridge_client`
+    /// times tasks out itself.
+    pub fn task_timeouts(mut self, enforce: bool) -> Self {
+        self.config.task_timeouts = enforce;
+        self
+    }
+
+    /// Also connects to the Main App at `endpoint`. Tasks it sends are
+    /// answered to it; other extension messages go to the primary Main App.
+    pub fn peer(mut self, endpoint: EndpointSpec) -> Self {
+        self.peers.push(endpoint);
+        self
+    }
+
+    /// Stays dormant until the extension needs the Main App (see [`run_stdio_lazy`](crate::run_stdio_lazy)).
+    pub fn lazy(mut self, lazy: bool) -> Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Whether SIGTERM/SIGINT (Ctrl+C or closing the console on Windows) end
+    /// the relay gracefully: it stops reading, drains its queues and sends
+    /// both sides a `shutdown` notice. Turn off to handle signals yourself.
+    pub fn handle_signals(mut self, handle: bool) -> Self {
+        self.config.handle_signals = handle;
+        self
+    }
+
+    /// Runtime built by [`Broker::run_stdio_blocking`]; the async methods
+    /// run on the caller's.
+    pub fn runtime(mut self, options: RuntimeOptions) -> Self {
+        self.runtime = options;
+        self
+    }
+
+    pub fn build(self) -> Broker {
+        let mut config = self.config;
+        config.hooks = Arc::new(self.hooks);
+        Broker {
+            config,
+            endpoint: self.endpoint,
+            launch: self.launch,
+            reconnect: self.reconnect,
+            peers: self.peers,
+            lazy: self.lazy,
+            runtime: self.runtime,
+        }
+    }
+}
+
+/// Endpoints of the profiles in `RZN_PEER_PROFILES` (comma-separated), each
+/// served by another Main App.
+fn peers_from_env() -> Vec<EndpointSpec> {
+    let Ok(names) = std::env::var("RZN_PEER_PROFILES") else {
+        return Vec::new();
+    };
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| match Profile::named(name) {
+            Ok(profile) => Some(profile.endpoint()),
+            Err(e) => {
+                log::warn!("Ignoring peer profile in RZN_PEER_PROFILES: {}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::HookAction;
+    use crate::lifecycle::next_message;
+    use shared_types::frame::{read_frame, write_frame, write_message_bytes, FrameFlags};
+    use tokio::io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+    use tokio::task::JoinHandle;
+
+    /// A message from the Main App as the extension got it, without the
+    /// `msg_id` the broker gave it.
+    fn unstamped(message: &[u8]) -> serde_json::Value {
+        let mut value: serde_json::Value = serde_json::from_slice(message).unwrap();
+        assert!(value.as_object_mut().unwrap().remove("msg_id").is_some());
+        value
+    }
+
+    /// One side's halves of its connection to the broker.
+    type Ends = (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>);
+
+    /// The extension's and the Main App's ends of a relay by `broker`, and
+    /// the relay.
+    fn spawn_relay(broker: Broker) -> (Ends, Ends, JoinHandle<()>) {
+        let (extension, native) = duplex(4096);
+        let (host, ipc) = duplex(4096);
+        let (native_reader, native_writer) = split(native);
+        let (ipc_reader, ipc_writer) = split(ipc);
+        let relay = tokio::spawn(async move { broker.relay(native_reader, native_writer, ipc_reader, ipc_writer).await });
+        (split(extension), split(host), relay)
+    }
+
+    struct Tag;
+
+    impl RelayHook for Tag {
+        fn before_forward_to_host(&self, message: Vec<u8>) -> HookAction {
+            let mut value: serde_json::Value = serde_json::from_slice(&message).unwrap();
+            value["tagged"] = true.into();
+            HookAction::Forward(serde_json::to_vec(&value).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_both_ways_over_in_memory_streams() {
+        let broker = Broker::builder().hook(Tag).build();
+        let ((mut extension_reader, mut extension_writer), (mut host_reader, mut host_writer), relay) = spawn_relay(broker);
+
+        let hello = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        let hello: serde_json::Value = serde_json::from_slice(&hello.payload).unwrap();
+        assert_eq!(hello["action"], "hello");
+        write_message_bytes(&mut extension_writer, br#"{"action":"ping","task_id":"1"}"#, "test").await.unwrap();
+        let frame = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        let forwarded: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+        assert_eq!(forwarded, serde_json::json!({ "action": "ping", "task_id": "1", "tagged": true }));
+
+        write_frame(&mut host_writer, FrameFlags::NONE, 0, br#"{"action":"pong","task_id":"1"}"#, "test").await.unwrap();
+        let reply = next_message(&mut extension_reader).await;
+        assert_eq!(unstamped(&reply), serde_json::json!({ "action": "pong", "task_id": "1" }));
+
+        // Closing the extension side ends the relay
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_out_what_is_queued_before_every_task_stops() {
+        let broker = Broker::builder().build();
+        let ((_extension_reader, mut extension_writer), (mut host_reader, _host_writer), relay) = spawn_relay(broker);
+
+        write_message_bytes(&mut extension_writer, br#"{"action":"task_result","task_id":"1","success":true}"#, "test").await.unwrap();
+        extension_writer.shutdown().await.unwrap();
+        relay.await.unwrap();
+
+        // The last message still reached the Main App, and nothing holds the connection open
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        let frame = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        assert_eq!(frame.payload, br#"{"action":"task_result","task_id":"1","success":true}"#);
+        assert!(read_frame(&mut host_reader, "test").await.unwrap().is_none());
+    }
+
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl Notifier for Arc<Recorder> {
+        fn task_failed(&self, task_id: &str, error: Option<&str>) {
+            self.0.lock().unwrap().push(format!("failed {} {:?}", task_id, error));
+        }
+
+        fn approval_requested(&self, task_id: &str, request: &shared_types::CommitRequest) {
+            self.0.lock().unwrap().push(format!("approval {} {}", task_id, request.step_index));
+        }
+
+        fn extension_disconnected(&self) {
+            self.0.lock().unwrap().push("disconnected".to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn notifies_failures_approvals_and_disconnects() {
+        let recorder = Arc::new(Recorder::default());
+        let broker = Broker::builder().notifier(recorder.clone()).build();
+        let ((extension_reader, mut extension_writer), (mut host_reader, _host_writer), relay) = spawn_relay(broker);
+
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        let messages: [&[u8]; 3] = [
+            br#"{"action":"task_result","task_id":"1","success":true,"result":{"steps":[]}}"#,
+            br#"{"action":"commit_request","task_id":"2","data":{"step_index":1,"step":{"type":"click","selector":".buy"}}}"#,
+            br#"{"action":"task_result","task_id":"3","success":false,"error":"Timeout"}"#,
+        ];
+        for message in messages {
+            write_message_bytes(&mut extension_writer, message, "test").await.unwrap();
+            read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        }
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), ["approval 2 1", "failed 3 Some(\"Timeout\")", "disconnected"]);
+    }
+
+    #[tokio::test]
+    async fn reports_a_main_app_with_another_major_version() {
+        let broker = Broker::builder().build();
+        let ((mut extension_reader, _extension_writer), (mut host_reader, mut host_writer), relay) = spawn_relay(broker);
+
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        let ack = br#"{"action":"hello_ack","task_id":"broker-hello","success":true,"result":{"protocol_version":"2.0","software":"future_app 9"}}"#;
+        write_frame(&mut host_writer, FrameFlags::NONE, 0, ack, "test").await.unwrap();
+        let error = next_message(&mut extension_reader).await;
+        let error: serde_json::Value = serde_json::from_slice(&error).unwrap();
+        assert_eq!(error["action"], "bridge_error");
+        assert_eq!(error["result"]["code"], "E_PROTOCOL_VERSION");
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn answers_frames_it_cannot_relay_with_structured_errors() {
+        let broker = Broker::builder().build();
+        let ((mut extension_reader, extension_writer), (mut host_reader, mut host_writer), relay) = spawn_relay(broker);
+
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        write_frame(&mut host_writer, FrameFlags::ENCRYPTED, 0, b"sealed bytes", "test").await.unwrap();
+        let error: serde_json::Value = serde_json::from_slice(&read_frame(&mut host_reader, "test").await.unwrap().unwrap().payload).unwrap();
+        assert_eq!(error["action"], "bridge_error");
+        assert_eq!((error["result"]["code"].as_str(), error["result"]["task_id"].as_str()), (Some(shared_types::E_UNSUPPORTED_FRAME), Some("N/A")));
+        assert!(error["result"]["message"].as_str().unwrap().contains("not supported"));
+
+        // The relay goes on with the next frame
+        write_frame(&mut host_writer, FrameFlags::NONE, 0, br#"{"action":"pong","task_id":"1"}"#, "test").await.unwrap();
+        assert_eq!(unstamped(&next_message(&mut extension_reader).await), serde_json::json!({ "action": "pong", "task_id": "1" }));
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_sent_back_as_too_large() {
+        let broker = Broker::builder().max_message_size(40).build();
+        let ((mut extension_reader, mut extension_writer), (mut host_reader, _host_writer), relay) = spawn_relay(broker);
+
+        write_message_bytes(&mut extension_writer, br#"{"action":"ping","task_id":"t1","data":"too long for the limit"}"#, "test").await.unwrap();
+        let reply: serde_json::Value = serde_json::from_slice(&next_message(&mut extension_reader).await).unwrap();
+        assert_eq!((reply["action"].as_str(), reply["task_id"].as_str(), reply["result"]["limit"].as_u64()), (Some("message_too_large"), Some("t1"), Some(40)));
+
+        // The relay goes on with the next message
+        write_message_bytes(&mut extension_writer, br#"{"action":"ping"}"#, "test").await.unwrap();
+        let forwarded = loop {
+            let frame = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+            if frame.payload.starts_with(br#"{"action":"ping""#) {
+                break frame.payload;
+            }
+        };
+        assert_eq!(forwarded, br#"{"action":"ping"}"#);
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_messages_cross_in_chunks() {
+        let broker = Broker::builder().build();
+        let ((mut extension_reader, mut extension_writer), (mut host_reader, mut host_writer), relay) = spawn_relay(broker);
+
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        let large = serde_json::to_vec(&serde_json::json!({ "action": "pong", "task_id": "1", "data": "x".repeat(1536 * 1024) })).unwrap();
+        let sent = large.clone();
+        tokio::spawn(async move { write_frame(&mut host_writer, FrameFlags::NONE, 0, &sent, "test").await.unwrap() });
+
+        // Over Chrome's limit, so the extension gets it in pieces
+        let mut reassembler = shared_types::Reassembler::new(2 * large.len());
+        let received = loop {
+            let message = next_message(&mut extension_reader).await;
+            assert!(shared_types::is_chunk(&message) && message.len() <= shared_types::chunk::NATIVE_TO_EXTENSION_LIMIT);
+            if let Some(whole) = reassembler.accept(&message).unwrap() {
+                break whole;
+            }
+        };
+        assert_eq!(unstamped(&received), serde_json::from_slice::<serde_json::Value>(&large).unwrap());
+
+        // Pieces from the extension reach the Main App whole
+        let result = br#"{"action":"task_result","task_id":"2","success":true,"result":{"steps":[]}}"#;
+        for piece in shared_types::chunk_message(result, "ext-1", 16).unwrap() {
+            write_message_bytes(&mut extension_writer, &piece, "test").await.unwrap();
+        }
+        let frame = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        assert_eq!(frame.payload, result);
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn compresses_frames_once_the_main_app_reads_them() {
+        let compression = Compression { codec: shared_types::Codec::Zstd, threshold: 1024 };
+        let broker = Broker::builder().compression(Some(compression)).build();
+        let ((mut extension_reader, mut extension_writer), (mut host_reader, mut host_writer), relay) = spawn_relay(broker);
+
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        let ack = serde_json::json!({
+            "action": "hello_ack",
+            "task_id": "broker-hello",
+            "success": true,
+            "result": shared_types::Hello::new("test app", shared_types::COMPRESSION_CAPABILITIES),
+        });
+        write_frame(&mut host_writer, FrameFlags::NONE, 0, &serde_json::to_vec(&ack).unwrap(), "test").await.unwrap();
+
+        // A compressed task reaches the extension decompressed
+        let task = serde_json::to_vec(&serde_json::json!({ "action": "pong", "task_id": "1", "data": "x".repeat(4096) })).unwrap();
+        let compressed = Compression { codec: shared_types::Codec::Gzip, threshold: 0 }.compress(&task).unwrap().unwrap();
+        write_frame(&mut host_writer, FrameFlags::COMPRESSED, 0, &compressed, "test").await.unwrap();
+        assert_eq!(unstamped(&next_message(&mut extension_reader).await), serde_json::from_slice::<serde_json::Value>(&task).unwrap());
+
+        // A large result goes out compressed, a small one as it is
+        let result = serde_json::to_vec(&serde_json::json!({ "action": "task_result", "task_id": "1", "result": "<p>".repeat(4096) })).unwrap();
+        write_message_bytes(&mut extension_writer, &result, "test").await.unwrap();
+        write_message_bytes(&mut extension_writer, b"{}", "test").await.unwrap();
+        let mut header = [0u8; shared_types::frame::FRAME_HEADER_LEN];
+        host_reader.read_exact(&mut header).await.unwrap();
+        let header = shared_types::frame::FrameHeader::decode(&header).unwrap();
+        assert!(header.flags.contains(FrameFlags::COMPRESSED) && (header.length as usize) < result.len() / 10);
+        let mut payload = vec![0u8; header.length as usize];
+        host_reader.read_exact(&mut payload).await.unwrap();
+        assert_eq!(shared_types::compress::decompress(&payload, result.len()).unwrap(), result);
+        let frame = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        assert_eq!((frame.header.flags, &frame.payload[..]), (FrameFlags::NONE, &b"{}"[..]));
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transcodes_for_a_main_app_that_asks_for_msgpack() {
+        let broker = Broker::builder().build();
+        let ((mut extension_reader, mut extension_writer), (mut host_reader, mut host_writer), relay) = spawn_relay(broker);
+
+        read_frame(&mut host_reader, "test").await.unwrap().unwrap(); // hello
+        let ack = serde_json::json!({
+            "action": "hello_ack",
+            "task_id": "broker-hello",
+            "success": true,
+            "result": shared_types::Hello::new("test app", &["encoding:msgpack"]),
+        });
+        write_frame(&mut host_writer, FrameFlags::NONE, 0, &serde_json::to_vec(&ack).unwrap(), "test").await.unwrap();
+
+        // A MessagePack task reaches the extension as JSON
+        let task = serde_json::json!({ "action": "pong", "task_id": "1" });
+        let encoded = shared_types::Encoding::MessagePack.to_vec(&task).unwrap();
+        write_frame(&mut host_writer, FrameFlags::MSGPACK, 0, &encoded, "test").await.unwrap();
+        assert_eq!(unstamped(&next_message(&mut extension_reader).await), task);
+
+        // The extension's JSON goes out as MessagePack
+        let result = serde_json::json!({ "action": "task_result", "task_id": "1", "success": true, "result": [1, 2, 3] });
+        write_message_bytes(&mut extension_writer, &serde_json::to_vec(&result).unwrap(), "test").await.unwrap();
+        let frame = read_frame(&mut host_reader, "test").await.unwrap().unwrap();
+        assert_eq!(frame.header.flags, FrameFlags::MSGPACK);
+        assert_eq!(frame.deserialize::<serde_json::Value>().unwrap(), result);
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+//! Enforcement of per-task result budgets (`max_result_bytes`).
+//!
+//! The extension is expected to keep results within budget itself. The broker
+//! remembers the budget of every task it relays and truncates the matching
+//! `task_result` if the extension did not.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use shared_types::{TaskResult, TASK_RESULT_ACTION};
+
+use crate::metrics;
+
+/// Budgets of the tasks currently running in the extension, by task id.
+#[derive(Default)]
+pub(crate) struct ResultBudgets {
+    caps: Mutex<HashMap<String, usize>>,
+}
+
+impl ResultBudgets {
+    /// Remembers the budget of a `perform_task` on its way to the extension.
+    pub(crate) fn record(&self, value: &serde_json::Value) {
+        let cap = value.get("task").and_then(|t| t.get("max_result_bytes")).and_then(|v| v.as_u64());
+        let task_id = value.get("task_id").and_then(|v| v.as_str());
+        if let (Some(cap), Some(task_id)) = (cap, task_id) {
+            let cap = usize::try_from(cap).unwrap_or(usize::MAX);
+            self.caps.lock().unwrap().insert(task_id.to_string(), cap);
+        }
+    }
+
+    /// Returns the bytes to forward for a message from the extension: unchanged,
+    /// or re-encoded with a truncated result if it is a `task_result` over budget.
+    pub(crate) fn enforce(&self, value: &serde_json::Value, message_bytes: Bytes) -> Bytes {
+        if value.get("action").and_then(|v| v.as_str()) != Some(TASK_RESULT_ACTION) {
+            return message_bytes;
+        }
+        let Some(task_id) = value.get("task_id").and_then(|v| v.as_str()) else {
+            return message_bytes;
+        };
+        let Some(cap) = self.caps.lock().unwrap().remove(task_id) else {
+            return message_bytes;
+        };
+        let Some(mut result) = value.get("result").and_then(|r| serde_json::from_value::<TaskResult>(r.clone()).ok()) else {
+            return message_bytes;
+        };
+        let original_len = result.encoded_len();
+        if original_len <= cap || !result.truncate_to(cap) {
+            return message_bytes;
+        }
+
+        log::warn!("ResultBudget: Truncated result of task {} from {} to {} bytes (budget {}).",
+                   task_id, original_len, result.encoded_len(), cap);
+        metrics::record_result_truncated();
+        let mut value = value.clone();
+        value["result"] = serde_json::to_value(&result).unwrap_or_default();
+        serde_json::to_vec(&value).map_or(message_bytes, Bytes::from)
+    }
+}
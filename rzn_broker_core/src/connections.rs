@@ -0,0 +1,116 @@
+//! The state of each Main App connection (see [`ConnectionState`]).
+//!
+//! Which state a connection was in used to follow only from which of its
+//! tasks were still running. Each one is now tracked explicitly, from
+//! `connecting` through `handshaking` and `ready` (and `reconnecting` after a
+//! drop) to `draining` and `closed`. Every change is logged and reported to
+//! the [`Notifier`], and the open connections are listed by
+//! [`connection_states`] and in the `bridge_stats` answer.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use shared_types::{ConnectionState, ConnectionStatus};
+
+use crate::notifier::Notifier;
+
+/// Every open connection, by ID.
+static CONNECTIONS: Mutex<BTreeMap<u64, ConnectionStatus>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The broker's open Main App connections, oldest first.
+pub fn connection_states() -> Vec<ConnectionStatus> {
+    CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner()).values().cloned().collect()
+}
+
+/// One Main App connection, across its reconnects. Listed from when it
+/// starts `connecting` until it is dropped, which closes it.
+pub(crate) struct TrackedConnection {
+    id: u64,
+    peer: usize,
+    notifier: Arc<dyn Notifier>,
+}
+
+impl TrackedConnection {
+    pub(crate) fn new(peer: usize, notifier: Arc<dyn Notifier>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let status = ConnectionStatus { id, peer, state: ConnectionState::Connecting, since_ms: now_ms() };
+        CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner()).insert(id, status);
+        log::info!("Connection {}: Main App {} connecting.", id, peer);
+        TrackedConnection { id, peer, notifier }
+    }
+
+    pub(crate) fn state(&self) -> ConnectionState {
+        CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner()).get(&self.id).map_or(ConnectionState::Closed, |status| status.state)
+    }
+
+    /// Moves to `next` and reports the change. Does nothing if the connection
+    /// is already there or can't go there from where it is, e.g. a reconnect
+    /// while draining.
+    pub(crate) fn advance(&self, next: ConnectionState) {
+        let change = {
+            let mut connections = CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner());
+            let Some(status) = connections.get_mut(&self.id) else { return };
+            if status.state == next {
+                return;
+            }
+            let Some(change) = status.state.advance(next) else {
+                log::debug!("Connection {}: Staying {:?} instead of becoming {:?}.", self.id, status.state, next);
+                return;
+            };
+            status.since_ms = now_ms();
+            if next == ConnectionState::Closed {
+                connections.remove(&self.id);
+            }
+            change
+        };
+        log::info!("Connection {}: {:?} -> {:?}", self.id, change.previous, change.state);
+        self.notifier.connection_state_changed(self.peer, &change);
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.advance(ConnectionState::Closed);
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::ConnectionStateChange;
+    use ConnectionState::*;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<ConnectionStateChange>>);
+
+    impl Notifier for Recorder {
+        fn connection_state_changed(&self, _peer: usize, change: &ConnectionStateChange) {
+            self.0.lock().unwrap().push(*change);
+        }
+    }
+
+    #[test]
+    fn lists_open_connections_and_reports_each_change() {
+        let recorder = Arc::new(Recorder::default());
+        let connection = TrackedConnection::new(3, recorder.clone());
+        let listed = |id| connection_states().into_iter().find(|status| status.id == id).map(|status| (status.peer, status.state));
+        assert_eq!(listed(connection.id), Some((3, Connecting)));
+
+        for next in [Handshaking, Ready, Ready, Connecting, Reconnecting, Handshaking, Draining, Ready] {
+            connection.advance(next);
+        }
+        assert_eq!(connection.state(), Draining);
+        let id = connection.id;
+        drop(connection);
+        assert_eq!(listed(id), None);
+        let states: Vec<_> = recorder.0.lock().unwrap().iter().map(|change| change.state).collect();
+        assert_eq!(states, [Handshaking, Ready, Reconnecting, Handshaking, Draining, Closed]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Per-task timeouts (`timeout_ms`), when the broker keeps them.
+//!
+//! Main Apps on `rzn_bridge_client` time their tasks out themselves. For the
+//! others the broker can do it (`RZN_TASK_TIMEOUTS=1`): a task without a
+//! `task_result` once its `timeout_ms` has passed is answered with a failed
+//! `task_result` (error `timeout`), and the extension is sent a `cancel_task`
+//! for it. Whatever result the extension still sends is dropped.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use shared_types::{Action, Envelope, ExtensionResponse, Message, TASK_TIMEOUT_ERROR};
+
+use crate::metrics;
+use crate::relay::Queued;
+
+// Tasks remembered as timed out, to drop their late answers
+const MAX_TIMED_OUT: usize = 1000;
+
+/// Clocks of the running tasks that have a `timeout_ms`.
+#[derive(Default)]
+pub(crate) struct TaskDeadlines(Mutex<Deadlines>);
+
+#[derive(Default)]
+struct Deadlines {
+    /// Timers by task id.
+    running: HashMap<String, AbortHandle>,
+    timed_out: VecDeque<String>,
+}
+
+impl TaskDeadlines {
+    /// Starts the clock of a `perform_task` with a `timeout_ms`. When it runs
+    /// out, the failed result goes to `host_tx` and the `cancel_task` to
+    /// `native_tx`.
+    pub(crate) fn start(self: &Arc<Self>, value: &Value, host_tx: &mpsc::Sender<Queued>, native_tx: &mpsc::Sender<Queued>) {
+        if Action::of(value) != Some(Action::PerformTask) {
+            return;
+        }
+        let timeout_ms = value.get("task").and_then(|t| t.get("timeout_ms")).and_then(|v| v.as_u64());
+        let (Some(timeout_ms), Some(task_id)) = (timeout_ms, value.get("task_id").and_then(|v| v.as_str())) else {
+            return;
+        };
+        let mut deadlines = self.lock();
+        let (this, id, host_tx, native_tx) = (self.clone(), task_id.to_string(), host_tx.clone(), native_tx.clone());
+        let timer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(timeout_ms)).await;
+            this.expire(&id, timeout_ms, &host_tx, &native_tx).await;
+        });
+        if let Some(earlier) = deadlines.running.insert(task_id.to_string(), timer.abort_handle()) {
+            earlier.abort();
+        }
+    }
+
+    /// Whether a message from the extension answers, or reports the steps
+    /// of, a task that timed out, so it is dropped. A `task_result` stops its
+    /// task's clock.
+    pub(crate) fn is_late(&self, envelope: &Envelope) -> bool {
+        let action = envelope.action.as_deref().map(Action::parse);
+        if !matches!(action, Some(Action::TaskResult | Action::TaskCancelled | Action::StepStarted | Action::StepProgress | Action::StepCompleted)) {
+            return false;
+        }
+        let Some(task_id) = envelope.task_id.as_deref() else {
+            return false;
+        };
+        let mut deadlines = self.lock();
+        if action == Some(Action::TaskResult) {
+            if let Some(timer) = deadlines.running.remove(task_id) {
+                timer.abort();
+                return false;
+            }
+        }
+        deadlines.timed_out.iter().any(|id| id == task_id)
+    }
+
+    async fn expire(&self, task_id: &str, timeout_ms: u64, host_tx: &mpsc::Sender<Queued>, native_tx: &mpsc::Sender<Queued>) {
+        {
+            let mut deadlines = self.lock();
+            if deadlines.running.remove(task_id).is_none() {
+                return;
+            }
+            deadlines.timed_out.push_back(task_id.to_string());
+            if deadlines.timed_out.len() > MAX_TIMED_OUT {
+                deadlines.timed_out.pop_front();
+            }
+        }
+        log::warn!("TaskTimeout: Task {} has no result after {} ms, failing and cancelling it.", task_id, timeout_ms);
+        metrics::record_task_timed_out();
+        if let Ok(bytes) = serde_json::to_vec(&ExtensionResponse::task_timed_out(task_id)) {
+            let _ = host_tx.send(bytes.into()).await;
+        }
+        if let Ok(bytes) = serde_json::to_vec(&Message::cancel_task(task_id, Some(TASK_TIMEOUT_ERROR.to_string()))) {
+            let _ = native_tx.send(bytes.into()).await;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Deadlines> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_types::peek_envelope;
+
+    fn task(task_id: &str, timeout_ms: u64) -> Value {
+        serde_json::json!({ "action": "perform_task", "task_id": task_id, "task": { "steps": [], "timeout_ms": timeout_ms } })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_and_cancels_tasks_past_their_timeout() {
+        let deadlines = Arc::new(TaskDeadlines::default());
+        let (host_tx, mut host_rx) = mpsc::channel(4);
+        let (native_tx, mut native_rx) = mpsc::channel(4);
+        deadlines.start(&task("t-1", 1_000), &host_tx, &native_tx);
+        deadlines.start(&task("t-2", 1_000), &host_tx, &native_tx);
+
+        // t-1 answers in time
+        let result = |task_id: &str| format!(r#"{{"action":"task_result","task_id":"{}","success":true}}"#, task_id);
+        assert!(!deadlines.is_late(&peek_envelope(result("t-1").as_bytes())));
+        tokio::time::sleep(Duration::from_millis(1_500)).await;
+
+        let failed: ExtensionResponse = serde_json::from_slice(&host_rx.recv().await.unwrap().bytes).unwrap();
+        assert_eq!((failed.task_id.as_str(), failed.success, failed.error.as_deref()), ("t-2", false, Some(TASK_TIMEOUT_ERROR)));
+        let cancel: Message = serde_json::from_slice(&native_rx.recv().await.unwrap().bytes).unwrap();
+        assert_eq!((cancel.action, cancel.task_id.as_str()), (Action::CancelTask, "t-2"));
+        assert!(host_rx.try_recv().is_err());
+
+        assert!(deadlines.is_late(&peek_envelope(result("t-2").as_bytes())));
+        assert!(deadlines.is_late(&peek_envelope(br#"{"action":"task_cancelled","task_id":"t-2"}"#)));
+        assert!(deadlines.is_late(&peek_envelope(br#"{"action":"step_progress","task_id":"t-2"}"#)));
+        assert!(!deadlines.is_late(&peek_envelope(br#"{"action":"log","task_id":"t-2"}"#)));
+    }
+}
@@ -0,0 +1,276 @@
+//! Message IDs, acks and duplicates of the Main App's messages (see
+//! `shared_types::delivery`).
+//!
+//! Each message from the Main App keeps its `msg_id` or is given one, unique
+//! to this broker process and Main App connection, before it is relayed. The
+//! IDs of recent messages are remembered per Main App across its reconnects,
+//! so a message sent again after a reconnect is acknowledged as a duplicate
+//! and not relayed twice.
+//!
+//! The other way round, a Main App that asked for at-least-once delivery gets
+//! the extension's messages with a `msg_id` too. Those it hasn't acknowledged
+//! when its connection drops are written again to the next one ([`Unacked`]).
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use shared_types::{peek_str, with_msg_id, ACK_CAPABILITY, AT_LEAST_ONCE_CAPABILITY};
+
+use crate::relay::Queued;
+
+// Message IDs remembered to spot duplicates
+const MAX_REMEMBERED: usize = 4096;
+// Messages kept for the Main App to acknowledge; the oldest are given up beyond this
+const MAX_UNACKED: usize = 1000;
+
+/// What to do with a message from the Main App.
+pub(crate) enum Delivery {
+    /// Relay it as these bytes, which carry its `msg_id` (none if the message
+    /// isn't a JSON object).
+    New(Bytes, Option<String>),
+    /// It was taken before under this `msg_id`, so it isn't relayed again.
+    Duplicate(String),
+}
+
+/// The message IDs of one Main App connection, across its reconnects.
+pub(crate) struct Deliveries {
+    /// Start of the IDs this broker assigns, so they don't repeat another's.
+    prefix: String,
+    /// Whether the Main App asked for acks.
+    acks: AtomicBool,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    assigned: u64,
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Default for Deliveries {
+    /// The primary Main App's.
+    fn default() -> Self {
+        Deliveries::for_peer(0)
+    }
+}
+
+/// Start of the message IDs this broker process assigns.
+fn id_prefix() -> String {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis());
+    format!("{:x}.{:x}", std::process::id(), started)
+}
+
+impl Deliveries {
+    /// The message IDs of Main App connection `peer`, which assigns IDs of
+    /// its own.
+    pub(crate) fn for_peer(peer: usize) -> Self {
+        Deliveries { prefix: format!("{}.{}", id_prefix(), peer), acks: AtomicBool::new(false), seen: Mutex::default() }
+    }
+
+    /// Takes the capabilities of the Main App's `hello_ack`.
+    pub(crate) fn negotiate(&self, capabilities: &[String]) {
+        self.acks.store(capabilities.iter().any(|c| c == ACK_CAPABILITY), Ordering::Relaxed);
+    }
+
+    /// Whether the Main App gets an `ack` for each message.
+    pub(crate) fn acks(&self) -> bool {
+        self.acks.load(Ordering::Relaxed)
+    }
+
+    /// Gives `message` its `msg_id` and remembers it.
+    pub(crate) fn accept(&self, message: Bytes) -> Delivery {
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(id) = peek_str(&message, "msg_id") {
+            if seen.ids.contains(id.as_ref()) {
+                return Delivery::Duplicate(id.into_owned());
+            }
+            let id = id.into_owned();
+            seen.remember(id.clone());
+            return Delivery::New(message, Some(id));
+        }
+        let id = format!("{}-{}", self.prefix, seen.assigned + 1);
+        let Some(tagged) = with_msg_id(&message, &id) else {
+            return Delivery::New(message, None);
+        };
+        seen.assigned += 1;
+        seen.remember(id.clone());
+        Delivery::New(Bytes::from(tagged), Some(id))
+    }
+}
+
+impl Seen {
+    fn remember(&mut self, id: String) {
+        if self.order.len() == MAX_REMEMBERED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.clone());
+        self.order.push_back(id);
+    }
+}
+
+/// The extension's messages written to one Main App, across its reconnects,
+/// that it hasn't acknowledged yet. Only kept while the Main App asked for
+/// at-least-once delivery.
+pub(crate) struct Unacked {
+    prefix: String,
+    enabled: AtomicBool,
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    assigned: u64,
+    /// `msg_id`, the message carrying it and whether it was written since
+    /// the last reconnect, oldest first.
+    messages: VecDeque<(String, Bytes, bool)>,
+}
+
+impl Default for Unacked {
+    fn default() -> Self {
+        Unacked { prefix: id_prefix(), enabled: AtomicBool::new(false), pending: Mutex::default() }
+    }
+}
+
+impl Unacked {
+    /// Takes the capabilities of the Main App's `hello_ack`. A Main App that
+    /// doesn't ask for at-least-once delivery isn't sent anything again.
+    pub(crate) fn negotiate(&self, capabilities: &[String]) {
+        let enabled = capabilities.iter().any(|c| c == AT_LEAST_ONCE_CAPABILITY);
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            self.pending.lock().unwrap().messages.clear();
+        }
+    }
+
+    /// The bytes to write for `queued`, with a `msg_id` that the Main App is
+    /// to acknowledge, if it is one of the extension's messages and the Main
+    /// App asked for it.
+    pub(crate) fn track(&self, queued: &Queued) -> Bytes {
+        if !queued.redeliver || !self.enabled.load(Ordering::Relaxed) {
+            return queued.bytes.clone();
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let (id, bytes) = match peek_str(&queued.bytes, "msg_id") {
+            Some(id) => {
+                // Written before, and now again
+                if let Some(message) = pending.messages.iter_mut().find(|(pending_id, ..)| *pending_id == id) {
+                    message.2 = true;
+                    return queued.bytes.clone();
+                }
+                (id.into_owned(), queued.bytes.clone())
+            }
+            None => {
+                pending.assigned += 1;
+                let id = format!("{}.e-{}", self.prefix, pending.assigned);
+                let Some(tagged) = with_msg_id(&queued.bytes, &id) else {
+                    return queued.bytes.clone();
+                };
+                (id, Bytes::from(tagged))
+            }
+        };
+        if pending.messages.len() == MAX_UNACKED {
+            log::warn!("Delivery: {} messages unacknowledged by the Main App, giving up on the oldest.", MAX_UNACKED);
+            pending.messages.pop_front();
+        }
+        pending.messages.push_back((id, bytes.clone(), true));
+        bytes
+    }
+
+    /// Forgets the message `msg_id` the Main App acknowledged. `false` if it
+    /// wasn't waiting for that.
+    pub(crate) fn acknowledge(&self, msg_id: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.messages.len();
+        pending.messages.retain(|(id, ..)| id != msg_id);
+        pending.messages.len() < before
+    }
+
+    /// The messages written since the last reconnect and not acknowledged,
+    /// oldest first, to be written again.
+    pub(crate) fn take_written(&self) -> Vec<Queued> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut again = Vec::new();
+        for (_, bytes, written) in pending.messages.iter_mut().filter(|(.., written)| *written) {
+            *written = false;
+            let mut queued = Queued::from(bytes.clone());
+            queued.redeliver = true;
+            again.push(queued);
+        }
+        again
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn accept(deliveries: &Deliveries, message: Value) -> Delivery {
+        deliveries.accept(Bytes::from(serde_json::to_vec(&message).unwrap()))
+    }
+
+    #[test]
+    fn assigns_ids_and_spots_duplicates() {
+        let deliveries = Deliveries::default();
+        let Delivery::New(bytes, Some(assigned)) = accept(&deliveries, serde_json::json!({ "action": "perform_task", "task_id": "t1" })) else {
+            panic!("expected a new message");
+        };
+        let relayed: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(relayed["msg_id"], assigned.as_str());
+        assert!(assigned.starts_with(&deliveries.prefix));
+
+        let sent = serde_json::json!({ "action": "perform_task", "task_id": "t2", "msg_id": "app-1" });
+        assert!(matches!(accept(&deliveries, sent.clone()), Delivery::New(bytes, Some(id)) if id == "app-1" && bytes == serde_json::to_vec(&sent).unwrap()));
+        assert!(matches!(accept(&deliveries, sent), Delivery::Duplicate(id) if id == "app-1"));
+        // A message without an ID is never a duplicate
+        assert!(matches!(accept(&deliveries, serde_json::json!({ "action": "perform_task", "task_id": "t1" })), Delivery::New(_, Some(id)) if id != assigned));
+
+        assert!(!deliveries.acks());
+        deliveries.negotiate(&["encoding:cbor".to_string(), ACK_CAPABILITY.to_string()]);
+        assert!(deliveries.acks());
+
+        // Another Main App assigns its own IDs and asks for acks on its own
+        let other = Deliveries::for_peer(1);
+        let Delivery::New(_, Some(theirs)) = accept(&other, serde_json::json!({ "action": "perform_task", "task_id": "t1" })) else {
+            panic!("expected a new message");
+        };
+        assert!(!theirs.starts_with(&format!("{}-", deliveries.prefix)) && theirs != assigned);
+        assert!(!other.acks());
+    }
+
+    #[test]
+    fn writes_unacknowledged_messages_again() {
+        let from_extension = |json: &[u8]| {
+            let mut queued = Queued::from(json.to_vec());
+            queued.redeliver = true;
+            queued
+        };
+        let unacked = Unacked::default();
+        let result = from_extension(br#"{"action":"task_result","task_id":"t1"}"#);
+        assert_eq!(unacked.track(&result), result.bytes);
+
+        unacked.negotiate(&[AT_LEAST_ONCE_CAPABILITY.to_string()]);
+        let tagged = unacked.track(&result);
+        let id = peek_str(&tagged, "msg_id").unwrap().into_owned();
+        let log = unacked.track(&from_extension(br#"{"action":"log","task_id":"t2","msg_id":"ext-1"}"#));
+        assert_eq!(peek_str(&log, "msg_id").as_deref(), Some("ext-1"));
+        // The broker's own messages aren't tracked
+        assert!(peek_str(&unacked.track(&Queued::from(br#"{"action":"ping"}"#.to_vec())), "msg_id").is_none());
+
+        assert!(unacked.acknowledge("ext-1"));
+        assert!(!unacked.acknowledge("ext-1"));
+        let again = unacked.take_written();
+        assert_eq!(again.iter().map(|queued| queued.bytes.clone()).collect::<Vec<_>>(), std::slice::from_ref(&tagged));
+        // Once written again, it keeps its ID
+        assert_eq!(unacked.track(&again[0]), tagged);
+        assert!(unacked.take_written().len() == 1 && unacked.take_written().is_empty());
+        assert!(unacked.acknowledge(&id));
+    }
+}
@@ -0,0 +1,148 @@
+//! Why the relay, or a message in it, failed.
+//!
+//! Each relay task ends with a [`BrokerError`]: its peer disconnected, a
+//! read or write failed or timed out, the other half of the relay is gone,
+//! or the peer failed the handshake. A single message the broker can't relay
+//! is a [`ProtocolError`], answered to its sender with
+//! [`ProtocolError::to_response`] while the relay goes on. Both carry a
+//! stable `code`, which is also the `code` of the `bridge_error` sent for
+//! them.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use shared_types::{ChunkError, Encoding, ExtensionResponse, JsonLimitError, MessageTooLarge, E_CHUNK, E_INVALID_JSON, E_TRANSCODE, E_UNSUPPORTED_FRAME};
+
+/// One side of the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Extension,
+    MainApp,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Peer::Extension => "the extension",
+            Peer::MainApp => "the Main App",
+        })
+    }
+}
+
+/// A message that can't be relayed. The relay goes on without it.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    /// Over the receiver's size limit.
+    #[error(transparent)]
+    FrameTooLarge(MessageTooLarge),
+    /// Over the JSON limits, so it wasn't parsed.
+    #[error("message exceeds the JSON limits: {0}")]
+    InvalidJson(#[source] JsonLimitError),
+    /// A chunked transfer that doesn't add up.
+    #[error("{0}")]
+    Chunk(#[source] ChunkError),
+    /// A frame with flags the broker can't handle.
+    #[error("frame flags {flags:#010b} are not supported")]
+    UnsupportedFlags { flags: u8 },
+    /// A MessagePack or CBOR message that couldn't be turned into JSON.
+    #[error("could not transcode {encoding:?} message to JSON: {source}")]
+    Transcode { encoding: Encoding, source: io::Error },
+    /// The peer can't be talked to. Holds the `bridge_error` telling it why.
+    #[error("handshake failed: {}", .0.error.as_deref().unwrap_or("no reason given"))]
+    HandshakeFailed(Box<ExtensionResponse>),
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> &str {
+        match self {
+            ProtocolError::FrameTooLarge(_) => "E_FRAME_TOO_LARGE",
+            ProtocolError::InvalidJson(_) => E_INVALID_JSON,
+            ProtocolError::Chunk(_) => E_CHUNK,
+            ProtocolError::UnsupportedFlags { .. } => E_UNSUPPORTED_FRAME,
+            ProtocolError::Transcode { .. } => E_TRANSCODE,
+            ProtocolError::HandshakeFailed(response) => {
+                response.result.as_ref().and_then(|result| result.get("code")).and_then(|code| code.as_str()).unwrap_or("E_HANDSHAKE")
+            }
+        }
+    }
+
+    /// The answer to the sender of the message, which had `task_id`: a
+    /// `bridge_error` with the structured error in its `result`. A message
+    /// over the limit gets the usual `message_too_large` under its own
+    /// `task_id`.
+    pub fn to_response(&self, task_id: &str) -> ExtensionResponse {
+        match self {
+            ProtocolError::FrameTooLarge(too_large) => ExtensionResponse::message_too_large(too_large),
+            ProtocolError::Chunk(ChunkError::TooLarge(too_large)) => ExtensionResponse::message_too_large(too_large),
+            ProtocolError::HandshakeFailed(response) => (**response).clone(),
+            error => ExtensionResponse::bridge_error(task_id, error.code(), &error.to_string()),
+        }
+    }
+}
+
+/// Why a relay task stopped.
+#[derive(Debug, Error)]
+pub enum BrokerError {
+    #[error("{0} disconnected")]
+    PeerDisconnected(Peer),
+    #[error("reading from {peer} failed: {source}")]
+    Read { peer: Peer, source: io::Error },
+    #[error("writing to {peer} failed: {source}")]
+    Write { peer: Peer, source: io::Error },
+    /// The peer stopped taking data, e.g. a hung Main App.
+    #[error("{peer} took nothing for {after:?}")]
+    WriteTimeout { peer: Peer, after: Duration },
+    /// The relay queue to the peer is closed, as the task writing to it
+    /// stopped.
+    #[error("the queue to {0} is closed")]
+    ChannelClosed(Peer),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+}
+
+impl BrokerError {
+    pub fn code(&self) -> &str {
+        match self {
+            BrokerError::PeerDisconnected(_) => "E_PEER_DISCONNECTED",
+            BrokerError::Read { .. } => "E_READ",
+            BrokerError::Write { .. } => "E_WRITE",
+            BrokerError::WriteTimeout { .. } => "E_WRITE_TIMEOUT",
+            BrokerError::ChannelClosed(_) => "E_CHANNEL_CLOSED",
+            BrokerError::Protocol(e) => e.code(),
+        }
+    }
+
+    /// Whether the task stopped because a peer or the other half of the relay
+    /// went away, as happens at the end of every relay.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, BrokerError::PeerDisconnected(_) | BrokerError::ChannelClosed(_))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_with_structured_errors() {
+        let error = ProtocolError::UnsupportedFlags { flags: 0b10 };
+        let response = serde_json::to_value(error.to_response("t-1")).unwrap();
+        assert_eq!(response["action"], "bridge_error");
+        assert_eq!(
+            response["result"],
+            serde_json::json!({ "code": E_UNSUPPORTED_FRAME, "message": "frame flags 0b00000010 are not supported", "task_id": "t-1" })
+        );
+        assert_eq!(response["error"], "[E_UNSUPPORTED_FRAME] frame flags 0b00000010 are not supported");
+
+        let too_large = MessageTooLarge { len: 20, limit: 10, action: None, task_id: Some("t-2".to_string()) };
+        let response = ProtocolError::FrameTooLarge(too_large).to_response("N/A");
+        assert_eq!((response.action.as_str(), response.task_id.as_str()), ("message_too_large", "t-2"));
+
+        let ended = BrokerError::from(ProtocolError::Chunk(ChunkError::Unknown("m-1".to_string())));
+        assert_eq!((ended.code(), ended.is_disconnect()), (E_CHUNK, false));
+        assert!(BrokerError::PeerDisconnected(Peer::MainApp).to_string().contains("the Main App"));
+    }
+}
@@ -0,0 +1,147 @@
+//! `hello`/`hello_ack` exchange on both legs of the relay.
+//!
+//! The extension says hello to the broker, which answers itself; the broker
+//! says hello to every Main App connection and checks the answer. A side with
+//! another major protocol version gets a `bridge_error` with code
+//! [`E_PROTOCOL_VERSION`] instead of messages it would misread. Peers that
+//! never say hello are assumed compatible, as they predate the handshake.
+//!
+//! An extension that says hello with a pairing token (`data.pairing_token`)
+//! the Main App revoked gets a `bridge_error` with code [`E_REVOKED`] and is
+//! disconnected, before any of its messages reach a Main App.
+
+use rzn_protocol::{Handshake, Verdict};
+use serde_json::Value;
+
+use shared_types::{Action, ExtensionResponse, Hello, Pairings, E_PROTOCOL_VERSION, E_REVOKED, HELLO_ACTION};
+
+use crate::error::ProtocolError;
+
+/// Optional features the broker handles itself.
+pub const BROKER_CAPABILITIES: &[&str] = &["selftest", "ttl", "result_budget", "reconnect", "peers", "pause", "heartbeat", "lifecycle", "chunking", "compression:zstd", "compression:gzip", "encoding:msgpack", "encoding:cbor", "ack", "at_least_once"];
+
+// Task ID of the broker's own hello to the Main App
+const HELLO_TASK_ID: &str = "broker-hello";
+
+/// The broker's hello.
+pub fn broker_hello() -> Hello {
+    Hello::new(concat!("rzn_broker ", env!("CARGO_PKG_VERSION")), BROKER_CAPABILITIES)
+}
+
+pub(crate) fn is_hello(message: &Value) -> bool {
+    message.get("action").and_then(|v| v.as_str()) == Some(HELLO_ACTION)
+}
+
+/// Answers the extension's hello with a `hello_ack` if it is compatible.
+/// Otherwise the relay ends with the error, after sending its `bridge_error`.
+pub(crate) fn answer_hello(message: &Value) -> Result<Vec<u8>, ProtocolError> {
+    let task_id = message.get("task_id").and_then(|v| v.as_str()).unwrap_or("hello");
+    if let Some(error) = refuse_revoked(message, task_id) {
+        return Err(refused(error));
+    }
+    let ours = broker_hello();
+    let response = match Handshake::new(ours.clone()).on_hello(message) {
+        Verdict::Agreed(theirs) => {
+            log::info!("Handshake: Extension {} (protocol {}, capabilities {:?}).",
+                     theirs.software, theirs.protocol_version, theirs.capabilities);
+            hello_ack(task_id, &ours, None)
+        }
+        Verdict::Incompatible(theirs, mismatch) => return Err(refused(version_error(task_id, &format!("Extension speaks {}", mismatch), &ours, &theirs))),
+        // Not worth ending the relay over; the extension learns about it from the ack
+        Verdict::Rejected(..) | Verdict::Malformed => hello_ack(task_id, &ours, Some("hello without a valid data payload")),
+    };
+    Ok(serde_json::to_vec(&response).unwrap_or_default())
+}
+
+fn refused(error: ExtensionResponse) -> ProtocolError {
+    log::error!("Handshake: {}", error.error.as_deref().unwrap_or_default());
+    ProtocolError::HandshakeFailed(Box::new(error))
+}
+
+/// The `bridge_error` for a hello with a revoked pairing token, which is
+/// recorded in the pairing audit log.
+fn refuse_revoked(message: &Value, task_id: &str) -> Option<ExtensionResponse> {
+    let token = message.get("data")?.get("pairing_token")?.as_str()?;
+    let pairings = match Pairings::load(Pairings::default_path()?) {
+        Ok(pairings) => pairings,
+        Err(e) => {
+            log::warn!("Handshake: Could not read the pairings: {}", e);
+            return None;
+        }
+    };
+    let identity = pairings.identity(token).filter(|identity| identity.is_revoked())?;
+    pairings.record_refusal(identity, "broker");
+    let message = format!("The pairing of this browser ({}) was revoked", identity.id);
+    Some(ExtensionResponse::bridge_error(task_id, E_REVOKED, &message).with_details(serde_json::json!({ "id": identity.id })))
+}
+
+fn hello_ack(task_id: &str, ours: &Hello, error: Option<&str>) -> ExtensionResponse {
+    ExtensionResponse {
+        action: Action::HelloAck,
+        task_id: task_id.to_string(),
+        success: error.is_none(),
+        result: serde_json::to_value(ours).ok(),
+        error: error.map(String::from),
+    }
+}
+
+/// The broker's hello to a Main App.
+pub(crate) fn hello_message() -> Vec<u8> {
+    serde_json::to_vec(&Handshake::new(broker_hello()).hello(HELLO_TASK_ID)).unwrap_or_default()
+}
+
+/// Checks the Main App's `hello_ack` and returns its capabilities, or the
+/// error, with the `bridge_error` for the extension, if the Main App can't be
+/// talked to.
+pub(crate) fn check_hello_ack(message: &Value) -> Result<Vec<String>, ProtocolError> {
+    let mut handshake = Handshake::new(broker_hello());
+    let error = match handshake.on_hello_ack(message) {
+        Verdict::Agreed(theirs) => {
+            log::info!("Handshake: Main App {} (protocol {}, capabilities {:?}).",
+                     theirs.software, theirs.protocol_version, theirs.capabilities);
+            return Ok(theirs.capabilities);
+        }
+        Verdict::Rejected(theirs, reason) => {
+            version_error(HELLO_TASK_ID, &format!("Main App rejected the broker: {}", reason), handshake.ours(), &theirs)
+        }
+        Verdict::Incompatible(theirs, mismatch) => version_error(HELLO_TASK_ID, &format!("Main App speaks {}", mismatch), handshake.ours(), &theirs),
+        Verdict::Malformed => {
+            log::warn!("Handshake: Ignoring hello_ack without a valid result.");
+            return Ok(Vec::new());
+        }
+    };
+    Err(refused(error))
+}
+
+fn version_error(task_id: &str, message: &str, ours: &Hello, theirs: &Hello) -> ExtensionResponse {
+    ExtensionResponse::bridge_error(task_id, E_PROTOCOL_VERSION, message).with_details(serde_json::json!({
+        "expected": ours.protocol_version,
+        "received": theirs.protocol_version,
+        "peer": theirs.software,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn acknowledges_a_compatible_extension_and_rejects_another_major() {
+        let hello = |version: &str| json!({
+            "action": "hello",
+            "task_id": "h1",
+            "data": { "protocol_version": version, "software": "extension 2.3", "capabilities": ["regex"] },
+        });
+        let ack: Value = serde_json::from_slice(&answer_hello(&hello("1.4")).unwrap()).unwrap();
+        assert_eq!((ack["action"].as_str(), ack["success"].as_bool()), (Some("hello_ack"), Some(true)));
+
+        let refused = answer_hello(&hello("2.0")).unwrap_err();
+        assert_eq!(refused.code(), E_PROTOCOL_VERSION);
+        let error = serde_json::to_value(refused.to_response("h1")).unwrap();
+        assert_eq!(error["action"], "bridge_error");
+        assert_eq!(error["task_id"], "h1");
+        assert_eq!(error["result"]["code"], "E_PROTOCOL_VERSION");
+        assert_eq!(error["result"]["received"], "2.0");
+    }
+}
@@ -0,0 +1,82 @@
+//! Broker side of the IPC heartbeat (see [`shared_types::heartbeat`]).
+//!
+//! While a Main App connection is up, [`monitor`] queues a `ping` for it every
+//! interval. Any frame the Main App sends afterwards counts as an answer; if
+//! none arrives in time the session ends, which takes the reconnect path when
+//! a [`ReconnectPolicy`](crate::ReconnectPolicy) is set.
+
+use tokio::sync::{mpsc, watch};
+
+use shared_types::{Action, Envelope, Heartbeat, Message};
+
+use crate::metrics;
+use crate::relay::Queued;
+
+/// Pings the Main App through `host_tx` and returns once a ping goes
+/// unanswered. `seen` changes whenever a frame arrives from the Main App.
+pub(crate) async fn monitor(heartbeat: Heartbeat, host_tx: mpsc::Sender<Queued>, mut seen: watch::Receiver<()>, peer: usize) {
+    let mut seq: u64 = 0;
+    loop {
+        tokio::time::sleep(heartbeat.interval).await;
+        seq += 1;
+        // A ping that can't be written in time is pointless afterwards
+        let ping = Message {
+            action: Action::Ping,
+            task_id: Heartbeat::task_id(seq),
+            task: None,
+            data: None,
+            ttl_ms: Some(heartbeat.timeout.as_millis() as u64),
+            msg_id: None,
+        };
+        let Ok(bytes) = serde_json::to_vec(&ping) else { continue };
+        seen.borrow_and_update();
+        if host_tx.send(Queued::new(bytes)).await.is_err() {
+            // The session is ending anyway
+            return std::future::pending().await;
+        }
+        match tokio::time::timeout(heartbeat.timeout, seen.changed()).await {
+            Ok(Ok(())) => {}
+            // The reader is gone, which ends the session on its own
+            Ok(Err(_)) => return std::future::pending().await,
+            Err(_) => {
+                log::warn!("Heartbeat: Main App {} sent nothing within {:?} of a ping, dropping the connection.",
+                           peer, heartbeat.timeout);
+                metrics::record_heartbeat_timeout();
+                return;
+            }
+        }
+    }
+}
+
+/// Whether `message` is the Main App's `pong` to a heartbeat, which the
+/// extension has no use for.
+pub(crate) fn is_reply(message: &Envelope) -> bool {
+    message.action.as_deref() == Some("pong") && message.task_id.as_deref().is_some_and(Heartbeat::is_heartbeat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn gives_up_on_a_silent_main_app() {
+        let (host_tx, mut host_rx) = mpsc::channel(4);
+        let (seen_tx, seen_rx) = watch::channel(());
+        let heartbeat = Heartbeat { interval: Duration::from_millis(5), timeout: Duration::from_millis(50) };
+        let monitor = tokio::spawn(monitor(heartbeat, host_tx, seen_rx, 0));
+
+        // The Main App answers two pings, then hangs
+        let mut pings = Vec::new();
+        while let Some(queued) = host_rx.recv().await {
+            let ping: serde_json::Value = serde_json::from_slice(&queued.bytes).unwrap();
+            assert!(queued.expires_at.is_some());
+            pings.push(ping["task_id"].as_str().unwrap().to_string());
+            if pings.len() <= 2 {
+                seen_tx.send_replace(());
+            }
+        }
+        monitor.await.unwrap();
+        assert_eq!(pings, ["broker-heartbeat-1", "broker-heartbeat-2", "broker-heartbeat-3"]);
+    }
+}
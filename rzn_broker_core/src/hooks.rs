@@ -0,0 +1,64 @@
+//! Per-message hook points in the relay.
+//!
+//! Hooks see the raw message bytes before they are forwarded and can rewrite
+//! them (e.g. inject machine metadata) or veto them entirely.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+/// What a hook decided to do with a message.
+#[derive(Debug)]
+pub enum HookAction {
+    /// Forward these bytes (the original or a transformed message).
+    Forward(Vec<u8>),
+    /// Drop the message. The reason is logged.
+    Veto(String),
+}
+
+/// Hook invoked by the relay for every message, in both directions.
+/// Both methods default to forwarding the message unchanged.
+pub trait RelayHook: Send + Sync {
+    /// Called for every message read from the extension, before it is sent to the Main App.
+    fn before_forward_to_host(&self, message: Vec<u8>) -> HookAction {
+        HookAction::Forward(message)
+    }
+
+    /// Called for every message read from the Main App, before it is sent to the extension.
+    fn before_forward_to_extension(&self, message: Vec<u8>) -> HookAction {
+        HookAction::Forward(message)
+    }
+}
+
+/// Ordered list of hooks shared by the relay tasks.
+pub type Hooks = Arc<Vec<Arc<dyn RelayHook>>>;
+
+/// Runs `message` through each hook in order.
+/// Returns `None` if any hook vetoed it.
+pub(crate) fn apply_hooks(
+    hooks: &[Arc<dyn RelayHook>],
+    message: Bytes,
+    to_host: bool,
+    log_prefix: &str,
+) -> Option<Bytes> {
+    // Hooks own what they get, so only hand over a copy when there are any
+    if hooks.is_empty() {
+        return Some(message);
+    }
+    let mut message = Vec::from(message);
+    for hook in hooks {
+        let action = if to_host {
+            hook.before_forward_to_host(message)
+        } else {
+            hook.before_forward_to_extension(message)
+        };
+        match action {
+            HookAction::Forward(bytes) => message = bytes,
+            HookAction::Veto(reason) => {
+                log::info!("{}: Message vetoed by hook: {}", log_prefix, reason);
+                return None;
+            }
+        }
+    }
+    Some(message.into())
+}
@@ -0,0 +1,103 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use interprocess::local_socket::{
+    tokio::{prelude::*, Stream}, // Use Stream directly and prelude for traits
+    Name,
+};
+
+use shared_types::{EndpointSpec, Profile};
+use tokio::process::Child;
+
+use crate::launch::{launch_and_connect, LaunchConfig};
+
+/// Resolves the Main App's IPC endpoint name for the current user and profile
+/// (`RZN_PROFILE`). A namespaced name is preferred; the platform-specific
+/// filesystem path is the fallback.
+pub fn get_ipc_endpoint_name() -> io::Result<Name<'static>> {
+    Profile::current()?.endpoint().to_name()
+}
+
+/// Directory holding the IPC socket file, or `None` when no socket file is
+/// created (namespaced endpoints and Windows named pipes).
+pub fn socket_directory() -> Option<PathBuf> {
+    Profile::current()
+        .ok()?
+        .endpoint()
+        .socket_file()
+        .and_then(|path| path.parent())
+        .map(PathBuf::from)
+}
+
+/// Connects to the Main App at `endpoint`, or at the current profile's
+/// endpoint when `None`, logging the outcome. If it isn't listening and
+/// `launch` is set, the Main App is started; the child is returned so the
+/// caller can keep a non-detached Main App alive. Without `launch`, `retry`
+/// chooses between [`connect_to_main_app`] and a single attempt.
+pub(crate) async fn connect_endpoint(
+    endpoint: Option<&EndpointSpec>,
+    launch: Option<&LaunchConfig>,
+    retry: bool,
+) -> io::Result<(Stream, Option<Child>)> {
+    let ipc_endpoint = match endpoint {
+        Some(spec) => spec.to_name()?,
+        None => get_ipc_endpoint_name()?,
+    };
+    log::info!("Attempting to connect to Main App via IPC: {:?}", ipc_endpoint);
+
+    let result = match launch {
+        // One attempt first, so an already running Main App isn't launched twice
+        Some(config) => match Stream::connect(ipc_endpoint.clone()).await {
+            Ok(stream) => Ok((stream, None)),
+            Err(e) => {
+                log::info!("Main App is not listening ({}), launching it.", e);
+                launch_and_connect(config, &ipc_endpoint).await.map(|(stream, child)| (stream, Some(child)))
+            }
+        },
+        None if retry => connect_to_main_app(&ipc_endpoint).await.map(|stream| (stream, None)),
+        None => Stream::connect(ipc_endpoint).await.map(|stream| (stream, None)),
+    };
+    match result {
+        Ok(connection) => {
+            log::info!("Successfully connected to Main App via IPC.");
+            Ok(connection)
+        }
+        // Single attempts are retried by the caller
+        Err(e) if !retry => Err(e),
+        Err(e) => {
+            log::error!("Failed to connect to Main App: {}", e);
+            log::error!("Broker exiting because Main App connection failed.");
+            Err(e)
+        }
+    }
+}
+
+/// Attempts to connect to the Main Application's IPC endpoint using Stream::connect with retries.
+pub async fn connect_to_main_app(
+    endpoint: &Name<'_>,
+) -> io::Result<Stream> {
+    let mut attempts = 0;
+    let max_attempts = 5;
+    let retry_delay = Duration::from_secs(1);
+
+    loop {
+        match Stream::connect(endpoint.clone()).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                attempts += 1;
+                log::warn!(
+                    "IPC connection attempt {}/{} failed: {}. Retrying in {:?}...",
+                    attempts,
+                    max_attempts,
+                    e,
+                    retry_delay
+                );
+                if attempts >= max_attempts {
+                    log::error!("Max IPC connection attempts reached.");
+                    return Err(e);
+                }
+                tokio::time::sleep(retry_delay).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+//! Starting the Main App when the browser starts the broker first.
+//!
+//! Without a launch configuration the broker retries the connection a few
+//! times and exits. With one, a failed first attempt spawns the Main App and
+//! the broker waits for its endpoint to accept connections.
+
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use interprocess::local_socket::{tokio::prelude::*, tokio::Stream, Name};
+use tokio::process::{Child, Command};
+
+// Delay between connection attempts while the launched Main App starts up
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How to start the Main App.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LaunchConfig {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    /// Let the Main App outlive the broker (and the browser). Otherwise it is
+    /// killed when the broker exits.
+    pub detach: bool,
+    /// How long to wait for the endpoint to accept connections.
+    pub wait: Duration,
+}
+
+impl LaunchConfig {
+    /// Launches `program` without arguments, detached, waiting up to 10 seconds.
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        LaunchConfig { program: program.into(), args: Vec::new(), detach: true, wait: Duration::from_secs(10) }
+    }
+
+    /// Reads `RZN_MAIN_APP` (program), `RZN_MAIN_APP_ARGS` (whitespace-separated),
+    /// `RZN_MAIN_APP_DETACH` (`0` to tie the Main App to the broker) and
+    /// `RZN_MAIN_APP_WAIT_MS`. `None` if `RZN_MAIN_APP` is unset.
+    pub fn from_env() -> Option<Self> {
+        let program = std::env::var_os("RZN_MAIN_APP").filter(|p| !p.is_empty())?;
+        let mut config = LaunchConfig::new(program);
+        if let Ok(args) = std::env::var("RZN_MAIN_APP_ARGS") {
+            config.args = args.split_whitespace().map(String::from).collect();
+        }
+        if let Ok(detach) = std::env::var("RZN_MAIN_APP_DETACH") {
+            config.detach = detach != "0";
+        }
+        if let Ok(wait) = std::env::var("RZN_MAIN_APP_WAIT_MS") {
+            match wait.trim().parse() {
+                Ok(ms) => config.wait = Duration::from_millis(ms),
+                Err(_) => log::warn!("Ignoring invalid RZN_MAIN_APP_WAIT_MS={:?}", wait),
+            }
+        }
+        Some(config)
+    }
+
+    /// Spawns the Main App. Its stdin and stdout must not be the broker's,
+    /// which carry the native messaging stream; a tied Main App logs to the
+    /// broker's stderr.
+    fn spawn(&self) -> io::Result<Child> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(if self.detach { Stdio::null() } else { Stdio::inherit() })
+            .kill_on_drop(!self.detach);
+        if self.detach {
+            // Keep it out of the broker's process group, which the browser kills
+            #[cfg(unix)]
+            command.process_group(0);
+            // DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP
+            #[cfg(windows)]
+            command.creation_flags(0x0000_0008 | 0x0000_0200);
+        }
+        command.spawn()
+    }
+}
+
+/// Launches the Main App and connects once its endpoint is up. The child is
+/// returned so a non-detached Main App lives as long as the caller holds it.
+pub(crate) async fn launch_and_connect(config: &LaunchConfig, endpoint: &Name<'_>) -> io::Result<(Stream, Child)> {
+    log::info!("Launch: Starting Main App {} {:?}", config.program.display(), config.args);
+    let mut child = config.spawn().inspect_err(|e| {
+        log::error!("Launch: Failed to start {}: {}", config.program.display(), e);
+    })?;
+    let deadline = Instant::now() + config.wait;
+    loop {
+        match Stream::connect(endpoint.clone()).await {
+            Ok(stream) => {
+                log::info!("Launch: Main App is up (pid {:?}).", child.id());
+                return Ok((stream, child));
+            }
+            Err(e) => {
+                // A clean exit may be a launcher handing off to the real app, so keep waiting
+                if let Some(status) = child.try_wait()?.filter(|status| !status.success()) {
+                    log::error!("Launch: Main App exited with {} before accepting connections.", status);
+                    return Err(io::Error::other(format!("Main App exited with {}", status)));
+                }
+                if Instant::now() >= deadline {
+                    log::error!("Launch: Main App did not accept connections within {:?}.", config.wait);
+                    return Err(e);
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
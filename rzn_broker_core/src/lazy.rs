@@ -0,0 +1,105 @@
+//! Lazy ("dormant") mode: the broker starts without connecting to the Main App
+//! and only connects once the extension sends a message that needs it.
+//!
+//! Users who rarely trigger automation then don't keep a Main App connection
+//! (or a Main App) around just because the browser started the broker. The
+//! extension is told about the state through `bridge_state` messages.
+
+use std::io::{self, Cursor};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+
+use shared_types::frame::{read_message_bytes, write_message_bytes};
+use shared_types::{Action, ExtensionResponse, CONFIGURE_REQUEST_ACTION, LOG_ACTION};
+
+use crate::broker::Broker;
+use crate::handshake::{answer_hello, is_hello};
+use crate::hooks::Hooks;
+
+/// Action of the broker's connection state notifications to the extension.
+pub const BRIDGE_STATE_ACTION: &str = "bridge_state";
+
+// Messages that don't need the Main App right away; they are held until it is connected
+const PASSIVE_ACTIONS: &[&str] = &[LOG_ACTION, CONFIGURE_REQUEST_ACTION];
+// Oldest held messages are dropped beyond this
+const MAX_HELD_MESSAGES: usize = 100;
+
+/// Same as [`run_stdio_with_hooks`](crate::run_stdio_with_hooks), but stays
+/// dormant until the extension sends something other than log records or
+/// configuration requests. Messages received while dormant are relayed, in
+/// order, once the connection is up.
+pub async fn run_stdio_lazy(hooks: Hooks) -> io::Result<()> {
+    Broker::builder().hooks(hooks).lazy(true).build().run_stdio().await
+}
+
+/// Lazy [`Broker::serve`].
+pub(crate) async fn serve_lazy<NR, NW>(broker: &Broker, mut native_reader: NR, mut native_writer: NW) -> io::Result<()>
+where
+    NR: AsyncRead + Unpin + Send + 'static,
+    NW: AsyncWrite + Unpin + Send + 'static,
+{
+    send_state(&mut native_writer, "dormant").await?;
+    log::info!("Lazy: Dormant until the extension sends a message for the Main App.");
+
+    let mut held: Vec<Vec<u8>> = Vec::new();
+    loop {
+        let Some(message_bytes) = read_message_bytes(&mut native_reader, "Lazy").await? else {
+            log::info!("Lazy: Extension disconnected while dormant.");
+            broker.notifier().extension_disconnected();
+            return Ok(());
+        };
+        let parsed = serde_json::from_slice::<serde_json::Value>(&message_bytes).ok();
+        // The handshake doesn't need the Main App
+        if let Some(value) = parsed.as_ref().filter(|v| is_hello(v)) {
+            match answer_hello(value) {
+                Ok(ack) => write_message_bytes(&mut native_writer, &ack, "Lazy").await?,
+                Err(e) => {
+                    let error = serde_json::to_vec(&e.to_response("hello")).map_err(io::Error::other)?;
+                    return write_message_bytes(&mut native_writer, &error, "Lazy").await;
+                }
+            }
+            continue;
+        }
+        let action = parsed.and_then(|v| v.get("action").and_then(|a| a.as_str()).map(String::from));
+        let wakes = !action.as_deref().is_some_and(|a| PASSIVE_ACTIONS.contains(&a));
+        if held.len() == MAX_HELD_MESSAGES {
+            log::warn!("Lazy: Too many messages while dormant, dropping the oldest.");
+            held.remove(0);
+        }
+        held.push(message_bytes);
+        if wakes {
+            log::info!("Lazy: Waking up for action {}.", action.as_deref().unwrap_or("N/A"));
+            break;
+        }
+    }
+
+    let connection = broker.connect().await?;
+    send_state(&mut native_writer, "active").await?;
+
+    // Replay the held messages ahead of the rest of stdin
+    let mut replay = Vec::new();
+    for message_bytes in &held {
+        write_message_bytes(&mut replay, message_bytes, "Lazy").await?;
+    }
+    let native_reader = Cursor::new(replay).chain(native_reader);
+
+    broker.relay_connected(native_reader, native_writer, connection).await;
+    Ok(())
+}
+
+/// Tells the extension the broker's connection state.
+async fn send_state(writer: &mut (impl AsyncWrite + Unpin), state: &str) -> io::Result<()> {
+    write_message_bytes(writer, &state_message(state)?, "Lazy").await
+}
+
+/// A `bridge_state` message: "dormant", "active" or "reconnecting".
+pub(crate) fn state_message(state: &str) -> io::Result<Vec<u8>> {
+    let message = ExtensionResponse {
+        action: Action::BridgeState,
+        task_id: "broker".to_string(),
+        success: true,
+        result: Some(serde_json::json!({ "state": state })),
+        error: None,
+    };
+    serde_json::to_vec(&message).map_err(io::Error::other)
+}
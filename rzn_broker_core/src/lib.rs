@@ -0,0 +1,56 @@
+//! Relay engine of the Rzn:Browser Bridge broker.
+//!
+//! The `rzn_broker` binary is a thin wrapper around [`Broker`]. Products that
+//! ship their own native messaging host can embed the relay directly: configure
+//! the endpoint, message size and JSON limits and hooks with [`Broker::builder`],
+//! then run it on stdin/stdout or hand it their own streams. Messages can be
+//! transformed or vetoed on the way through by registering a [`RelayHook`], and
+//! a [`Notifier`] hears about failed tasks, approval requests, disconnects and
+//! the state of each Main App connection ([`connection_states`]).
+//! [`run_stdio`], [`run_stdio_lazy`] and [`relay`] are shorthands for the
+//! default configuration. A [`LaunchConfig`] lets the broker start the Main App
+//! when it isn't running, and a [`ReconnectPolicy`] keeps the extension
+//! connected while the Main App restarts, spilling its messages to disk with a
+//! [`SpillConfig`]. On SIGTERM/SIGINT the broker drains
+//! its queues and tells both sides it is shutting down before exiting.
+
+mod broker;
+mod budget;
+mod connections;
+mod deadline;
+mod delivery;
+mod error;
+mod handshake;
+mod heartbeat;
+mod hooks;
+mod ipc;
+mod launch;
+mod lazy;
+mod lifecycle;
+mod metrics;
+mod notifier;
+mod pause;
+mod peers;
+mod reconnect;
+mod relay;
+mod selftest;
+mod shutdown;
+mod spill;
+mod stats;
+mod validate;
+
+pub use broker::{Broker, BrokerBuilder};
+pub use connections::connection_states;
+pub use error::{BrokerError, Peer, ProtocolError};
+pub use handshake::{broker_hello, BROKER_CAPABILITIES};
+pub use hooks::{HookAction, Hooks, RelayHook};
+pub use ipc::{connect_to_main_app, get_ipc_endpoint_name, socket_directory};
+pub use launch::LaunchConfig;
+pub use lazy::{run_stdio_lazy, BRIDGE_STATE_ACTION};
+pub use metrics::{metrics, RelayMetrics};
+pub use notifier::{NoopNotifier, Notifier};
+pub use reconnect::ReconnectPolicy;
+pub use relay::{relay, run_stdio, run_stdio_with_hooks};
+pub use selftest::{SELFTEST_ACTION, SELFTEST_RESULT_ACTION};
+pub use spill::SpillConfig;
+pub use stats::{origin_stats, tagged_origin_stats, task_history};
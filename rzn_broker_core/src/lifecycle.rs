@@ -0,0 +1,126 @@
+//! Broker side of the lifecycle notifications (see [`shared_types::lifecycle`]).
+//!
+//! The relay moves through the [`BrokerState`]s of the primary Main App
+//! connection. Every allowed change is sent to the extension; the Main App
+//! only hears about `draining` and `shutting_down`.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use shared_types::{Action, BrokerState, BrokerStateChange, ExtensionResponse};
+
+use crate::relay::Queued;
+
+/// Longest wait for a notification to be written before the relay moves on,
+/// e.g. when the broker is about to exit.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The current state of a relay.
+#[derive(Default)]
+pub(crate) struct Lifecycle(Mutex<Option<BrokerState>>);
+
+impl Lifecycle {
+    /// Moves to `next`. `None` if the relay is already there or can't go
+    /// there from where it is, e.g. a reconnect finishing while draining.
+    pub(crate) fn advance(&self, next: BrokerState) -> Option<BrokerStateChange> {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let previous = *state;
+        if previous.is_some_and(|previous| !previous.can_become(next)) {
+            log::debug!("Lifecycle: Staying {:?} instead of becoming {:?}.", previous, next);
+            return None;
+        }
+        log::info!("Lifecycle: {:?} -> {:?}", previous, next);
+        *state = Some(next);
+        Some(BrokerStateChange { state: next, previous })
+    }
+
+    /// Moves to `next` and notifies the extension through `native_tx`, and
+    /// the Main App through `host_tx` once the broker is going away. Waits
+    /// until the extension's notification is written, for at most
+    /// [`NOTIFY_TIMEOUT`].
+    pub(crate) async fn enter(&self, next: BrokerState, native_tx: &mpsc::Sender<Queued>, host_tx: &mpsc::Sender<Queued>) {
+        let Some(change) = self.advance(next) else { return };
+        let message = ExtensionResponse {
+            action: Action::BrokerState,
+            task_id: "broker".to_string(),
+            success: true,
+            result: serde_json::to_value(change).ok(),
+            error: None,
+        };
+        let bytes = match serde_json::to_vec(&message) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::error!("Lifecycle: Failed to serialize broker state: {}", e);
+                return;
+            }
+        };
+        // The Main App can tell the rest from its own connection. Not waited
+        // for, as it may be the Main App that is stuck
+        if matches!(next, BrokerState::Draining | BrokerState::ShuttingDown) && host_tx.try_send(bytes.clone().into()).is_err() {
+            log::warn!("Lifecycle: Could not queue {:?} for the Main App.", next);
+        }
+        let (queued, written) = Queued::with_receipt(bytes);
+        if native_tx.send(queued).await.is_ok() {
+            let _ = tokio::time::timeout(NOTIFY_TIMEOUT, written).await;
+        }
+    }
+}
+
+/// Next message from the broker to the extension, skipping lifecycle
+/// notifications.
+#[cfg(test)]
+pub(crate) async fn next_message(reader: &mut (impl tokio::io::AsyncRead + Unpin)) -> Vec<u8> {
+    loop {
+        let bytes = shared_types::frame::read_message_bytes(reader, "test").await.unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        if Action::of(&value) != Some(Action::BrokerState) {
+            return bytes;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BrokerState::*;
+
+    #[test]
+    fn ignores_transitions_that_are_not_allowed() {
+        let lifecycle = Lifecycle::default();
+        assert_eq!(lifecycle.advance(ExtensionConnected), Some(BrokerStateChange { state: ExtensionConnected, previous: None }));
+        assert!(lifecycle.advance(IpcConnected).is_some());
+        assert!(lifecycle.advance(IpcConnected).is_none());
+        assert!(lifecycle.advance(Draining).is_some());
+        assert!(lifecycle.advance(IpcConnected).is_none());
+        assert_eq!(lifecycle.advance(ShuttingDown), Some(BrokerStateChange { state: ShuttingDown, previous: Some(Draining) }));
+    }
+
+    #[tokio::test]
+    async fn tells_the_extension_when_the_main_app_goes_away() {
+        use crate::relay::{relay_with, RelayConfig};
+        use crate::Hooks;
+        use tokio::io::{duplex, split};
+
+        let (extension, native) = duplex(4096);
+        let (host, ipc) = duplex(4096);
+        let mut host = Some(host);
+        let (native_reader, native_writer) = split(native);
+        let (ipc_reader, ipc_writer) = split(ipc);
+        let relay = tokio::spawn(relay_with(RelayConfig::new(Hooks::default()), native_reader, native_writer, ipc_reader, ipc_writer));
+
+        let (mut extension_reader, _extension_writer) = split(extension);
+        let mut states = Vec::new();
+        for n in 0..3 {
+            if n == 2 {
+                drop(host.take());
+            }
+            let bytes = shared_types::frame::read_message_bytes(&mut extension_reader, "test").await.unwrap().unwrap();
+            let message: ExtensionResponse = serde_json::from_slice(&bytes).unwrap();
+            states.push(serde_json::from_value::<BrokerStateChange>(message.result.unwrap()).unwrap().state);
+        }
+        assert_eq!(states, [ExtensionConnected, IpcConnected, IpcLost]);
+        relay.await.unwrap();
+    }
+}
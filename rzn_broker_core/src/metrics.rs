@@ -0,0 +1,113 @@
+//! Process-wide relay counters.
+//!
+//! A broker process runs a single relay, so the counters are global and can be
+//! read from anywhere (e.g. the interactive `:doctor` command).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static EXPIRED_TO_HOST: AtomicU64 = AtomicU64::new(0);
+static EXPIRED_TO_EXTENSION: AtomicU64 = AtomicU64::new(0);
+static REJECTED_BY_JSON_LIMITS: AtomicU64 = AtomicU64::new(0);
+static TRUNCATED_RESULTS: AtomicU64 = AtomicU64::new(0);
+static DROPPED_WHILE_DISCONNECTED: AtomicU64 = AtomicU64::new(0);
+static HEARTBEAT_TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+static TOO_LARGE: AtomicU64 = AtomicU64::new(0);
+static HANDSHAKE_REFUSALS: AtomicU64 = AtomicU64::new(0);
+static TASKS_TIMED_OUT: AtomicU64 = AtomicU64::new(0);
+static DUPLICATES_DROPPED: AtomicU64 = AtomicU64::new(0);
+static DEAD_LETTERS: AtomicU64 = AtomicU64::new(0);
+
+/// Snapshot of the relay counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayMetrics {
+    /// Messages from the extension dropped because their TTL ran out while queued.
+    pub expired_to_host: u64,
+    /// Messages from the Main App dropped because their TTL ran out while queued.
+    pub expired_to_extension: u64,
+    /// Messages (either direction) dropped for exceeding the JSON depth/size limits.
+    pub rejected_by_json_limits: u64,
+    /// Task results the broker had to cut down to the task's `max_result_bytes`.
+    pub truncated_results: u64,
+    /// Messages from the extension dropped because the reconnect buffer was full.
+    pub dropped_while_disconnected: u64,
+    /// Main App connections dropped because a heartbeat went unanswered.
+    pub heartbeat_timeouts: u64,
+    /// Messages (either direction) skipped for exceeding the message size limit.
+    pub too_large: u64,
+    /// Main App connections closed because the handshake failed.
+    pub handshake_refusals: u64,
+    /// Tasks the broker failed and cancelled for running past their `timeout_ms`.
+    pub tasks_timed_out: u64,
+    /// Messages from the Main App not relayed as their `msg_id` was seen before.
+    pub duplicates_dropped: u64,
+    /// Spilled messages from the extension that expired before the Main App was back.
+    pub dead_letters: u64,
+}
+
+/// Returns the current counter values.
+pub fn metrics() -> RelayMetrics {
+    RelayMetrics {
+        expired_to_host: EXPIRED_TO_HOST.load(Ordering::Relaxed),
+        expired_to_extension: EXPIRED_TO_EXTENSION.load(Ordering::Relaxed),
+        rejected_by_json_limits: REJECTED_BY_JSON_LIMITS.load(Ordering::Relaxed),
+        truncated_results: TRUNCATED_RESULTS.load(Ordering::Relaxed),
+        dropped_while_disconnected: DROPPED_WHILE_DISCONNECTED.load(Ordering::Relaxed),
+        heartbeat_timeouts: HEARTBEAT_TIMEOUTS.load(Ordering::Relaxed),
+        too_large: TOO_LARGE.load(Ordering::Relaxed),
+        handshake_refusals: HANDSHAKE_REFUSALS.load(Ordering::Relaxed),
+        tasks_timed_out: TASKS_TIMED_OUT.load(Ordering::Relaxed),
+        duplicates_dropped: DUPLICATES_DROPPED.load(Ordering::Relaxed),
+        dead_letters: DEAD_LETTERS.load(Ordering::Relaxed),
+    }
+}
+
+/// Counts a message dropped because its TTL expired.
+pub(crate) fn record_expired(to_host: bool) {
+    let counter = if to_host { &EXPIRED_TO_HOST } else { &EXPIRED_TO_EXTENSION };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a message rejected by the JSON limits.
+pub(crate) fn record_json_rejected() {
+    REJECTED_BY_JSON_LIMITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a task result truncated by the broker.
+pub(crate) fn record_result_truncated() {
+    TRUNCATED_RESULTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a message dropped while waiting for the Main App to come back.
+pub(crate) fn record_dropped_while_disconnected() {
+    DROPPED_WHILE_DISCONNECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a Main App connection given up on for missing a heartbeat.
+pub(crate) fn record_heartbeat_timeout() {
+    HEARTBEAT_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a message skipped for its size.
+pub(crate) fn record_too_large() {
+    TOO_LARGE.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a Main App connection closed for a failed handshake.
+pub(crate) fn record_handshake_refused() {
+    HANDSHAKE_REFUSALS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a task timed out by the broker.
+pub(crate) fn record_task_timed_out() {
+    TASKS_TIMED_OUT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a message from the Main App dropped as a duplicate.
+pub(crate) fn record_duplicate() {
+    DUPLICATES_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a spilled message that expired.
+pub(crate) fn record_dead_letter() {
+    DEAD_LETTERS.fetch_add(1, Ordering::Relaxed);
+}
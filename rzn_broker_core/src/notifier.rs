@@ -0,0 +1,53 @@
+//! Notifications for events a user may want to hear about.
+//!
+//! Embedders that show their own notification UI register a [`Notifier`]
+//! instead of inspecting every relayed message through a hook.
+
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use shared_types::{Action, CommitRequest, ConnectionStateChange, SelectorDegradation};
+
+/// Receives key relay events. Every method defaults to doing nothing.
+///
+/// Methods are called from the relay tasks, so they should hand slow work
+/// (e.g. showing a desktop notification) off instead of blocking.
+pub trait Notifier: Send + Sync {
+    /// A task finished with `success: false`.
+    fn task_failed(&self, _task_id: &str, _error: Option<&str>) {}
+
+    /// The extension paused a destructive step and waits for the host to
+    /// commit or abort it.
+    fn approval_requested(&self, _task_id: &str, _request: &CommitRequest) {}
+
+    /// A selector that kept working on a site failed in task `task_id`.
+    fn selector_degraded(&self, _task_id: &str, _degradation: &SelectorDegradation) {}
+
+    /// The extension closed the native messaging connection.
+    fn extension_disconnected(&self) {}
+
+    /// The connection to Main App `peer` (0 for the primary) changed state.
+    fn connection_state_changed(&self, _peer: usize, _change: &ConnectionStateChange) {}
+}
+
+/// The default notifier, ignoring every event.
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {}
+
+/// Calls `notifier` for a message read from the extension, if it is one of
+/// the events it receives.
+pub(crate) fn notify_from_extension(notifier: &Arc<dyn Notifier>, message: &Value) {
+    let task_id = message.get("task_id").and_then(|v| v.as_str()).unwrap_or("N/A");
+    match Action::of(message) {
+        Some(Action::TaskResult) if message.get("success").and_then(|v| v.as_bool()) == Some(false) => {
+            notifier.task_failed(task_id, message.get("error").and_then(|v| v.as_str()));
+        }
+        Some(Action::CommitRequest) => match message.get("data").cloned().map(serde_json::from_value::<CommitRequest>) {
+            Some(Ok(request)) => notifier.approval_requested(task_id, &request),
+            _ => log::warn!("Notifier: Ignoring commit_request without a valid step for task {}.", task_id),
+        },
+        _ => {}
+    }
+}
@@ -0,0 +1,133 @@
+//! Pause-all / resume-all switch.
+//!
+//! A Main App sends `pause_all` to stop all automation at once. The broker
+//! relays it to the extension, which stops before its next step, and holds new
+//! tasks from every Main App connection until a `resume_all`, after which they
+//! are relayed in the order they arrived. A held task that is cancelled is
+//! answered by the broker, as the extension never saw it.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use serde_json::Value;
+
+use shared_types::{peek_envelope, Action, ExtensionResponse, E_PAUSED};
+
+use crate::relay::Queued;
+
+// Further tasks are failed instead of held
+const MAX_HELD_TASKS: usize = 100;
+
+/// What to do with a message from a Main App.
+pub(crate) enum Admission {
+    /// Relay it now.
+    Forward(Queued),
+    /// Held until `resume_all`.
+    Held,
+    /// Not held; the Main App gets this failed `task_result` instead.
+    Rejected(ExtensionResponse),
+    /// A `cancel_task` for a held task, which is dropped. The Main App gets
+    /// these answers: the `task_cancelled` and the task's failed `task_result`.
+    Cancelled(Vec<ExtensionResponse>),
+}
+
+/// Whether automation is paused, and the tasks held meanwhile.
+#[derive(Default)]
+pub(crate) struct PauseSwitch(Mutex<PauseState>);
+
+#[derive(Default)]
+struct PauseState {
+    paused: bool,
+    held: VecDeque<Queued>,
+}
+
+impl PauseSwitch {
+    /// Applies `pause_all` and `resume_all` (which are relayed too) and holds
+    /// tasks while paused.
+    pub(crate) fn admit(&self, value: &Value, queued: Queued) -> Admission {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let task_id = value.get("task_id").and_then(|v| v.as_str()).unwrap_or("N/A");
+        match Action::of(value) {
+            Some(Action::PauseAll) => {
+                let reason = value.get("data").and_then(|d| d.get("reason")).and_then(|v| v.as_str());
+                log::warn!("Pause: Pausing all automation ({}).", reason.unwrap_or("no reason given"));
+                state.paused = true;
+            }
+            Some(Action::ResumeAll) if state.paused => {
+                log::info!("Pause: Resuming, {} task(s) were held.", state.held.len());
+                state.paused = false;
+            }
+            Some(Action::PerformTask) if state.paused && state.held.len() >= MAX_HELD_TASKS => {
+                log::warn!("Pause: Rejecting task {}, {} tasks are already held.", task_id, MAX_HELD_TASKS);
+                return Admission::Rejected(ExtensionResponse {
+                    action: Action::TaskResult,
+                    task_id: task_id.to_string(),
+                    success: false,
+                    result: None,
+                    error: Some(format!("[{}] Automation is paused and {} tasks are already waiting", E_PAUSED, MAX_HELD_TASKS)),
+                });
+            }
+            Some(Action::PerformTask) if state.paused => {
+                log::info!("Pause: Holding task {} until resume_all.", task_id);
+                state.held.push_back(queued);
+                return Admission::Held;
+            }
+            Some(Action::CancelTask) => {
+                if let Some(index) = state.held.iter().position(|held| peek_envelope(&held.bytes).task_id.as_deref() == Some(task_id)) {
+                    state.held.remove(index);
+                    let reason = value.get("data").and_then(|d| d.get("reason")).and_then(|v| v.as_str());
+                    log::info!("Pause: Dropping held task {}, it was cancelled ({}).", task_id, reason.unwrap_or("no reason given"));
+                    let result = ExtensionResponse {
+                        action: Action::TaskResult,
+                        task_id: task_id.to_string(),
+                        success: false,
+                        result: None,
+                        error: Some(format!("Cancelled while paused: {}", reason.unwrap_or("no reason given"))),
+                    };
+                    return Admission::Cancelled(vec![ExtensionResponse::task_cancelled(task_id, true), result]);
+                }
+            }
+            _ => {}
+        }
+        Admission::Forward(queued)
+    }
+
+    /// Takes the held tasks once automation is no longer paused.
+    pub(crate) fn release(&self) -> Vec<Queued> {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if state.paused {
+            return Vec::new();
+        }
+        state.held.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn holds_tasks_until_resumed() {
+        let switch = PauseSwitch::default();
+        let admit = |value: Value| match switch.admit(&value, serde_json::to_vec(&value).unwrap().into()) {
+            Admission::Forward(_) => "forward",
+            Admission::Held => "held",
+            Admission::Rejected(_) => "rejected",
+            Admission::Cancelled(_) => "cancelled",
+        };
+        assert_eq!(admit(json!({"action": "pause_all", "task_id": "p"})), "forward");
+        assert_eq!(admit(json!({"action": "perform_task", "task_id": "t1"})), "held");
+        assert_eq!(admit(json!({"action": "perform_task", "task_id": "t0"})), "held");
+        assert_eq!(admit(json!({"action": "ping", "task_id": "x"})), "forward");
+        // The extension never saw a held task, so the broker answers its cancellation
+        assert_eq!(admit(json!({"action": "cancel_task", "task_id": "t0"})), "cancelled");
+        assert_eq!(admit(json!({"action": "cancel_task", "task_id": "t0"})), "forward");
+        assert!(switch.release().is_empty());
+
+        assert_eq!(admit(json!({"action": "resume_all", "task_id": "r"})), "forward");
+        let released: Vec<Value> = switch.release().iter().map(|q| serde_json::from_slice(&q.bytes).unwrap()).collect();
+        assert_eq!(released, [json!({"action": "perform_task", "task_id": "t1"})]);
+        assert_eq!(admit(json!({"action": "perform_task", "task_id": "t2"})), "forward");
+    }
+}
@@ -0,0 +1,159 @@
+//! Several Main App connections ("peers") at once.
+//!
+//! Each peer gets a connection ID, its index in the broker's peer list; the
+//! primary Main App is peer 0. Messages from a peer that carry a `task_id`
+//! record the peer as the task's origin, and the extension's messages for
+//! that task (commit requests, logs, the final `task_result`) are routed back
+//! to it. Everything else goes to the primary, except the broker's `shutdown`
+//! notice, which every peer gets.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+
+use shared_types::{SHUTDOWN_ACTION, TASK_RESULT_ACTION};
+
+use crate::reconnect::{run_sessions, Connection, ReconnectPolicy};
+use crate::relay::{ipc_session, IpcLinks};
+
+// Oldest routes are forgotten beyond this, for tasks that never report a result
+const MAX_ROUTES: usize = 4096;
+
+/// Which peer each task came from.
+#[derive(Default)]
+pub(crate) struct Routes(Mutex<RouteTable>);
+
+#[derive(Default)]
+struct RouteTable {
+    peers: HashMap<String, usize>,
+    order: VecDeque<String>,
+}
+
+/// The fields routing looks at.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow, default)]
+    action: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    task_id: Option<Cow<'a, str>>,
+}
+
+impl Routes {
+    /// Remembers that `task_id` came from `peer`.
+    pub(crate) fn record(&self, task_id: &str, peer: usize) {
+        let mut table = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if table.peers.insert(task_id.to_string(), peer).is_none() {
+            table.order.push_back(task_id.to_string());
+        }
+        while table.peers.len() > MAX_ROUTES {
+            let Some(oldest) = table.order.pop_front() else { break };
+            table.peers.remove(&oldest);
+        }
+    }
+
+    /// Peer a message from the extension belongs to, if its task came from
+    /// one. A `task_result` ends the task's route.
+    pub(crate) fn take(&self, message: &[u8]) -> Option<usize> {
+        let envelope: Envelope = serde_json::from_slice(message).ok()?;
+        let task_id = envelope.task_id?;
+        let mut table = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if envelope.action.as_deref() == Some(TASK_RESULT_ACTION) {
+            table.peers.remove(task_id.as_ref())
+        } else {
+            table.peers.get(task_id.as_ref()).copied()
+        }
+    }
+}
+
+/// Relays with every peer in `connections` (each an established connection,
+/// if any, and a way to open a new one) and routes the extension's messages
+/// between them. Returns once the extension side or every peer is gone, or
+/// the relay stops and every peer has written out what it was sent.
+pub(crate) async fn run_peers<S, C, Fut>(
+    policy: Option<ReconnectPolicy>,
+    connections: Vec<(Option<Connection<S>>, C)>,
+    mut links: IpcLinks,
+)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    C: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<Connection<S>>> + Send + 'static,
+{
+    let routes = Arc::new(Routes::default());
+    let mut senders = Vec::new();
+    let mut sessions = JoinSet::new();
+    for (peer, (connection, connect)) in connections.into_iter().enumerate() {
+        let (tx, mut peer_links) = links.for_peer(peer, &routes);
+        senders.push(tx);
+        match (policy.clone(), connection) {
+            (Some(policy), connection) => {
+                sessions.spawn(run_sessions(policy, connection, connect, peer_links));
+            }
+            (None, Some((stream, main_app))) => {
+                sessions.spawn(async move {
+                    let _main_app = main_app;
+                    let (ipc_reader, ipc_writer) = tokio::io::split(stream);
+                    ipc_session(ipc_reader, ipc_writer, &mut peer_links, &mut VecDeque::new()).await;
+                });
+            }
+            (None, None) => log::warn!("Peers: Connection {} is not up and reconnecting is off.", peer),
+        }
+    }
+
+    loop {
+        tokio::select! {
+            biased;
+            queued = links.rx.recv() => {
+                let Some(queued) = queued else { return };
+                // Every Main App hears that the broker is going away
+                if is_shutdown(&queued.bytes) {
+                    for (peer, tx) in senders.iter().enumerate().skip(1) {
+                        if tx.send(queued.bytes.clone().into()).await.is_err() {
+                            log::warn!("Peers: Connection {} is gone, not telling it about the shutdown.", peer);
+                        }
+                    }
+                }
+                let peer = routes.take(&queued.bytes).unwrap_or(0);
+                if senders[peer].send(queued).await.is_err() {
+                    log::warn!("Peers: Connection {} is gone, dropping a message for it.", peer);
+                }
+            }
+            finished = sessions.join_next() => {
+                if finished.is_none() || sessions.is_empty() {
+                    log::info!("Peers: Every Main App connection is gone.");
+                    return;
+                }
+            }
+            // Everything queued was routed above; each session drains its own queue
+            () = links.stop.stopping.cancelled() => break,
+        }
+    }
+    drop(senders);
+    while sessions.join_next().await.is_some() {}
+}
+
+/// Whether `message` is the broker's `shutdown` notice.
+fn is_shutdown(message: &[u8]) -> bool {
+    serde_json::from_slice::<Envelope>(message).is_ok_and(|envelope| envelope.action.as_deref() == Some(SHUTDOWN_ACTION))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_a_task_back_to_its_peer_until_the_result() {
+        let routes = Routes::default();
+        routes.record("t1", 2);
+        assert_eq!(routes.take(br#"{"action":"commit_request","task_id":"t1"}"#), Some(2));
+        assert_eq!(routes.take(br#"{"action":"task_result","task_id":"t1"}"#), Some(2));
+        assert_eq!(routes.take(br#"{"action":"log","task_id":"t1"}"#), None);
+        assert_eq!(routes.take(br#"{"action":"log"}"#), None);
+    }
+}
@@ -0,0 +1,358 @@
+//! Reconnecting to the Main App after it goes away.
+//!
+//! A restarted Main App used to end the relay and with it the broker, which
+//! closes the extension's native messaging port. With a [`ReconnectPolicy`] the
+//! broker keeps the extension connected, retries the Main App with exponential
+//! backoff and holds the extension's messages until the connection is back.
+//! The extension sees `bridge_state` "reconnecting" and then "active".
+//! With a [`SpillConfig`] the held messages go to disk (see [`crate::spill`]).
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::process::Child;
+
+use shared_types::{BrokerState, ConnectionState, ExtensionResponse};
+
+use crate::lazy::state_message;
+use crate::metrics;
+use crate::relay::{ipc_session, IpcLinks, Queued};
+use crate::spill::{Spill, SpillConfig};
+
+/// A Main App connection, with the Main App process if the broker launched it.
+pub(crate) type Connection<S> = (S, Option<Child>);
+
+/// How the broker reconnects to the Main App.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Wait before the first attempt; doubled after every failed attempt.
+    pub initial_delay: Duration,
+    /// Upper bound of the wait between attempts.
+    pub max_delay: Duration,
+    /// Messages from the extension held while disconnected. The oldest are
+    /// dropped beyond this.
+    pub max_buffered: usize,
+    /// Spill the primary Main App's held messages to disk instead, so that
+    /// they outlive the broker.
+    pub spill: Option<SpillConfig>,
+}
+
+impl Default for ReconnectPolicy {
+    /// 500 ms doubling up to 30 s, holding up to 100 messages in memory.
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_buffered: 100,
+            spill: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The default policy adjusted by `RZN_RECONNECT_MAX_DELAY_MS`,
+    /// `RZN_RECONNECT_BUFFER` and [`SpillConfig::from_env`]. `None` if
+    /// `RZN_RECONNECT=0`.
+    pub fn from_env() -> Option<Self> {
+        if std::env::var_os("RZN_RECONNECT").is_some_and(|v| v == "0") {
+            return None;
+        }
+        let mut policy = ReconnectPolicy::default();
+        if let Ok(delay) = std::env::var("RZN_RECONNECT_MAX_DELAY_MS") {
+            match delay.trim().parse() {
+                Ok(ms) => policy.max_delay = Duration::from_millis(ms),
+                Err(_) => log::warn!("Ignoring invalid RZN_RECONNECT_MAX_DELAY_MS={:?}", delay),
+            }
+        }
+        if let Ok(buffer) = std::env::var("RZN_RECONNECT_BUFFER") {
+            match buffer.trim().parse() {
+                Ok(messages) => policy.max_buffered = messages,
+                Err(_) => log::warn!("Ignoring invalid RZN_RECONNECT_BUFFER={:?}", buffer),
+            }
+        }
+        policy.spill = SpillConfig::from_env();
+        Some(policy)
+    }
+}
+
+/// Relays over `connection` (or a first one from `connect`), and over a new
+/// one from `connect` each time the Main App goes away. Returns once the
+/// extension side is gone or the relay stops.
+pub(crate) async fn run_sessions<S, C, Fut>(
+    policy: ReconnectPolicy,
+    mut connection: Option<Connection<S>>,
+    mut connect: C,
+    mut links: IpcLinks,
+)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    C: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<Connection<S>>>,
+{
+    let mut backlog = VecDeque::new();
+    // Messages spilled are always older than the ones held in memory
+    let mut spill = match policy.spill.clone().filter(|_| links.peer == 0) {
+        Some(config) => match Spill::open(config.clone()) {
+            Ok(spill) => Some(spill),
+            Err(e) => {
+                log::warn!("Spill: Failed to open {}: {}. Holding messages in memory.", config.path.display(), e);
+                None
+            }
+        },
+        None => None,
+    };
+    // Listed as connecting until the first connection is up
+    links.connection();
+    loop {
+        let (stream, main_app) = match connection.take() {
+            Some(connection) => connection,
+            None => {
+                links.enter(BrokerState::IpcConnecting).await;
+                if let Some(spill) = &mut spill {
+                    spill_backlog(spill, &mut backlog);
+                }
+                match reconnect(&policy, &mut connect, &mut links, &mut backlog, &mut spill).await {
+                    Some(connection) => {
+                        let held = backlog.len() + spill.as_ref().map_or(0, Spill::len);
+                        log::info!("Reconnect: Connected to Main App {}, sending {} held message(s).", links.peer, held);
+                        notify_state(&links, "active").await;
+                        connection
+                    }
+                    None => return,
+                }
+            }
+        };
+        if let Some(spill) = &mut spill {
+            match spill.drain() {
+                Ok((messages, letters)) => {
+                    for queued in messages.into_iter().rev() {
+                        backlog.push_front(queued);
+                    }
+                    send_dead_letters(&links, letters).await;
+                }
+                Err(e) => log::error!("Spill: Failed to read {}: {}", spill.path().display(), e),
+            }
+        }
+        let (ipc_reader, ipc_writer) = tokio::io::split(stream);
+        if ipc_session(ipc_reader, ipc_writer, &mut links, &mut backlog).await {
+            // What was drained from the spill but not written goes back for the next broker
+            if let Some(spill) = &mut spill {
+                spill_backlog(spill, &mut backlog);
+            }
+            return;
+        }
+        // A Main App the broker launched and tied to itself is restarted with the connection
+        drop(main_app);
+        log::warn!("Reconnect: Lost the connection to Main App {}, reconnecting.", links.peer);
+        links.track(ConnectionState::Reconnecting);
+        notify_state(&links, "reconnecting").await;
+    }
+}
+
+/// Retries `connect` with backoff, holding the extension's messages meanwhile
+/// and sending dead letters for the spilled ones that expire. `None` if the
+/// extension side went away or the relay stopped first.
+async fn reconnect<S, C, Fut>(
+    policy: &ReconnectPolicy,
+    connect: &mut C,
+    links: &mut IpcLinks,
+    backlog: &mut VecDeque<Queued>,
+    spill: &mut Option<Spill>,
+) -> Option<Connection<S>>
+where
+    C: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<Connection<S>>>,
+{
+    let mut delay = policy.initial_delay;
+    let mut attempts = 0;
+    loop {
+        let wait = tokio::time::sleep(delay);
+        tokio::pin!(wait);
+        loop {
+            tokio::select! {
+                _ = &mut wait => break,
+                queued = links.rx.recv() => match queued {
+                    Some(queued) => match spill {
+                        Some(spill) if backlog.is_empty() => {
+                            if let Err(e) = spill.push(&queued) {
+                                log::error!("Spill: Failed to write {}: {}. Holding in memory.", spill.path().display(), e);
+                                hold(backlog, queued, policy.max_buffered);
+                            }
+                        }
+                        _ => hold(backlog, queued, policy.max_buffered),
+                    },
+                    None => return None,
+                },
+                () = links.stop.stopping.cancelled() => {
+                    log::info!("Reconnect: Relay stopping, giving up on Main App with {} message(s) held.", backlog.len());
+                    return None;
+                }
+            }
+        }
+        if let Some(spill) = spill {
+            match spill.expire() {
+                Ok(letters) => send_dead_letters(links, letters).await,
+                Err(e) => log::error!("Spill: Failed to read {}: {}", spill.path().display(), e),
+            }
+        }
+        attempts += 1;
+        match connect().await {
+            Ok(connection) => return Some(connection),
+            Err(e) => {
+                delay = (delay * 2).min(policy.max_delay);
+                log::warn!("Reconnect: Attempt {} failed: {}. Retrying in {:?}...", attempts, e, delay);
+            }
+        }
+    }
+}
+
+/// Adds `queued` to `backlog`, making room by dropping expired messages and
+/// then the oldest one.
+fn hold(backlog: &mut VecDeque<Queued>, queued: Queued, max_buffered: usize) {
+    if backlog.len() >= max_buffered {
+        backlog.retain(|held| !held.is_expired());
+    }
+    if backlog.len() >= max_buffered {
+        log::warn!("Reconnect: Too many messages while disconnected, dropping the oldest.");
+        metrics::record_dropped_while_disconnected();
+        if backlog.pop_front().is_none() {
+            // Nothing is held at all
+            return;
+        }
+    }
+    backlog.push_back(queued);
+}
+
+/// Moves the messages held in memory to `spill`, oldest first, keeping the
+/// rest in memory if writing fails.
+fn spill_backlog(spill: &mut Spill, backlog: &mut VecDeque<Queued>) {
+    while let Some(queued) = backlog.front() {
+        if let Err(e) = spill.push(queued) {
+            log::error!("Spill: Failed to write {}: {}. Holding in memory.", spill.path().display(), e);
+            return;
+        }
+        backlog.pop_front();
+    }
+}
+
+/// Tells the extension about the spilled messages that expired.
+async fn send_dead_letters(links: &IpcLinks, letters: Vec<ExtensionResponse>) {
+    for letter in letters {
+        match serde_json::to_vec(&letter) {
+            Ok(bytes) => {
+                let _ = links.native_tx.send(bytes.into()).await;
+            }
+            Err(e) => log::error!("Spill: Failed to serialize dead letter: {}", e),
+        }
+    }
+}
+
+/// Reports the primary Main App's connection state to the extension.
+async fn notify_state(links: &IpcLinks, state: &str) {
+    if links.peer != 0 {
+        return;
+    }
+    match state_message(state) {
+        Ok(bytes) => {
+            // The extension writer only stops when the relay does
+            let _ = links.native_tx.send(bytes.into()).await;
+        }
+        Err(e) => log::error!("Reconnect: Failed to serialize bridge state: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::next_message;
+    use crate::relay::{relay_via, RelayConfig};
+    use crate::Hooks;
+    use shared_types::frame::{read_frame, write_message_bytes};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{duplex, split, DuplexStream};
+
+    #[tokio::test]
+    async fn holds_messages_until_the_main_app_is_back() {
+        let (extension, native) = duplex(4096);
+        let (first_host, first_ipc) = duplex(4096);
+        let (native_reader, native_writer) = split(native);
+        let next: Arc<Mutex<Option<DuplexStream>>> = Arc::default();
+        let connect = {
+            let next = next.clone();
+            move || {
+                let stream = next.lock().unwrap().take();
+                async move { stream.map(|s| (s, None)).ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused)) }
+            }
+        };
+        let policy = ReconnectPolicy { initial_delay: Duration::from_millis(10), max_delay: Duration::from_millis(20), max_buffered: 4, spill: None };
+        let relay = tokio::spawn(relay_via(RelayConfig::new(Hooks::default()), native_reader, native_writer, |links| {
+            run_sessions(policy, Some((first_ipc, None)), connect, links)
+        }));
+
+        let (mut extension_reader, mut extension_writer) = split(extension);
+        let state = |bytes: Vec<u8>| serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["result"]["state"].clone();
+        drop(first_host);
+        let reconnecting = next_message(&mut extension_reader).await;
+        assert_eq!(state(reconnecting), "reconnecting");
+
+        write_message_bytes(&mut extension_writer, br#"{"action":"ping","task_id":"1"}"#, "test").await.unwrap();
+        let (mut second_host, second_ipc) = duplex(4096);
+        *next.lock().unwrap() = Some(second_ipc);
+        let hello = read_frame(&mut second_host, "test").await.unwrap().unwrap();
+        assert!(hello.payload.starts_with(br#"{"action":"hello""#));
+        let frame = read_frame(&mut second_host, "test").await.unwrap().unwrap();
+        assert_eq!(frame.payload, br#"{"action":"ping","task_id":"1"}"#);
+        let active = next_message(&mut extension_reader).await;
+        assert_eq!(state(active), "active");
+
+        drop((extension_reader, extension_writer));
+        relay.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_spilled_messages_that_were_not_written() {
+        let dir = std::env::temp_dir().join(format!("rzn-respill-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let large = format!(r#"{{"action":"task_result","task_id":"1","result":"{}"}}"#, "x".repeat(64 * 1024)).into_bytes();
+        let mut spill = Spill::open(SpillConfig::new(&dir)).unwrap();
+        spill.push(&Queued::from(large.clone())).unwrap();
+        drop(spill);
+
+        // The Main App reads the hello and nothing more, then goes away with the extension
+        let (extension, native) = duplex(4096);
+        let (mut host, ipc) = duplex(1024);
+        let (native_reader, native_writer) = split(native);
+        let connect = || async { Err::<Connection<DuplexStream>, _>(io::Error::from(io::ErrorKind::ConnectionRefused)) };
+        let policy = ReconnectPolicy { spill: Some(SpillConfig::new(&dir)), ..ReconnectPolicy::default() };
+        let relay = tokio::spawn(relay_via(RelayConfig::new(Hooks::default()), native_reader, native_writer, move |links| {
+            run_sessions(policy, Some((ipc, None)), connect, links)
+        }));
+        let hello = read_frame(&mut host, "test").await.unwrap().unwrap();
+        assert!(hello.payload.starts_with(br#"{"action":"hello""#));
+        drop(extension);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(host);
+        relay.await.unwrap();
+
+        let mut spill = (0..100)
+            .find_map(|_| Spill::open(SpillConfig::new(&dir)).ok().or_else(|| { std::thread::sleep(Duration::from_millis(20)); None }))
+            .unwrap();
+        let (messages, _) = spill.drain().unwrap();
+        assert_eq!(messages.iter().map(|queued| queued.bytes.to_vec()).collect::<Vec<_>>(), [large]);
+        drop(spill);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn drops_the_oldest_held_message() {
+        let mut backlog = VecDeque::new();
+        for n in 0..3u8 {
+            hold(&mut backlog, vec![n].into(), 2);
+        }
+        let held: Vec<Vec<u8>> = backlog.into_iter().map(|queued| queued.bytes.to_vec()).collect();
+        assert_eq!(held, [vec![1], vec![2]]);
+    }
+}
@@ -0,0 +1,1087 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+// MPSC channels for task communication
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{JoinError, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+use shared_types::frame::{read_frame_into, read_message_into, write_frame, write_frame_unflushed, write_message_unflushed, FlushPolicy, FrameFlags};
+use shared_types::chunk::{CHUNK_TEXT_LEN, NATIVE_TO_EXTENSION_LIMIT};
+use shared_types::{chunk_message, is_chunk, peek_envelope, peek_u64, Action, BrokerState, ChunkError, Compression, ConnectionState, Encoding, Reassembler, Envelope, ExtensionResponse, Heartbeat, JsonError, JsonLimits, MessageLimits, MessageTooLarge, PERFORM_TASK_ACTION};
+
+use crate::broker::Broker;
+use crate::budget::ResultBudgets;
+use crate::connections::TrackedConnection;
+use crate::deadline::TaskDeadlines;
+use crate::delivery::{Deliveries, Delivery, Unacked};
+use crate::error::{BrokerError, Peer, ProtocolError};
+use crate::handshake::{answer_hello, check_hello_ack, hello_message};
+use crate::heartbeat;
+use crate::hooks::{apply_hooks, Hooks};
+use crate::lifecycle::Lifecycle;
+use crate::metrics;
+use crate::peers::Routes;
+use crate::notifier::{notify_from_extension, Notifier, NoopNotifier};
+use crate::pause::{Admission, PauseSwitch};
+use crate::selftest::SelfTest;
+use crate::shutdown::{self, Stop, DRAIN_TIMEOUT};
+use crate::stats;
+use crate::validate::reject_invalid_task;
+
+/// How long a write may wait for the peer to take data before the relay
+/// gives up on it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message waiting in one of the relay queues. The bytes are shared with
+/// the buffer they were read into, not copied.
+pub(crate) struct Queued {
+    pub(crate) bytes: Bytes,
+    /// When the message's TTL runs out, if it has one.
+    pub(crate) expires_at: Option<Instant>,
+    /// Signalled once the message has been written out.
+    written: Option<oneshot::Sender<()>>,
+    /// One of the extension's messages, written again after a reconnect
+    /// until a Main App that asked for at-least-once delivery acknowledges it.
+    pub(crate) redeliver: bool,
+}
+
+impl Queued {
+    /// Queues `bytes`, starting the TTL clock if the envelope carries a `ttl_ms`.
+    pub(crate) fn new(bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        let expires_at = peek_u64(&bytes, "ttl_ms").map(|ttl| Instant::now() + Duration::from_millis(ttl));
+        Queued { bytes, expires_at, written: None, redeliver: false }
+    }
+
+    /// Queues `bytes` with a receipt that resolves once they are written.
+    pub(crate) fn with_receipt(bytes: impl Into<Bytes>) -> (Self, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        (Queued { bytes: bytes.into(), expires_at: None, written: Some(tx), redeliver: false }, rx)
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Written messages that aren't flushed yet.
+#[derive(Default)]
+struct Unflushed {
+    bytes: usize,
+    /// Receipts of the messages, signalled once they are flushed.
+    receipts: Vec<oneshot::Sender<()>>,
+}
+
+impl Unflushed {
+    fn add(&mut self, queued: Queued) {
+        self.bytes += queued.bytes.len();
+        self.receipts.extend(queued.written);
+    }
+
+    async fn flush(&mut self, writer: &mut (impl AsyncWrite + Unpin)) -> std::io::Result<()> {
+        if self.bytes == 0 && self.receipts.is_empty() {
+            return Ok(());
+        }
+        writer.flush().await?;
+        self.bytes = 0;
+        for written in self.receipts.drain(..) {
+            let _ = written.send(());
+        }
+        Ok(())
+    }
+}
+
+impl From<Vec<u8>> for Queued {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes::from(bytes).into()
+    }
+}
+
+impl From<Bytes> for Queued {
+    fn from(bytes: Bytes) -> Self {
+        Queued { bytes, expires_at: None, written: None, redeliver: false }
+    }
+}
+
+/// Settings shared by the relay tasks.
+#[derive(Clone)]
+pub(crate) struct RelayConfig {
+    pub(crate) hooks: Hooks,
+    pub(crate) json_limits: JsonLimits,
+    /// Largest messages accepted from either side.
+    pub(crate) limits: MessageLimits,
+    pub(crate) notifier: Arc<dyn Notifier>,
+    /// Drain the queues and notify both sides on SIGTERM/SIGINT instead of
+    /// dying mid-frame.
+    pub(crate) handle_signals: bool,
+    /// Keepalive on each Main App connection, if any.
+    pub(crate) heartbeat: Option<Heartbeat>,
+    /// When the writers to either side flush.
+    pub(crate) flush_policy: FlushPolicy,
+    /// Parse every message that is written to check and log it in full,
+    /// instead of only peeking at its `action` and `task_id`.
+    pub(crate) inspect: bool,
+    /// Compression of large frames to Main Apps that read it.
+    pub(crate) compression: FrameCompression,
+    /// Encoding of the frames to the Main App.
+    pub(crate) encoding: FrameEncoding,
+    /// Fail and cancel tasks that run past their `timeout_ms`.
+    pub(crate) task_timeouts: bool,
+    /// The Main App connection these settings are for, if they are for one.
+    pub(crate) connection: Option<Arc<TrackedConnection>>,
+    /// The extension's messages that connection is to acknowledge.
+    pub(crate) unacked: Arc<Unacked>,
+    /// The IDs of that connection's messages.
+    pub(crate) deliveries: Arc<Deliveries>,
+}
+
+impl RelayConfig {
+    /// Defaults: `hooks`, JSON limits, heartbeat, flush policy, compression,
+    /// inspection (`RZN_INSPECT=1`) and task timeouts (`RZN_TASK_TIMEOUTS=1`)
+    /// from the environment, the default
+    /// [`MessageLimits`], no notifications and no signal handling.
+    pub(crate) fn new(hooks: Hooks) -> Self {
+        RelayConfig {
+            hooks,
+            json_limits: JsonLimits::from_env(),
+            limits: MessageLimits::default(),
+            notifier: Arc::new(NoopNotifier),
+            handle_signals: false,
+            heartbeat: Heartbeat::from_env(),
+            flush_policy: FlushPolicy::from_env(),
+            inspect: std::env::var("RZN_INSPECT").is_ok_and(|v| v == "1"),
+            compression: FrameCompression::new(Compression::from_env()),
+            encoding: FrameEncoding::default(),
+            task_timeouts: std::env::var("RZN_TASK_TIMEOUTS").is_ok_and(|v| v == "1"),
+            connection: None,
+            unacked: Arc::default(),
+            deliveries: Arc::default(),
+        }
+    }
+
+    /// The settings for one Main App connection, which negotiates
+    /// compression and encoding afresh.
+    fn for_connection(&self, connection: &Arc<TrackedConnection>, unacked: &Arc<Unacked>, deliveries: &Arc<Deliveries>) -> Self {
+        RelayConfig {
+            compression: FrameCompression::new(self.compression.compression),
+            encoding: FrameEncoding::default(),
+            connection: Some(connection.clone()),
+            unacked: unacked.clone(),
+            deliveries: deliveries.clone(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Compression of the frames written to a Main App. Frames go out
+/// uncompressed until the Main App's `hello_ack` lists the codec.
+#[derive(Clone, Default)]
+pub(crate) struct FrameCompression {
+    compression: Option<Compression>,
+    accepted: Arc<AtomicBool>,
+}
+
+impl FrameCompression {
+    pub(crate) fn new(compression: Option<Compression>) -> Self {
+        FrameCompression { compression, accepted: Arc::default() }
+    }
+
+    /// Takes the capabilities of the Main App's `hello_ack`.
+    fn negotiate(&self, capabilities: &[String]) {
+        let Some(compression) = self.compression else {
+            return;
+        };
+        let accepted = compression.accepted_by(capabilities);
+        if accepted {
+            log::info!("Handshake: Compressing frames over {} bytes with {:?}.", compression.threshold, compression.codec);
+        } else {
+            log::info!("Handshake: Main App doesn't read {}, frames go out uncompressed.", compression.codec.capability());
+        }
+        self.accepted.store(accepted, Ordering::Relaxed);
+    }
+
+    /// The compressed payload, if it is worth sending compressed.
+    fn compress(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let compression = self.compression.filter(|_| self.accepted.load(Ordering::Relaxed))?;
+        compression.compress(payload).unwrap_or_else(|e| {
+            log::warn!("IpcWrite: Sending frame uncompressed, compression failed: {}", e);
+            None
+        })
+    }
+}
+
+/// Encoding of the frames written to a Main App. Messages are JSON, as on
+/// the extension's side, until the Main App's `hello_ack` asks for a binary
+/// encoding.
+#[derive(Clone, Default)]
+pub(crate) struct FrameEncoding(Arc<OnceLock<Encoding>>);
+
+impl FrameEncoding {
+    /// Takes the capabilities of the Main App's `hello_ack`.
+    fn negotiate(&self, capabilities: &[String]) {
+        let encoding = Encoding::negotiate(capabilities);
+        if encoding != Encoding::Json {
+            log::info!("Handshake: Sending frames to the Main App as {:?}.", encoding);
+        }
+        let _ = self.0.set(encoding);
+    }
+
+    /// The flags of `message` as written and, if it has to be transcoded,
+    /// the transcoded payload. A message that isn't JSON goes out as it is.
+    fn encode(&self, message: &[u8]) -> (FrameFlags, Option<Vec<u8>>) {
+        let encoding = self.0.get().copied().unwrap_or_default();
+        if encoding == Encoding::Json {
+            return (FrameFlags::NONE, None);
+        }
+        match Encoding::Json.transcode(message, encoding) {
+            Ok(encoded) => (encoding.flags(), Some(encoded)),
+            Err(e) => {
+                log::warn!("IpcWrite: Sending message as it is, transcoding to {:?} failed: {}", encoding, e);
+                (FrameFlags::NONE, None)
+            }
+        }
+    }
+}
+
+/// State the relay tasks share across Main App connections.
+#[derive(Clone, Default)]
+pub(crate) struct RelayState {
+    selftest: Arc<SelfTest>,
+    /// Result budgets are learned from tasks going out and applied to results coming back
+    budgets: Arc<ResultBudgets>,
+    /// Timeouts start with tasks going out and stop with their results
+    deadlines: Arc<TaskDeadlines>,
+    pause: Arc<PauseSwitch>,
+    /// Set once the relay is shutting down; messages from either side are dropped from then on
+    closing: Arc<AtomicBool>,
+    /// Where the relay is, as reported to both sides
+    lifecycle: Arc<Lifecycle>,
+}
+
+/// Runs the broker as a native messaging host: connects to the Main App and
+/// relays between stdin/stdout and the IPC socket until either side disconnects.
+pub async fn run_stdio() -> io::Result<()> {
+    run_stdio_with_hooks(Hooks::default()).await
+}
+
+/// Same as [`run_stdio`], running every relayed message through `hooks`.
+pub async fn run_stdio_with_hooks(hooks: Hooks) -> io::Result<()> {
+    Broker::builder().hooks(hooks).build().run_stdio().await
+}
+
+/// Relays messages between a native messaging pair (extension side) and an IPC
+/// pair (Main App side). Returns once any of the four relay tasks finishes and
+/// the others have stopped.
+pub async fn relay<NR, NW, IR, IW>(
+    native_reader: NR,
+    native_writer: NW,
+    ipc_reader: IR,
+    ipc_writer: IW,
+    hooks: Hooks,
+)
+where
+    NR: AsyncRead + Unpin + Send + 'static,
+    NW: AsyncWrite + Unpin + Send + 'static,
+    IR: AsyncRead + Unpin + Send + 'static,
+    IW: AsyncWrite + Unpin + Send + 'static,
+{
+    relay_with(RelayConfig::new(hooks), native_reader, native_writer, ipc_reader, ipc_writer).await
+}
+
+/// [`relay`] with explicit settings.
+pub(crate) async fn relay_with<NR, NW, IR, IW>(
+    config: RelayConfig,
+    native_reader: NR,
+    native_writer: NW,
+    ipc_reader: IR,
+    ipc_writer: IW,
+)
+where
+    NR: AsyncRead + Unpin + Send + 'static,
+    NW: AsyncWrite + Unpin + Send + 'static,
+    IR: AsyncRead + Unpin + Send + 'static,
+    IW: AsyncWrite + Unpin + Send + 'static,
+{
+    relay_via(config, native_reader, native_writer, |mut links| async move {
+        ipc_session(ipc_reader, ipc_writer, &mut links, &mut VecDeque::new()).await;
+    })
+    .await
+}
+
+/// Ends of the relay channels used by the Main App side, which may outlive
+/// a single IPC connection.
+pub(crate) struct IpcLinks {
+    /// Messages from the extension for the Main App.
+    pub(crate) rx: mpsc::Receiver<Queued>,
+    /// Messages for the extension.
+    pub(crate) native_tx: mpsc::Sender<Queued>,
+    /// Messages for the Main App that the broker answers itself.
+    host_tx: mpsc::Sender<Queued>,
+    config: RelayConfig,
+    state: RelayState,
+    /// Connection ID of the Main App these links serve; 0 is the primary.
+    pub(crate) peer: usize,
+    /// Task origins, when there are several Main App connections.
+    routes: Option<Arc<Routes>>,
+    /// Stops the whole relay.
+    pub(crate) stop: Stop,
+    /// State of the Main App connection, tracked once it is first used.
+    connection: Option<Arc<TrackedConnection>>,
+    /// Kept across reconnects, so they are written again to the next connection.
+    unacked: Arc<Unacked>,
+    /// Kept across reconnects, so a message sent again is known as a duplicate.
+    deliveries: Arc<Deliveries>,
+}
+
+impl IpcLinks {
+    /// Links for connection `peer`, fed through the returned sender instead
+    /// of `rx`. Tasks it sends are recorded in `routes`.
+    pub(crate) fn for_peer(&self, peer: usize, routes: &Arc<Routes>) -> (mpsc::Sender<Queued>, IpcLinks) {
+        let (tx, rx) = mpsc::channel::<Queued>(10);
+        let links = IpcLinks {
+            rx,
+            native_tx: self.native_tx.clone(),
+            host_tx: tx.clone(),
+            config: self.config.clone(),
+            state: self.state.clone(),
+            peer,
+            routes: Some(routes.clone()),
+            stop: self.stop.clone(),
+            connection: None,
+            unacked: Arc::default(),
+            deliveries: Arc::new(Deliveries::for_peer(peer)),
+        };
+        (tx, links)
+    }
+
+    /// The Main App connection these links serve, across its reconnects.
+    pub(crate) fn connection(&mut self) -> &Arc<TrackedConnection> {
+        let (peer, notifier) = (self.peer, &self.config.notifier);
+        self.connection.get_or_insert_with(|| Arc::new(TrackedConnection::new(peer, notifier.clone())))
+    }
+
+    /// Moves the Main App connection to `state`.
+    pub(crate) fn track(&mut self, state: ConnectionState) {
+        self.connection().advance(state);
+    }
+
+    /// Moves the relay to `state` and tells both sides. Only the primary
+    /// connection drives the lifecycle.
+    pub(crate) async fn enter(&self, state: BrokerState) {
+        if self.peer == 0 {
+            self.state.lifecycle.enter(state, &self.native_tx, &self.host_tx).await;
+        }
+    }
+}
+
+/// Runs the extension side of the relay and hands the Main App side to `ipc`.
+/// Returns once the extension disconnects or `ipc` finishes, and every task
+/// has stopped.
+pub(crate) async fn relay_via<NR, NW, F, Fut>(config: RelayConfig, native_reader: NR, native_writer: NW, ipc: F)
+where
+    NR: AsyncRead + Unpin + Send + 'static,
+    NW: AsyncWrite + Unpin + Send + 'static,
+    F: FnOnce(IpcLinks) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    // 1. Create channels for communication between tasks
+    // Channel for messages from Extension (NativeRead) to Main App (IpcWrite)
+    let (ext_to_ipc_tx, ext_to_ipc_rx) = mpsc::channel::<Queued>(10);
+    // Channel for messages from Main App (IpcRead) to Extension (NativeWrite)
+    let (ipc_to_ext_tx, ipc_to_ext_rx) = mpsc::channel::<Queued>(10);
+
+    // Self-tests, result budgets and the pause switch need both directions
+    let state = RelayState::default();
+    let handle_signals = config.handle_signals;
+    // Whichever task ends first stops the others
+    let stop = Stop::default();
+
+    // Kept to send the shutdown notices behind whatever is still queued
+    let (host_tx, native_tx, closing) = (ext_to_ipc_tx.clone(), ipc_to_ext_tx.clone(), state.closing.clone());
+    let lifecycle = state.lifecycle.clone();
+
+    // 2. Spawn Tasks for Relaying Messages. Writers and readers are joined
+    // apart, so the writers are flushed before the readers let go.
+    let mut writers: JoinSet<Finished> = JoinSet::new();
+    let mut readers: JoinSet<Finished> = JoinSet::new();
+
+    // Task: Read from Extension Channel (ipc_to_ext_rx) -> Write to Extension (stdout)
+    let (writing, flush_policy, inspect) = (stop.clone(), config.flush_policy, config.inspect);
+    writers.spawn(async move {
+        let finished = writing.ending(handle_native_write(native_writer, ipc_to_ext_rx, flush_policy, inspect, &writing.stopping)).await;
+        ("Extension writer", finished)
+    });
+    lifecycle.enter(BrokerState::ExtensionConnected, &native_tx, &host_tx).await;
+
+    // Task: Read from Extension (stdin) -> Send to IPC Channel (ext_to_ipc_tx)
+    let reading = stop.clone();
+    let (tx, ext_tx, reader_config, reader_state) = (ext_to_ipc_tx.clone(), ipc_to_ext_tx.clone(), config.clone(), state.clone());
+    readers.spawn(async move {
+        let mut native_reader = native_reader;
+        let finished = reading.until_stopped(handle_native_read(&mut native_reader, tx, ext_tx, reader_config, reader_state)).await;
+        // stdin stays open until everything for the extension is flushed
+        reading.flushed.cancelled().await;
+        ("Extension reader", finished.unwrap_or(Ok(())))
+    });
+
+    // Task: IPC Channel (ext_to_ipc_rx) <-> Main App <-> Extension Channel (ipc_to_ext_tx)
+    let ipc_task = ipc(IpcLinks {
+        rx: ext_to_ipc_rx,
+        native_tx: ipc_to_ext_tx,
+        host_tx: ext_to_ipc_tx,
+        config,
+        state,
+        peer: 0,
+        routes: None,
+        stop: stop.clone(),
+        connection: None,
+        unacked: Arc::default(),
+        deliveries: Arc::default(),
+    });
+    // Writes to the Main App, so it is flushed along with the extension writer
+    let ipc_stop = stop.clone();
+    writers.spawn(async move {
+        ipc_stop.ending(ipc_task).await;
+        ("IPC", Ok(()))
+    });
+
+    // 3. Wait for any task to finish (indicates disconnection or error).
+    // On a shutdown signal, stop relaying and let the writers drain first
+    tokio::select! {
+        () = stop.stopping.cancelled() => {}
+        signal = shutdown::signal(), if handle_signals => {
+            log::info!("Shutdown: Received {}, draining the relay queues.", signal);
+            closing.store(true, Ordering::Relaxed);
+            lifecycle.enter(BrokerState::Draining, &native_tx, &host_tx).await;
+            lifecycle.enter(BrokerState::ShuttingDown, &native_tx, &host_tx).await;
+            shutdown::drain(&host_tx, &native_tx, signal).await;
+        }
+    }
+    drop((host_tx, native_tx));
+    join_in_order(&stop, writers, readers).await;
+    log::info!("Relay finished. Counters: {:?}", metrics::metrics());
+}
+
+/// Stops the relay tasks: the writers write out and flush what is queued,
+/// for at most [`DRAIN_TIMEOUT`], and then the readers let go of their
+/// streams. Returns once every task has ended.
+async fn join_in_order(stop: &Stop, mut writers: JoinSet<Finished>, mut readers: JoinSet<Finished>) {
+    stop.stopping.cancel();
+    let flushed = tokio::time::timeout(DRAIN_TIMEOUT, async {
+        while let Some(finished) = writers.join_next().await {
+            log_finished(finished);
+        }
+    })
+    .await;
+    if flushed.is_err() {
+        log::warn!("Relay: Writers still busy after {:?}, stopping them.", DRAIN_TIMEOUT);
+        writers.abort_all();
+        while let Some(finished) = writers.join_next().await {
+            log_finished(finished);
+        }
+    }
+    stop.flushed.cancel();
+    while let Some(finished) = readers.join_next().await {
+        log_finished(finished);
+    }
+}
+
+/// Relays over one Main App connection, sending `backlog` first. Returns
+/// `true` once the relay is stopping, or `false` when the connection drops;
+/// a message that could not be written is put back on `backlog`.
+pub(crate) async fn ipc_session<IR, IW>(
+    ipc_reader: IR,
+    ipc_writer: IW,
+    links: &mut IpcLinks,
+    backlog: &mut VecDeque<Queued>,
+) -> bool
+where
+    IR: AsyncRead + Unpin + Send + 'static,
+    IW: AsyncWrite + Unpin + Send + 'static,
+{
+    // Introduce the broker before anything else
+    links.track(ConnectionState::Handshaking);
+    let connection = links.connection().clone();
+    let config = links.config.for_connection(&connection, &links.unacked, &links.deliveries);
+    let mut ipc_writer = ipc_writer;
+    if let Err(e) = timed_write(Peer::MainApp, write_frame(&mut ipc_writer, FrameFlags::NONE, 0, &hello_message(), "IpcWrite")).await {
+        log::error!("IpcWrite: Error sending hello to Main App: [{}] {}", e.code(), e);
+        links.enter(BrokerState::IpcLost).await;
+        return false;
+    }
+    links.enter(BrokerState::IpcConnected).await;
+    // The connection's tasks stop together; the relay stopping ends the writer
+    let session = Stop::default();
+    let mut tasks: JoinSet<Finished> = JoinSet::new();
+
+    // Task: Read from Main App (IPC reader) -> Send to Extension Channel (native_tx)
+    let (seen_tx, seen_rx) = watch::channel(());
+    let reading = session.clone();
+    let (tx, host_tx, reader_config, state) = (links.native_tx.clone(), links.host_tx.clone(), config.clone(), links.state.clone());
+    let routes = links.routes.clone().map(|routes| (routes, links.peer));
+    tasks.spawn(async move {
+        let mut ipc_reader = ipc_reader;
+        let finished = reading.until_stopped(handle_ipc_read(&mut ipc_reader, tx, host_tx, reader_config, state, routes, seen_tx)).await;
+        reading.flushed.cancelled().await;
+        ("IPC reader", finished.unwrap_or(Ok(())))
+    });
+    // Pings go out through the IPC channel like any other message
+    if let Some(keepalive) = config.heartbeat {
+        let (watching, host_tx, peer) = (session.clone(), links.host_tx.clone(), links.peer);
+        tasks.spawn(async move {
+            watching.until_stopped(heartbeat::monitor(keepalive, host_tx, seen_rx, peer)).await;
+            ("Heartbeat", Ok(()))
+        });
+    }
+
+    // Once the relay stops, the writer only writes out what is queued
+    let (watching, stopping) = (session.clone(), links.stop.stopping.clone());
+    tasks.spawn(async move {
+        watching.until_stopped(async move {
+            stopping.cancelled().await;
+            connection.advance(ConnectionState::Draining);
+            std::future::pending::<()>().await
+        }).await;
+        ("Connection state", Ok(()))
+    });
+
+    // Read from IPC Channel (rx) -> Write to Main App (IPC writer). A lost
+    // connection stops it right away, leaving the queue to the next one
+    let written = tokio::select! {
+        biased;
+        written = session.ending(handle_ipc_write(ipc_writer, &mut links.rx, backlog, &config, &links.stop.stopping)) => written,
+        () = session.stopping.cancelled() => Ok(()),
+    };
+    if let Err(e) = &written {
+        log::error!("IpcWrite: [{}] {}", e.code(), e);
+    }
+    session.flushed.cancel();
+    while let Some(finished) = tasks.join_next().await {
+        log_finished(finished);
+    }
+    let extension_gone = links.stop.stopping.is_cancelled();
+    if !extension_gone {
+        links.enter(BrokerState::IpcLost).await;
+        // Whatever the Main App didn't acknowledge goes to the next one first
+        let again = links.unacked.take_written();
+        if !again.is_empty() {
+            log::warn!("IpcWrite: {} message(s) unacknowledged by Main App {}, writing them again after reconnecting.", again.len(), links.peer);
+        }
+        for queued in again.into_iter().rev() {
+            backlog.push_front(queued);
+        }
+    }
+    extension_gone
+}
+
+// --- Task Implementations ---
+
+/// Reads messages from the browser extension (stdin) and sends them to the IPC channel.
+async fn handle_native_read(
+    mut reader: impl AsyncRead + Unpin, // Generic so embedders can relay any stream
+    tx: mpsc::Sender<Queued>,
+    ext_tx: mpsc::Sender<Queued>, // For replies the broker answers itself
+    config: RelayConfig,
+    state: RelayState,
+) -> Result<(), BrokerError> {
+    log::info!("NativeRead: Waiting for messages from extension...");
+    // Reused for every message once the previous ones are written out
+    let mut buffer = BytesMut::new();
+    // Large results arrive in pieces and are passed on once whole
+    let mut chunks = Reassembler::new(config.limits.to_app);
+    loop {
+        match read_message_into(&mut reader, config.limits.to_app, &mut buffer, "NativeRead").await {
+            Ok(Some(message_bytes)) => {
+                if state.closing.load(Ordering::Relaxed) {
+                    log::warn!("NativeRead: Shutting down, dropping message from extension.");
+                    continue;
+                }
+                let message_bytes = if is_chunk(&message_bytes) {
+                    match chunks.accept(&message_bytes) {
+                        Ok(Some(whole)) => Bytes::from(whole),
+                        Ok(None) => continue,
+                        Err(e) => {
+                            let error = ProtocolError::Chunk(e);
+                            if matches!(error, ProtocolError::Chunk(ChunkError::TooLarge(_))) {
+                                metrics::record_too_large();
+                            } else {
+                                log::error!("NativeRead: Dropping chunked message from extension: {}", error);
+                            }
+                            let envelope = peek_envelope(&message_bytes);
+                            answer(&ext_tx, Peer::Extension, &error.to_response(envelope.task_id.as_deref().unwrap_or("N/A"))).await?;
+                            continue;
+                        }
+                    }
+                } else {
+                    message_bytes
+                };
+                // Routed by its envelope; only what reads the body has it parsed
+                let envelope = peek_envelope(&message_bytes);
+                let action = envelope.action.as_deref().map(Action::parse);
+                log::info!("NativeRead: Received message (action: {}, task_id: {})",
+                         envelope.action.as_deref().unwrap_or("N/A"), envelope.task_id.as_deref().unwrap_or("N/A"));
+                // The Main App was already told the task timed out
+                if state.deadlines.is_late(&envelope) {
+                    log::warn!("NativeRead: Dropping late answer to a task that timed out.");
+                    continue;
+                }
+                // Self-tests are answered by the broker, not forwarded
+                if SelfTest::is_request(&envelope) {
+                    state.selftest.spawn(envelope.task_id.as_deref().unwrap_or("N/A").to_string(), tx.clone(), ext_tx.clone());
+                    continue;
+                }
+                let task_id = envelope.task_id.map(Cow::into_owned);
+                let read_in_full = config.inspect || !config.hooks.is_empty()
+                    || matches!(action, Some(Action::Hello | Action::TaskResult | Action::CommitRequest));
+                let parsed = match read_in_full.then(|| config.json_limits.from_slice::<serde_json::Value>(&message_bytes)) {
+                    None => None,
+                    Some(Ok(value)) => Some(value),
+                    Some(Err(JsonError::Limit(e))) => {
+                        log::error!("NativeRead: Dropping message from extension: {}", e);
+                        metrics::record_json_rejected();
+                        let error = ProtocolError::InvalidJson(e);
+                        answer(&ext_tx, Peer::Extension, &error.to_response(task_id.as_deref().unwrap_or("N/A"))).await?;
+                        continue;
+                    }
+                    Some(Err(JsonError::Parse(e))) => {
+                        log::warn!("NativeRead: Message from extension is not valid JSON: {}", e);
+                        None
+                    }
+                };
+                if let Some(value) = &parsed {
+                    notify_from_extension(&config.notifier, value);
+                    // Degraded selectors are reported just ahead of the result that revealed them
+                    let task_id = task_id.as_deref().unwrap_or("N/A");
+                    for degradation in stats::record_task_result(value) {
+                        config.notifier.selector_degraded(task_id, &degradation);
+                        match serde_json::to_vec(&stats::degradation_event(task_id, &degradation)) {
+                            // A closed channel stops the task below, when the result is sent
+                            Ok(bytes) => {
+                                let _ = tx.send(bytes.into()).await;
+                            }
+                            Err(e) => log::error!("NativeRead: Failed to serialize selector report: {}", e),
+                        }
+                    }
+                }
+
+                // The handshake is answered by the broker; an incompatible extension is cut off
+                if let Some(value) = parsed.as_ref().filter(|_| action == Some(Action::Hello)) {
+                    match answer_hello(value) {
+                        Ok(ack) => ext_tx.send(ack.into()).await.map_err(|_| BrokerError::ChannelClosed(Peer::Extension))?,
+                        Err(e) => {
+                            // Make sure the error reaches the extension before the relay ends
+                            let (error, written) = Queued::with_receipt(serde_json::to_vec(&e.to_response("hello")).unwrap_or_default());
+                            if ext_tx.send(error).await.is_ok() {
+                                let _ = written.await;
+                            }
+                            return Err(e.into());
+                        }
+                    }
+                    continue;
+                }
+
+                // Task results must fit the budget the task declared
+                let message_bytes = match &parsed {
+                    Some(value) => state.budgets.enforce(value, message_bytes),
+                    None => message_bytes,
+                };
+
+                // Give hooks a chance to transform or veto the message
+                let Some(message_bytes) = apply_hooks(&config.hooks, message_bytes, true, "NativeRead") else {
+                    continue;
+                };
+
+                // Send the raw bytes to the channel for the IPC writer task
+                let mut queued = Queued::new(message_bytes);
+                queued.redeliver = true;
+                tx.send(queued).await.map_err(|_| BrokerError::ChannelClosed(Peer::MainApp))?;
+            }
+            Ok(None) => {
+                config.notifier.extension_disconnected();
+                return Err(BrokerError::PeerDisconnected(Peer::Extension));
+            }
+            Err(e) => {
+                if let Some(too_large) = MessageTooLarge::of(&e) {
+                    // The message was skipped, so the extension is told and reading goes on
+                    metrics::record_too_large();
+                    answer(&ext_tx, Peer::Extension, &ProtocolError::FrameTooLarge(too_large.clone()).to_response("N/A")).await?;
+                    continue;
+                }
+                config.notifier.extension_disconnected();
+                return Err(BrokerError::Read { peer: Peer::Extension, source: e });
+            }
+        }
+    }
+    // tx is dropped on return, signaling the receiver
+}
+
+/// Reads messages from `backlog`, then the IPC channel, and writes them to the
+/// Main Application (IPC socket) until the channel closes, or until nothing
+/// is left once `stopping`.
+async fn handle_ipc_write(
+    mut writer: impl AsyncWrite + Unpin, // Generic over AsyncWrite + Unpin
+    rx: &mut mpsc::Receiver<Queued>,
+    backlog: &mut VecDeque<Queued>,
+    config: &RelayConfig,
+    stopping: &CancellationToken,
+) -> Result<(), BrokerError> {
+    log::info!("IpcWrite: Waiting for messages to send to Main App...");
+    let mut unflushed = Unflushed::default();
+    // Process messages from the channel until it's closed or drained
+    while let Some(mut queued) = match backlog.pop_front() {
+        Some(queued) => Some(queued),
+        None => next_queued(rx, stopping).await,
+    } {
+        // A stale command is worse than none, so expired messages are dropped
+        if queued.is_expired() {
+            log::warn!("IpcWrite: Dropping message whose TTL expired while queued.");
+            metrics::record_expired(true);
+            continue;
+        }
+        queued.bytes = config.unacked.track(&queued);
+        if log::log_enabled!(log::Level::Info) || config.inspect {
+            log_forwarding("IpcWrite", "Main App", &queued.bytes, &peek_envelope(&queued.bytes), config.inspect);
+        }
+
+        // Write the message to the IPC stream as a default-channel frame, in
+        // the Main App's encoding and compressed if large enough and it reads it
+        let (flags, encoded) = config.encoding.encode(&queued.bytes);
+        let payload = encoded.as_deref().unwrap_or(&queued.bytes[..]);
+        let written = match config.compression.compress(payload) {
+            Some(compressed) => timed_write(Peer::MainApp, write_frame_unflushed(&mut writer, flags | FrameFlags::COMPRESSED, 0, &compressed, "IpcWrite")).await,
+            None => timed_write(Peer::MainApp, write_frame_unflushed(&mut writer, flags, 0, payload, "IpcWrite")).await,
+        };
+        if let Err(e) = written {
+            // Kept for the next connection, if there is one
+            backlog.push_front(queued);
+            return Err(e);
+        }
+        unflushed.add(queued);
+        if config.flush_policy.should_flush(unflushed.bytes, backlog.is_empty() && rx.is_empty()) {
+            timed_write(Peer::MainApp, unflushed.flush(&mut writer)).await?;
+        }
+    }
+    if let Err(e) = timed_write(Peer::MainApp, unflushed.flush(&mut writer)).await {
+        log::error!("IpcWrite: {}", e);
+    }
+    log::info!("IpcWrite: Nothing left to write. Task finished.");
+    Ok(())
+}
+
+/// Reads messages from the Main Application (IPC socket) and sends them to the Native channel.
+async fn handle_ipc_read(
+    mut reader: impl AsyncRead + Unpin, // Generic over AsyncRead + Unpin
+    tx: mpsc::Sender<Queued>,
+    host_tx: mpsc::Sender<Queued>, // For rejections the broker answers itself
+    config: RelayConfig,
+    state: RelayState,
+    routes: Option<(Arc<Routes>, usize)>, // Records this connection as the origin of its tasks
+    seen: watch::Sender<()>, // Tells the heartbeat the Main App is alive
+) -> Result<(), BrokerError> {
+    log::info!("IpcRead: Waiting for messages from Main App...");
+    // Reused for every frame once the previous ones are written out
+    let mut buffer = BytesMut::new();
+    loop {
+        match read_frame_into(&mut reader, config.limits.to_extension, &mut buffer, "IpcRead").await {
+            Ok(Some((header, payload))) => {
+                seen.send_replace(());
+                // Compressed payloads come decompressed; encryption isn't negotiated, so such payloads can't be relayed
+                if header.flags.contains(FrameFlags::ENCRYPTED) {
+                    let error = ProtocolError::UnsupportedFlags { flags: header.flags.bits() };
+                    log::error!("IpcRead: Dropping frame on channel {}: {}", header.channel_id, error);
+                    answer(&host_tx, Peer::MainApp, &error.to_response("N/A")).await?;
+                    continue;
+                }
+                if state.closing.load(Ordering::Relaxed) {
+                    log::warn!("IpcRead: Shutting down, dropping message from Main App.");
+                    continue;
+                }
+                // The extension reads JSON only, so binary-encoded messages are transcoded
+                let message_bytes = match Encoding::of_flags(header.flags) {
+                    Encoding::Json => payload,
+                    encoding => match encoding.transcode(&payload, Encoding::Json) {
+                        Ok(json) => Bytes::from(json),
+                        Err(source) => {
+                            let error = ProtocolError::Transcode { encoding, source };
+                            log::error!("IpcRead: Dropping message from Main App: {}", error);
+                            answer(&host_tx, Peer::MainApp, &error.to_response("N/A")).await?;
+                            continue;
+                        }
+                    },
+                };
+                // Routed by its envelope; only what reads the body has it parsed
+                let envelope = peek_envelope(&message_bytes);
+                let action = envelope.action.as_deref().map(Action::parse);
+                log::info!("IpcRead: Received message from Main App (action: {}, task_id: {})",
+                         envelope.action.as_deref().unwrap_or("N/A"), envelope.task_id.as_deref().unwrap_or("N/A"));
+                // Replies to self-test probes and heartbeats stay inside the broker
+                if state.selftest.complete_probe(&envelope) || heartbeat::is_reply(&envelope) {
+                    continue;
+                }
+                let task_id = envelope.task_id.map(Cow::into_owned);
+                let read_in_full = config.inspect || !config.hooks.is_empty() || matches!(action, Some(
+                    Action::HelloAck | Action::Ack | Action::Stats | Action::History | Action::PerformTask | Action::PauseAll | Action::ResumeAll | Action::CancelTask
+                ));
+                let parsed = match read_in_full.then(|| config.json_limits.from_slice::<serde_json::Value>(&message_bytes)) {
+                    None => None,
+                    Some(Ok(value)) => Some(value),
+                    Some(Err(JsonError::Limit(e))) => {
+                        log::error!("IpcRead: Dropping message from Main App: {}", e);
+                        metrics::record_json_rejected();
+                        let error = ProtocolError::InvalidJson(e);
+                        answer(&host_tx, Peer::MainApp, &error.to_response(task_id.as_deref().unwrap_or("N/A"))).await?;
+                        continue;
+                    }
+                    Some(Err(JsonError::Parse(e))) => {
+                        log::warn!("IpcRead: Message from Main App is not valid JSON: {}", e);
+                        None
+                    }
+                };
+
+                // Acks of the extension's messages end at the broker
+                if let Some(value) = parsed.as_ref().filter(|_| action == Some(Action::Ack)) {
+                    let msg_id = value.get("data").and_then(|data| data.get("msg_id")).and_then(|v| v.as_str()).unwrap_or("N/A");
+                    if !config.unacked.acknowledge(msg_id) {
+                        log::debug!("IpcRead: Main App acknowledged {}, which wasn't waiting for it.", msg_id);
+                    }
+                    continue;
+                }
+                // Statistics requests are answered by the broker
+                if let Some(response) = parsed.as_ref().and_then(stats::stats_response) {
+                    answer(&host_tx, Peer::MainApp, &response).await?;
+                    continue;
+                }
+                // So does the handshake; an incompatible Main App is disconnected
+                if let Some(value) = parsed.as_ref().filter(|_| action == Some(Action::HelloAck)) {
+                    match check_hello_ack(value) {
+                        Ok(capabilities) => {
+                            config.compression.negotiate(&capabilities);
+                            config.encoding.negotiate(&capabilities);
+                            config.deliveries.negotiate(&capabilities);
+                            config.unacked.negotiate(&capabilities);
+                            if let Some(connection) = &config.connection {
+                                connection.advance(ConnectionState::Ready);
+                            }
+                            continue;
+                        }
+                        Err(e) => {
+                            metrics::record_handshake_refused();
+                            let (error, written) = Queued::with_receipt(serde_json::to_vec(&e.to_response(task_id.as_deref().unwrap_or("N/A"))).unwrap_or_default());
+                            if tx.send(error).await.is_ok() {
+                                let _ = written.await;
+                            }
+                            return Err(e.into());
+                        }
+                    }
+                }
+                // A Main App that never answers the hello is taken as it is
+                if let Some(connection) = config.connection.as_ref().filter(|c| c.state() == ConnectionState::Handshaking) {
+                    connection.advance(ConnectionState::Ready);
+                }
+                // Malformed tasks are bounced back instead of started
+                if let Some(rejection) = parsed.as_ref().and_then(reject_invalid_task) {
+                    answer(&host_tx, Peer::MainApp, &rejection).await?;
+                    continue;
+                }
+                // Every message goes on with a msg_id; one taken before doesn't go on again
+                let message_bytes = match config.deliveries.accept(message_bytes) {
+                    Delivery::New(message_bytes, msg_id) => {
+                        if let Some(msg_id) = msg_id.filter(|_| config.deliveries.acks()) {
+                            answer(&host_tx, Peer::MainApp, &ExtensionResponse::ack(task_id.as_deref().unwrap_or("N/A"), msg_id, false)).await?;
+                        }
+                        message_bytes
+                    }
+                    Delivery::Duplicate(msg_id) => {
+                        let task_id = task_id.as_deref().unwrap_or("N/A");
+                        log::warn!("IpcRead: Dropping message {} of task {}, it was relayed before.", msg_id, task_id);
+                        metrics::record_duplicate();
+                        if config.deliveries.acks() {
+                            answer(&host_tx, Peer::MainApp, &ExtensionResponse::ack(task_id, msg_id, true)).await?;
+                        }
+                        continue;
+                    }
+                };
+                if let Some(value) = &parsed {
+                    state.budgets.record(value);
+                }
+                if let (Some((routes, peer)), Some(task_id)) = (&routes, &task_id) {
+                    routes.record(task_id, *peer);
+                }
+
+                // Give hooks a chance to transform or veto the message
+                let Some(message_bytes) = apply_hooks(&config.hooks, message_bytes, false, "IpcRead") else {
+                    continue;
+                };
+
+                // While automation is paused, new tasks wait for resume_all
+                let queued = Queued::new(message_bytes);
+                let queued = match &parsed {
+                    Some(value) => {
+                        let admission = state.pause.admit(value, queued);
+                        // A held task's time runs too
+                        if config.task_timeouts && matches!(admission, Admission::Forward(_) | Admission::Held) {
+                            state.deadlines.start(value, &host_tx, &tx);
+                        }
+                        match admission {
+                            Admission::Forward(queued) => queued,
+                            Admission::Held => continue,
+                            Admission::Rejected(rejection) => {
+                                answer(&host_tx, Peer::MainApp, &rejection).await?;
+                                continue;
+                            }
+                            Admission::Cancelled(answers) => {
+                                for response in &answers {
+                                    answer(&host_tx, Peer::MainApp, response).await?;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    None => queued,
+                };
+
+                // Send the raw bytes to the channel for the Native writer task,
+                // followed by any tasks a resume_all released
+                for queued in std::iter::once(queued).chain(state.pause.release()) {
+                    tx.send(queued).await.map_err(|_| BrokerError::ChannelClosed(Peer::Extension))?;
+                }
+            }
+            Ok(None) => return Err(BrokerError::PeerDisconnected(Peer::MainApp)),
+            Err(e) => {
+                if let Some(too_large) = MessageTooLarge::of(&e) {
+                    // The message was skipped, so the Main App is told and reading goes on
+                    metrics::record_too_large();
+                    answer(&host_tx, Peer::MainApp, &ProtocolError::FrameTooLarge(too_large.clone()).to_response("N/A")).await?;
+                    continue;
+                }
+                return Err(BrokerError::Read { peer: Peer::MainApp, source: e });
+            }
+        }
+    }
+    // tx is dropped on return, signaling the receiver
+}
+
+/// Logs a message about to be written to `destination`. Only when
+/// inspecting is the payload parsed, to check it and log it in full.
+fn log_forwarding(log_prefix: &str, destination: &str, bytes: &[u8], envelope: &Envelope, inspect: bool) {
+    log::info!("{}: Forwarding message to {} (action: {}, task_id: {})", log_prefix, destination,
+               envelope.action.as_deref().unwrap_or("N/A"), envelope.task_id.as_deref().unwrap_or("N/A"));
+    if inspect {
+        match serde_json::from_slice::<serde_json::Value>(bytes) {
+            Ok(value) => log::debug!("{}: Payload ({} bytes): {}", log_prefix, bytes.len(), value),
+            Err(e) => log::warn!("{}: Forwarding message that is not valid JSON: {}", log_prefix, e),
+        }
+    }
+}
+
+/// Sends a reply the broker makes itself to `peer`, through `tx`, its queue.
+async fn answer(tx: &mpsc::Sender<Queued>, peer: Peer, response: &ExtensionResponse) -> Result<(), BrokerError> {
+    match serde_json::to_vec(response) {
+        Ok(bytes) => tx.send(bytes.into()).await.map_err(|_| BrokerError::ChannelClosed(peer)),
+        Err(e) => {
+            log::error!("Relay: Failed to serialize reply to {}: {}", peer, e);
+            Ok(())
+        }
+    }
+}
+
+/// Runs a write to `peer`, failing it if the peer takes nothing for
+/// [`WRITE_TIMEOUT`].
+async fn timed_write<T>(peer: Peer, write: impl Future<Output = io::Result<T>>) -> Result<T, BrokerError> {
+    match tokio::time::timeout(WRITE_TIMEOUT, write).await {
+        Ok(Ok(written)) => Ok(written),
+        Ok(Err(source)) => Err(BrokerError::Write { peer, source }),
+        Err(_) => Err(BrokerError::WriteTimeout { peer, after: WRITE_TIMEOUT }),
+    }
+}
+
+/// A relay task's name and how it ended.
+type Finished = (&'static str, Result<(), BrokerError>);
+
+/// Logs how a relay task ended.
+fn log_finished(finished: Result<Finished, JoinError>) {
+    match finished {
+        Ok((task, Ok(()))) => log::info!("{} task finished.", task),
+        Ok((task, Err(e))) if e.is_disconnect() => log::info!("{} task finished: {}", task, e),
+        Ok((task, Err(e))) => log::error!("{} task failed: [{}] {}", task, e.code(), e),
+        Err(e) if e.is_cancelled() => log::warn!("Relay task stopped before it finished."),
+        Err(e) => log::error!("Relay task did not finish: {}", e),
+    }
+}
+
+/// The next message for a writer: `None` once the channel is closed, or
+/// once `stopping` and nothing more is queued.
+async fn next_queued(rx: &mut mpsc::Receiver<Queued>, stopping: &CancellationToken) -> Option<Queued> {
+    tokio::select! {
+        biased;
+        queued = rx.recv() => queued,
+        () = stopping.cancelled() => None,
+    }
+}
+
+/// Reads messages from the Native channel and writes them to the browser extension (stdout).
+async fn handle_native_write(
+    mut writer: impl AsyncWrite + Unpin, // Generic so embedders can relay any stream
+    mut rx: mpsc::Receiver<Queued>,
+    flush_policy: FlushPolicy,
+    inspect: bool,
+    stopping: &CancellationToken, // Once cancelled, what is queued is written out and the task ends
+) -> Result<(), BrokerError> {
+    log::info!("NativeWrite: Waiting for messages to send to extension...");
+    let mut unflushed = Unflushed::default();
+    // Names the chunked transfers of this relay
+    let mut transfers = 0u64;
+    // Process messages from the channel until it's closed or drained
+    while let Some(queued) = next_queued(&mut rx, stopping).await {
+        if queued.is_expired() {
+            log::warn!("NativeWrite: Dropping message whose TTL expired while queued.");
+            metrics::record_expired(false);
+            continue;
+        }
+        let envelope = peek_envelope(&queued.bytes);
+        if log::log_enabled!(log::Level::Info) || inspect {
+            log_forwarding("NativeWrite", "extension", &queued.bytes, &envelope, inspect);
+        }
+        // Tasks are small, unlike the results coming back, and the statistics need their steps
+        if envelope.action.as_deref() == Some(PERFORM_TASK_ACTION) {
+            if let Ok(value) = serde_json::from_slice::<serde_json::Value>(&queued.bytes) {
+                stats::record_task_sent(&value);
+            }
+        }
+
+        // Chrome doesn't deliver messages over its limit, so those go in pieces
+        let pieces = if queued.bytes.len() > NATIVE_TO_EXTENSION_LIMIT {
+            transfers += 1;
+            let Some(pieces) = chunk_message(&queued.bytes, &format!("broker-{}", transfers), CHUNK_TEXT_LEN) else {
+                log::error!("NativeWrite: Dropping {} byte message that isn't UTF-8 text.", queued.bytes.len());
+                continue;
+            };
+            log::info!("NativeWrite: Sending {} byte message in {} chunks.", queued.bytes.len(), pieces.len() - 2);
+            pieces.into_iter().map(Bytes::from).collect()
+        } else {
+            vec![queued.bytes.clone()]
+        };
+
+        // Write the raw bytes to stdout for the extension
+        for piece in &pieces {
+            timed_write(Peer::Extension, write_message_unflushed(&mut writer, piece, "NativeWrite")).await?;
+        }
+        unflushed.add(queued);
+        if flush_policy.should_flush(unflushed.bytes, rx.is_empty()) {
+            timed_write(Peer::Extension, unflushed.flush(&mut writer)).await?;
+        }
+    }
+    if let Err(e) = timed_write(Peer::Extension, unflushed.flush(&mut writer)).await {
+        log::error!("NativeWrite: {}", e);
+    }
+    log::info!("NativeWrite: Nothing left to write. Task finished.");
+    Ok(())
+}
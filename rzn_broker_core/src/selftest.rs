@@ -0,0 +1,178 @@
+//! Built-in `bridge_selftest` action answered by the broker itself.
+//!
+//! The report covers every layer short of the Main App's business logic:
+//! framing round-trips on both legs, IPC connectivity, and the IPC round-trip
+//! latency measured with a `ping` probe.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use shared_types::frame::{read_frame, read_message_bytes, write_frame, write_message_bytes, FrameFlags};
+use shared_types::{Action, Envelope, ExtensionResponse};
+
+use crate::relay::Queued;
+
+/// Action the extension sends to request a self-test.
+pub const SELFTEST_ACTION: &str = "bridge_selftest";
+/// Action of the report the broker sends back.
+pub const SELFTEST_RESULT_ACTION: &str = "bridge_selftest_result";
+
+// Probe task ids carry this prefix so their pongs can be intercepted
+const PROBE_PREFIX: &str = "bridge_selftest-probe-";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Tracks in-flight self-tests and their IPC probes.
+#[derive(Default)]
+pub(crate) struct SelfTest {
+    pending: Mutex<HashMap<String, oneshot::Sender<()>>>,
+    next_probe: AtomicU64,
+}
+
+impl SelfTest {
+    /// Returns true if `envelope` is that of a self-test request from the
+    /// extension.
+    pub(crate) fn is_request(envelope: &Envelope) -> bool {
+        envelope.action.as_deref() == Some(SELFTEST_ACTION)
+    }
+
+    /// If `envelope` is that of an answer to one of our probes, completes it
+    /// and returns true. Such messages must not be relayed to the extension.
+    pub(crate) fn complete_probe(&self, envelope: &Envelope) -> bool {
+        let Some(task_id) = envelope.task_id.as_deref() else {
+            return false;
+        };
+        if !task_id.starts_with(PROBE_PREFIX) {
+            return false;
+        }
+        if let Some(done) = self.pending.lock().unwrap().remove(task_id) {
+            let _ = done.send(());
+        }
+        true // Late replies to timed-out probes are swallowed too
+    }
+
+    /// Runs the self-test in the background and sends the report to the extension.
+    pub(crate) fn spawn(
+        self: &Arc<Self>,
+        task_id: String,
+        to_ipc: mpsc::Sender<Queued>,
+        to_ext: mpsc::Sender<Queued>,
+    ) {
+        let this = Arc::clone(self);
+        tokio::spawn(async move {
+            let report = this.run(task_id, to_ipc).await;
+            match serde_json::to_vec(&report) {
+                Ok(bytes) => {
+                    if to_ext.send(bytes.into()).await.is_err() {
+                        log::error!("SelfTest: Extension channel closed before the report could be sent.");
+                    }
+                }
+                Err(e) => log::error!("SelfTest: Failed to serialize report: {}", e),
+            }
+        });
+    }
+
+    async fn run(&self, task_id: String, to_ipc: mpsc::Sender<Queued>) -> ExtensionResponse {
+        log::info!("SelfTest: Running bridge self-test (task_id: {})", task_id);
+        let native_framing = check_native_framing().await;
+        let ipc_framing = check_ipc_framing().await;
+        let ipc = self.probe_ipc(&to_ipc).await;
+
+        let mut errors = Vec::new();
+        for (name, result) in [("native_framing", &native_framing), ("ipc_framing", &ipc_framing)] {
+            if let Err(e) = result {
+                errors.push(format!("{}: {}", name, e));
+            }
+        }
+        let ipc_connectivity = match &ipc {
+            Ok(latency) => json!({ "ok": true, "latency_ms": latency.as_secs_f64() * 1000.0 }),
+            Err(e) => {
+                errors.push(format!("ipc_connectivity: {}", e));
+                json!({ "ok": false, "error": e })
+            }
+        };
+
+        ExtensionResponse {
+            action: Action::SelftestResult,
+            task_id,
+            success: errors.is_empty(),
+            result: Some(json!({
+                "broker_version": env!("CARGO_PKG_VERSION"),
+                "native_framing": check_json(&native_framing),
+                "ipc_framing": check_json(&ipc_framing),
+                "ipc_connectivity": ipc_connectivity,
+            })),
+            error: (!errors.is_empty()).then(|| errors.join("; ")),
+        }
+    }
+
+    /// Sends a `ping` to the Main App and waits for the matching `pong`.
+    async fn probe_ipc(&self, to_ipc: &mpsc::Sender<Queued>) -> Result<Duration, String> {
+        let probe_id = format!("{}{}", PROBE_PREFIX, self.next_probe.fetch_add(1, Ordering::Relaxed));
+        let (done_tx, done_rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(probe_id.clone(), done_tx);
+
+        let probe = json!({ "action": "ping", "task_id": probe_id });
+        let started = Instant::now();
+        if to_ipc.send(probe.to_string().into_bytes().into()).await.is_err() {
+            self.pending.lock().unwrap().remove(&probe_id);
+            return Err("IPC channel closed".to_string());
+        }
+        match tokio::time::timeout(PROBE_TIMEOUT, done_rx).await {
+            Ok(Ok(())) => Ok(started.elapsed()),
+            Ok(Err(_)) => Err("probe cancelled".to_string()),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&probe_id);
+                Err(format!("no reply from Main App within {:?}", PROBE_TIMEOUT))
+            }
+        }
+    }
+}
+
+fn check_json(result: &Result<(), String>) -> serde_json::Value {
+    match result {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => json!({ "ok": false, "error": e }),
+    }
+}
+
+// Payload used for the in-memory framing round-trips
+fn sample_payload() -> Vec<u8> {
+    json!({ "action": SELFTEST_ACTION, "task_id": "framing-check", "data": "x".repeat(1024) })
+        .to_string()
+        .into_bytes()
+}
+
+/// Round-trips a message through the native messaging framing in memory.
+async fn check_native_framing() -> Result<(), String> {
+    let payload = sample_payload();
+    let mut buf = Vec::new();
+    write_message_bytes(&mut buf, &payload, "SelfTest").await.map_err(|e| e.to_string())?;
+    match read_message_bytes(&mut buf.as_slice(), "SelfTest").await {
+        Ok(Some(read)) if read == payload => Ok(()),
+        Ok(_) => Err("payload mismatch after round-trip".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Round-trips a message through the IPC frame header framing in memory.
+async fn check_ipc_framing() -> Result<(), String> {
+    let payload = sample_payload();
+    let mut buf = Vec::new();
+    write_frame(&mut buf, FrameFlags::PRIORITY, 7, &payload, "SelfTest").await.map_err(|e| e.to_string())?;
+    match read_frame(&mut buf.as_slice(), "SelfTest").await {
+        Ok(Some(frame))
+            if frame.payload == payload
+                && frame.header.channel_id == 7
+                && frame.header.flags == FrameFlags::PRIORITY =>
+        {
+            Ok(())
+        }
+        Ok(_) => Err("frame mismatch after round-trip".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,121 @@
+//! Graceful shutdown on SIGTERM/SIGINT (console close or Ctrl+C on Windows).
+//!
+//! Killing the broker mid-frame leaves the extension and the Main App with a
+//! truncated message and no idea why the link went away. On a signal the relay
+//! instead stops reading from either side, lets the queues drain, and sends
+//! both sides a `shutdown` notice; the broker then exits normally.
+//!
+//! However the relay ends, its tasks stop together through a [`Stop`].
+
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use shared_types::Message;
+
+use crate::relay::Queued;
+
+/// Longest wait for the queues to drain before exiting anyway, e.g. while
+/// the Main App is unreachable.
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a group of relay tasks stops together. The first task to end cancels
+/// `stopping`: the readers stop reading, the writers write out and flush
+/// what is queued and end. Readers keep their streams until `flushed`, which
+/// the owner of the group cancels once the writers are done.
+#[derive(Clone, Default)]
+pub(crate) struct Stop {
+    pub(crate) stopping: CancellationToken,
+    pub(crate) flushed: CancellationToken,
+}
+
+impl Stop {
+    /// Runs `task`, stopping the group once it ends, however it ends.
+    pub(crate) async fn ending<T>(&self, task: impl Future<Output = T>) -> T {
+        let _stop = self.stopping.clone().drop_guard();
+        task.await
+    }
+
+    /// Runs `task` like [`Stop::ending`], but only until the group stops.
+    /// `None` if it was stopped.
+    pub(crate) async fn until_stopped<T>(&self, task: impl Future<Output = T>) -> Option<T> {
+        tokio::select! {
+            biased;
+            ended = self.ending(task) => Some(ended),
+            () = self.stopping.cancelled() => None,
+        }
+    }
+}
+
+/// Resolves with the name of the first shutdown signal the process receives.
+/// Never resolves if the signal handlers can't be installed.
+pub(crate) async fn signal() -> &'static str {
+    match wait_for_signal().await {
+        Ok(name) => name,
+        Err(e) => {
+            log::error!("Shutdown: Failed to listen for signals: {}", e);
+            std::future::pending().await
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = interrupt.recv() => Ok("SIGINT"),
+    }
+}
+
+#[cfg(windows)]
+async fn wait_for_signal() -> io::Result<&'static str> {
+    use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_shutdown};
+
+    let mut ctrl_c = ctrl_c()?;
+    let mut ctrl_break = ctrl_break()?;
+    let mut ctrl_close = ctrl_close()?;
+    let mut ctrl_shutdown = ctrl_shutdown()?;
+    tokio::select! {
+        _ = ctrl_c.recv() => Ok("CTRL_C"),
+        _ = ctrl_break.recv() => Ok("CTRL_BREAK"),
+        _ = ctrl_close.recv() => Ok("CTRL_CLOSE"),
+        _ = ctrl_shutdown.recv() => Ok("CTRL_SHUTDOWN"),
+    }
+}
+
+/// Sends a `shutdown` notice behind everything already queued for the Main
+/// App (`host_tx`) and the extension (`native_tx`), and waits until both are
+/// written or [`DRAIN_TIMEOUT`] runs out.
+pub(crate) async fn drain(host_tx: &mpsc::Sender<Queued>, native_tx: &mpsc::Sender<Queued>, reason: &str) {
+    let notice = match serde_json::to_vec(&Message::shutdown("broker", Some(reason.to_string()))) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Shutdown: Failed to serialize shutdown notice: {}", e);
+            return;
+        }
+    };
+    let (to_host, host_written) = Queued::with_receipt(notice.clone());
+    let (to_extension, extension_written) = Queued::with_receipt(notice);
+    let host = async {
+        host_tx.send(to_host).await.is_ok() && host_written.await.is_ok()
+    };
+    let extension = async {
+        native_tx.send(to_extension).await.is_ok() && extension_written.await.is_ok()
+    };
+    match tokio::time::timeout(DRAIN_TIMEOUT, async { tokio::join!(host, extension) }).await {
+        Ok((true, true)) => log::info!("Shutdown: Queues drained, both sides notified."),
+        Ok((host, extension)) => log::warn!("Shutdown: Could not notify {}.", match (host, extension) {
+            (false, false) => "either side",
+            (false, _) => "the Main App",
+            _ => "the extension",
+        }),
+        Err(_) => log::warn!("Shutdown: Queues not drained after {:?}, exiting anyway.", DRAIN_TIMEOUT),
+    }
+}
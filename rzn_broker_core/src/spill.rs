@@ -0,0 +1,250 @@
+//! Spilling the extension's messages to disk while the Main App is away.
+//!
+//! Without a spill, the messages held while reconnecting (see
+//! [`ReconnectPolicy`](crate::ReconnectPolicy)) are kept in memory, at most
+//! `max_buffered` of them, and are lost with the broker process. With a
+//! [`SpillConfig`] they go to a sled database instead, up to its size, and
+//! are replayed in order once a Main App is connected, by this broker or the
+//! next one the browser starts. Those not written by the time the connection
+//! or the broker ends are spilled again.
+//!
+//! A spilled message past the spill's TTL, or its own `ttl_ms`, is not
+//! replayed: the extension gets a `dead_letter` ([`DeadLetter`]) for it
+//! instead, as soon as the broker notices. Only the primary Main App's
+//! messages are spilled, as a database is opened by one broker at a time; a
+//! broker that can't open it holds messages in memory.
+
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use shared_types::{peek_envelope, BridgeConfig, DeadLetter, ExtensionResponse};
+
+use crate::metrics;
+use crate::relay::Queued;
+
+// Spilled-at and expires-at times (ms since the epoch, 0 for none) and whether it
+// is the extension's own (see `Queued::redeliver`) before each message
+const HEADER_LEN: usize = 17;
+
+/// Where and how much the broker spills.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpillConfig {
+    /// Directory of the database.
+    pub path: PathBuf,
+    /// Bytes of messages kept at most; the oldest are dropped beyond this.
+    pub max_bytes: u64,
+    /// How long a message is kept before it is a dead letter.
+    pub ttl: Duration,
+}
+
+impl SpillConfig {
+    /// Spills to `path`, keeping up to 64 MiB of messages for a day.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SpillConfig { path: path.into(), max_bytes: 64 * 1024 * 1024, ttl: Duration::from_secs(24 * 60 * 60) }
+    }
+
+    /// `spill/` next to the bridge config file.
+    pub fn default_path() -> Option<PathBuf> {
+        BridgeConfig::path().map(|path| path.with_file_name("spill"))
+    }
+
+    /// A spill if `RZN_SPILL` is set, to the directory it names or to
+    /// [`default_path`](Self::default_path) for `1`, adjusted by
+    /// `RZN_SPILL_MAX_BYTES` and `RZN_SPILL_TTL_MS`.
+    pub fn from_env() -> Option<Self> {
+        let path = match std::env::var("RZN_SPILL") {
+            Ok(value) if value == "0" || value.is_empty() => return None,
+            Ok(value) if value == "1" => Self::default_path()?,
+            Ok(value) => PathBuf::from(value),
+            Err(_) => return None,
+        };
+        let mut config = SpillConfig::new(path);
+        if let Ok(bytes) = std::env::var("RZN_SPILL_MAX_BYTES") {
+            match bytes.trim().parse() {
+                Ok(bytes) => config.max_bytes = bytes,
+                Err(_) => log::warn!("Ignoring invalid RZN_SPILL_MAX_BYTES={:?}", bytes),
+            }
+        }
+        if let Ok(ttl) = std::env::var("RZN_SPILL_TTL_MS") {
+            match ttl.trim().parse() {
+                Ok(ms) => config.ttl = Duration::from_millis(ms),
+                Err(_) => log::warn!("Ignoring invalid RZN_SPILL_TTL_MS={:?}", ttl),
+            }
+        }
+        Some(config)
+    }
+}
+
+/// The spilled messages, oldest first.
+pub(crate) struct Spill {
+    db: sled::Db,
+    config: SpillConfig,
+    /// Size of the spilled messages.
+    bytes: u64,
+}
+
+impl Spill {
+    pub(crate) fn open(config: SpillConfig) -> io::Result<Self> {
+        let db = sled::open(&config.path).map_err(io::Error::other)?;
+        let bytes = db.iter().values().filter_map(Result::ok).map(|value| value.len() as u64).sum();
+        if !db.is_empty() {
+            log::info!("Spill: {} message(s) left in {} by an earlier broker.", db.len(), config.path.display());
+        }
+        Ok(Spill { db, config, bytes })
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.config.path
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    /// Writes `queued` behind the messages spilled before, making room by
+    /// dropping the oldest ones.
+    pub(crate) fn push(&mut self, queued: &Queued) -> io::Result<()> {
+        let now = now_ms();
+        let expires_at = queued.expires_at.map_or(0, |at| now + at.saturating_duration_since(Instant::now()).as_millis() as u64);
+        let mut value = Vec::with_capacity(HEADER_LEN + queued.bytes.len());
+        value.extend_from_slice(&now.to_be_bytes());
+        value.extend_from_slice(&expires_at.to_be_bytes());
+        value.push(u8::from(queued.redeliver));
+        value.extend_from_slice(&queued.bytes);
+        // Monotonic across restarts, so the keys sort oldest first
+        let id = self.db.generate_id().map_err(io::Error::other)?;
+        self.bytes += value.len() as u64;
+        self.db.insert(id.to_be_bytes(), value).map_err(io::Error::other)?;
+        while self.bytes > self.config.max_bytes {
+            let Some((_, oldest)) = self.db.pop_min().map_err(io::Error::other)? else {
+                break;
+            };
+            log::warn!("Spill: Over {} bytes, dropping the oldest message.", self.config.max_bytes);
+            metrics::record_dropped_while_disconnected();
+            self.bytes = self.bytes.saturating_sub(oldest.len() as u64);
+        }
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Removes the messages that expired and returns their dead letters.
+    pub(crate) fn expire(&mut self) -> io::Result<Vec<ExtensionResponse>> {
+        let now = now_ms();
+        let mut letters = Vec::new();
+        for entry in self.db.iter() {
+            let (key, value) = entry.map_err(io::Error::other)?;
+            if let Some(letter) = self.dead_letter(&value, now) {
+                self.remove(&key)?;
+                letters.push(letter);
+            }
+        }
+        Ok(letters)
+    }
+
+    /// Takes every spilled message, oldest first. The ones that expired are
+    /// left out, and their dead letters returned instead.
+    pub(crate) fn drain(&mut self) -> io::Result<(Vec<Queued>, Vec<ExtensionResponse>)> {
+        let now = now_ms();
+        let (mut messages, mut letters) = (Vec::new(), Vec::new());
+        while let Some((_, value)) = self.db.pop_min().map_err(io::Error::other)? {
+            self.bytes = self.bytes.saturating_sub(value.len() as u64);
+            if let Some(letter) = self.dead_letter(&value, now) {
+                letters.push(letter);
+                continue;
+            }
+            let Some((_, expires_at, redeliver, bytes)) = decode(&value) else {
+                log::warn!("Spill: Dropping unreadable message.");
+                continue;
+            };
+            let mut queued = Queued::from(bytes.to_vec());
+            queued.redeliver = redeliver;
+            queued.expires_at = (expires_at != 0).then(|| Instant::now() + Duration::from_millis(expires_at.saturating_sub(now)));
+            messages.push(queued);
+        }
+        self.db.flush().map_err(io::Error::other)?;
+        Ok((messages, letters))
+    }
+
+    fn remove(&mut self, key: &[u8]) -> io::Result<()> {
+        if let Some(value) = self.db.remove(key).map_err(io::Error::other)? {
+            self.bytes = self.bytes.saturating_sub(value.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// The dead letter of a spilled message that expired by `now`.
+    fn dead_letter(&self, value: &[u8], now: u64) -> Option<ExtensionResponse> {
+        let (spilled_at, expires_at, _, bytes) = decode(value)?;
+        let held_ms = now.saturating_sub(spilled_at);
+        if held_ms < self.config.ttl.as_millis() as u64 && (expires_at == 0 || now < expires_at) {
+            return None;
+        }
+        let envelope = peek_envelope(bytes);
+        log::warn!("Spill: Message (action: {}, task_id: {}) expired after {} ms.",
+                 envelope.action.as_deref().unwrap_or("N/A"), envelope.task_id.as_deref().unwrap_or("N/A"), held_ms);
+        metrics::record_dead_letter();
+        let letter = DeadLetter { action: envelope.action.map(|action| action.into_owned()), held_ms };
+        Some(ExtensionResponse::dead_letter(envelope.task_id.as_deref().unwrap_or("N/A"), &letter))
+    }
+}
+
+/// Spilled-at time, expires-at time, whether it is the extension's own and
+/// bytes of a spilled message.
+fn decode(value: &[u8]) -> Option<(u64, u64, bool, &[u8])> {
+    let (header, bytes) = (value.get(..HEADER_LEN)?, &value[HEADER_LEN..]);
+    let time = |at: usize| u64::from_be_bytes(header[at..at + 8].try_into().unwrap_or_default());
+    Some((time(0), time(8), header[16] != 0, bytes))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spill_in(dir: &std::path::Path) -> SpillConfig {
+        SpillConfig { max_bytes: 1024, ..SpillConfig::new(dir) }
+    }
+
+    #[test]
+    fn replays_in_order_across_brokers_and_expires_the_rest() {
+        let dir = std::env::temp_dir().join(format!("rzn-spill-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        {
+            let mut spill = Spill::open(spill_in(&dir)).unwrap();
+            spill.push(&Queued::from(br#"{"action":"log","task_id":"1"}"#.to_vec())).unwrap();
+            spill.push(&Queued::from(br#"{"action":"task_result","task_id":"2"}"#.to_vec())).unwrap();
+            let mut stale = Queued::from(br#"{"action":"task_result","task_id":"3"}"#.to_vec());
+            stale.expires_at = Some(Instant::now());
+            spill.push(&stale).unwrap();
+        }
+
+        // The next broker finds them, once sled's threads let go of the first
+        let mut spill = (0..100)
+            .find_map(|_| Spill::open(spill_in(&dir)).ok().or_else(|| { std::thread::sleep(Duration::from_millis(20)); None }))
+            .unwrap();
+        assert_eq!(spill.len(), 3);
+        let letters = spill.expire().unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!((letters[0].task_id.as_str(), letters[0].result.as_ref().unwrap()["action"].as_str()), ("3", Some("task_result")));
+        let (messages, letters) = spill.drain().unwrap();
+        assert!(letters.is_empty());
+        let replayed: Vec<_> = messages.iter().map(|queued| peek_envelope(&queued.bytes).task_id.unwrap().into_owned()).collect();
+        assert_eq!(replayed, ["1", "2"]);
+        assert_eq!((spill.len(), spill.bytes), (0, 0));
+
+        // Beyond the size limit the oldest go
+        for n in 0..40 {
+            spill.push(&Queued::from(format!(r#"{{"action":"log","task_id":"{}"}}"#, n).into_bytes())).unwrap();
+        }
+        assert!(spill.bytes <= 1024);
+        let (messages, _) = spill.drain().unwrap();
+        assert_eq!(peek_envelope(&messages.last().unwrap().bytes).task_id.as_deref(), Some("39"));
+        assert!(messages.len() < 40);
+        drop(spill);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
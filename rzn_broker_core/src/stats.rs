@@ -0,0 +1,455 @@
+//! Per-origin task statistics.
+//!
+//! The broker sees every task go out to the extension and its result come
+//! back, so it can tell which sites automations keep failing on. A task counts
+//! towards the origin of its first `navigate` step. Like the relay counters,
+//! the statistics are process-wide; Main Apps query them with a `bridge_stats`
+//! message, optionally for the tasks carrying one tag only, and get the
+//! state of the broker's Main App connections along with them. The most recent
+//! tasks are also kept one by one, for `bridge_history` queries that filter
+//! them and summarize their durations.
+//!
+//! Each selector is tracked per origin too. When one that succeeded
+//! [`RELIABLE_RUNS`] times in a row fails, the Main App is sent a
+//! `selector_degraded` report so the task can be fixed before it breaks for good.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use shared_types::{
+    Action, url_origin, BridgeStats, DurationSummary, ExtensionResponse, HistoryPage, HistoryQuery, OriginStats, SelectorDegradation,
+    StatsQuery, StepErrorKind, TaskRecord, TaskStatus, PERFORM_TASK_ACTION, TASK_RESULT_ACTION,
+};
+
+use crate::connections::connection_states;
+
+// Tasks that never report a result stop being tracked beyond this
+const MAX_RUNNING: usize = 4096;
+// Origin of tasks without a navigate step
+const NO_ORIGIN: &str = "(none)";
+/// Successes in a row after which a failing selector is reported.
+pub(crate) const RELIABLE_RUNS: u64 = 5;
+// Selectors first seen beyond this are not tracked
+const MAX_SELECTORS: usize = 4096;
+// Tags first seen beyond this are not tracked
+const MAX_TAGS: usize = 256;
+// Finished tasks kept for history queries; the oldest are forgotten first
+const MAX_HISTORY: usize = 1000;
+// History page size when the query doesn't set one, and the largest allowed
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker::new());
+
+/// Returns the statistics of every origin, most tasks first.
+pub fn origin_stats() -> Vec<OriginStats> {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner()).snapshot(None)
+}
+
+/// Same as [`origin_stats`], counting only the tasks tagged `tag`.
+pub fn tagged_origin_stats(tag: &str) -> Vec<OriginStats> {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner()).snapshot(Some(tag))
+}
+
+/// Returns the page of recently finished tasks matching `query`, newest
+/// first, and the duration percentiles of all matches. Only the last 1000
+/// tasks are kept.
+pub fn task_history(query: &HistoryQuery) -> HistoryPage {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner()).history(query)
+}
+
+/// Starts the clock for a `perform_task` delivered to the extension.
+pub(crate) fn record_task_sent(value: &Value) {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner()).sent(value, Instant::now());
+}
+
+/// Counts a `task_result` from the extension towards its task's origin.
+/// Returns the reliable selectors it saw fail.
+pub(crate) fn record_task_result(value: &Value) -> Vec<SelectorDegradation> {
+    TRACKER.lock().unwrap_or_else(|e| e.into_inner()).finished(value, Instant::now())
+}
+
+/// The `selector_degraded` message reporting `degradation` in task `task_id`.
+pub(crate) fn degradation_event(task_id: &str, degradation: &SelectorDegradation) -> ExtensionResponse {
+    ExtensionResponse {
+        action: Action::SelectorDegraded,
+        task_id: task_id.to_string(),
+        success: true,
+        result: serde_json::to_value(degradation).ok(),
+        error: None,
+    }
+}
+
+/// The broker's answer if `value` is a `bridge_stats` or `bridge_history` request.
+pub(crate) fn stats_response(value: &Value) -> Option<ExtensionResponse> {
+    let (action, result_action) = match Action::of(value)? {
+        Action::Stats => (Action::Stats, Action::StatsResult),
+        Action::History => (Action::History, Action::HistoryResult),
+        _ => return None,
+    };
+    let task_id = value.get("task_id").and_then(|v| v.as_str()).unwrap_or("N/A").to_string();
+    // Both queries may be left out entirely
+    let data = value.get("data").filter(|data| !data.is_null()).cloned().unwrap_or_else(|| serde_json::json!({}));
+    let result = if action == Action::Stats {
+        serde_json::from_value::<StatsQuery>(data).map(|query| {
+            let origins = match &query.tag {
+                Some(tag) => tagged_origin_stats(tag),
+                None => origin_stats(),
+            };
+            serde_json::to_value(BridgeStats { origins, connections: connection_states() }).ok()
+        })
+    } else {
+        serde_json::from_value::<HistoryQuery>(data).map(|query| serde_json::to_value(task_history(&query)).ok())
+    };
+    Some(match result {
+        Ok(result) => ExtensionResponse { action: result_action, task_id, success: true, result, error: None },
+        Err(e) => ExtensionResponse {
+            action: result_action,
+            task_id,
+            success: false,
+            result: None,
+            error: Some(format!("Invalid {} query: {}", action, e)),
+        },
+    })
+}
+
+struct Tracker {
+    /// Tasks in flight, by task ID.
+    running: BTreeMap<String, Running>,
+    origins: BTreeMap<String, Totals>,
+    /// Like `origins`, for the tasks carrying each tag.
+    tagged: BTreeMap<String, BTreeMap<String, Totals>>,
+    /// By origin and serialized selector.
+    selectors: BTreeMap<(String, String), SelectorHealth>,
+    /// Most recently finished tasks, oldest first.
+    history: VecDeque<TaskRecord>,
+}
+
+struct Running {
+    origin: String,
+    started: Instant,
+    /// Selector of each step, if it has one.
+    selectors: Vec<Option<Value>>,
+    tags: Vec<String>,
+}
+
+/// Successes of a selector since it last failed.
+struct SelectorHealth {
+    streak: u64,
+    last_success: SystemTime,
+}
+
+#[derive(Default)]
+struct Totals {
+    succeeded: u64,
+    failed: u64,
+    duration: Duration,
+    failure_codes: BTreeMap<String, u64>,
+}
+
+impl Tracker {
+    const fn new() -> Self {
+        Tracker {
+            running: BTreeMap::new(),
+            origins: BTreeMap::new(),
+            tagged: BTreeMap::new(),
+            selectors: BTreeMap::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    fn sent(&mut self, value: &Value, now: Instant) {
+        if value.get("action").and_then(|v| v.as_str()) != Some(PERFORM_TASK_ACTION) {
+            return;
+        }
+        let Some(task_id) = value.get("task_id").and_then(|v| v.as_str()) else { return };
+        if self.running.len() >= MAX_RUNNING {
+            log::warn!("Stats: Tracking {} tasks without results, not counting task {}.", MAX_RUNNING, task_id);
+            return;
+        }
+        let steps = value.get("task").and_then(|task| task.get("steps")).and_then(|steps| steps.as_array());
+        let origin = steps
+            .and_then(|steps| {
+                steps.iter().find_map(|step| match step.get("type").and_then(|v| v.as_str()) {
+                    Some("navigate") => step.get("url").and_then(|v| v.as_str()).and_then(url_origin),
+                    _ => None,
+                })
+            })
+            .unwrap_or_else(|| NO_ORIGIN.to_string());
+        let selectors = steps.map_or_else(Vec::new, |steps| steps.iter().map(|step| step.get("selector").cloned()).collect());
+        let tags = value
+            .get("task")
+            .and_then(|task| task.get("tags"))
+            .and_then(|tags| tags.as_array())
+            .map_or_else(Vec::new, |tags| tags.iter().filter_map(|tag| tag.as_str().map(str::to_string)).collect());
+        self.running.insert(task_id.to_string(), Running { origin, started: now, selectors, tags });
+    }
+
+    fn finished(&mut self, value: &Value, now: Instant) -> Vec<SelectorDegradation> {
+        if value.get("action").and_then(|v| v.as_str()) != Some(TASK_RESULT_ACTION) {
+            return Vec::new();
+        }
+        // Results of tasks that didn't go through this broker can't be attributed
+        let Some((task_id, running)) = value.get("task_id").and_then(|v| v.as_str()).and_then(|id| self.running.remove_entry(id)) else {
+            return Vec::new();
+        };
+        let duration = now.saturating_duration_since(running.started);
+        let failure = (value.get("success").and_then(|v| v.as_bool()) != Some(true)).then(|| failure_code(value));
+        self.origins.entry(running.origin.clone()).or_default().add(duration, failure.as_deref());
+        for tag in &running.tags {
+            if !self.tagged.contains_key(tag) && self.tagged.len() >= MAX_TAGS {
+                log::warn!("Stats: Tracking {} tags already, not counting tag {:?}.", MAX_TAGS, tag);
+                continue;
+            }
+            let origins = self.tagged.entry(tag.clone()).or_default();
+            origins.entry(running.origin.clone()).or_default().add(duration, failure.as_deref());
+        }
+        if self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(TaskRecord {
+            task_id,
+            origin: running.origin.clone(),
+            tags: running.tags.clone(),
+            status: if failure.is_some() { TaskStatus::Failed } else { TaskStatus::Succeeded },
+            failure_code: failure,
+            finished_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+            duration_ms: duration.as_millis() as u64,
+        });
+        self.check_selectors(&running, value)
+    }
+
+    /// Updates the selectors' streaks from the step results and returns the
+    /// reliable ones that failed.
+    fn check_selectors(&mut self, running: &Running, value: &Value) -> Vec<SelectorDegradation> {
+        let Some(steps) = value.get("result").and_then(|r| r.get("steps")).and_then(|s| s.as_array()) else {
+            return Vec::new();
+        };
+        let mut degraded = Vec::new();
+        for (step_index, (step, selector)) in steps.iter().zip(&running.selectors).enumerate() {
+            let Some(selector) = selector else { continue };
+            let error_kind = step.get("error_kind").cloned().and_then(|kind| serde_json::from_value(kind).ok());
+            let found = match (step.get("success").and_then(|v| v.as_bool()), error_kind) {
+                // A value that didn't fit its type was still found
+                (Some(true), _) | (_, Some(StepErrorKind::Coercion)) => true,
+                // An abort or cancellation says nothing about the selector
+                (_, Some(StepErrorKind::Aborted | StepErrorKind::Cancelled)) => continue,
+                _ => false,
+            };
+            let key = (running.origin.clone(), selector.to_string());
+            if found {
+                if !self.selectors.contains_key(&key) && self.selectors.len() >= MAX_SELECTORS {
+                    continue;
+                }
+                let health = self.selectors.entry(key).or_insert(SelectorHealth { streak: 0, last_success: SystemTime::now() });
+                health.streak += 1;
+                health.last_success = SystemTime::now();
+                continue;
+            }
+            let Some(health) = self.selectors.get_mut(&key) else { continue };
+            if health.streak >= RELIABLE_RUNS {
+                log::warn!("Stats: Selector {} on {} failed after {} successful runs.", key.1, key.0, health.streak);
+                degraded.push(SelectorDegradation {
+                    origin: key.0.clone(),
+                    step_index,
+                    step_type: step.get("type").and_then(|v| v.as_str()).unwrap_or("unknown").to_string(),
+                    selector: selector.clone(),
+                    error: step.get("error").and_then(|v| v.as_str()).map(str::to_string),
+                    error_kind,
+                    successes: health.streak,
+                    last_success_ms: health.last_success.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+                });
+            }
+            health.streak = 0;
+        }
+        degraded
+    }
+
+    fn history(&self, query: &HistoryQuery) -> HistoryPage {
+        let matches: Vec<&TaskRecord> = self.history.iter().rev().filter(|record| query.matches(record)).collect();
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE).min(MAX_PAGE);
+        HistoryPage {
+            total: matches.len(),
+            records: matches.iter().skip(query.offset).take(limit).map(|&record| record.clone()).collect(),
+            durations: DurationSummary::from_durations(matches.iter().map(|record| record.duration_ms).collect()),
+        }
+    }
+
+    fn snapshot(&self, tag: Option<&str>) -> Vec<OriginStats> {
+        let origins = match tag {
+            Some(tag) => match self.tagged.get(tag) {
+                Some(origins) => origins,
+                None => return Vec::new(),
+            },
+            None => &self.origins,
+        };
+        let mut stats: Vec<OriginStats> = origins
+            .iter()
+            .map(|(origin, totals)| {
+                let finished = (totals.succeeded + totals.failed).max(1);
+                let mut failure_codes: Vec<(String, u64)> =
+                    totals.failure_codes.iter().map(|(code, count)| (code.clone(), *count)).collect();
+                failure_codes.sort_by_key(|&(_, count)| Reverse(count));
+                OriginStats {
+                    origin: origin.clone(),
+                    succeeded: totals.succeeded,
+                    failed: totals.failed,
+                    average_duration_ms: (totals.duration.as_millis() / u128::from(finished)) as u64,
+                    failure_codes,
+                }
+            })
+            .collect();
+        stats.sort_by_key(|origin| Reverse(origin.succeeded + origin.failed));
+        stats
+    }
+}
+
+impl Totals {
+    /// Counts a finished task; `failure` is its failure code if it failed.
+    fn add(&mut self, duration: Duration, failure: Option<&str>) {
+        self.duration += duration;
+        match failure {
+            None => self.succeeded += 1,
+            Some(code) => {
+                self.failed += 1;
+                *self.failure_codes.entry(code.to_string()).or_default() += 1;
+            }
+        }
+    }
+}
+
+/// The failed step's error kind, else the `[CODE]` the error starts with,
+/// else "other".
+fn failure_code(value: &Value) -> String {
+    let steps = value.get("result").and_then(|r| r.get("steps")).and_then(|s| s.as_array());
+    let failed_step = steps.and_then(|steps| steps.iter().find(|step| step.get("success").and_then(|v| v.as_bool()) == Some(false)));
+    if let Some(kind) = failed_step.and_then(|step| step.get("error_kind")).and_then(|v| v.as_str()) {
+        return kind.to_string();
+    }
+    value
+        .get("error")
+        .and_then(|v| v.as_str())
+        .and_then(|error| error.strip_prefix('['))
+        .and_then(|error| error.split_once(']'))
+        .map_or_else(|| "other".to_string(), |(code, _)| code.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn aggregates_results_by_origin() {
+        let mut tracker = Tracker::new();
+        let start = Instant::now();
+        let task = |id: &str, url: &str| json!({
+            "action": "perform_task",
+            "task_id": id,
+            "task": { "steps": [{ "type": "navigate", "url": url }, { "type": "click", "selector": ".buy" }] },
+        });
+        tracker.sent(&task("t1", "https://Shop.example.com/cart?x=1"), start);
+        tracker.sent(&task("t2", "https://shop.example.com/"), start);
+        tracker.sent(&task("t3", "http://other.test:8080"), start);
+        tracker.finished(&json!({ "action": "task_result", "task_id": "t1", "success": true }), start + Duration::from_millis(100));
+        tracker.finished(&json!({
+            "action": "task_result",
+            "task_id": "t2",
+            "success": false,
+            "result": { "steps": [{ "type": "navigate", "success": true }, { "type": "click", "success": false, "error_kind": "timeout" }] },
+        }), start + Duration::from_millis(300));
+        tracker.finished(&json!({ "action": "task_result", "task_id": "t3", "success": false, "error": "[E_PAUSED] full" }), start);
+
+        let stats = tracker.snapshot(None);
+        assert_eq!(stats[0].origin, "https://shop.example.com");
+        assert_eq!((stats[0].succeeded, stats[0].failed, stats[0].average_duration_ms), (1, 1, 200));
+        assert_eq!(stats[0].failure_codes, [("timeout".to_string(), 1)]);
+        assert_eq!(stats[1].origin, "http://other.test:8080");
+        assert_eq!(stats[1].failure_codes, [("E_PAUSED".to_string(), 1)]);
+        assert!(tracker.running.is_empty());
+    }
+
+    #[test]
+    fn filters_by_tag() {
+        let mut tracker = Tracker::new();
+        let now = Instant::now();
+        let task = |id: &str, tags: Value| json!({
+            "action": "perform_task",
+            "task_id": id,
+            "task": { "steps": [{ "type": "navigate", "url": "https://shop.example.com/" }], "tags": tags },
+        });
+        tracker.sent(&task("t1", json!(["checkout", "beta"])), now);
+        tracker.sent(&task("t2", json!(["search"])), now);
+        tracker.finished(&json!({ "action": "task_result", "task_id": "t1", "success": true }), now);
+        tracker.finished(&json!({ "action": "task_result", "task_id": "t2", "success": false }), now);
+
+        let checkout = tracker.snapshot(Some("checkout"));
+        assert_eq!((checkout[0].succeeded, checkout[0].failed), (1, 0));
+        assert_eq!(tracker.snapshot(Some("search"))[0].failed, 1);
+        assert!(tracker.snapshot(Some("other")).is_empty());
+        assert_eq!(tracker.snapshot(None)[0].succeeded + tracker.snapshot(None)[0].failed, 2);
+    }
+
+    #[test]
+    fn pages_and_summarizes_the_history() {
+        let mut tracker = Tracker::new();
+        let start = Instant::now();
+        for n in 0..10u64 {
+            let id = format!("t{}", n);
+            let tags = if n % 2 == 0 { json!(["even"]) } else { json!([]) };
+            tracker.sent(&json!({ "action": "perform_task", "task_id": id, "task": { "steps": [], "tags": tags } }), start);
+            let result = json!({ "action": "task_result", "task_id": id, "success": n != 3 });
+            tracker.finished(&result, start + Duration::from_millis(n * 100));
+        }
+
+        let page = tracker.history(&HistoryQuery { offset: 1, limit: Some(2), ..HistoryQuery::default() });
+        assert_eq!(page.total, 10);
+        let ids: Vec<&str> = page.records.iter().map(|record| record.task_id.as_str()).collect();
+        assert_eq!(ids, ["t8", "t7"]);
+        assert_eq!((page.durations.p50_ms, page.durations.p90_ms, page.durations.max_ms), (400, 800, 900));
+
+        let even = tracker.history(&HistoryQuery { tag: Some("even".to_string()), min_duration_ms: Some(300), ..HistoryQuery::default() });
+        assert_eq!(even.records.iter().map(|record| record.duration_ms).collect::<Vec<_>>(), [800, 600, 400]);
+        let failed = tracker.history(&HistoryQuery { status: Some(TaskStatus::Failed), ..HistoryQuery::default() });
+        assert_eq!((failed.total, failed.records[0].failure_code.as_deref()), (1, Some("other")));
+    }
+
+    #[test]
+    fn reports_a_reliable_selector_that_starts_failing() {
+        let mut tracker = Tracker::new();
+        let now = Instant::now();
+        let mut run = |id: &str, clicked: bool| {
+            tracker.sent(&json!({
+                "action": "perform_task",
+                "task_id": id,
+                "task": { "steps": [{ "type": "navigate", "url": "https://shop.example.com/" }, { "type": "click", "selector": ".buy" }] },
+            }), now);
+            let click = if clicked {
+                json!({ "type": "click", "success": true })
+            } else {
+                json!({ "type": "click", "success": false, "error": "not found", "error_kind": "timeout" })
+            };
+            tracker.finished(&json!({
+                "action": "task_result",
+                "task_id": id,
+                "success": clicked,
+                "result": { "steps": [{ "type": "navigate", "success": true }, click] },
+            }), now)
+        };
+        assert!(run("new", false).is_empty());
+        for n in 0..RELIABLE_RUNS {
+            assert!(run(&format!("ok{}", n), true).is_empty());
+        }
+        let reports = run("broken", false);
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].step_index, reports[0].successes), (1, RELIABLE_RUNS));
+        assert_eq!(reports[0].selector, json!(".buy"));
+        assert_eq!(reports[0].error_kind, Some(StepErrorKind::Timeout));
+        // Reported once, not on every failure that follows
+        assert!(run("still-broken", false).is_empty());
+    }
+}
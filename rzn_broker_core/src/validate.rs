@@ -0,0 +1,26 @@
+//! Validation of tasks on their way from the Main App to the extension.
+//!
+//! A malformed task is answered by the broker with a failed `task_result`
+//! instead of being started in the browser and failing halfway through.
+
+use shared_types::{Action, ExtensionResponse, Task, PERFORM_TASK_ACTION};
+
+/// Returns the failed `task_result` to send back to the Main App if `value`
+/// is a `perform_task` whose task doesn't validate.
+pub(crate) fn reject_invalid_task(value: &serde_json::Value) -> Option<ExtensionResponse> {
+    if value.get("action").and_then(|v| v.as_str()) != Some(PERFORM_TASK_ACTION) {
+        return None;
+    }
+    // Tasks this broker can't parse (e.g. newer step types) are left to the extension
+    let task = serde_json::from_value::<Task>(value.get("task")?.clone()).ok()?;
+    let error = task.validate().err()?;
+    let task_id = value.get("task_id").and_then(|v| v.as_str()).unwrap_or("N/A");
+    log::warn!("Validate: Rejecting task {}: {}", task_id, error);
+    Some(ExtensionResponse {
+        action: Action::TaskResult,
+        task_id: task_id.to_string(),
+        success: false,
+        result: None,
+        error: Some(error.to_string()),
+    })
+}
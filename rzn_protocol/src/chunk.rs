@@ -0,0 +1,263 @@
+//! Chunked transfer of messages over the native messaging limit.
+//!
+//! Chrome delivers at most 1 MiB from a native host to an extension, and
+//! large results from the extension are better kept well under its own
+//! limit. A message over the limit is sent as its JSON text in pieces:
+//!
+//! 1. `chunk_start` ([`ChunkStart`]) with the message's length and number of pieces;
+//! 2. one `chunk_data` ([`ChunkData`]) per piece, numbered from 0;
+//! 3. `chunk_end` ([`ChunkEnd`]).
+//!
+//! Each carries the wrapped message's `task_id`, so logs and errors can name
+//! the task. The native pipe keeps messages in order, so pieces arriving out
+//! of order mean a broken transfer. The receiver puts the text back together
+//! with a [`Reassembler`]; the Main App only ever sees whole messages.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::frame::MessageTooLarge;
+use crate::peek::peek_envelope;
+
+pub const CHUNK_START_ACTION: &str = "chunk_start";
+pub const CHUNK_DATA_ACTION: &str = "chunk_data";
+pub const CHUNK_END_ACTION: &str = "chunk_end";
+/// Error code of a transfer that couldn't be put back together.
+pub const E_CHUNK: &str = "E_CHUNK";
+
+/// Largest message Chrome delivers from a native host to an extension.
+pub const NATIVE_TO_EXTENSION_LIMIT: usize = 1024 * 1024;
+/// Bytes of text per `chunk_data`. JSON escaping grows text at most sixfold,
+/// so a piece stays under [`NATIVE_TO_EXTENSION_LIMIT`].
+pub const CHUNK_TEXT_LEN: usize = 128 * 1024;
+/// Transfers a [`Reassembler`] keeps open at once.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// `data` of a `chunk_start`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkStart {
+    pub message_id: String,
+    /// Length of the whole message in bytes.
+    pub total_len: usize,
+    pub chunks: u32,
+    /// `action` of the wrapped message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+}
+
+/// `data` of a `chunk_data`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkData {
+    pub message_id: String,
+    pub seq: u32,
+    pub data: String,
+}
+
+/// `data` of a `chunk_end`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkEnd {
+    pub message_id: String,
+    pub chunks: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action")]
+enum ChunkMessage {
+    #[serde(rename = "chunk_start")]
+    Start { task_id: Option<String>, data: ChunkStart },
+    #[serde(rename = "chunk_data")]
+    Data { data: ChunkData },
+    #[serde(rename = "chunk_end")]
+    End { data: ChunkEnd },
+}
+
+/// Whether `message` is part of a chunked transfer, judged by its `action`.
+pub fn is_chunk(message: &[u8]) -> bool {
+    peek_envelope(message)
+        .action
+        .is_some_and(|action| matches!(&*action, CHUNK_START_ACTION | CHUNK_DATA_ACTION | CHUNK_END_ACTION))
+}
+
+/// Splits the serialized `message` into the messages of a chunked transfer
+/// named `message_id`, pieces of at most `chunk_len` bytes. `None` if the
+/// message isn't UTF-8 text.
+pub fn chunk_message(message: &[u8], message_id: &str, chunk_len: usize) -> Option<Vec<Vec<u8>>> {
+    let text = std::str::from_utf8(message).ok()?;
+    let envelope = peek_envelope(message);
+    let task_id = envelope.task_id.as_deref().unwrap_or("N/A");
+
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(chunk_len.max(4));
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+
+    let wrap = |action: &str, data: serde_json::Value| {
+        serde_json::to_vec(&serde_json::json!({ "action": action, "task_id": task_id, "data": data })).unwrap_or_default()
+    };
+    let chunks = pieces.len() as u32;
+    let start = ChunkStart {
+        message_id: message_id.to_string(),
+        total_len: message.len(),
+        chunks,
+        action: envelope.action.as_deref().map(str::to_string),
+    };
+    let mut messages = vec![wrap(CHUNK_START_ACTION, serde_json::to_value(start).ok()?)];
+    for (seq, piece) in pieces.into_iter().enumerate() {
+        let data = ChunkData { message_id: message_id.to_string(), seq: seq as u32, data: piece.to_string() };
+        messages.push(wrap(CHUNK_DATA_ACTION, serde_json::to_value(data).ok()?));
+    }
+    let end = ChunkEnd { message_id: message_id.to_string(), chunks };
+    messages.push(wrap(CHUNK_END_ACTION, serde_json::to_value(end).ok()?));
+    Some(messages)
+}
+
+/// A transfer that can't be completed; its pieces so far are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The message would be over the receiver's limit.
+    TooLarge(MessageTooLarge),
+    /// A piece or end for a transfer that wasn't started (or was dropped).
+    Unknown(String),
+    /// A piece out of order, or an end before all pieces.
+    OutOfOrder { message_id: String, expected: u32, got: u32 },
+    /// The pieces don't add up to the announced length.
+    LengthMismatch { message_id: String, expected: usize, got: usize },
+    /// More transfers open at once than a receiver keeps.
+    TooMany(String),
+    /// Not a valid chunk message.
+    Malformed(String),
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::TooLarge(too_large) => write!(f, "{}", too_large),
+            ChunkError::Unknown(id) => write!(f, "chunk for unknown transfer {}", id),
+            ChunkError::OutOfOrder { message_id, expected, got } => {
+                write!(f, "transfer {}: expected chunk {}, got {}", message_id, expected, got)
+            }
+            ChunkError::LengthMismatch { message_id, expected, got } => {
+                write!(f, "transfer {}: expected {} bytes, got {}", message_id, expected, got)
+            }
+            ChunkError::TooMany(id) => write!(f, "transfer {}: more than {} transfers open", id, MAX_IN_FLIGHT),
+            ChunkError::Malformed(e) => write!(f, "malformed chunk message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+struct Transfer {
+    start: ChunkStart,
+    text: String,
+    next_seq: u32,
+}
+
+/// Puts chunked transfers back together.
+pub struct Reassembler {
+    max_len: usize,
+    transfers: HashMap<String, Transfer>,
+}
+
+impl Reassembler {
+    /// Accepts messages of up to `max_len` bytes once put together.
+    pub fn new(max_len: usize) -> Self {
+        Reassembler { max_len, transfers: HashMap::new() }
+    }
+
+    /// Takes a `chunk_start`, `chunk_data` or `chunk_end`. Returns the whole
+    /// message once its `chunk_end` arrives.
+    pub fn accept(&mut self, message: &[u8]) -> Result<Option<Vec<u8>>, ChunkError> {
+        let message: ChunkMessage = serde_json::from_slice(message).map_err(|e| ChunkError::Malformed(e.to_string()))?;
+        match message {
+            ChunkMessage::Start { task_id, data: start } => {
+                if start.total_len > self.max_len {
+                    return Err(ChunkError::TooLarge(MessageTooLarge {
+                        len: start.total_len,
+                        limit: self.max_len,
+                        action: start.action,
+                        task_id,
+                    }));
+                }
+                if self.transfers.len() >= MAX_IN_FLIGHT && !self.transfers.contains_key(&start.message_id) {
+                    return Err(ChunkError::TooMany(start.message_id));
+                }
+                let transfer = Transfer { text: String::with_capacity(start.total_len), next_seq: 0, start };
+                self.transfers.insert(transfer.start.message_id.clone(), transfer);
+                Ok(None)
+            }
+            ChunkMessage::Data { data } => {
+                let transfer = self.transfers.get_mut(&data.message_id).ok_or_else(|| ChunkError::Unknown(data.message_id.clone()))?;
+                let error = if data.seq != transfer.next_seq {
+                    Some(ChunkError::OutOfOrder { message_id: data.message_id.clone(), expected: transfer.next_seq, got: data.seq })
+                } else if transfer.text.len() + data.data.len() > transfer.start.total_len {
+                    let got = transfer.text.len() + data.data.len();
+                    Some(ChunkError::LengthMismatch { message_id: data.message_id.clone(), expected: transfer.start.total_len, got })
+                } else {
+                    None
+                };
+                if let Some(error) = error {
+                    self.transfers.remove(&data.message_id);
+                    return Err(error);
+                }
+                transfer.text.push_str(&data.data);
+                transfer.next_seq += 1;
+                Ok(None)
+            }
+            ChunkMessage::End { data: end } => {
+                let transfer = self.transfers.remove(&end.message_id).ok_or_else(|| ChunkError::Unknown(end.message_id.clone()))?;
+                if transfer.next_seq != transfer.start.chunks || end.chunks != transfer.start.chunks {
+                    return Err(ChunkError::OutOfOrder { message_id: end.message_id, expected: transfer.start.chunks, got: transfer.next_seq });
+                }
+                if transfer.text.len() != transfer.start.total_len {
+                    return Err(ChunkError::LengthMismatch { message_id: end.message_id, expected: transfer.start.total_len, got: transfer.text.len() });
+                }
+                Ok(Some(transfer.text.into_bytes()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_and_reassembles_a_message() {
+        let message = serde_json::to_vec(&serde_json::json!({
+            "action": "task_result",
+            "task_id": "t1",
+            "result": { "text": "ünïcödé ".repeat(100) },
+        }))
+        .unwrap();
+        let messages = chunk_message(&message, "m1", 64).unwrap();
+        assert!(messages.len() > 3);
+        assert!(messages.iter().all(|m| is_chunk(m)));
+        let start: serde_json::Value = serde_json::from_slice(&messages[0]).unwrap();
+        assert_eq!((start["task_id"].as_str(), start["data"]["action"].as_str()), (Some("t1"), Some("task_result")));
+
+        let mut reassembler = Reassembler::new(message.len());
+        let (last, rest) = messages.split_last().unwrap();
+        for chunk in rest {
+            assert_eq!(reassembler.accept(chunk).unwrap(), None);
+        }
+        assert_eq!(reassembler.accept(last).unwrap(), Some(message.clone()));
+
+        // Pieces out of order end the transfer
+        reassembler.accept(&messages[0]).unwrap();
+        assert!(matches!(reassembler.accept(&messages[2]), Err(ChunkError::OutOfOrder { expected: 0, got: 1, .. })));
+        assert_eq!(reassembler.accept(&messages[1]), Err(ChunkError::Unknown("m1".to_string())));
+
+        let mut small = Reassembler::new(10);
+        assert!(matches!(small.accept(&messages[0]), Err(ChunkError::TooLarge(MessageTooLarge { limit: 10, .. }))));
+    }
+}
@@ -0,0 +1,127 @@
+//! Matching answers to the requests waiting for them.
+//!
+//! A task's outcome (`task_result`, or a `bridge_error` or
+//! `message_too_large` in its place) and the `task_cancelled` of a
+//! `cancel_task` and the `resolve_selectors_result` of a `resolve_selectors`
+//! answer by `task_id`; an `ack` answers by the `msg_id` in its `result`.
+//! A [`Correlator`] holds whatever waits for each answer, e.g. the sending
+//! half of a channel, and hands it back when the answer comes.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Action of the response the extension sends when a task finishes.
+pub const TASK_RESULT_ACTION: &str = "task_result";
+/// Acknowledgment of a `cancel_task`.
+pub const TASK_CANCELLED_ACTION: &str = "task_cancelled";
+/// Action of the structured errors the broker sends, in place of an answer.
+pub const BRIDGE_ERROR_ACTION: &str = "bridge_error";
+/// Sent back instead of a message that was over the receiver's size limit.
+pub const MESSAGE_TOO_LARGE_ACTION: &str = "message_too_large";
+/// Action of the broker's acknowledgment of a message from the Main App.
+pub const ACK_ACTION: &str = "ack";
+/// Answer to a `resolve_selectors`.
+pub const RESOLVE_SELECTORS_RESULT_ACTION: &str = "resolve_selectors_result";
+
+/// An answer that is waited for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expect {
+    /// The outcome of the task `task_id`.
+    Result,
+    /// The `task_cancelled` of the task `task_id`.
+    Cancellation,
+    /// The `ack` of the message `msg_id`.
+    Ack,
+    /// The `resolve_selectors_result` of the preview `task_id`.
+    Preview,
+}
+
+impl Expect {
+    /// Every answer `message` may be, first to last, with the ID it answers
+    /// to. An error sent in place of an answer may be a task's or a preview's.
+    pub fn answered_by(message: &Value) -> Option<(&'static [Expect], &str)> {
+        let field = |name: &str| message.get(name).and_then(Value::as_str);
+        match field("action")? {
+            TASK_RESULT_ACTION => Some((&[Expect::Result], field("task_id")?)),
+            BRIDGE_ERROR_ACTION | MESSAGE_TOO_LARGE_ACTION => Some((&[Expect::Result, Expect::Preview], field("task_id")?)),
+            TASK_CANCELLED_ACTION => Some((&[Expect::Cancellation], field("task_id")?)),
+            ACK_ACTION => Some((&[Expect::Ack], message.get("result")?.get("msg_id")?.as_str()?)),
+            RESOLVE_SELECTORS_RESULT_ACTION => Some((&[Expect::Preview], field("task_id")?)),
+            _ => None,
+        }
+    }
+}
+
+/// Waiters `W` for answers, by the ID they answer to.
+#[derive(Debug)]
+pub struct Correlator<W> {
+    waiting: [HashMap<String, W>; 4],
+}
+
+impl<W> Default for Correlator<W> {
+    fn default() -> Self {
+        Correlator { waiting: [HashMap::new(), HashMap::new(), HashMap::new(), HashMap::new()] }
+    }
+}
+
+impl<W> Correlator<W> {
+    pub fn new() -> Self {
+        Correlator::default()
+    }
+
+    /// Waits with `waiter` for the answer `expect` to `id`, returning the
+    /// one that waited for it before.
+    pub fn expect(&mut self, expect: Expect, id: impl Into<String>, waiter: W) -> Option<W> {
+        self.waiting[expect as usize].insert(id.into(), waiter)
+    }
+
+    /// Stops waiting for the answer `expect` to `id`.
+    pub fn forget(&mut self, expect: Expect, id: &str) -> Option<W> {
+        self.waiting[expect as usize].remove(id)
+    }
+
+    /// The waiter `message` answers, which no longer waits. `None` if
+    /// `message` isn't an answer or nothing waits for it.
+    pub fn answer(&mut self, message: &Value) -> Option<W> {
+        let (expects, id) = Expect::answered_by(message)?;
+        expects.iter().find_map(|&expect| self.forget(expect, id))
+    }
+
+    pub fn is_waiting(&self, expect: Expect, id: &str) -> bool {
+        self.waiting[expect as usize].contains_key(id)
+    }
+
+    /// Drops every waiter, e.g. when the connection is gone.
+    pub fn clear(&mut self) {
+        self.waiting.iter_mut().for_each(HashMap::clear);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn hands_each_answer_to_its_waiter() {
+        let mut correlator = Correlator::new();
+        correlator.expect(Expect::Result, "t1", "result");
+        correlator.expect(Expect::Cancellation, "t1", "cancellation");
+        correlator.expect(Expect::Ack, "m1", "ack");
+
+        assert_eq!(correlator.answer(&json!({ "action": "task_cancelled", "task_id": "t1", "success": true })), Some("cancellation"));
+        assert_eq!(correlator.answer(&json!({ "action": "ack", "task_id": "t1", "result": { "msg_id": "m1" } })), Some("ack"));
+        assert!(correlator.answer(&json!({ "action": "log", "task_id": "t1" })).is_none());
+        assert!(correlator.is_waiting(Expect::Result, "t1"));
+        // An error in place of a result answers the task
+        assert_eq!(correlator.answer(&json!({ "action": "bridge_error", "task_id": "t1", "success": false })), Some("result"));
+        assert!(correlator.answer(&json!({ "action": "task_result", "task_id": "t1" })).is_none());
+
+        correlator.expect(Expect::Preview, "p1", "preview");
+        correlator.expect(Expect::Preview, "p2", "preview 2");
+        assert!(correlator.answer(&json!({ "action": "task_result", "task_id": "p1" })).is_none());
+        assert_eq!(correlator.answer(&json!({ "action": "resolve_selectors_result", "task_id": "p1" })), Some("preview"));
+        assert_eq!(correlator.answer(&json!({ "action": "message_too_large", "task_id": "p2" })), Some("preview 2"));
+    }
+}
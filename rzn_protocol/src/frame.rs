@@ -0,0 +1,576 @@
+//! Message framing for both legs of the bridge, as a push decoder and plain
+//! encoders.
+//!
+//! * **Native messaging leg** (extension <-> broker, stdin/stdout): every message is
+//!   prefixed with a bare 4-byte length in the machine's native byte order, as
+//!   required by Chrome.
+//! * **IPC leg** (broker <-> Main App): every message is prefixed with a fixed
+//!   12-byte [`FrameHeader`]. The IPC format is the same on every machine, so
+//!   a Main App never depends on the broker's architecture.
+//!
+//! IPC frame header layout (all integers little-endian):
+//!
+//! ```text
+//! offset  size  field
+//! 0       4     magic       b"RZNB"
+//! 4       1     version     FRAME_VERSION
+//! 5       1     flags       FrameFlags bits (compressed / encrypted / priority / msgpack / cbor)
+//! 6       2     channel_id  logical channel, 0 = default
+//! 8       4     length      payload length in bytes
+//! ```
+//!
+//! The magic read as a little-endian `u32` is far larger than [`MAX_MESSAGE_SIZE`],
+//! so a peer can tell a header frame apart from a legacy bare-length frame by
+//! looking at the first four bytes ([`Decoder::detecting`]). Legacy IPC
+//! lengths are little-endian, too.
+//!
+//! A [`Decoder`] takes bytes as they come, in pieces of any size, and hands
+//! out whole messages. A message over its limit is skipped rather than kept,
+//! and decoding it fails with a [`MessageTooLarge`] error. The decoder stays
+//! in step, so the reader can answer the sender and go on with the next
+//! message.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+use crate::peek::peek_envelope;
+
+// Constants
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024; // 10MB limit for messages
+
+/// Bytes at the start of an oversized message that are kept to find its
+/// `action` and `task_id`.
+const TOO_LARGE_PEEK_LEN: usize = 4096;
+
+/// Largest messages accepted in each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Messages from the extension to the Main App.
+    pub to_app: usize,
+    /// Messages from the Main App to the extension.
+    pub to_extension: usize,
+}
+
+impl MessageLimits {
+    /// The same limit both ways.
+    pub const fn uniform(bytes: usize) -> Self {
+        MessageLimits { to_app: bytes, to_extension: bytes }
+    }
+}
+
+impl Default for MessageLimits {
+    /// [`MAX_MESSAGE_SIZE`] both ways.
+    fn default() -> Self {
+        MessageLimits::uniform(MAX_MESSAGE_SIZE)
+    }
+}
+
+/// A message that was skipped for being over the reader's limit, carried
+/// by the [`io::Error`] of the read; see [`MessageTooLarge::of`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub len: usize,
+    pub limit: usize,
+    /// `action` and `task_id`, if they were near the start of the message.
+    pub action: Option<String>,
+    pub task_id: Option<String>,
+}
+
+impl MessageTooLarge {
+    /// The skipped message behind `error`, if that is what failed the read.
+    pub fn of(error: &io::Error) -> Option<&MessageTooLarge> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// A `len`-byte message over `limit` that starts with `head`.
+    pub fn new(len: usize, limit: usize, head: &[u8]) -> Self {
+        let envelope = peek_envelope(head);
+        MessageTooLarge {
+            len,
+            limit,
+            action: envelope.action.map(|action| action.into_owned()),
+            task_id: envelope.task_id.map(|task_id| task_id.into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Message length {} exceeds limit {}", self.len, self.limit)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// Marker at the start of every IPC frame header.
+pub const FRAME_MAGIC: [u8; 4] = *b"RZNB";
+/// Current IPC frame header version.
+pub const FRAME_VERSION: u8 = 1;
+/// Size of the encoded IPC frame header in bytes.
+pub const FRAME_HEADER_LEN: usize = 12;
+
+/// Bit flags carried in the IPC frame header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameFlags(u8);
+
+impl FrameFlags {
+    pub const NONE: FrameFlags = FrameFlags(0);
+    /// Payload is compressed.
+    pub const COMPRESSED: FrameFlags = FrameFlags(0b0000_0001);
+    /// Payload is encrypted.
+    pub const ENCRYPTED: FrameFlags = FrameFlags(0b0000_0010);
+    /// Frame should be delivered ahead of normal traffic.
+    pub const PRIORITY: FrameFlags = FrameFlags(0b0000_0100);
+    /// Payload is MessagePack rather than JSON.
+    pub const MSGPACK: FrameFlags = FrameFlags(0b0000_1000);
+    /// Payload is CBOR rather than JSON.
+    pub const CBOR: FrameFlags = FrameFlags(0b0001_0000);
+
+    pub fn from_bits(bits: u8) -> Self {
+        FrameFlags(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: FrameFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: FrameFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl std::ops::BitOr for FrameFlags {
+    type Output = FrameFlags;
+
+    fn bitor(self, rhs: FrameFlags) -> FrameFlags {
+        FrameFlags(self.0 | rhs.0)
+    }
+}
+
+/// Decoded IPC frame header (magic is validated and not stored).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub flags: FrameFlags,
+    pub channel_id: u16,
+    pub length: u32,
+}
+
+impl FrameHeader {
+    /// Creates a header for the current frame version.
+    pub fn new(flags: FrameFlags, channel_id: u16, length: u32) -> Self {
+        FrameHeader { version: FRAME_VERSION, flags, channel_id, length }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_HEADER_LEN] {
+        let mut buf = [0u8; FRAME_HEADER_LEN];
+        buf[0..4].copy_from_slice(&FRAME_MAGIC);
+        buf[4] = self.version;
+        buf[5] = self.flags.bits();
+        buf[6..8].copy_from_slice(&self.channel_id.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.length.to_le_bytes());
+        buf
+    }
+
+    /// Decodes and validates a header (magic and version).
+    pub fn decode(buf: &[u8; FRAME_HEADER_LEN]) -> io::Result<Self> {
+        if buf[0..4] != FRAME_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid frame magic"));
+        }
+        let version = buf[4];
+        if version != FRAME_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported frame version {} (expected {})", version, FRAME_VERSION),
+            ));
+        }
+        Ok(FrameHeader {
+            version,
+            flags: FrameFlags::from_bits(buf[5]),
+            channel_id: u16::from_le_bytes([buf[6], buf[7]]),
+            length: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        })
+    }
+}
+
+/// Framing used by an IPC peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingMode {
+    /// Fixed [`FrameHeader`] before every payload.
+    Header,
+    /// Bare 4-byte length prefix, as used by brokers predating the frame header.
+    Legacy,
+}
+
+/// Byte order of a bare 4-byte length prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    /// The machine's byte order, which native messaging uses.
+    pub const NATIVE: ByteOrder = if cfg!(target_endian = "big") { ByteOrder::Big } else { ByteOrder::Little };
+
+    pub fn decode(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn encode(self, len: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Little => len.to_le_bytes(),
+            ByteOrder::Big => len.to_be_bytes(),
+        }
+    }
+}
+
+/// The length of a message to be sent, if it fits a prefix. Receivers
+/// enforce their own limits.
+pub fn outgoing_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(ErrorKind::InvalidInput, format!("Attempted to send message larger than limit: {} bytes", len))
+    })
+}
+
+/// `payload` behind a bare 4-byte length in `order`.
+pub fn encode_message(order: ByteOrder, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let len = outgoing_len(payload.len())?;
+    Ok([&order.encode(len)[..], payload].concat())
+}
+
+/// `payload` behind a [`FrameHeader`] with `flags` and `channel_id`.
+pub fn encode_frame(flags: FrameFlags, channel_id: u16, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let header = FrameHeader::new(flags, channel_id, outgoing_len(payload.len())?);
+    Ok([&header.encode()[..], payload].concat())
+}
+
+/// What goes before each payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prefix {
+    Length(ByteOrder),
+    Header,
+    /// Either, told apart by the first four bytes of the stream.
+    Detect,
+}
+
+impl Prefix {
+    fn len(self) -> usize {
+        match self {
+            Prefix::Header => FRAME_HEADER_LEN,
+            Prefix::Length(_) | Prefix::Detect => 4,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Stage {
+    Prefix,
+    Body(FrameHeader),
+    /// Past `skipped` of `len` bytes, keeping the first few in `head`.
+    Skip { len: usize, skipped: usize, head: Vec<u8> },
+}
+
+/// Turns the bytes of one direction of a connection into messages.
+///
+/// [`feed`](Decoder::feed) it bytes as they arrive, then call
+/// [`decode`](Decoder::decode) until it returns `Ok(None)`. Each message
+/// comes with its [`FrameHeader`]; bare-length messages get a default one
+/// (no flags, channel 0). A reader that must not read past the end of a
+/// message, e.g. one handing the stream on afterwards, reads no more than
+/// [`wanted`](Decoder::wanted) at a time.
+#[derive(Debug)]
+pub struct Decoder {
+    prefix: Prefix,
+    max_len: usize,
+    input: Vec<u8>,
+    stage: Stage,
+}
+
+impl Decoder {
+    /// Native messaging: a length in [`ByteOrder::NATIVE`] before each message.
+    pub fn native(max_len: usize) -> Self {
+        Decoder::length_prefixed(ByteOrder::NATIVE, max_len)
+    }
+
+    /// A bare length in `order` before each message.
+    pub fn length_prefixed(order: ByteOrder, max_len: usize) -> Self {
+        Decoder::with_prefix(Prefix::Length(order), max_len)
+    }
+
+    /// IPC frames, each behind a [`FrameHeader`].
+    pub fn ipc(max_len: usize) -> Self {
+        Decoder::with_prefix(Prefix::Header, max_len)
+    }
+
+    /// IPC frames in the framing the stream starts with: header frames if it
+    /// starts with [`FRAME_MAGIC`], else legacy little-endian lengths.
+    pub fn detecting(max_len: usize) -> Self {
+        Decoder::with_prefix(Prefix::Detect, max_len)
+    }
+
+    /// A decoder in `mode`, or detecting the mode if there is none yet.
+    pub fn for_mode(mode: Option<FramingMode>, max_len: usize) -> Self {
+        match mode {
+            Some(FramingMode::Header) => Decoder::ipc(max_len),
+            Some(FramingMode::Legacy) => Decoder::length_prefixed(ByteOrder::Little, max_len),
+            None => Decoder::detecting(max_len),
+        }
+    }
+
+    fn with_prefix(prefix: Prefix, max_len: usize) -> Self {
+        Decoder { prefix, max_len, input: Vec::new(), stage: Stage::Prefix }
+    }
+
+    /// The IPC framing of the stream, once known. Bare lengths count as
+    /// [`FramingMode::Legacy`].
+    pub fn mode(&self) -> Option<FramingMode> {
+        match self.prefix {
+            Prefix::Header => Some(FramingMode::Header),
+            Prefix::Length(_) => Some(FramingMode::Legacy),
+            Prefix::Detect => None,
+        }
+    }
+
+    /// Adds bytes read from the stream.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.extend_from_slice(bytes);
+    }
+
+    /// The next whole message, if the bytes fed so far hold one. A message
+    /// over the limit fails with an [`ErrorKind::InvalidData`] error carrying
+    /// its [`MessageTooLarge`] once it has been skipped; a bad frame header
+    /// fails, too, after which the stream can't be read on.
+    pub fn decode(&mut self) -> io::Result<Option<(FrameHeader, Vec<u8>)>> {
+        let Some(header) = self.body_header()? else {
+            return Ok(None);
+        };
+        let len = header.length as usize;
+        if self.input.len() < len {
+            self.input.reserve(len - self.input.len());
+            return Ok(None);
+        }
+        let rest = self.input.split_off(len);
+        let payload = std::mem::replace(&mut self.input, rest);
+        self.stage = Stage::Prefix;
+        Ok(Some((header, payload)))
+    }
+
+    /// Like [`decode`](Decoder::decode), but hands out the next message's
+    /// header as soon as its prefix is decoded, for readers that put bodies
+    /// into storage of their own. Its `length` bytes of body come next: first
+    /// those fed already, taken with [`take_fed`](Decoder::take_fed), then
+    /// the rest from the stream, all before the decoder is fed again.
+    pub fn decode_header(&mut self) -> io::Result<Option<FrameHeader>> {
+        let header = self.body_header()?;
+        if header.is_some() {
+            self.stage = Stage::Prefix;
+        }
+        Ok(header)
+    }
+
+    /// Takes up to `max` of the bytes fed but not decoded yet.
+    pub fn take_fed(&mut self, max: usize) -> Vec<u8> {
+        self.input.drain(..max.min(self.input.len())).collect()
+    }
+
+    /// Decodes up to the body of the next message within the limit, skipping
+    /// those over it, and returns its header.
+    fn body_header(&mut self) -> io::Result<Option<FrameHeader>> {
+        loop {
+            match &mut self.stage {
+                Stage::Prefix => {
+                    let Some(header) = self.take_prefix()? else {
+                        return Ok(None);
+                    };
+                    let len = header.length as usize;
+                    if len > self.max_len {
+                        self.stage = Stage::Skip { len, skipped: 0, head: Vec::new() };
+                        continue;
+                    }
+                    // Handle zero-length messages if necessary (might indicate keep-alive or error)
+                    if len == 0 {
+                        log::warn!("Received message length 0.");
+                    }
+                    self.stage = Stage::Body(header);
+                }
+                Stage::Body(header) => return Ok(Some(*header)),
+                Stage::Skip { len, skipped, head } => {
+                    let n = (*len - *skipped).min(self.input.len());
+                    let keep = TOO_LARGE_PEEK_LEN.saturating_sub(head.len()).min(n);
+                    head.extend_from_slice(&self.input[..keep]);
+                    self.input.drain(..n);
+                    *skipped += n;
+                    if skipped < len {
+                        return Ok(None);
+                    }
+                    let too_large = MessageTooLarge::new(*len, self.max_len, head);
+                    self.stage = Stage::Prefix;
+                    return Err(io::Error::new(ErrorKind::InvalidData, too_large));
+                }
+            }
+        }
+    }
+
+    /// Takes the next prefix off the input, as a header.
+    fn take_prefix(&mut self) -> io::Result<Option<FrameHeader>> {
+        if self.prefix == Prefix::Detect {
+            if self.input.len() < 4 {
+                return Ok(None);
+            }
+            self.prefix = if self.input[..4] == FRAME_MAGIC { Prefix::Header } else { Prefix::Length(ByteOrder::Little) };
+        }
+        if self.input.len() < self.prefix.len() {
+            return Ok(None);
+        }
+        let prefix: Vec<u8> = self.input.drain(..self.prefix.len()).collect();
+        match self.prefix {
+            Prefix::Length(order) => {
+                let len = order.decode([prefix[0], prefix[1], prefix[2], prefix[3]]);
+                Ok(Some(FrameHeader::new(FrameFlags::NONE, 0, len)))
+            }
+            _ => {
+                let mut header = [0u8; FRAME_HEADER_LEN];
+                header.copy_from_slice(&prefix);
+                FrameHeader::decode(&header).map(Some)
+            }
+        }
+    }
+
+    /// Bytes still needed to finish the prefix or message being decoded,
+    /// never more than its end. Zero while [`decode`](Decoder::decode) has
+    /// something to hand out.
+    pub fn wanted(&self) -> usize {
+        let needed = match &self.stage {
+            Stage::Prefix => self.prefix.len(),
+            Stage::Body(header) => header.length as usize,
+            Stage::Skip { len, skipped, .. } => len - skipped,
+        };
+        needed.saturating_sub(self.input.len())
+    }
+
+    /// Whether the stream may end here: nothing but part of the next prefix
+    /// has been fed since the last message.
+    pub fn is_between_messages(&self) -> bool {
+        matches!(self.stage, Stage::Prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `stream` in pieces of `size` and collects what comes out.
+    fn decode_all(decoder: &mut Decoder, stream: &[u8], size: usize) -> Vec<Result<Vec<u8>, usize>> {
+        let mut out = Vec::new();
+        for piece in stream.chunks(size) {
+            decoder.feed(piece);
+            loop {
+                match decoder.decode() {
+                    Ok(Some((_, payload))) => out.push(Ok(payload)),
+                    Ok(None) => break,
+                    Err(e) => out.push(Err(MessageTooLarge::of(&e).unwrap().len)),
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn decodes_messages_fed_in_any_pieces() {
+        let big = format!(r#"{{"action":"task_result","task_id":"t1","result":"{}"}}"#, "x".repeat(5000));
+        let mut stream = encode_message(ByteOrder::Little, b"{}").unwrap();
+        stream.extend(encode_message(ByteOrder::Little, big.as_bytes()).unwrap());
+        stream.extend(encode_message(ByteOrder::Little, b"[1]").unwrap());
+
+        for size in [1, 3, 7, 4096, stream.len()] {
+            let mut decoder = Decoder::length_prefixed(ByteOrder::Little, 1024);
+            let out = decode_all(&mut decoder, &stream, size);
+            assert_eq!(out, [Ok(b"{}".to_vec()), Err(big.len()), Ok(b"[1]".to_vec())], "pieces of {}", size);
+            assert!(decoder.is_between_messages());
+        }
+
+        // The skipped message is named
+        let mut decoder = Decoder::native(1024);
+        decoder.feed(&encode_message(ByteOrder::NATIVE, big.as_bytes()).unwrap());
+        let error = decoder.decode().unwrap_err();
+        let too_large = MessageTooLarge::of(&error).unwrap();
+        assert_eq!((too_large.action.as_deref(), too_large.task_id.as_deref()), (Some("task_result"), Some("t1")));
+    }
+
+    #[test]
+    fn detects_the_framing_and_never_wants_past_a_message() {
+        let frame = encode_frame(FrameFlags::PRIORITY, 7, b"{}").unwrap();
+        let mut decoder = Decoder::detecting(MAX_MESSAGE_SIZE);
+        assert_eq!((decoder.mode(), decoder.wanted()), (None, 4));
+        decoder.feed(&frame[..4]);
+        assert!(decoder.decode().unwrap().is_none());
+        assert_eq!((decoder.mode(), decoder.wanted()), (Some(FramingMode::Header), FRAME_HEADER_LEN - 4));
+        decoder.feed(&frame[4..FRAME_HEADER_LEN]);
+        assert!(decoder.decode().unwrap().is_none());
+        assert_eq!(decoder.wanted(), 2);
+        assert!(!decoder.is_between_messages());
+        decoder.feed(&frame[FRAME_HEADER_LEN..]);
+        let (header, payload) = decoder.decode().unwrap().unwrap();
+        assert_eq!((header.flags, header.channel_id, payload), (FrameFlags::PRIORITY, 7, b"{}".to_vec()));
+
+        let mut legacy = Decoder::detecting(MAX_MESSAGE_SIZE);
+        legacy.feed(&[2, 0, 0, 0, b'{', b'}']);
+        assert_eq!(legacy.decode().unwrap().map(|(_, payload)| payload), Some(b"{}".to_vec()));
+        assert_eq!(legacy.mode(), Some(FramingMode::Legacy));
+
+        // Bodies may be left to the reader
+        let mut headers = Decoder::ipc(MAX_MESSAGE_SIZE);
+        headers.feed(&frame[..FRAME_HEADER_LEN + 1]);
+        let header = headers.decode_header().unwrap().unwrap();
+        assert_eq!((header.channel_id, header.length, headers.take_fed(2)), (7, 2, b"{".to_vec()));
+        assert_eq!((headers.wanted(), headers.is_between_messages()), (FRAME_HEADER_LEN, true));
+
+        let mut bad = Decoder::ipc(MAX_MESSAGE_SIZE);
+        bad.feed(b"RZNB\x09\0\0\0\0\0\0\0");
+        assert_eq!(bad.decode().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    /// Property tests feeding the decoder arbitrary streams in arbitrary pieces.
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn never_panics_on_arbitrary_bytes(
+                stream in prop::collection::vec(any::<u8>(), 0..512),
+                size in 1usize..64,
+                limit in 0usize..256,
+            ) {
+                for mut decoder in [Decoder::detecting(limit), Decoder::native(limit)] {
+                    for piece in stream.chunks(size) {
+                        decoder.feed(piece);
+                        while let Ok(Some(_)) = decoder.decode() {}
+                    }
+                }
+            }
+
+            #[test]
+            fn frames_round_trip(
+                payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..300), 1..6),
+                size in 1usize..64,
+                limit in 0usize..300,
+            ) {
+                let mut stream = Vec::new();
+                for payload in &payloads {
+                    stream.extend(encode_frame(FrameFlags::NONE, 0, payload).unwrap());
+                }
+                let expected: Vec<_> = payloads.iter().map(|p| if p.len() > limit { Err(p.len()) } else { Ok(p.clone()) }).collect();
+                prop_assert_eq!(decode_all(&mut Decoder::ipc(limit), &stream, size), expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! The `hello`/`hello_ack` exchange that opens a connection.
+//!
+//! One side says hello ([`HELLO_ACTION`], `data` is its [`Hello`]), the other
+//! answers with a `hello_ack` ([`HELLO_ACK_ACTION`], `result` is its own
+//! [`Hello`]) that fails if it won't talk to the first. Sides with different
+//! major protocol versions would misread each other's messages, so neither
+//! accepts the other. A [`Handshake`] is one side's part in it.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Protocol version spoken by this build, as `major.minor`. Minor versions
+/// only add optional fields and actions; a different major version means the
+/// two sides would misread each other's messages.
+pub const PROTOCOL_VERSION: &str = "1.0";
+
+/// Sent first on a new connection (extension to broker, broker to Main App);
+/// `data` is a [`Hello`].
+pub const HELLO_ACTION: &str = "hello";
+/// Answer to a `hello`; `result` is the answering side's [`Hello`].
+pub const HELLO_ACK_ACTION: &str = "hello_ack";
+
+/// What one side of a connection tells the other about itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol_version: String,
+    /// Name and version of the sending software, e.g. "rzn_broker 0.1.0".
+    pub software: String,
+    /// Optional features the sender supports, e.g. "selftest".
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// A hello for this build's [`PROTOCOL_VERSION`].
+    pub fn new(software: impl Into<String>, capabilities: &[&str]) -> Self {
+        Hello {
+            protocol_version: PROTOCOL_VERSION.to_string(),
+            software: software.into(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    /// Checks that `other` speaks the same major protocol version as we do.
+    pub fn check_compatible(&self, other: &Hello) -> Result<(), VersionMismatch> {
+        let major = |version: &str| version.split('.').next().unwrap_or_default().trim().to_string();
+        if major(&self.protocol_version) == major(&other.protocol_version) {
+            Ok(())
+        } else {
+            Err(VersionMismatch { ours: self.protocol_version.clone(), theirs: other.protocol_version.clone() })
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Protocol versions with different major numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub ours: String,
+    pub theirs: String,
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "incompatible protocol version {} (expected {}.x)", self.theirs,
+               self.ours.split('.').next().unwrap_or_default())
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+/// What a peer's `hello` or `hello_ack` came to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// The peer speaks our major version (and took our hello).
+    Agreed(Hello),
+    /// The peer speaks another major version.
+    Incompatible(Hello, VersionMismatch),
+    /// The peer turned down our hello, for the reason given.
+    Rejected(Hello, String),
+    /// The message carries no valid [`Hello`].
+    Malformed,
+}
+
+/// One side's part in the handshake of a connection.
+///
+/// The side that opens sends [`hello`](Handshake::hello) and takes the answer
+/// with [`on_hello_ack`](Handshake::on_hello_ack); the other takes it with
+/// [`on_hello`](Handshake::on_hello) and sends
+/// [`hello_ack`](Handshake::hello_ack). Peers that never say hello predate
+/// the handshake and are left to the caller.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    ours: Hello,
+    peer: Option<Hello>,
+}
+
+impl Handshake {
+    pub fn new(ours: Hello) -> Self {
+        Handshake { ours, peer: None }
+    }
+
+    pub fn ours(&self) -> &Hello {
+        &self.ours
+    }
+
+    /// The peer's hello, once the two sides agreed.
+    pub fn peer(&self) -> Option<&Hello> {
+        self.peer.as_ref()
+    }
+
+    /// Our `hello`.
+    pub fn hello(&self, task_id: &str) -> Value {
+        json!({ "action": HELLO_ACTION, "task_id": task_id, "data": self.ours })
+    }
+
+    /// Our `hello_ack`, turning the peer down with `error` if there is one.
+    pub fn hello_ack(&self, task_id: &str, error: Option<&str>) -> Value {
+        json!({
+            "action": HELLO_ACK_ACTION,
+            "task_id": task_id,
+            "success": error.is_none(),
+            "result": self.ours,
+            "error": error,
+        })
+    }
+
+    /// Takes the peer's `hello`.
+    pub fn on_hello(&mut self, message: &Value) -> Verdict {
+        let Some(theirs) = hello_in(message, "data") else {
+            return Verdict::Malformed;
+        };
+        self.judge(theirs, None)
+    }
+
+    /// Takes the peer's `hello_ack` to our hello.
+    pub fn on_hello_ack(&mut self, message: &Value) -> Verdict {
+        let Some(theirs) = hello_in(message, "result") else {
+            return Verdict::Malformed;
+        };
+        let rejected = (message.get("success").and_then(Value::as_bool) == Some(false))
+            .then(|| message.get("error").and_then(Value::as_str).unwrap_or("no reason given").to_string());
+        self.judge(theirs, rejected)
+    }
+
+    fn judge(&mut self, theirs: Hello, rejected: Option<String>) -> Verdict {
+        if let Err(mismatch) = self.ours.check_compatible(&theirs) {
+            return Verdict::Incompatible(theirs, mismatch);
+        }
+        if let Some(reason) = rejected {
+            return Verdict::Rejected(theirs, reason);
+        }
+        self.peer = Some(theirs.clone());
+        Verdict::Agreed(theirs)
+    }
+}
+
+/// The [`Hello`] in `message`'s `field`.
+fn hello_in(message: &Value, field: &str) -> Option<Hello> {
+    serde_json::from_value(message.get(field)?.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agrees_on_the_major_version() {
+        let mut broker = Handshake::new(Hello::new("broker", &["ack"]));
+        let mut app = Handshake::new(Hello::new("app", &[]));
+
+        let hello = broker.hello("h1");
+        assert!(matches!(app.on_hello(&hello), Verdict::Agreed(peer) if peer.has_capability("ack")));
+        let ack = app.hello_ack("h1", None);
+        assert!(matches!(broker.on_hello_ack(&ack), Verdict::Agreed(peer) if peer.software == "app"));
+        assert_eq!(broker.peer().map(|peer| peer.software.as_str()), Some("app"));
+
+        let refused = app.hello_ack("h1", Some("not today"));
+        assert!(matches!(Handshake::new(Hello::new("broker", &[])).on_hello_ack(&refused), Verdict::Rejected(_, reason) if reason == "not today"));
+
+        let mut old = Hello::new("old", &[]);
+        old.protocol_version = "0.9".to_string();
+        let verdict = app.on_hello(&Handshake::new(old).hello("h2"));
+        assert!(matches!(verdict, Verdict::Incompatible(_, mismatch) if mismatch.to_string() == "incompatible protocol version 0.9 (expected 1.x)"));
+        assert_eq!(app.on_hello(&json!({ "action": "hello", "data": 1 })), Verdict::Malformed);
+    }
+}
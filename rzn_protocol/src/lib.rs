@@ -0,0 +1,23 @@
+//! The bridge protocol without I/O: framing, the handshake, matching answers
+//! to requests and putting chunked messages back together.
+//!
+//! Everything here is driven by bytes and messages in and bytes and messages
+//! out. Nothing reads a socket, spawns a task or waits: the tokio adapters in
+//! `shared_types::frame`, the broker and the Main App client feed in what
+//! they read and write out what comes back. So each state machine can be
+//! tested, or fuzzed, by feeding it bytes, and the crate builds for
+//! `wasm32-unknown-unknown` for an extension module.
+//!
+//! `shared_types` re-exports all of it under its old paths.
+
+pub mod chunk;
+pub mod correlate;
+pub mod frame;
+pub mod handshake;
+pub mod peek;
+
+pub use chunk::{chunk_message, is_chunk, ChunkError, Reassembler, CHUNK_DATA_ACTION, CHUNK_END_ACTION, CHUNK_START_ACTION, E_CHUNK};
+pub use correlate::{Correlator, Expect};
+pub use frame::{ByteOrder, Decoder, FrameFlags, FrameHeader, FramingMode, MessageLimits, MessageTooLarge, FRAME_HEADER_LEN, FRAME_MAGIC, FRAME_VERSION, MAX_MESSAGE_SIZE};
+pub use handshake::{Handshake, Hello, Verdict, VersionMismatch, HELLO_ACK_ACTION, HELLO_ACTION, PROTOCOL_VERSION};
+pub use peek::{peek_envelope, peek_str, peek_u64, Envelope};
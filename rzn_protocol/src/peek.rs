@@ -0,0 +1,221 @@
+//! Reading a message's `action` and `task_id` without parsing all of it.
+//!
+//! Logging which message goes by shouldn't cost a full parse of a
+//! multi-megabyte payload. [`peek_envelope`] walks the top-level object only
+//! until both fields are found, skipping over other values without building
+//! them. Both sides serialize `action` and `task_id` first, so that is usually
+//! a few dozen bytes.
+
+use std::borrow::Cow;
+
+/// The routing fields of a message, as far as they could be found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    pub action: Option<Cow<'a, str>>,
+    pub task_id: Option<Cow<'a, str>>,
+}
+
+/// Finds the top-level string fields `action` and `task_id` in `bytes`.
+/// Fields that are missing, not strings, or behind malformed JSON are `None`.
+pub fn peek_envelope(bytes: &[u8]) -> Envelope<'_> {
+    let mut envelope = Envelope::default();
+    let mut scanner = Scanner { bytes, pos: 0 };
+    let _ = scanner.fields(&mut envelope);
+    envelope
+}
+
+/// Finds the top-level string field `name` in `bytes`, e.g. a `msg_id`,
+/// walking the object only until it is found.
+pub fn peek_str<'a>(bytes: &'a [u8], name: &str) -> Option<Cow<'a, str>> {
+    let mut scanner = Scanner { bytes, pos: 0 };
+    scanner.field(name)?;
+    match scanner.peek()? {
+        b'"' => scanner.string(),
+        _ => None,
+    }
+}
+
+/// Finds the top-level field `name` in `bytes` if it is a whole number that
+/// fits a `u64`, e.g. a `ttl_ms`.
+pub fn peek_u64(bytes: &[u8], name: &str) -> Option<u64> {
+    let mut scanner = Scanner { bytes, pos: 0 };
+    scanner.field(name)?;
+    let start = scanner.pos;
+    scanner.skip_value()?;
+    std::str::from_utf8(&bytes[start..scanner.pos]).ok()?.parse().ok()
+}
+
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    /// Walks the top-level object; `None` once the JSON turns out malformed.
+    fn fields(&mut self, envelope: &mut Envelope<'a>) -> Option<()> {
+        self.expect(b'{')?;
+        if self.peek()? == b'}' {
+            return Some(());
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            let field = match &*key {
+                "action" => Some(&mut envelope.action),
+                "task_id" => Some(&mut envelope.task_id),
+                _ => None,
+            };
+            match field {
+                Some(field) if self.peek()? == b'"' => *field = Some(self.string()?),
+                _ => self.skip_value()?,
+            }
+            if envelope.action.is_some() && envelope.task_id.is_some() {
+                return Some(());
+            }
+            match self.next()? {
+                b',' => {}
+                b'}' => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Moves to the value of the top-level field `name`.
+    fn field(&mut self, name: &str) -> Option<()> {
+        self.expect(b'{')?;
+        if self.peek()? == b'}' {
+            return None;
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':')?;
+            if key == name {
+                self.peek()?;
+                return Some(());
+            }
+            self.skip_value()?;
+            if self.next()? != b',' {
+                return None;
+            }
+        }
+    }
+
+    /// The next non-whitespace byte, without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        while let Some(&byte) = self.bytes.get(self.pos) {
+            if !byte.is_ascii_whitespace() {
+                return Some(byte);
+            }
+            self.pos += 1;
+        }
+        None
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        (self.next()? == byte).then_some(())
+    }
+
+    /// Consumes a string and returns its contents, borrowed unless it has
+    /// escapes.
+    fn string(&mut self) -> Option<Cow<'a, str>> {
+        self.peek()?;
+        let start = self.pos;
+        let escaped = self.skip_string()?;
+        if escaped {
+            serde_json::from_slice::<String>(&self.bytes[start..self.pos]).ok().map(Cow::Owned)
+        } else {
+            std::str::from_utf8(&self.bytes[start + 1..self.pos - 1]).ok().map(Cow::Borrowed)
+        }
+    }
+
+    /// Consumes a string; tells whether it had escapes.
+    fn skip_string(&mut self) -> Option<bool> {
+        self.expect(b'"')?;
+        let mut escaped = false;
+        loop {
+            match *self.bytes.get(self.pos)? {
+                b'"' => {
+                    self.pos += 1;
+                    return Some(escaped);
+                }
+                b'\\' => {
+                    escaped = true;
+                    self.pos += 2;
+                }
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    /// Consumes any value, counting brackets instead of parsing what is
+    /// inside them.
+    fn skip_value(&mut self) -> Option<()> {
+        match self.peek()? {
+            b'"' => self.skip_string().map(drop),
+            b'{' | b'[' => {
+                let mut depth = 0usize;
+                loop {
+                    match self.peek()? {
+                        b'"' => {
+                            self.skip_string()?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => depth -= 1,
+                        _ => {}
+                    }
+                    self.pos += 1;
+                    if depth == 0 {
+                        return Some(());
+                    }
+                }
+            }
+            _ => {
+                // Numbers, true, false and null
+                while self.bytes.get(self.pos).is_some_and(|b| !matches!(b, b',' | b'}' | b']') && !b.is_ascii_whitespace()) {
+                    self.pos += 1;
+                }
+                Some(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peek(json: &str) -> (Option<String>, Option<String>) {
+        let envelope = peek_envelope(json.as_bytes());
+        (envelope.action.map(Cow::into_owned), envelope.task_id.map(Cow::into_owned))
+    }
+
+    #[test]
+    fn finds_top_level_fields_only() {
+        let some = |action: &str, task_id: &str| (Some(action.to_string()), Some(task_id.to_string()));
+        assert_eq!(peek(r#"{"action":"ping","task_id":"p1","data":{}}"#), some("ping", "p1"));
+        assert_eq!(
+            peek(r#" { "data": {"action": "inner", "list": [1, "]", {"task_id": "x"}]}, "ok": true, "n": -1.5e3, "task_id": "t\"2", "action": "pong" } "#),
+            some("pong", "t\"2")
+        );
+        assert_eq!(peek(r#"{"task_id": 7, "action": "task_result"}"#), (Some("task_result".to_string()), None));
+        // Whatever was found before the JSON breaks
+        assert_eq!(peek(r#"{"action":"ping","data":{"#), (Some("ping".to_string()), None));
+        assert_eq!(peek("[]"), (None, None));
+
+        let message = br#"{"action":"task_result","result":{"msg_id":"inner"},"msg_id":"b-1"}"#;
+        assert_eq!(peek_str(message, "msg_id").as_deref(), Some("b-1"));
+        assert_eq!(peek_str(br#"{"msg_id":1}"#, "msg_id"), None);
+
+        assert_eq!(peek_u64(br#"{"action":"ping","data":{"ttl_ms":1},"ttl_ms": 5000 }"#, "ttl_ms"), Some(5000));
+        assert_eq!(peek_u64(br#"{"ttl_ms":-1}"#, "ttl_ms"), None);
+        assert_eq!(peek_u64(br#"{"ttl_ms":"5000"}"#, "ttl_ms"), None);
+        assert_eq!(peek_u64(br#"{"action":"ping"}"#, "ttl_ms"), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Round-trip latencies of one report window.
+
+use std::fmt;
+use std::time::Duration;
+
+/// The latencies recorded since the window was last taken.
+#[derive(Debug, Default)]
+pub struct Window {
+    samples: Vec<Duration>,
+}
+
+impl Window {
+    pub fn record(&mut self, latency: Duration) {
+        self.samples.push(latency);
+    }
+
+    /// Summarizes the window and starts a new one, `None` if nothing was
+    /// recorded.
+    pub fn take(&mut self) -> Option<Summary> {
+        let mut samples = std::mem::take(&mut self.samples);
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        // Nearest rank
+        let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100).max(1) - 1];
+        Some(Summary { count: samples.len(), p50: percentile(50), p99: percentile(99), max: samples[samples.len() - 1] })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "p50 {:.2} ms, p99 {:.2} ms, max {:.2} ms", millis(self.p50), millis(self.p99), millis(self.max))
+    }
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_and_resets() {
+        let mut window = Window::default();
+        assert_eq!(window.take(), None);
+        for ms in (1..=100).rev() {
+            window.record(Duration::from_millis(ms));
+        }
+        let summary = window.take().unwrap();
+        assert_eq!(
+            summary,
+            Summary { count: 100, p50: Duration::from_millis(50), p99: Duration::from_millis(99), max: Duration::from_millis(100) }
+        );
+        assert_eq!(window.take(), None);
+    }
+}
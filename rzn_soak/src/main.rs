@@ -0,0 +1,417 @@
+//! Soak test for the bridge.
+//!
+//! Runs a Main App (a `BridgeServer` echoing `soak_echo` messages) and a
+//! broker in this process, connected over a real local socket, and plays the
+//! extension on the broker's native messaging side: tens of thousands of
+//! messages a minute, mostly small with some large ones, for hours if asked.
+//! Every report interval it prints the message rate, round-trip latencies,
+//! resident memory and open file descriptors. At the end it compares the last
+//! interval with the first and exits with status 1 if memory or descriptors
+//! kept growing, latency drifted or messages went missing, so a leak in the
+//! relay's channels and buffers fails the run.
+//!
+//! Settings, from the environment:
+//! * `RZN_SOAK_DURATION_SECS`: how long to send (default 7200);
+//! * `RZN_SOAK_RATE`: messages per minute (default 30000);
+//! * `RZN_SOAK_MAX_SIZE`: largest message in bytes (default 262144, at most
+//!   just under 1 MiB so the echoes aren't chunked);
+//! * `RZN_SOAK_REPORT_SECS`: report interval (default 60);
+//! * `RZN_SOAK_MAX_RSS_GROWTH_MB`: memory growth allowed after the first
+//!   interval (default 64);
+//! * `RZN_SOAK_MAX_FD_GROWTH`: descriptor growth allowed (default 4);
+//! * `RZN_SOAK_MAX_LATENCY_DRIFT`: p99 latency allowed, as a multiple of the
+//!   first interval's (default 4).
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use tokio::io::AsyncRead;
+
+use rzn_bridge_client::{BridgeServer, ClientOptions};
+use rzn_broker_core::Broker;
+use shared_types::chunk::NATIVE_TO_EXTENSION_LIMIT;
+use shared_types::frame::{read_message_bytes_limited, write_message_bytes};
+use shared_types::{Action, EndpointSpec, ExtensionResponse, Message};
+
+mod latency;
+mod process;
+
+use latency::{millis, Summary, Window};
+
+const SOAK_ACTION: &str = "soak_echo";
+const SOAK_RESULT_ACTION: &str = "soak_echo_result";
+const TASK_PREFIX: &str = "soak-";
+/// Messages not answered within this long count as lost.
+const LOST_AFTER: Duration = Duration::from_secs(30);
+/// Sending holds off while this many messages are unanswered.
+const MAX_IN_FLIGHT: usize = 10_000;
+const TICK: Duration = Duration::from_millis(10);
+/// How long the unanswered messages get once sending stops.
+const DRAIN_TIME: Duration = Duration::from_secs(10);
+
+struct Settings {
+    duration: Duration,
+    rate_per_minute: u64,
+    max_size: usize,
+    report_every: Duration,
+    max_rss_growth: u64,
+    max_fd_growth: usize,
+    max_latency_drift: f64,
+}
+
+impl Settings {
+    fn from_env() -> Self {
+        // Room for the envelope, which the echo carries too
+        let max_size = env_or("RZN_SOAK_MAX_SIZE", 256 * 1024usize).min(NATIVE_TO_EXTENSION_LIMIT - 1024);
+        Settings {
+            duration: Duration::from_secs(env_or("RZN_SOAK_DURATION_SECS", 7200)),
+            rate_per_minute: env_or("RZN_SOAK_RATE", 30_000),
+            max_size,
+            report_every: Duration::from_secs(env_or("RZN_SOAK_REPORT_SECS", 60u64).max(1)),
+            max_rss_growth: env_or("RZN_SOAK_MAX_RSS_GROWTH_MB", 64u64) * 1024 * 1024,
+            max_fd_growth: env_or("RZN_SOAK_MAX_FD_GROWTH", 4),
+            max_latency_drift: env_or("RZN_SOAK_MAX_LATENCY_DRIFT", 4.0),
+        }
+    }
+}
+
+fn env_or<T: FromStr + Display>(name: &str, default: T) -> T {
+    let Ok(value) = std::env::var(name) else {
+        return default;
+    };
+    value.trim().parse().unwrap_or_else(|_| {
+        log::warn!("Ignoring invalid {}={:?}, using {}", name, value, default);
+        default
+    })
+}
+
+/// Messages sent and not yet answered, and what came back.
+#[derive(Default)]
+struct Tracker {
+    in_flight: HashMap<u64, Instant>,
+    window: Window,
+    received: u64,
+    lost: u64,
+}
+
+/// One report interval.
+struct Report {
+    elapsed: Duration,
+    sent: u64,
+    received: u64,
+    lost: u64,
+    in_flight: usize,
+    latency: Option<Summary>,
+    rss: Option<u64>,
+    fds: Option<usize>,
+}
+
+impl Report {
+    fn take(started: Instant, sent: u64, tracker: &Mutex<Tracker>) -> Self {
+        let mut tracker = tracker.lock().unwrap();
+        let before = tracker.in_flight.len();
+        tracker.in_flight.retain(|_, sent_at| sent_at.elapsed() < LOST_AFTER);
+        tracker.lost += (before - tracker.in_flight.len()) as u64;
+        Report {
+            elapsed: started.elapsed(),
+            sent,
+            received: tracker.received,
+            lost: tracker.lost,
+            in_flight: tracker.in_flight.len(),
+            latency: tracker.window.take(),
+            rss: process::rss_bytes(),
+            fds: process::open_fds(),
+        }
+    }
+
+    fn print(&self, previous: Option<&Report>, baseline: Option<&Report>) {
+        let (since, received_before) = previous.map_or((Duration::ZERO, 0), |previous| (previous.elapsed, previous.received));
+        let window = (self.elapsed - since).as_secs_f64().max(0.001);
+        let rate = (self.received - received_before) as f64 / window * 60.0;
+        let latency = self.latency.map_or_else(|| "no answers".to_string(), |latency| latency.to_string());
+        let drift = match (self.latency, baseline.and_then(|baseline| baseline.latency)) {
+            (Some(latency), Some(baseline)) => format!(", drift x{:.2}", drift(baseline, latency)),
+            _ => String::new(),
+        };
+        println!(
+            "{:>6}s  sent {}  received {}  lost {}  in flight {}  {:.0}/min  {}{}  rss {}  fds {}",
+            self.elapsed.as_secs(),
+            self.sent,
+            self.received,
+            self.lost,
+            self.in_flight,
+            rate,
+            latency,
+            drift,
+            process::format_mib(self.rss),
+            self.fds.map_or_else(|| "n/a".to_string(), |fds| fds.to_string()),
+        );
+    }
+}
+
+/// The p99 latency of `latency` as a multiple of `baseline`'s.
+fn drift(baseline: Summary, latency: Summary) -> f64 {
+    millis(latency.p99) / millis(baseline.p99).max(0.001)
+}
+
+/// What went wrong between the first report and the last one.
+fn problems(settings: &Settings, baseline: &Report, last: &Report) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let (Some(before), Some(after)) = (baseline.rss, last.rss) {
+        if after.saturating_sub(before) > settings.max_rss_growth {
+            problems.push(format!("resident memory grew from {} to {}", process::format_mib(Some(before)), process::format_mib(Some(after))));
+        }
+    }
+    if let (Some(before), Some(after)) = (baseline.fds, last.fds) {
+        if after.saturating_sub(before) > settings.max_fd_growth {
+            problems.push(format!("open file descriptors grew from {} to {}", before, after));
+        }
+    }
+    if let (Some(before), Some(after)) = (baseline.latency, last.latency) {
+        if drift(before, after) > settings.max_latency_drift {
+            problems.push(format!("p99 latency drifted from {:.2} ms to {:.2} ms", millis(before.p99), millis(after.p99)));
+        }
+    }
+    problems
+}
+
+/// xorshift64*, seeded from the clock; the sizes only need to vary.
+struct Rng(u64);
+
+impl Rng {
+    fn seeded() -> Self {
+        Rng(SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_nanos() as u64) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A payload size: 80% up to 1 KiB, 18% up to 64 KiB and 2% up to `max`.
+    fn size(&mut self, max: usize) -> usize {
+        let bound = match self.next() % 100 {
+            0..=79 => 1024,
+            80..=97 => 64 * 1024,
+            _ => max,
+        };
+        (self.next() % bound.min(max).max(1) as u64) as usize
+    }
+}
+
+fn soak_message(seq: u64, size: usize) -> Vec<u8> {
+    let message = serde_json::json!({
+        "action": SOAK_ACTION,
+        "task_id": format!("{}{}", TASK_PREFIX, seq),
+        "data": { "padding": "x".repeat(size) },
+    });
+    serde_json::to_vec(&message).unwrap_or_default()
+}
+
+/// Reads what the broker sends the extension and times the echoes.
+async fn read_echoes<R: AsyncRead + Unpin>(mut reader: R, tracker: Arc<Mutex<Tracker>>) {
+    loop {
+        let bytes = match read_message_bytes_limited(&mut reader, NATIVE_TO_EXTENSION_LIMIT, "Soak").await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => break,
+            Err(e) => {
+                log::error!("Soak: Reading from the broker failed: {}", e);
+                break;
+            }
+        };
+        // Hellos, broker states and the like
+        let Ok(value) = serde_json::from_slice::<Value>(&bytes) else { continue };
+        if value["action"] != SOAK_RESULT_ACTION {
+            continue;
+        }
+        let Some(seq) = value["task_id"].as_str().and_then(|id| id.strip_prefix(TASK_PREFIX)).and_then(|seq| seq.parse().ok()) else {
+            continue;
+        };
+        let mut tracker = tracker.lock().unwrap();
+        // An echo of a message already counted as lost is ignored
+        if let Some(sent_at) = tracker.in_flight.remove(&seq) {
+            tracker.window.record(sent_at.elapsed());
+            tracker.received += 1;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    shared_types::logging::init("rzn_soak");
+    let settings = Settings::from_env();
+
+    // The Main App: echoes every soak message
+    let endpoint = EndpointSpec::resolve(&format!("rzn-soak-{}.sock", std::process::id()));
+    let options = ClientOptions {
+        software: concat!("rzn_soak ", env!("CARGO_PKG_VERSION")).to_string(),
+        max_message_size: NATIVE_TO_EXTENSION_LIMIT,
+        ..ClientOptions::default()
+    };
+    let mut server = BridgeServer::bind(&endpoint, options)?;
+    server.on_action(SOAK_ACTION, |message: Message| async move {
+        Ok(ExtensionResponse { action: Action::from(SOAK_RESULT_ACTION), task_id: message.task_id, success: true, result: message.data, error: None })
+    });
+
+    // The broker, with this process as the extension
+    let (extension, native) = tokio::io::duplex(2 * NATIVE_TO_EXTENSION_LIMIT);
+    let (native_reader, native_writer) = tokio::io::split(native);
+    let broker = Broker::builder().endpoint(endpoint.clone()).launch(None).reconnect(None).handle_signals(false).build();
+    let mut relay = tokio::spawn(async move { broker.serve(native_reader, native_writer).await });
+    // The events aren't needed; dropping them keeps them from backing up
+    let (_client, _) = tokio::select! {
+        accepted = server.accept() => accepted?,
+        served = &mut relay => {
+            let e = served.map_err(io::Error::other).and_then(|served| served).err();
+            return Err(e.unwrap_or_else(|| io::Error::other("the broker stopped before connecting")));
+        }
+    };
+
+    let (extension_reader, mut extension_writer) = tokio::io::split(extension);
+    let tracker = Arc::new(Mutex::new(Tracker::default()));
+    tokio::spawn(read_echoes(extension_reader, tracker.clone()));
+
+    println!(
+        "Soaking for {} s at {} messages/min, up to {} bytes each, reporting every {} s",
+        settings.duration.as_secs(),
+        settings.rate_per_minute,
+        settings.max_size,
+        settings.report_every.as_secs()
+    );
+    let started = Instant::now();
+    let mut rng = Rng::seeded();
+    let mut sent: u64 = 0;
+    let mut ticks = tokio::time::interval(TICK);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut reports = tokio::time::interval_at(tokio::time::Instant::now() + settings.report_every, settings.report_every);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut baseline: Option<Report> = None;
+    let mut last: Option<Report> = None;
+    let mut failures = Vec::new();
+    'soak: loop {
+        tokio::select! {
+            _ = ticks.tick() => {
+                let elapsed = started.elapsed();
+                if elapsed >= settings.duration {
+                    break;
+                }
+                let due = (settings.rate_per_minute as f64 * elapsed.as_secs_f64() / 60.0) as u64;
+                while sent < due {
+                    {
+                        let mut tracker = tracker.lock().unwrap();
+                        if tracker.in_flight.len() >= MAX_IN_FLIGHT {
+                            break;
+                        }
+                        tracker.in_flight.insert(sent, Instant::now());
+                    }
+                    if let Err(e) = write_message_bytes(&mut extension_writer, &soak_message(sent, rng.size(settings.max_size)), "Soak").await {
+                        failures.push(format!("writing to the broker failed after {} messages: {}", sent, e));
+                        break 'soak;
+                    }
+                    sent += 1;
+                }
+            }
+            _ = reports.tick() => {
+                let report = Report::take(started, sent, &tracker);
+                report.print(last.as_ref(), baseline.as_ref());
+                if baseline.is_none() {
+                    baseline = Some(report);
+                } else {
+                    last = Some(report);
+                }
+            }
+            _ = &mut ctrl_c => {
+                println!("Interrupted");
+                break;
+            }
+        }
+    }
+
+    let drain_until = Instant::now() + DRAIN_TIME;
+    while !tracker.lock().unwrap().in_flight.is_empty() && Instant::now() < drain_until {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // Whatever is left now counts as lost
+    {
+        let mut tracker = tracker.lock().unwrap();
+        tracker.lost += tracker.in_flight.len() as u64;
+        tracker.in_flight.clear();
+    }
+    let summary = Report::take(started, sent, &tracker);
+    println!("Done:");
+    summary.print(last.as_ref().or(baseline.as_ref()), baseline.as_ref());
+
+    if summary.lost > 0 {
+        failures.push(format!("{} of {} messages were never answered", summary.lost, summary.sent));
+    }
+    match (&baseline, &last) {
+        (Some(baseline), Some(last)) => failures.extend(problems(&settings, baseline, last)),
+        _ => println!("Too short to compare intervals; run for at least two report intervals"),
+    }
+
+    drop(extension_writer);
+    relay.abort();
+    if let Some(path) = endpoint.socket_file() {
+        let _ = std::fs::remove_file(path);
+    }
+    if failures.is_empty() {
+        println!("PASS");
+        return Ok(());
+    }
+    for failure in &failures {
+        println!("FAIL: {}", failure);
+    }
+    std::process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(rss_mib: u64, fds: usize, p99_ms: u64) -> Report {
+        let p99 = Duration::from_millis(p99_ms);
+        Report {
+            elapsed: Duration::ZERO,
+            sent: 0,
+            received: 0,
+            lost: 0,
+            in_flight: 0,
+            latency: Some(Summary { count: 1, p50: p99, p99, max: p99 }),
+            rss: Some(rss_mib * 1024 * 1024),
+            fds: Some(fds),
+        }
+    }
+
+    #[test]
+    fn flags_growth_and_drift() {
+        let settings = Settings {
+            duration: Duration::ZERO,
+            rate_per_minute: 0,
+            max_size: 0,
+            report_every: Duration::ZERO,
+            max_rss_growth: 64 * 1024 * 1024,
+            max_fd_growth: 4,
+            max_latency_drift: 4.0,
+        };
+        assert!(problems(&settings, &report(100, 20, 5), &report(150, 24, 15)).is_empty());
+        let found = problems(&settings, &report(100, 20, 5), &report(200, 30, 25));
+        assert_eq!(found.len(), 3, "{:?}", found);
+    }
+
+    #[test]
+    fn sizes_stay_within_the_maximum() {
+        let mut rng = Rng(42);
+        let sizes: Vec<usize> = (0..10_000).map(|_| rng.size(200_000)).collect();
+        assert!(sizes.iter().all(|&size| size < 200_000));
+        assert!(sizes.iter().any(|&size| size > 64 * 1024));
+        assert!(sizes.iter().filter(|&&size| size < 1024).count() > 7_000);
+    }
+}
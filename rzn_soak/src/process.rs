@@ -0,0 +1,48 @@
+//! Resource usage of this process, where the platform makes it cheap to read.
+
+/// Resident memory, from `/proc/self/status`. `None` where there is no
+/// procfs.
+pub fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_vm_rss(&status)
+}
+
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line["VmRSS:".len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Open file descriptors, counted in `/proc/self/fd` (Linux) or `/dev/fd`
+/// (macOS). `None` elsewhere.
+pub fn open_fds() -> Option<usize> {
+    let dir = if cfg!(target_os = "linux") {
+        "/proc/self/fd"
+    } else if cfg!(target_os = "macos") {
+        "/dev/fd"
+    } else {
+        return None;
+    };
+    // Minus the descriptor of the listing itself
+    Some(std::fs::read_dir(dir).ok()?.count().saturating_sub(1))
+}
+
+/// `bytes` in MiB, or "n/a".
+pub fn format_mib(bytes: Option<u64>) -> String {
+    bytes.map_or_else(|| "n/a".to_string(), |bytes| format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_resident_memory() {
+        assert_eq!(parse_vm_rss("Name:\trzn_soak\nVmRSS:\t   12345 kB\nThreads:\t4\n"), Some(12345 * 1024));
+        assert_eq!(parse_vm_rss("Name:\trzn_soak\n"), None);
+        if cfg!(target_os = "linux") {
+            assert!(rss_bytes().is_some_and(|bytes| bytes > 0));
+            assert!(open_fds().is_some_and(|fds| fds >= 3));
+        }
+    }
+}
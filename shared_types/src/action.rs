@@ -0,0 +1,240 @@
+//! The `action` of a message, typed.
+//!
+//! Every message on the bridge names what it is in its `action` string.
+//! [`Action`] has a variant for each action the bridge knows, so both sides
+//! can match on them exhaustively, and keeps any other as
+//! [`Action::Unknown`] instead of failing to deserialize: a newer peer may
+//! send actions this build has never heard of. On the wire it is the plain
+//! string, as before.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::chunk::{CHUNK_DATA_ACTION, CHUNK_END_ACTION, CHUNK_START_ACTION};
+use crate::delivery::ACK_ACTION;
+use crate::lifecycle::BROKER_STATE_ACTION;
+use crate::messages::*;
+use crate::pairing::{PAIR_ACTION, PAIR_RESULT_ACTION};
+use crate::sealed::SEALED_ACTION;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Action {
+    PerformTask,
+    TaskResult,
+    CancelTask,
+    TaskCancelled,
+    Ping,
+    Pong,
+    Hello,
+    HelloAck,
+    BridgeError,
+    MessageTooLarge,
+    CommitRequest,
+    Commit,
+    Abort,
+    Log,
+    Configure,
+    ConfigureAck,
+    ConfigureRequest,
+    PauseAll,
+    ResumeAll,
+    Shutdown,
+    BrokerState,
+    Stats,
+    StatsResult,
+    History,
+    HistoryResult,
+    SelectorDegraded,
+    StepStarted,
+    StepProgress,
+    StepCompleted,
+    ChunkStart,
+    ChunkData,
+    ChunkEnd,
+    Sealed,
+    Pair,
+    PairResult,
+    Ack,
+    /// The broker's `bridge_state` (see `rzn_broker_core::lazy`).
+    BridgeState,
+    DeadLetter,
+    /// The broker's `bridge_selftest` and its result (see
+    /// `rzn_broker_core::selftest`).
+    Selftest,
+    SelftestResult,
+    ResolveSelectors,
+    ResolveSelectorsResult,
+    /// Any other action, as it was sent.
+    Unknown(String),
+}
+
+impl Action {
+    /// Every action but [`Action::Unknown`].
+    pub const KNOWN: &'static [Action] = &[
+        Action::PerformTask,
+        Action::TaskResult,
+        Action::CancelTask,
+        Action::TaskCancelled,
+        Action::Ping,
+        Action::Pong,
+        Action::Hello,
+        Action::HelloAck,
+        Action::BridgeError,
+        Action::MessageTooLarge,
+        Action::CommitRequest,
+        Action::Commit,
+        Action::Abort,
+        Action::Log,
+        Action::Configure,
+        Action::ConfigureAck,
+        Action::ConfigureRequest,
+        Action::PauseAll,
+        Action::ResumeAll,
+        Action::Shutdown,
+        Action::BrokerState,
+        Action::Stats,
+        Action::StatsResult,
+        Action::History,
+        Action::HistoryResult,
+        Action::SelectorDegraded,
+        Action::StepStarted,
+        Action::StepProgress,
+        Action::StepCompleted,
+        Action::ChunkStart,
+        Action::ChunkData,
+        Action::ChunkEnd,
+        Action::Sealed,
+        Action::Pair,
+        Action::PairResult,
+        Action::Ack,
+        Action::BridgeState,
+        Action::DeadLetter,
+        Action::Selftest,
+        Action::SelftestResult,
+        Action::ResolveSelectors,
+        Action::ResolveSelectorsResult,
+    ];
+
+    /// The action named `name`, [`Action::Unknown`] if there is none.
+    pub fn parse(name: &str) -> Self {
+        Action::KNOWN.iter().find(|action| action.as_str() == name).cloned().unwrap_or_else(|| Action::Unknown(name.to_string()))
+    }
+
+    /// The action of a message read as JSON, `None` if it has none.
+    pub fn of(message: &serde_json::Value) -> Option<Self> {
+        message.get("action").and_then(|v| v.as_str()).map(Action::parse)
+    }
+
+    /// The name on the wire.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Action::PerformTask => PERFORM_TASK_ACTION,
+            Action::TaskResult => TASK_RESULT_ACTION,
+            Action::CancelTask => CANCEL_TASK_ACTION,
+            Action::TaskCancelled => TASK_CANCELLED_ACTION,
+            Action::Ping => "ping",
+            Action::Pong => "pong",
+            Action::Hello => HELLO_ACTION,
+            Action::HelloAck => HELLO_ACK_ACTION,
+            Action::BridgeError => BRIDGE_ERROR_ACTION,
+            Action::MessageTooLarge => MESSAGE_TOO_LARGE_ACTION,
+            Action::CommitRequest => COMMIT_REQUEST_ACTION,
+            Action::Commit => COMMIT_ACTION,
+            Action::Abort => ABORT_ACTION,
+            Action::Log => LOG_ACTION,
+            Action::Configure => CONFIGURE_ACTION,
+            Action::ConfigureAck => CONFIGURE_ACK_ACTION,
+            Action::ConfigureRequest => CONFIGURE_REQUEST_ACTION,
+            Action::PauseAll => PAUSE_ALL_ACTION,
+            Action::ResumeAll => RESUME_ALL_ACTION,
+            Action::Shutdown => SHUTDOWN_ACTION,
+            Action::BrokerState => BROKER_STATE_ACTION,
+            Action::Stats => STATS_ACTION,
+            Action::StatsResult => STATS_RESULT_ACTION,
+            Action::History => HISTORY_ACTION,
+            Action::HistoryResult => HISTORY_RESULT_ACTION,
+            Action::SelectorDegraded => SELECTOR_DEGRADED_ACTION,
+            Action::StepStarted => STEP_STARTED_ACTION,
+            Action::StepProgress => STEP_PROGRESS_ACTION,
+            Action::StepCompleted => STEP_COMPLETED_ACTION,
+            Action::ChunkStart => CHUNK_START_ACTION,
+            Action::ChunkData => CHUNK_DATA_ACTION,
+            Action::ChunkEnd => CHUNK_END_ACTION,
+            Action::Sealed => SEALED_ACTION,
+            Action::Pair => PAIR_ACTION,
+            Action::PairResult => PAIR_RESULT_ACTION,
+            Action::Ack => ACK_ACTION,
+            Action::BridgeState => "bridge_state",
+            Action::DeadLetter => DEAD_LETTER_ACTION,
+            Action::Selftest => "bridge_selftest",
+            Action::SelftestResult => "bridge_selftest_result",
+            Action::ResolveSelectors => RESOLVE_SELECTORS_ACTION,
+            Action::ResolveSelectorsResult => RESOLVE_SELECTORS_RESULT_ACTION,
+            Action::Unknown(name) => name,
+        }
+    }
+}
+
+impl From<&str> for Action {
+    fn from(name: &str) -> Self {
+        Action::parse(name)
+    }
+}
+
+impl From<String> for Action {
+    fn from(name: String) -> Self {
+        match Action::parse(&name) {
+            Action::Unknown(_) => Action::Unknown(name),
+            action => action,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Compares with the wire name, so `message.action == HELLO_ACTION` still reads well
+impl PartialEq<str> for Action {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Action {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Serialize for Action {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Action::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_known_and_unknown_actions() {
+        for action in Action::KNOWN {
+            assert_eq!(Action::parse(action.as_str()), *action);
+            assert_eq!(serde_json::to_value(action).unwrap(), action.as_str());
+        }
+        let message: Message = serde_json::from_str(r#"{"action":"teleport","task_id":"t1"}"#).unwrap();
+        assert_eq!(message.action, Action::Unknown("teleport".to_string()));
+        assert_eq!(serde_json::to_value(&message).unwrap()["action"], "teleport");
+        assert!(Action::Ping == "ping" && Action::of(&serde_json::json!({ "action": "pong" })) == Some(Action::Pong));
+    }
+}
@@ -0,0 +1,374 @@
+//! Alert rules over extracted variables.
+//!
+//! A rule pairs a condition such as `price < 100 and stock > 0` with actions to
+//! take when a task result matches it. Evaluating rules is pure; performing the
+//! actions (calling a webhook, raising an event, showing a notification) is
+//! left to the embedding app, which gets the matching [`Alert`]s.
+//!
+//! Condition syntax:
+//!
+//! ```text
+//! condition  := or
+//! or         := and ("or" and)*
+//! and        := term ("and" term)*
+//! term       := "(" or ")" | path op literal
+//! path       := variable ("." field)*
+//! op         := "<" | "<=" | ">" | ">=" | "==" | "!=" | "contains"
+//! literal    := number | "string" | 'string' | true | false | null
+//! ```
+//!
+//! Strings compared with numbers are parsed with the task's [`Locale`], so
+//! `price < 100` works on "1.299,00 €". A comparison on an array (from
+//! `extract` with `all: true`) holds if it holds for any element.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::locale::Locale;
+use crate::messages::TaskResult;
+
+/// A configured alert.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    pub condition: String,
+    #[serde(default)]
+    pub actions: Vec<AlertAction>,
+}
+
+/// What to do when a rule matches. Performed by the embedding app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertAction {
+    /// POST the alert as JSON to `url`.
+    Webhook { url: String },
+    /// Emit an event on the app's own event stream.
+    Event {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// Show a desktop notification.
+    Notify {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        message: String,
+    },
+}
+
+/// A rule that matched a task result.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub actions: Vec<AlertAction>,
+    /// Values of the variables the condition refers to.
+    pub values: BTreeMap<String, Value>,
+}
+
+/// A rule whose condition doesn't parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConditionError {
+    pub rule: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid condition in alert rule {:?}: {}", self.rule, self.message)
+    }
+}
+
+impl std::error::Error for ConditionError {}
+
+/// Parsed alert rules, ready to evaluate.
+#[derive(Debug, Clone, Default)]
+pub struct AlertRules {
+    rules: Vec<(AlertRule, Condition)>,
+}
+
+impl AlertRules {
+    /// Parses every rule's condition.
+    pub fn new(rules: Vec<AlertRule>) -> Result<Self, ConditionError> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| match parse(&rule.condition) {
+                Ok(condition) => Ok((rule, condition)),
+                Err(message) => Err(ConditionError { rule: rule.name.clone(), message }),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AlertRules { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns an alert for every rule `result` matches.
+    pub fn evaluate(&self, result: &TaskResult, locale: &Locale) -> Vec<Alert> {
+        let variables: BTreeMap<&str, &Value> = result
+            .steps
+            .iter()
+            .filter_map(|step| step.data.as_ref()?.as_object())
+            .flat_map(|data| data.iter().map(|(name, value)| (name.as_str(), value)))
+            .collect();
+        self.rules
+            .iter()
+            .filter(|(_, condition)| condition.holds(&variables, locale))
+            .map(|(rule, condition)| {
+                let mut names = Vec::new();
+                condition.variables(&mut names);
+                let values = names
+                    .into_iter()
+                    .filter_map(|name| Some((name.to_string(), (*variables.get(name)?).clone())))
+                    .collect();
+                Alert { rule: rule.name.clone(), actions: rule.actions.clone(), values }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Or(Box<Condition>, Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Compare { path: Vec<String>, op: Op, literal: Value },
+}
+
+impl Condition {
+    fn holds(&self, variables: &BTreeMap<&str, &Value>, locale: &Locale) -> bool {
+        match self {
+            Condition::Or(a, b) => a.holds(variables, locale) || b.holds(variables, locale),
+            Condition::And(a, b) => a.holds(variables, locale) && b.holds(variables, locale),
+            Condition::Compare { path, op, literal } => variables
+                .get(path[0].as_str())
+                .is_some_and(|value| resolve(value, &path[1..]).iter().any(|v| compare(v, *op, literal, locale))),
+        }
+    }
+
+    fn variables<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Condition::Or(a, b) | Condition::And(a, b) => {
+                a.variables(names);
+                b.variables(names);
+            }
+            Condition::Compare { path, .. } => {
+                if !names.contains(&path[0].as_str()) {
+                    names.push(&path[0]);
+                }
+            }
+        }
+    }
+}
+
+/// Values at `fields` below `value`, fanning out over arrays.
+fn resolve<'a>(value: &'a Value, fields: &[String]) -> Vec<&'a Value> {
+    match (value, fields.split_first()) {
+        (Value::Array(items), _) => items.iter().flat_map(|item| resolve(item, fields)).collect(),
+        (_, None) => vec![value],
+        (Value::Object(record), Some((field, rest))) => record.get(field).map(|v| resolve(v, rest)).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+fn compare(value: &Value, op: Op, literal: &Value, locale: &Locale) -> bool {
+    if let Some(expected) = literal.as_f64() {
+        let actual = match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => locale.parse_number(s),
+            _ => None,
+        };
+        let Some(actual) = actual else { return op == Op::Ne };
+        return match op {
+            Op::Lt => actual < expected,
+            Op::Le => actual <= expected,
+            Op::Gt => actual > expected,
+            Op::Ge => actual >= expected,
+            Op::Eq => actual == expected,
+            Op::Ne => actual != expected,
+            Op::Contains => false,
+        };
+    }
+    match (op, value, literal) {
+        (Op::Contains, Value::String(s), Value::String(needle)) => s.contains(needle.as_str()),
+        (Op::Eq, Value::String(s), Value::String(expected)) => s.trim() == expected,
+        (Op::Ne, Value::String(s), Value::String(expected)) => s.trim() != expected,
+        (Op::Eq, _, _) => value == literal,
+        (Op::Ne, _, _) => value != literal,
+        _ => false,
+    }
+}
+
+// --- Parser ---
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Path(Vec<String>),
+    Literal(Value),
+    Op(Op),
+    And,
+    Or,
+    Open,
+    Close,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        i += 1;
+        match c {
+            _ if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '<' | '>' | '=' | '!' => {
+                let eq = chars.get(i) == Some(&'=');
+                if eq {
+                    i += 1;
+                }
+                tokens.push(Token::Op(match (c, eq) {
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    ('=', true) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    _ => return Err(format!("unknown operator at {}", start)),
+                }));
+            }
+            '"' | '\'' => {
+                let end = chars[i..].iter().position(|&q| q == c).ok_or("unterminated string")? + i;
+                tokens.push(Token::Literal(Value::String(chars[i..end].iter().collect())));
+                i = end + 1;
+            }
+            _ if c.is_ascii_digit() || c == '-' => {
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                let number: f64 = text.parse().map_err(|_| format!("invalid number {:?}", text))?;
+                tokens.push(Token::Literal(Value::from(number)));
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '-') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "contains" => Token::Op(Op::Contains),
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ if word.split('.').any(str::is_empty) => return Err(format!("invalid variable {:?}", word)),
+                    _ => Token::Path(word.split('.').map(String::from).collect()),
+                });
+            }
+            _ => return Err(format!("unexpected {:?} at {}", c, start)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse(input: &str) -> Result<Condition, String> {
+    let tokens = tokenize(input)?;
+    let mut pos = 0;
+    let condition = parse_or(&tokens, &mut pos)?;
+    match tokens.get(pos) {
+        None => Ok(condition),
+        Some(token) => Err(format!("unexpected {:?}", token)),
+    }
+}
+
+fn parse_or(tokens: &[Token], pos: &mut usize) -> Result<Condition, String> {
+    let mut left = parse_and(tokens, pos)?;
+    while tokens.get(*pos) == Some(&Token::Or) {
+        *pos += 1;
+        left = Condition::Or(Box::new(left), Box::new(parse_and(tokens, pos)?));
+    }
+    Ok(left)
+}
+
+fn parse_and(tokens: &[Token], pos: &mut usize) -> Result<Condition, String> {
+    let mut left = parse_term(tokens, pos)?;
+    while tokens.get(*pos) == Some(&Token::And) {
+        *pos += 1;
+        left = Condition::And(Box::new(left), Box::new(parse_term(tokens, pos)?));
+    }
+    Ok(left)
+}
+
+fn parse_term(tokens: &[Token], pos: &mut usize) -> Result<Condition, String> {
+    let token = tokens.get(*pos).ok_or("condition ends early")?;
+    *pos += 1;
+    match token {
+        Token::Open => {
+            let inner = parse_or(tokens, pos)?;
+            if tokens.get(*pos) != Some(&Token::Close) {
+                return Err("missing ')'".to_string());
+            }
+            *pos += 1;
+            Ok(inner)
+        }
+        Token::Path(path) => match (tokens.get(*pos), tokens.get(*pos + 1)) {
+            (Some(Token::Op(op)), Some(Token::Literal(literal))) => {
+                *pos += 2;
+                Ok(Condition::Compare { path: path.clone(), op: *op, literal: literal.clone() })
+            }
+            _ => Err(format!("expected an operator and a value after {:?}", path.join("."))),
+        },
+        other => Err(format!("expected a variable, found {:?}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn result(data: Value) -> TaskResult {
+        serde_json::from_value(json!({ "steps": [{ "type": "extract", "success": true, "data": data }] })).unwrap()
+    }
+
+    fn fires(condition: &str, data: Value, locale: &Locale) -> bool {
+        let rule = AlertRule { name: "r".to_string(), condition: condition.to_string(), actions: Vec::new() };
+        !AlertRules::new(vec![rule]).unwrap().evaluate(&result(data), locale).is_empty()
+    }
+
+    #[test]
+    fn evaluates_comparisons_on_scraped_values() {
+        let us = Locale::default();
+        let de = Locale::from_tag("de").unwrap();
+        assert!(fires("price < 100", json!({ "price": "$99.50" }), &us));
+        assert!(!fires("price < 100", json!({ "price": "1.299,00 €" }), &de));
+        assert!(fires("title contains 'Sale' and (stock > 0 or backorder == true)", json!({ "title": "Summer Sale", "stock": "0", "backorder": true }), &us));
+        // Any element of a multi-value extract
+        assert!(fires("items.price <= 5", json!({ "items": [{ "price": "9" }, { "price": "4.99" }] }), &us));
+        // Missing variables never match
+        assert!(!fires("price < 100", json!({}), &us));
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        for condition in ["price <", "price < 100 and", "(price < 1", "price ~ 1", "price < 'x"] {
+            let rule = AlertRule { name: "r".to_string(), condition: condition.to_string(), actions: Vec::new() };
+            assert!(AlertRules::new(vec![rule]).is_err(), "{}", condition);
+        }
+    }
+}
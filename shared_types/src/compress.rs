@@ -0,0 +1,141 @@
+//! Compression of IPC frame payloads.
+//!
+//! Large results, such as HTML dumps, compress well. A sender that is
+//! configured to compress ([`Compression`]) compresses payloads over its
+//! threshold and sets [`FrameFlags::COMPRESSED`](crate::frame::FrameFlags),
+//! but only once the peer's hello listed the codec's capability
+//! ([`Codec::capability`]). Both the broker and the Main App read either
+//! codec: [`decompress`] tells them apart by their magic bytes, and the frame
+//! read helpers decompress flagged payloads before returning them.
+
+use std::io::{self, Read, Write};
+
+use crate::frame::MessageTooLarge;
+use crate::peek::peek_envelope;
+
+/// Payloads smaller than this aren't worth compressing.
+pub const DEFAULT_THRESHOLD: usize = 64 * 1024;
+
+/// Capabilities of a peer that reads compressed frames, one per codec.
+pub const COMPRESSION_CAPABILITIES: &[&str] = &["compression:zstd", "compression:gzip"];
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zstd" => Some(Codec::Zstd),
+            "gzip" => Some(Codec::Gzip),
+            _ => None,
+        }
+    }
+
+    /// What a peer lists in its hello's capabilities if it reads this codec.
+    pub fn capability(self) -> &'static str {
+        match self {
+            Codec::Zstd => "compression:zstd",
+            Codec::Gzip => "compression:gzip",
+        }
+    }
+}
+
+/// How a sender compresses frame payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub codec: Codec,
+    /// Smallest payload that is compressed.
+    pub threshold: usize,
+}
+
+impl Compression {
+    /// The codec in `RZN_COMPRESSION` (`zstd` or `gzip`) with the threshold in
+    /// `RZN_COMPRESSION_THRESHOLD`, else [`DEFAULT_THRESHOLD`]. `None` if
+    /// compression isn't turned on.
+    pub fn from_env() -> Option<Self> {
+        let name = std::env::var("RZN_COMPRESSION").ok().filter(|name| !name.is_empty() && name != "off")?;
+        let Some(codec) = Codec::parse(&name) else {
+            log::warn!("Ignoring invalid RZN_COMPRESSION={:?}", name);
+            return None;
+        };
+        let threshold = std::env::var("RZN_COMPRESSION_THRESHOLD").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_THRESHOLD);
+        Some(Compression { codec, threshold })
+    }
+
+    /// Whether a peer with these hello capabilities reads our codec.
+    pub fn accepted_by(self, capabilities: &[String]) -> bool {
+        capabilities.iter().any(|capability| capability == self.codec.capability())
+    }
+
+    /// The compressed payload, or `None` if it is under the threshold or
+    /// doesn't get smaller.
+    pub fn compress(self, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if payload.len() < self.threshold {
+            return Ok(None);
+        }
+        let compressed = match self.codec {
+            Codec::Zstd => zstd::bulk::compress(payload, 3)?,
+            Codec::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()?
+            }
+        };
+        Ok((compressed.len() < payload.len()).then_some(compressed))
+    }
+}
+
+/// Decompresses a payload of either codec. A payload that decompresses to
+/// more than `max_len` bytes fails with [`MessageTooLarge`], like an
+/// uncompressed one over the limit.
+pub fn decompress(payload: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = if payload.starts_with(&ZSTD_MAGIC) {
+        Box::new(zstd::stream::read::Decoder::new(payload)?)
+    } else if payload.starts_with(&GZIP_MAGIC) {
+        Box::new(flate2::read::GzDecoder::new(payload))
+    } else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed payload of unknown format"));
+    };
+    let mut decompressed = Vec::new();
+    decoder.take(max_len as u64 + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_len {
+        let envelope = peek_envelope(&decompressed);
+        let too_large = MessageTooLarge {
+            len: decompressed.len(),
+            limit: max_len,
+            action: envelope.action.map(|action| action.into_owned()),
+            task_id: envelope.task_id.map(|task_id| task_id.into_owned()),
+        };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, too_large));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_large_payloads_with_either_codec() {
+        let payload = format!(r#"{{"action":"task_result","task_id":"t1","html":"{}"}}"#, "<div>row</div>".repeat(1000));
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let compression = Compression { codec, threshold: 1024 };
+            assert_eq!(compression.compress(b"{}").unwrap(), None);
+            let compressed = compression.compress(payload.as_bytes()).unwrap().unwrap();
+            assert!(compressed.len() < payload.len() / 10);
+            assert_eq!(decompress(&compressed, payload.len()).unwrap(), payload.as_bytes());
+
+            // The limit applies to the decompressed size
+            let error = decompress(&compressed, 100).unwrap_err();
+            let too_large = MessageTooLarge::of(&error).unwrap();
+            assert_eq!((too_large.limit, too_large.task_id.as_deref()), (100, Some("t1")));
+        }
+        assert!(decompress(b"plain", 100).is_err());
+    }
+}
@@ -0,0 +1,400 @@
+//! Bridge settings shared by the broker and the Main App.
+//!
+//! Both sides must derive the same endpoint, so they read the same settings,
+//! each from the first source that sets it:
+//!
+//! 1. command-line flags (`--socket`, `--profile`, `--config` and the
+//!    `--max-message-size` flags), see [`Overrides`];
+//! 2. the `RZN_BRIDGE_SOCKET` and `RZN_PROFILE` environment variables;
+//! 3. the TOML file named by `RZN_CONFIG`, or `rzn-bridge/bridge.toml` in the
+//!    platform's config directory;
+//! 4. the built-in defaults.
+//!
+//! ```toml
+//! # Endpoint base name; the OS user and profile are appended to it
+//! socket = "com.example.myapp.bridge"
+//! profile = "work"
+//! # Largest message in bytes, either way (default 10 MiB)
+//! max_message_size = 10485760
+//! # Per direction, winning over max_message_size
+//! max_message_size_to_app = 52428800
+//! max_message_size_to_extension = 1048576
+//! # Where the binaries log: stderr (default), native, journald, oslog or eventlog
+//! log_sink = "native"
+//! # Tokio runtime of the binaries: multi_thread (default) or current_thread
+//! runtime = "current_thread"
+//! worker_threads = 2
+//! max_blocking_threads = 8
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+use crate::frame::{MessageLimits, MAX_MESSAGE_SIZE};
+use crate::logging::{LogSink, LOG_SINK_ENV_VAR};
+use crate::profile::{DEFAULT_PROFILE, PROFILE_ENV_VAR};
+use crate::runtime::RuntimeFlavor;
+#[cfg(feature = "runtime")]
+use crate::runtime::{parse_threads, RuntimeOptions, MAX_BLOCKING_THREADS_ENV_VAR, RUNTIME_ENV_VAR, WORKER_THREADS_ENV_VAR};
+
+/// Environment variable naming the config file.
+pub const CONFIG_ENV_VAR: &str = "RZN_CONFIG";
+
+/// Environment variable overriding the endpoint base name.
+pub const SOCKET_ENV_VAR: &str = "RZN_BRIDGE_SOCKET";
+
+/// Endpoint base name used when nothing else sets one.
+pub const DEFAULT_SOCKET_BASE: &str = "com.yourcompany.projectagentis.broker";
+
+// Keeps socket names well within the socket path limit, with room for user and profile
+const MAX_SOCKET_BASE_LEN: usize = 48;
+
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// Contents of the config file. Unset fields fall back to the defaults.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    /// Endpoint base name, e.g. `com.example.myapp.bridge`.
+    pub socket: Option<String>,
+    /// Profile to use when neither `--profile` nor `RZN_PROFILE` is given.
+    pub profile: Option<String>,
+    /// Largest message in bytes, in either direction.
+    pub max_message_size: Option<usize>,
+    /// Largest message from the extension to the Main App.
+    pub max_message_size_to_app: Option<usize>,
+    /// Largest message from the Main App to the extension.
+    pub max_message_size_to_extension: Option<usize>,
+    /// Where the binaries log, see [`LogSink`].
+    pub log_sink: Option<String>,
+    /// Runtime flavor, see [`RuntimeFlavor`].
+    pub runtime: Option<String>,
+    /// Worker threads of a multi-thread runtime.
+    pub worker_threads: Option<usize>,
+    /// Most threads kept for blocking work.
+    pub max_blocking_threads: Option<usize>,
+}
+
+impl BridgeConfig {
+    /// The config file in effect: `--config`, else `RZN_CONFIG`, else the
+    /// default location (which need not exist).
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = Overrides::installed().and_then(|o| o.config.clone()) {
+            return Some(path);
+        }
+        match std::env::var_os(CONFIG_ENV_VAR) {
+            Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => default_config_dir().map(|dir| dir.join("rzn-bridge").join("bridge.toml")),
+        }
+    }
+
+    /// Reads the config file and checks the effective socket name. A missing
+    /// file at the default location reads as an empty config.
+    pub fn load() -> Result<Self, ConfigError> {
+        let config = match BridgeConfig::path() {
+            Some(path) => BridgeConfig::read(&path)?,
+            None => BridgeConfig::default(),
+        };
+        validate_socket_base(&config.socket())?;
+        Ok(config)
+    }
+
+    /// [`load`](Self::load), logging the error and using the defaults instead.
+    pub fn load_or_default() -> Self {
+        BridgeConfig::load().unwrap_or_else(|e| {
+            log::error!("Ignoring bridge configuration: {}", e);
+            BridgeConfig::default()
+        })
+    }
+
+    /// Parses a config file's contents.
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let config: BridgeConfig = toml::from_str(text).map_err(|e| ConfigError::new(None, e.message()))?;
+        if let Some(socket) = &config.socket {
+            validate_socket_base(socket)?;
+        }
+        if let Some(sink) = config.log_sink.as_deref().filter(|sink| LogSink::parse(sink).is_none()) {
+            return Err(ConfigError::new(None, format!("unknown log_sink {:?}", sink)));
+        }
+        if let Some(runtime) = config.runtime.as_deref().filter(|runtime| RuntimeFlavor::parse(runtime).is_none()) {
+            return Err(ConfigError::new(None, format!("unknown runtime {:?}", runtime)));
+        }
+        if config.worker_threads == Some(0) || config.max_blocking_threads == Some(0) {
+            return Err(ConfigError::new(None, "worker_threads and max_blocking_threads must be at least 1"));
+        }
+        Ok(config)
+    }
+
+    /// Endpoint base name in effect.
+    pub fn socket(&self) -> String {
+        Overrides::installed()
+            .and_then(|o| o.socket.clone())
+            .or_else(|| std::env::var(SOCKET_ENV_VAR).ok().filter(|s| !s.is_empty()))
+            .or_else(|| self.socket.clone())
+            .unwrap_or_else(|| DEFAULT_SOCKET_BASE.to_string())
+    }
+
+    /// Profile name in effect (validated by [`Profile`](crate::Profile)).
+    pub fn profile(&self) -> String {
+        Overrides::installed()
+            .and_then(|o| o.profile.clone())
+            .or_else(|| std::env::var(PROFILE_ENV_VAR).ok().filter(|s| !s.is_empty()))
+            .or_else(|| self.profile.clone())
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string())
+    }
+
+    /// Log sink in effect: `RZN_LOG_SINK`, else the file's `log_sink`, else
+    /// stderr.
+    pub fn log_sink(&self) -> LogSink {
+        std::env::var(LOG_SINK_ENV_VAR)
+            .ok()
+            .filter(|s| !s.is_empty())
+            .and_then(|name| {
+                LogSink::parse(&name).or_else(|| {
+                    eprintln!("Ignoring invalid {}={:?}", LOG_SINK_ENV_VAR, name);
+                    None
+                })
+            })
+            .or_else(|| self.log_sink.as_deref().and_then(LogSink::parse))
+            .unwrap_or_default()
+    }
+
+    /// Runtime options in effect: each of `RZN_RUNTIME`, `RZN_WORKER_THREADS`
+    /// and `RZN_MAX_BLOCKING_THREADS`, else its setting in the file, else
+    /// tokio's default. Only with the `runtime` feature.
+    #[cfg(feature = "runtime")]
+    pub fn runtime(&self) -> RuntimeOptions {
+        RuntimeOptions {
+            flavor: env_setting(RUNTIME_ENV_VAR, RuntimeFlavor::parse)
+                .or_else(|| self.runtime.as_deref().and_then(RuntimeFlavor::parse))
+                .unwrap_or_default(),
+            worker_threads: env_setting(WORKER_THREADS_ENV_VAR, parse_threads).or(self.worker_threads),
+            max_blocking_threads: env_setting(MAX_BLOCKING_THREADS_ENV_VAR, parse_threads).or(self.max_blocking_threads),
+        }
+    }
+
+    /// Message size limits in effect. Each direction takes its own flag, else
+    /// `--max-message-size`, else its own setting in the file, else the file's
+    /// `max_message_size`, else [`MAX_MESSAGE_SIZE`].
+    pub fn message_limits(&self) -> MessageLimits {
+        let flags = Overrides::installed();
+        let limit = |flag: Option<usize>, file: Option<usize>| {
+            flag.or_else(|| flags.and_then(|o| o.max_message_size))
+                .or(file)
+                .or(self.max_message_size)
+                .unwrap_or(MAX_MESSAGE_SIZE)
+        };
+        MessageLimits {
+            to_app: limit(flags.and_then(|o| o.max_message_size_to_app), self.max_message_size_to_app),
+            to_extension: limit(flags.and_then(|o| o.max_message_size_to_extension), self.max_message_size_to_extension),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => BridgeConfig::from_toml(&text).map_err(|e| ConfigError::new(Some(path), e.message)),
+            // Only the default location is optional
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit_path() => Ok(BridgeConfig::default()),
+            Err(e) => Err(ConfigError::new(Some(path), e)),
+        }
+    }
+}
+
+/// Settings given on the command line, which win over the environment and
+/// the config file once [installed](Self::install).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub config: Option<PathBuf>,
+    pub socket: Option<String>,
+    pub profile: Option<String>,
+    pub max_message_size: Option<usize>,
+    pub max_message_size_to_app: Option<usize>,
+    pub max_message_size_to_extension: Option<usize>,
+}
+
+impl Overrides {
+    /// Picks `--config`, `--socket`, `--profile`, `--max-message-size`,
+    /// `--max-message-size-to-app` and `--max-message-size-to-extension` (as
+    /// `--flag value` or `--flag=value`) out of `args` and returns the other
+    /// arguments, e.g. the extension origin the browser passes to the broker.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> (Self, Vec<String>) {
+        let mut overrides = Overrides::default();
+        let mut rest = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            let slot = match flag.as_str() {
+                "--socket" => &mut overrides.socket,
+                "--profile" => &mut overrides.profile,
+                "--config" => {
+                    overrides.config = inline.or_else(|| args.next()).map(PathBuf::from);
+                    continue;
+                }
+                "--max-message-size" => {
+                    overrides.max_message_size = size_arg(&flag, inline.or_else(|| args.next()));
+                    continue;
+                }
+                "--max-message-size-to-app" => {
+                    overrides.max_message_size_to_app = size_arg(&flag, inline.or_else(|| args.next()));
+                    continue;
+                }
+                "--max-message-size-to-extension" => {
+                    overrides.max_message_size_to_extension = size_arg(&flag, inline.or_else(|| args.next()));
+                    continue;
+                }
+                _ => {
+                    rest.push(arg);
+                    continue;
+                }
+            };
+            *slot = inline.or_else(|| args.next());
+        }
+        (overrides, rest)
+    }
+
+    /// Applies these settings to the whole process. Only the first call has
+    /// an effect; returns whether it was this one.
+    pub fn install(self) -> bool {
+        OVERRIDES.set(self).is_ok()
+    }
+
+    /// The installed overrides, if any.
+    pub fn installed() -> Option<&'static Overrides> {
+        OVERRIDES.get()
+    }
+}
+
+/// A config file that can't be read or used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(path: Option<&Path>, message: impl fmt::Display) -> Self {
+        ConfigError { path: path.map(Path::to_path_buf), message: message.to_string() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}: {}", path.display(), self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn validate_socket_base(name: &str) -> Result<(), ConfigError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_SOCKET_BASE_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && !name.starts_with('.');
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::new(None, format!(
+            "invalid socket name {:?}: use 1-{} ASCII letters, digits, '.', '-' or '_'",
+            name, MAX_SOCKET_BASE_LEN
+        )))
+    }
+}
+
+/// Parses the value of a size flag, in bytes.
+fn size_arg(flag: &str, value: Option<String>) -> Option<usize> {
+    let bytes = value.as_deref().and_then(|v| v.parse().ok()).filter(|&bytes| bytes > 0);
+    if bytes.is_none() {
+        log::warn!("Ignoring {} without a size in bytes: {:?}", flag, value.unwrap_or_default());
+    }
+    bytes
+}
+
+/// Parses the environment variable `key`, if set, warning about a value
+/// `parse` rejects.
+#[cfg(feature = "runtime")]
+fn env_setting<T>(key: &str, parse: impl Fn(&str) -> Option<T>) -> Option<T> {
+    let value = std::env::var(key).ok().filter(|v| !v.is_empty())?;
+    let parsed = parse(&value);
+    if parsed.is_none() {
+        log::warn!("Ignoring invalid {}={:?}", key, value);
+    }
+    parsed
+}
+
+fn explicit_path() -> bool {
+    Overrides::installed().is_some_and(|o| o.config.is_some())
+        || std::env::var_os(CONFIG_ENV_VAR).is_some_and(|p| !p.is_empty())
+}
+
+/// `%APPDATA%` on Windows, `~/Library/Application Support` on macOS and
+/// `$XDG_CONFIG_HOME` (or `~/.config`) elsewhere.
+fn default_config_dir() -> Option<PathBuf> {
+    let env = |key: &str| std::env::var_os(key).filter(|v| !v.is_empty()).map(PathBuf::from);
+    if cfg!(windows) {
+        env("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env("XDG_CONFIG_HOME").or_else(|| env("HOME").map(|home| home.join(".config")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_config_file() {
+        let config = BridgeConfig::from_toml("socket = \"com.example.app\"\nprofile = \"work\"\n").unwrap();
+        assert_eq!(config.socket.as_deref(), Some("com.example.app"));
+        assert_eq!(config.profile.as_deref(), Some("work"));
+        assert!(BridgeConfig::from_toml("sockt = \"x\"").is_err());
+        assert!(BridgeConfig::from_toml("socket = \"../tmp/x\"").is_err());
+
+        let config = BridgeConfig::from_toml("max_message_size = 1000\nmax_message_size_to_extension = 500\n").unwrap();
+        assert_eq!(config.message_limits(), MessageLimits { to_app: 1000, to_extension: 500 });
+
+        let config = BridgeConfig::from_toml("log_sink = \"journald\"").unwrap();
+        assert_eq!(config.log_sink.as_deref().and_then(LogSink::parse), Some(LogSink::Journald));
+        assert!(BridgeConfig::from_toml("log_sink = \"syslog\"").is_err());
+
+        let config = BridgeConfig::from_toml("runtime = \"current_thread\"\nmax_blocking_threads = 4\n").unwrap();
+        assert_eq!(config.runtime.as_deref().and_then(RuntimeFlavor::parse), Some(RuntimeFlavor::CurrentThread));
+        assert_eq!(config.max_blocking_threads, Some(4));
+        assert!(BridgeConfig::from_toml("runtime = \"fibers\"").is_err());
+        assert!(BridgeConfig::from_toml("worker_threads = 0").is_err());
+    }
+
+    #[test]
+    fn picks_flags_out_of_the_arguments() {
+        let args = [
+            "chrome-extension://abc/", "--socket", "com.example.app", "--profile=work", "--config", "/etc/b.toml",
+            "--max-message-size-to-app=2048",
+        ];
+        let (overrides, rest) = Overrides::from_args(args.map(String::from));
+        assert_eq!(overrides, Overrides {
+            config: Some(PathBuf::from("/etc/b.toml")),
+            socket: Some("com.example.app".to_string()),
+            profile: Some("work".to_string()),
+            max_message_size_to_app: Some(2048),
+            ..Overrides::default()
+        });
+        assert_eq!(rest, ["chrome-extension://abc/"]);
+    }
+}
@@ -0,0 +1,234 @@
+//! Delivering the Main App's messages once: message IDs, acks and duplicates.
+//!
+//! `task_id` is chosen by the Main App and doesn't tell a new message from
+//! the same one sent again. So every message the broker relays from the Main
+//! App to the extension carries a `msg_id` in its envelope: the one the Main
+//! App gave it, or one the broker assigns. A Main App whose `hello_ack`
+//! lists [`ACK_CAPABILITY`] gets an `ack` ([`Ack`]) for each message it sent,
+//! once the broker has taken it.
+//!
+//! A Main App that may send a message again, e.g. after a reconnect when it
+//! can't tell whether the first one arrived, gives it a `msg_id` of its own
+//! and sends it again under the same one. The broker acknowledges the copy
+//! as a [`duplicate`](Ack::duplicate) instead of relaying it, and the
+//! extension skips message IDs it has seen, so the task doesn't run twice.
+//!
+//! ```json
+//! {"action": "perform_task", "task_id": "t1", "task": {...}, "msg_id": "app-7"}
+//! {"action": "ack", "task_id": "t1", "success": true, "result": {"msg_id": "app-7", "duplicate": false}}
+//! ```
+//!
+//! The extension's messages, task results above all, are delivered at most
+//! once by default. A Main App that lists [`AT_LEAST_ONCE_CAPABILITY`] in its
+//! `hello_ack` gets each of them with a `msg_id` as its idempotency key and
+//! acknowledges it with an `ack` of its own ([`Message::ack`]). The broker
+//! writes the messages not acknowledged when the connection drops again to
+//! the next connection, so the Main App keeps the keys it processed
+//! ([`ProcessedKeys`]) to skip the ones it already has.
+//!
+//! ```json
+//! {"msg_id": "1f2.18c-e7", "action": "task_result", "task_id": "t1", "success": true, "result": {...}}
+//! {"action": "ack", "task_id": "t1", "data": {"msg_id": "1f2.18c-e7", "duplicate": false}}
+//! ```
+
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::action::Action;
+use crate::config::BridgeConfig;
+use crate::messages::{ExtensionResponse, Message};
+
+/// Action of the broker's acknowledgment of a message from the Main App.
+pub use rzn_protocol::correlate::ACK_ACTION;
+
+/// Capability of a Main App that wants an `ack` for every message.
+pub const ACK_CAPABILITY: &str = "ack";
+/// Capability of a Main App that acknowledges the extension's messages and
+/// wants them again until it does.
+pub const AT_LEAST_ONCE_CAPABILITY: &str = "at_least_once";
+
+// Keys a receiver remembers; the file is rewritten with these once it holds twice as many
+const MAX_PROCESSED_KEYS: usize = 10_000;
+
+/// `result` of an `ack`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ack {
+    pub msg_id: String,
+    /// The message was taken before under this ID, so it wasn't relayed again.
+    #[serde(default)]
+    pub duplicate: bool,
+}
+
+impl ExtensionResponse {
+    /// The `ack` of the message `msg_id` of task `task_id`.
+    pub fn ack(task_id: impl Into<String>, msg_id: impl Into<String>, duplicate: bool) -> Self {
+        ExtensionResponse {
+            action: Action::Ack,
+            task_id: task_id.into(),
+            success: true,
+            result: serde_json::to_value(Ack { msg_id: msg_id.into(), duplicate }).ok(),
+            error: None,
+        }
+    }
+}
+
+impl Message {
+    /// The Main App's `ack` of the extension's message `msg_id`, for task
+    /// `task_id`.
+    pub fn ack(task_id: impl Into<String>, msg_id: impl Into<String>, duplicate: bool) -> Self {
+        Message {
+            action: Action::Ack,
+            task_id: task_id.into(),
+            task: None,
+            data: serde_json::to_value(Ack { msg_id: msg_id.into(), duplicate }).ok(),
+            ttl_ms: None,
+            msg_id: None,
+        }
+    }
+}
+
+/// The idempotency keys (`msg_id`s) of the messages a receiver processed,
+/// kept in a file so that a message written again after a restart of either
+/// side is still recognized. Only the most recent keys are kept.
+pub struct ProcessedKeys {
+    path: PathBuf,
+    keys: HashSet<String>,
+    order: VecDeque<String>,
+    /// Lines in the file, including keys no longer kept.
+    lines: usize,
+}
+
+impl ProcessedKeys {
+    /// `processed-keys.log` next to the bridge config file.
+    pub fn default_path() -> Option<PathBuf> {
+        BridgeConfig::path().map(|path| path.with_file_name("processed-keys.log"))
+    }
+
+    /// Reads the keys in `path`, one per line, where new keys are added too.
+    /// A missing file reads as no keys.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        };
+        let mut processed = ProcessedKeys { path, keys: HashSet::new(), order: VecDeque::new(), lines: 0 };
+        for key in contents.lines().filter(|line| !line.is_empty()) {
+            processed.remember(key.to_string());
+            processed.lines += 1;
+        }
+        Ok(processed)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Records `key` as processed, in the file before in memory. `false` if
+    /// it already was.
+    pub fn insert(&mut self, key: &str) -> io::Result<bool> {
+        if self.contains(key) {
+            return Ok(false);
+        }
+        if self.lines >= 2 * MAX_PROCESSED_KEYS {
+            self.compact()?;
+        }
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(format!("{}\n", key).as_bytes())?;
+        self.lines += 1;
+        self.remember(key.to_string());
+        Ok(true)
+    }
+
+    fn remember(&mut self, key: String) {
+        if self.order.len() == MAX_PROCESSED_KEYS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.keys.remove(&oldest);
+            }
+        }
+        if self.keys.insert(key.clone()) {
+            self.order.push_back(key);
+        }
+    }
+
+    /// Rewrites the file with the keys still kept.
+    fn compact(&mut self) -> io::Result<()> {
+        let partial = self.path.with_extension("log.tmp");
+        let contents: String = self.order.iter().map(|key| format!("{}\n", key)).collect();
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, &self.path)?;
+        self.lines = self.order.len();
+        Ok(())
+    }
+}
+
+/// The `msg_id` of a message read as JSON, if it has one.
+pub fn msg_id(message: &serde_json::Value) -> Option<&str> {
+    message.get("msg_id").and_then(|v| v.as_str())
+}
+
+/// `message`, a JSON object, with `"msg_id": msg_id` added as its first
+/// field, without parsing the rest. `None` if it isn't an object.
+pub fn with_msg_id(message: &[u8], msg_id: &str) -> Option<Vec<u8>> {
+    let open = message.iter().position(|b| !b.is_ascii_whitespace()).filter(|&i| message[i] == b'{')?;
+    let empty = message[open + 1..].iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'}');
+    let field = serde_json::to_vec(&serde_json::json!({ "msg_id": msg_id })).ok()?;
+    let mut out = Vec::with_capacity(message.len() + field.len());
+    out.extend_from_slice(&message[..open]);
+    // The field without its closing brace, then the original fields
+    out.extend_from_slice(&field[..field.len() - 1]);
+    if !empty {
+        out.push(b',');
+    }
+    out.extend_from_slice(&message[open + 1..]);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_message_ids_in_place() {
+        let tagged = with_msg_id(br#" {"action":"perform_task","task_id":"t1"}"#, "b-1").unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&tagged).unwrap();
+        assert_eq!((msg_id(&value), value["task_id"].as_str()), (Some("b-1"), Some("t1")));
+        let empty: serde_json::Value = serde_json::from_slice(&with_msg_id(b"{ }", "b-2").unwrap()).unwrap();
+        assert_eq!(empty, serde_json::json!({ "msg_id": "b-2" }));
+        assert!(with_msg_id(b"[1]", "b-3").is_none());
+
+        let ack = ExtensionResponse::ack("t1", "app-7", true);
+        let ack: Ack = serde_json::from_value(ack.result.unwrap()).unwrap();
+        assert_eq!(ack, Ack { msg_id: "app-7".to_string(), duplicate: true });
+    }
+
+    #[test]
+    fn keeps_processed_keys_across_restarts() {
+        let path = std::env::temp_dir().join(format!("rzn-processed-keys-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut processed = ProcessedKeys::open(&path).unwrap();
+        assert!(processed.insert("b.e-1").unwrap());
+        assert!(!processed.insert("b.e-1").unwrap());
+        assert!(processed.insert("b.e-2").unwrap());
+
+        let mut reopened = ProcessedKeys::open(&path).unwrap();
+        assert!(reopened.contains("b.e-1") && reopened.contains("b.e-2"));
+        // The oldest keys go once there are too many, and the file shrinks with them
+        for n in 3..=2 * MAX_PROCESSED_KEYS + 1 {
+            reopened.insert(&format!("b.e-{}", n)).unwrap();
+        }
+        assert!(!reopened.contains("b.e-1"));
+        assert!(reopened.lines <= MAX_PROCESSED_KEYS + 1);
+        assert_eq!(ProcessedKeys::open(&path).unwrap().order.len(), MAX_PROCESSED_KEYS);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,190 @@
+//! Change detection between two runs of the same task.
+//!
+//! Monitoring users run a task repeatedly (e.g. a price list) and care about
+//! what changed since the previous run. [`diff_results`] compares the extracted
+//! variables of two [`TaskResult`]s:
+//!
+//! * Arrays of objects (from `extract` with `all: true` and `target: "attributes"`)
+//!   are matched by an ID field, e.g. `data-sku`. Records without that field are
+//!   matched by position.
+//! * Arrays of plain values are compared as sets.
+//! * Any other value is compared as a whole.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::messages::TaskResult;
+
+/// One difference between the previous and the current result.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    /// A record (or variable, when `id` is `None`) appeared.
+    Added {
+        variable: String,
+        id: Option<String>,
+        value: Value,
+    },
+    /// A record (or variable) disappeared.
+    Removed {
+        variable: String,
+        id: Option<String>,
+        value: Value,
+    },
+    /// A record (or variable) has a different value. `fields` lists the
+    /// changed keys when both sides are objects.
+    Changed {
+        variable: String,
+        id: Option<String>,
+        before: Value,
+        after: Value,
+        fields: Vec<String>,
+    },
+}
+
+/// Lists the changes from `previous` to `current`, by variable name. Records
+/// are matched on `id_field`.
+pub fn diff_results(previous: &TaskResult, current: &TaskResult, id_field: &str) -> Vec<ChangeEvent> {
+    let before = variables(previous);
+    let after = variables(current);
+    let mut names: Vec<&str> = before.keys().chain(after.keys()).copied().collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let mut events = Vec::new();
+    for name in names {
+        let variable = name.to_string();
+        match (before.get(name), after.get(name)) {
+            (Some(old), Some(new)) => match (records(old, id_field), records(new, id_field)) {
+                (Some(old), Some(new)) => diff_records(&variable, &old, &new, &mut events),
+                _ if old != new => events.push(changed(variable, None, old, new)),
+                _ => {}
+            },
+            (None, Some(new)) => match records(new, id_field) {
+                Some(new) => diff_records(&variable, &[], &new, &mut events),
+                None => events.push(ChangeEvent::Added { variable, id: None, value: (*new).clone() }),
+            },
+            (Some(old), None) => match records(old, id_field) {
+                Some(old) => diff_records(&variable, &old, &[], &mut events),
+                None => events.push(ChangeEvent::Removed { variable, id: None, value: (*old).clone() }),
+            },
+            (None, None) => {}
+        }
+    }
+    events
+}
+
+/// Extracted variables of a result; a later step's value wins over an earlier one's.
+fn variables(result: &TaskResult) -> BTreeMap<&str, &Value> {
+    result
+        .steps
+        .iter()
+        .filter_map(|step| step.data.as_ref()?.as_object())
+        .flat_map(|data| data.iter().map(|(name, value)| (name.as_str(), value)))
+        .collect()
+}
+
+/// Keys the elements of an array by their ID, or `None` for non-arrays.
+fn records<'a>(value: &'a Value, id_field: &str) -> Option<Vec<(String, &'a Value)>> {
+    let items = value.as_array()?;
+    let keyed = items.iter().enumerate().map(|(index, item)| {
+        let id = match item {
+            Value::Object(record) => match record.get(id_field) {
+                Some(Value::String(id)) => id.clone(),
+                Some(id) => id.to_string(),
+                None => format!("#{}", index),
+            },
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        (id, item)
+    });
+    Some(keyed.collect())
+}
+
+fn diff_records(variable: &str, old: &[(String, &Value)], new: &[(String, &Value)], events: &mut Vec<ChangeEvent>) {
+    let old_by_id: BTreeMap<&str, &Value> = old.iter().map(|(id, value)| (id.as_str(), *value)).collect();
+    let new_by_id: BTreeMap<&str, &Value> = new.iter().map(|(id, value)| (id.as_str(), *value)).collect();
+    for (id, value) in new {
+        match old_by_id.get(id.as_str()) {
+            None => events.push(ChangeEvent::Added {
+                variable: variable.to_string(),
+                id: Some(id.clone()),
+                value: (*value).clone(),
+            }),
+            Some(previous) if previous != value => {
+                events.push(changed(variable.to_string(), Some(id.clone()), previous, value));
+            }
+            Some(_) => {}
+        }
+    }
+    for (id, value) in old {
+        if !new_by_id.contains_key(id.as_str()) {
+            events.push(ChangeEvent::Removed {
+                variable: variable.to_string(),
+                id: Some(id.clone()),
+                value: (*value).clone(),
+            });
+        }
+    }
+}
+
+fn changed(variable: String, id: Option<String>, before: &Value, after: &Value) -> ChangeEvent {
+    let fields = match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+            keys.sort_unstable();
+            keys.dedup();
+            keys.into_iter().filter(|key| before.get(*key) != after.get(*key)).cloned().collect()
+        }
+        _ => Vec::new(),
+    };
+    ChangeEvent::Changed { variable, id, before: before.clone(), after: after.clone(), fields }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn result(data: Value) -> TaskResult {
+        serde_json::from_value(json!({ "steps": [{ "type": "extract", "success": true, "data": data }] })).unwrap()
+    }
+
+    #[test]
+    fn matches_records_by_id_field() {
+        let previous = result(json!({ "items": [
+            { "sku": "a", "price": "10" },
+            { "sku": "b", "price": "20" },
+        ] }));
+        let current = result(json!({ "items": [
+            { "sku": "b", "price": "18" },
+            { "sku": "c", "price": "5" },
+        ] }));
+        let events = diff_results(&previous, &current, "sku");
+        assert_eq!(events, vec![
+            ChangeEvent::Changed {
+                variable: "items".to_string(),
+                id: Some("b".to_string()),
+                before: json!({ "sku": "b", "price": "20" }),
+                after: json!({ "sku": "b", "price": "18" }),
+                fields: vec!["price".to_string()],
+            },
+            ChangeEvent::Added { variable: "items".to_string(), id: Some("c".to_string()), value: json!({ "sku": "c", "price": "5" }) },
+            ChangeEvent::Removed { variable: "items".to_string(), id: Some("a".to_string()), value: json!({ "sku": "a", "price": "10" }) },
+        ]);
+    }
+
+    #[test]
+    fn compares_scalars_and_value_lists() {
+        let previous = result(json!({ "title": "Old", "tags": ["x", "y"], "stock": 3 }));
+        let current = result(json!({ "title": "New", "tags": ["y", "z"], "stock": 3 }));
+        let events = diff_results(&previous, &current, "id");
+        let summary: Vec<String> = events.iter().map(|e| serde_json::to_value(e).unwrap()).map(|e| {
+            format!("{} {} {}", e["change"].as_str().unwrap(), e["variable"].as_str().unwrap(), e["id"])
+        }).collect();
+        assert_eq!(summary, ["added tags \"z\"", "removed tags \"x\"", "changed title null"]);
+    }
+}
@@ -0,0 +1,151 @@
+//! Wire encodings of IPC frame payloads.
+//!
+//! Browsers only speak JSON, so the native messaging leg always carries JSON.
+//! On the IPC leg, a Main App can have messages in MessagePack or CBOR
+//! instead, which is much cheaper to decode for large extraction results: it
+//! lists the encoding's capability ([`Encoding::capability`]) in its
+//! `hello_ack`, and the broker sends its frames in that encoding, flagged
+//! [`FrameFlags::MSGPACK`] or [`FrameFlags::CBOR`]. The flag says how each
+//! frame is encoded, so either side may send any encoding; the broker
+//! transcodes a message only when its encoding differs from the other leg's.
+//! A Main App decodes frames with [`Frame::deserialize`](crate::frame::Frame::deserialize).
+
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::frame::FrameFlags;
+
+/// Capabilities of a peer that reads binary-encoded frames, one per encoding.
+pub const ENCODING_CAPABILITIES: &[&str] = &["encoding:msgpack", "encoding:cbor"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MessagePack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// What a peer lists in its hello's capabilities if it reads this
+    /// encoding. Everyone reads JSON.
+    pub fn capability(self) -> Option<&'static str> {
+        match self {
+            Encoding::Json => None,
+            Encoding::MessagePack => Some("encoding:msgpack"),
+            Encoding::Cbor => Some("encoding:cbor"),
+        }
+    }
+
+    /// The frame flag of payloads in this encoding.
+    pub fn flags(self) -> FrameFlags {
+        match self {
+            Encoding::Json => FrameFlags::NONE,
+            Encoding::MessagePack => FrameFlags::MSGPACK,
+            Encoding::Cbor => FrameFlags::CBOR,
+        }
+    }
+
+    /// The encoding of a frame with these flags.
+    pub fn of_flags(flags: FrameFlags) -> Self {
+        if flags.contains(FrameFlags::MSGPACK) {
+            Encoding::MessagePack
+        } else if flags.contains(FrameFlags::CBOR) {
+            Encoding::Cbor
+        } else {
+            Encoding::Json
+        }
+    }
+
+    /// The first binary encoding a peer with these hello capabilities lists,
+    /// else JSON.
+    pub fn negotiate(capabilities: &[String]) -> Self {
+        capabilities
+            .iter()
+            .find_map(|capability| [Encoding::MessagePack, Encoding::Cbor].into_iter().find(|e| e.capability() == Some(capability)))
+            .unwrap_or_default()
+    }
+
+    /// The encoding in `RZN_ENCODING` (`json`, `msgpack` or `cbor`) that a
+    /// Main App asks the broker for, JSON if unset or invalid.
+    pub fn from_env() -> Self {
+        let Ok(name) = std::env::var("RZN_ENCODING") else {
+            return Encoding::Json;
+        };
+        Encoding::parse(&name).unwrap_or_else(|| {
+            log::warn!("Ignoring invalid RZN_ENCODING={:?}", name);
+            Encoding::Json
+        })
+    }
+
+    /// Encodes `value`. Structs keep their field names in every encoding.
+    pub fn to_vec<T: Serialize + ?Sized>(self, value: &T) -> io::Result<Vec<u8>> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(io::Error::other),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| invalid(e.to_string())),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| invalid(e.to_string()))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decodes a payload in this encoding.
+    pub fn from_slice<T: DeserializeOwned>(self, bytes: &[u8]) -> io::Result<T> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| invalid(e.to_string())),
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| invalid(e.to_string())),
+            Encoding::Cbor => ciborium::from_reader(bytes).map_err(|e| invalid(e.to_string())),
+        }
+    }
+
+    /// Re-encodes a payload in this encoding as `to`.
+    pub fn transcode(self, bytes: &[u8], to: Encoding) -> io::Result<Vec<u8>> {
+        if self == to {
+            return Ok(bytes.to_vec());
+        }
+        let value: serde_json::Value = self.from_slice(bytes)?;
+        to.to_vec(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::ExtensionResponse;
+
+    #[test]
+    fn transcodes_between_json_and_binary_encodings() {
+        let json = br#"{"action":"task_result","task_id":"t1","success":true,"result":{"rows":[1,2.5,"three",null]}}"#;
+        for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+            assert_eq!(Encoding::of_flags(encoding.flags()), encoding);
+            let encoded = Encoding::Json.transcode(json, encoding).unwrap();
+            let response: ExtensionResponse = encoding.from_slice(&encoded).unwrap();
+            assert_eq!((response.task_id.as_str(), response.success), ("t1", true));
+            let back = encoding.transcode(&encoded, Encoding::Json).unwrap();
+            assert_eq!(serde_json::from_slice::<serde_json::Value>(&back).unwrap(), serde_json::from_slice::<serde_json::Value>(json).unwrap());
+        }
+        assert!(Encoding::MessagePack.from_slice::<serde_json::Value>(b"\xc1").is_err());
+    }
+
+    #[test]
+    fn negotiates_the_first_listed_binary_encoding() {
+        let capabilities = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        assert_eq!(Encoding::negotiate(&capabilities(&["configure", "encoding:cbor", "encoding:msgpack"])), Encoding::Cbor);
+        assert_eq!(Encoding::negotiate(&capabilities(&["compression:zstd"])), Encoding::Json);
+    }
+}
@@ -0,0 +1,152 @@
+//! IPC endpoint resolution shared by the broker and the Main App.
+//!
+//! Both sides must arrive at the same endpoint, so the platform rules live here:
+//!
+//! * **Namespaced** names (Linux abstract sockets, Windows named pipes) are used
+//!   whenever the platform supports them.
+//! * **Filesystem** endpoints resolve to a named pipe path (`\\.\pipe\<name>`) on
+//!   Windows, `$XDG_RUNTIME_DIR` on Linux and `$TMPDIR` on macOS (which points
+//!   inside the app container for sandboxed apps), falling back to `/tmp`.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use interprocess::local_socket::{GenericFilePath, GenericNamespaced, Name, NameType, ToFsName, ToNsName};
+
+/// Base endpoint name. The broker and the Main App connect on
+/// [`Profile::socket_name`](crate::Profile::socket_name), which adds the OS user
+/// and profile to it.
+pub const DEFAULT_SOCKET_NAME: &str = "com.yourcompany.projectagentis.broker.sock";
+
+// Longest socket path accepted by the OS (sun_path is 104 bytes on macOS, 108 on Linux)
+#[cfg(target_os = "macos")]
+const MAX_SOCKET_PATH_LEN: usize = 103;
+#[cfg(not(target_os = "macos"))]
+const MAX_SOCKET_PATH_LEN: usize = 107;
+
+/// Where an IPC endpoint lives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EndpointSpec {
+    /// Namespaced name, no filesystem entry.
+    Namespaced(String),
+    /// Socket file (Unix) or named pipe path (Windows).
+    Path(PathBuf),
+}
+
+impl EndpointSpec {
+    /// Namespaced endpoint with the given name.
+    pub fn namespaced(name: &str) -> Self {
+        EndpointSpec::Namespaced(name.to_string())
+    }
+
+    /// Filesystem endpoint for `name` in the platform's runtime directory.
+    pub fn filesystem(name: &str) -> Self {
+        EndpointSpec::Path(filesystem_path(name, |key| std::env::var_os(key)))
+    }
+
+    /// Namespaced endpoint if the platform supports it, else the filesystem endpoint.
+    pub fn resolve(name: &str) -> Self {
+        if GenericNamespaced::is_supported() {
+            EndpointSpec::namespaced(name)
+        } else {
+            EndpointSpec::filesystem(name)
+        }
+    }
+
+    /// Converts the spec into an `interprocess` name.
+    pub fn to_name(&self) -> io::Result<Name<'static>> {
+        match self {
+            EndpointSpec::Namespaced(name) => name.clone().to_ns_name::<GenericNamespaced>(),
+            EndpointSpec::Path(path) => path.clone().to_fs_name::<GenericFilePath>(),
+        }
+    }
+
+    /// Path of the socket file, if this endpoint creates one (never on Windows).
+    pub fn socket_file(&self) -> Option<&Path> {
+        match self {
+            EndpointSpec::Path(path) if cfg!(unix) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves the filesystem endpoint path for `name`, reading environment
+/// variables through `env` (injected so the rules can be unit-tested).
+fn filesystem_path(name: &str, env: impl Fn(&str) -> Option<OsString>) -> PathBuf {
+    if cfg!(windows) {
+        return PathBuf::from(format!(r"\\.\pipe\{}", name));
+    }
+    let runtime_dir = if cfg!(target_os = "macos") {
+        env("TMPDIR")
+    } else {
+        env("XDG_RUNTIME_DIR")
+    };
+    let path = runtime_dir
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| PathBuf::from(dir).join(name));
+    match path {
+        // Deeply nested runtime dirs can exceed the socket path limit
+        Some(path) if path.as_os_str().len() <= MAX_SOCKET_PATH_LEN => path,
+        _ => Path::new("/tmp").join(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env_with(key: &'static str, value: &'static str) -> impl Fn(&str) -> Option<OsString> {
+        move |k| (k == key).then(|| OsString::from(value))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_uses_xdg_runtime_dir() {
+        let path = filesystem_path("bridge.sock", env_with("XDG_RUNTIME_DIR", "/run/user/1000"));
+        assert_eq!(path, PathBuf::from("/run/user/1000/bridge.sock"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_falls_back_to_tmp_without_xdg() {
+        assert_eq!(filesystem_path("bridge.sock", |_| None), PathBuf::from("/tmp/bridge.sock"));
+        let empty = filesystem_path("bridge.sock", env_with("XDG_RUNTIME_DIR", ""));
+        assert_eq!(empty, PathBuf::from("/tmp/bridge.sock"));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn macos_uses_tmpdir() {
+        let path = filesystem_path("bridge.sock", env_with("TMPDIR", "/var/folders/ab/T/"));
+        assert_eq!(path, PathBuf::from("/var/folders/ab/T/bridge.sock"));
+        assert_eq!(filesystem_path("bridge.sock", |_| None), PathBuf::from("/tmp/bridge.sock"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overlong_runtime_dir_falls_back_to_tmp() {
+        let long_dir: &'static str = Box::leak("/x".repeat(80).into_boxed_str());
+        let key = if cfg!(target_os = "macos") { "TMPDIR" } else { "XDG_RUNTIME_DIR" };
+        let path = filesystem_path(DEFAULT_SOCKET_NAME, env_with(key, long_dir));
+        assert_eq!(path, Path::new("/tmp").join(DEFAULT_SOCKET_NAME));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_uses_named_pipe_path() {
+        let spec = EndpointSpec::filesystem("bridge.sock");
+        assert_eq!(spec, EndpointSpec::Path(PathBuf::from(r"\\.\pipe\bridge.sock")));
+        assert_eq!(spec.socket_file(), None);
+        assert!(spec.to_name().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_path_endpoint_has_socket_file() {
+        let spec = EndpointSpec::Path(PathBuf::from("/tmp/bridge.sock"));
+        assert_eq!(spec.socket_file(), Some(Path::new("/tmp/bridge.sock")));
+        assert!(spec.to_name().is_ok());
+        assert_eq!(EndpointSpec::namespaced("bridge.sock").socket_file(), None);
+    }
+}
@@ -0,0 +1,147 @@
+//! Broker exit codes and the `last_exit.json` report.
+//!
+//! A supervising process or installer learns why the broker stopped from its
+//! exit status ([`ExitReason::code`]), and the details from the [`LastExit`]
+//! the broker writes next to the bridge config on its way out, instead of
+//! parsing the log.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::BridgeConfig;
+
+/// Why the broker exited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    /// The relay ran and one side disconnected, or a shutdown signal came in.
+    Ok,
+    /// Anything not covered below, e.g. a failed startup check of its own.
+    Failure,
+    /// The bridge config or profile is invalid.
+    Config,
+    /// The host manifest doesn't point at this broker (only fatal with
+    /// `RZN_STRICT_MANIFEST=1`).
+    ManifestMismatch,
+    /// The Main App could not be reached or launched.
+    IpcUnreachable,
+    /// The Main App refused the handshake or speaks another protocol version.
+    ProtocolFailure,
+}
+
+impl ExitReason {
+    pub const ALL: [ExitReason; 6] = [
+        ExitReason::Ok,
+        ExitReason::Failure,
+        ExitReason::Config,
+        ExitReason::ManifestMismatch,
+        ExitReason::IpcUnreachable,
+        ExitReason::ProtocolFailure,
+    ];
+
+    /// The process exit status.
+    pub fn code(self) -> i32 {
+        match self {
+            ExitReason::Ok => 0,
+            ExitReason::Failure => 1,
+            ExitReason::Config => 2,
+            ExitReason::ManifestMismatch => 3,
+            ExitReason::IpcUnreachable => 4,
+            ExitReason::ProtocolFailure => 5,
+        }
+    }
+
+    /// The reason for an exit status, if it is one of ours.
+    pub fn from_code(code: i32) -> Option<Self> {
+        ExitReason::ALL.into_iter().find(|reason| reason.code() == code)
+    }
+}
+
+/// One problem behind an exit, e.g. a failed startup check.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExitDetail {
+    /// Stable code, such as `E_INVALID_CONFIG`.
+    pub code: String,
+    pub message: String,
+}
+
+/// Contents of `last_exit.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LastExit {
+    /// The exit status, [`ExitReason::code`] of `reason`.
+    pub code: i32,
+    pub reason: ExitReason,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<ExitDetail>,
+    pub pid: u32,
+    /// Milliseconds since the Unix epoch.
+    pub exited_at_ms: u64,
+}
+
+impl LastExit {
+    /// A report of this process exiting now.
+    pub fn new(reason: ExitReason, message: impl Into<String>, details: Vec<ExitDetail>) -> Self {
+        LastExit {
+            code: reason.code(),
+            reason,
+            message: message.into(),
+            details,
+            pid: std::process::id(),
+            exited_at_ms: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+        }
+    }
+
+    /// `last_exit.json` next to the bridge config file.
+    pub fn default_path() -> Option<PathBuf> {
+        BridgeConfig::path().map(|path| path.with_file_name("last_exit.json"))
+    }
+
+    /// Writes the report to `path`, replacing the previous one.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, contents)
+    }
+
+    /// Reads the report at `path`. `None` if the broker never wrote one.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_and_reads_the_last_exit() {
+        for reason in ExitReason::ALL {
+            assert_eq!(ExitReason::from_code(reason.code()), Some(reason));
+        }
+        assert_eq!(ExitReason::from_code(101), None);
+
+        let dir = std::env::temp_dir().join(format!("rzn-last-exit-test-{}", std::process::id()));
+        let path = dir.join("last_exit.json");
+        assert_eq!(LastExit::read(&path).unwrap(), None);
+        let detail = ExitDetail { code: "E_INVALID_CONFIG".to_string(), message: "bad socket name".to_string() };
+        let last = LastExit::new(ExitReason::Config, "startup checks failed", vec![detail]);
+        last.write(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!((json["code"].as_i64(), json["reason"].as_str()), (Some(2), Some("config")));
+        assert_eq!(LastExit::read(&path).unwrap(), Some(last));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
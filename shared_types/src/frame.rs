@@ -0,0 +1,695 @@
+//! Message framing for both legs of the bridge, over tokio streams.
+//!
+//! The framing itself is decoded and encoded by `rzn_protocol::frame`, without
+//! I/O; the functions here read from and write to async streams with it.
+//!
+//! * **Native messaging leg** (extension <-> broker, stdin/stdout): every message is
+//!   prefixed with a bare 4-byte length in the machine's native byte order, as
+//!   required by Chrome. See [`read_message_bytes`] / [`write_message_bytes`].
+//! * **IPC leg** (broker <-> Main App): every message is prefixed with a fixed
+//!   12-byte [`FrameHeader`]. See [`read_frame`] / [`write_frame`]. The IPC
+//!   format is the same on every machine, so a Main App never depends on the
+//!   broker's architecture.
+//!
+//! IPC frame header layout (all integers little-endian):
+//!
+//! ```text
+//! offset  size  field
+//! 0       4     magic       b"RZNB"
+//! 4       1     version     FRAME_VERSION
+//! 5       1     flags       FrameFlags bits (compressed / encrypted / priority / msgpack / cbor)
+//! 6       2     channel_id  logical channel, 0 = default
+//! 8       4     length      payload length in bytes
+//! ```
+//!
+//! The magic read as a little-endian `u32` is far larger than [`MAX_MESSAGE_SIZE`],
+//! so a peer can tell a header frame apart from a legacy bare-length frame by
+//! looking at the first four bytes. [`read_frame_detecting`] uses this to keep
+//! older brokers (bare-length IPC framing) working. Legacy IPC lengths are
+//! little-endian, too.
+//!
+//! Relays that read many (possibly multi-MB) messages use [`read_message_into`]
+//! and [`read_frame_into`], which fill a reusable [`BytesMut`] instead of
+//! allocating a buffer per message.
+//!
+//! Every write puts the length prefix and the body out together, with one
+//! vectored write where the writer supports it. The `write_*` functions flush
+//! after each message; relays that write bursts use the `*_unflushed` variants
+//! and flush as their [`FlushPolicy`] says.
+//!
+//! A message over the reader's limit is skipped rather than read, and the read
+//! fails with a [`MessageTooLarge`] error. The stream stays in step, so the
+//! reader can answer the sender and go on with the next message.
+//!
+//! The IPC read helpers decompress payloads flagged [`FrameFlags::COMPRESSED`]
+//! (see [`crate::compress`]) and return them with the flag cleared. `max_len`
+//! applies to the decompressed payload, too.
+
+use std::io::{self, ErrorKind, IoSlice};
+
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::encoding::Encoding;
+
+pub use rzn_protocol::frame::{
+    ByteOrder, Decoder, FrameFlags, FrameHeader, FramingMode, MessageLimits, MessageTooLarge, FRAME_HEADER_LEN, FRAME_MAGIC, FRAME_VERSION, MAX_MESSAGE_SIZE,
+};
+
+/// A single IPC frame: header plus payload.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// How the payload is encoded, from the header's flags.
+    pub fn encoding(&self) -> Encoding {
+        Encoding::of_flags(self.header.flags)
+    }
+
+    /// Decodes the payload in whichever encoding it came in.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> io::Result<T> {
+        self.encoding().from_slice(&self.payload)
+    }
+}
+
+// --- Native Messaging Framing (bare length prefix) ---
+
+/// Reads a native messaging message: a 4-byte length in [`ByteOrder::NATIVE`],
+/// then the body. Generic over any AsyncRead + Unpin source.
+pub async fn read_message_bytes<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    log_prefix: &str, // For clearer logging
+) -> io::Result<Option<Vec<u8>>> {
+    read_message_bytes_limited(reader, MAX_MESSAGE_SIZE, log_prefix).await
+}
+
+/// Same as [`read_message_bytes`], rejecting messages over `max_len` bytes
+/// instead of [`MAX_MESSAGE_SIZE`].
+pub async fn read_message_bytes_limited<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    log_prefix: &str,
+) -> io::Result<Option<Vec<u8>>> {
+    read_length_prefixed(reader, ByteOrder::NATIVE, max_len, log_prefix).await
+}
+
+/// Same as [`read_message_bytes_limited`], reading the body into `buffer`.
+/// The returned [`Bytes`] share `buffer`'s memory, which is reused for later
+/// messages once they are dropped.
+pub async fn read_message_into<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    buffer: &mut BytesMut,
+    log_prefix: &str,
+) -> io::Result<Option<Bytes>> {
+    let message = read_decoded_into(reader, &mut Decoder::native(max_len), buffer, log_prefix).await?;
+    Ok(message.map(|(_, body)| body))
+}
+
+/// Reads a message prefixed with a bare 4-byte length in `order`.
+pub async fn read_length_prefixed<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    order: ByteOrder,
+    max_len: usize,
+    log_prefix: &str,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut decoder = Decoder::length_prefixed(order, max_len);
+    let frame = read_decoded(reader, &mut decoder, log_prefix).await?;
+    Ok(frame.map(|frame| frame.payload))
+}
+
+/// Writes a native messaging message: a 4-byte length in [`ByteOrder::NATIVE`],
+/// then the body. Generic over any AsyncWrite + Unpin sink.
+pub async fn write_message_bytes<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message_bytes: &[u8],
+    log_prefix: &str, // For clearer logging
+) -> io::Result<()> {
+    write_length_prefixed(writer, ByteOrder::NATIVE, message_bytes, log_prefix).await
+}
+
+/// Like [`write_message_bytes`], but leaves flushing to the caller.
+pub async fn write_message_unflushed<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message_bytes: &[u8],
+    log_prefix: &str,
+) -> io::Result<()> {
+    check_outgoing_size(message_bytes.len(), log_prefix)?;
+    write_prefixed(writer, &ByteOrder::NATIVE.encode(message_bytes.len() as u32), message_bytes).await
+}
+
+/// Writes a message prefixed with a bare 4-byte length in `order`.
+pub async fn write_length_prefixed<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    order: ByteOrder,
+    message_bytes: &[u8],
+    log_prefix: &str,
+) -> io::Result<()> {
+    check_outgoing_size(message_bytes.len(), log_prefix)?;
+    write_prefixed(writer, &order.encode(message_bytes.len() as u32), message_bytes).await?;
+    writer.flush().await
+}
+
+/// Largest body copied next to its prefix, so that both go out in one write
+/// to a writer without vectored writes. Larger bodies are written separately.
+const COPY_LIMIT: usize = 16 * 1024;
+
+/// Writes `prefix` followed by `body`, in as few writes as the writer allows.
+async fn write_prefixed<W: AsyncWrite + Unpin>(writer: &mut W, prefix: &[u8], body: &[u8]) -> io::Result<()> {
+    if writer.is_write_vectored() {
+        let mut written = 0;
+        while written < prefix.len() {
+            let n = writer.write_vectored(&[IoSlice::new(&prefix[written..]), IoSlice::new(body)]).await?;
+            if n == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            written += n;
+        }
+        writer.write_all(&body[written - prefix.len()..]).await
+    } else if body.len() <= COPY_LIMIT {
+        let mut buffer = Vec::with_capacity(prefix.len() + body.len());
+        buffer.extend_from_slice(prefix);
+        buffer.extend_from_slice(body);
+        writer.write_all(&buffer).await
+    } else {
+        writer.write_all(prefix).await?;
+        writer.write_all(body).await
+    }
+}
+
+/// When a relay flushes the messages it has written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// After every message.
+    Immediate,
+    /// Once nothing else is queued, or every [`FlushPolicy::COALESCE_BYTES`]
+    /// during a burst.
+    #[default]
+    Coalesced,
+    /// Only once nothing else is queued.
+    OnIdle,
+}
+
+impl FlushPolicy {
+    /// Bytes a [`FlushPolicy::Coalesced`] writer leaves unflushed at most.
+    pub const COALESCE_BYTES: usize = 64 * 1024;
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "immediate" => Some(FlushPolicy::Immediate),
+            "coalesced" => Some(FlushPolicy::Coalesced),
+            "on_idle" => Some(FlushPolicy::OnIdle),
+            _ => None,
+        }
+    }
+
+    /// The policy in `RZN_FLUSH_POLICY` (`immediate`, `coalesced` or
+    /// `on_idle`), else [`FlushPolicy::Coalesced`].
+    pub fn from_env() -> Self {
+        match std::env::var("RZN_FLUSH_POLICY") {
+            Ok(value) => FlushPolicy::parse(&value).unwrap_or_else(|| {
+                log::warn!("Ignoring invalid RZN_FLUSH_POLICY={:?}", value);
+                FlushPolicy::default()
+            }),
+            Err(_) => FlushPolicy::default(),
+        }
+    }
+
+    /// Whether to flush after a write that left `pending` bytes unflushed;
+    /// `idle` if no other message is waiting to be written.
+    pub fn should_flush(self, pending: usize, idle: bool) -> bool {
+        match self {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::Coalesced => idle || pending >= Self::COALESCE_BYTES,
+            FlushPolicy::OnIdle => idle,
+        }
+    }
+}
+
+/// Protects against sending messages whose length doesn't fit the prefix.
+fn check_outgoing_size(len: usize, log_prefix: &str) -> io::Result<()> {
+    rzn_protocol::frame::outgoing_len(len).map(drop).inspect_err(|e| {
+        log::error!("{}: {}", log_prefix, e);
+    })
+}
+
+// --- IPC Framing (fixed header) ---
+
+/// Reads an IPC frame (header + payload).
+/// Returns `Ok(None)` on a clean disconnect before the header.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    log_prefix: &str,
+) -> io::Result<Option<Frame>> {
+    read_frame_limited(reader, MAX_MESSAGE_SIZE, log_prefix).await
+}
+
+/// Same as [`read_frame`], rejecting payloads over `max_len` bytes instead of
+/// [`MAX_MESSAGE_SIZE`].
+pub async fn read_frame_limited<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    log_prefix: &str,
+) -> io::Result<Option<Frame>> {
+    let frame = read_decoded(reader, &mut Decoder::ipc(max_len), log_prefix).await?;
+    frame.map(|frame| decompress_frame(frame, max_len, log_prefix)).transpose()
+}
+
+/// Same as [`read_frame_limited`], reading the payload into `buffer` like
+/// [`read_message_into`].
+pub async fn read_frame_into<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+    buffer: &mut BytesMut,
+    log_prefix: &str,
+) -> io::Result<Option<(FrameHeader, Bytes)>> {
+    let Some((header, payload)) = read_decoded_into(reader, &mut Decoder::ipc(max_len), buffer, log_prefix).await? else {
+        return Ok(None);
+    };
+    if !header.flags.contains(FrameFlags::COMPRESSED) {
+        return Ok(Some((header, payload)));
+    }
+    let frame = decompress_frame(Frame { header, payload: payload.to_vec() }, max_len, log_prefix)?;
+    Ok(Some((frame.header, Bytes::from(frame.payload))))
+}
+
+/// Decompresses the payload of a [`FrameFlags::COMPRESSED`] frame and clears
+/// the flag. Other frames are returned as they are.
+fn decompress_frame(frame: Frame, max_len: usize, log_prefix: &str) -> io::Result<Frame> {
+    if !frame.header.flags.contains(FrameFlags::COMPRESSED) {
+        return Ok(frame);
+    }
+    let payload = crate::compress::decompress(&frame.payload, max_len).inspect_err(|e| {
+        log::error!("{}: Error decompressing frame: {}", log_prefix, e);
+    })?;
+    let flags = FrameFlags::from_bits(frame.header.flags.bits() & !FrameFlags::COMPRESSED.bits());
+    let header = FrameHeader::new(flags, frame.header.channel_id, payload.len() as u32);
+    Ok(Frame { header, payload })
+}
+
+/// Writes an IPC frame with the given flags and channel id.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    flags: FrameFlags,
+    channel_id: u16,
+    payload: &[u8],
+    log_prefix: &str,
+) -> io::Result<()> {
+    write_frame_unflushed(writer, flags, channel_id, payload, log_prefix).await?;
+    writer.flush().await
+}
+
+/// Like [`write_frame`], but leaves flushing to the caller.
+pub async fn write_frame_unflushed<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    flags: FrameFlags,
+    channel_id: u16,
+    payload: &[u8],
+    log_prefix: &str,
+) -> io::Result<()> {
+    check_outgoing_size(payload.len(), log_prefix)?;
+    let header = FrameHeader::new(flags, channel_id, payload.len() as u32);
+    write_prefixed(writer, &header.encode(), payload).await
+}
+
+/// Reads an IPC frame, detecting the peer's framing on the first call.
+///
+/// When `mode` is `None` the first four bytes are sniffed: the frame magic selects
+/// [`FramingMode::Header`], anything else is treated as a legacy length prefix.
+/// The detected mode is stored in `mode` and used for all later frames.
+/// Legacy frames are returned with a default header (no flags, channel 0).
+pub async fn read_frame_detecting<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    mode: &mut Option<FramingMode>,
+    log_prefix: &str,
+) -> io::Result<Option<Frame>> {
+    read_frame_detecting_limited(reader, mode, MAX_MESSAGE_SIZE, log_prefix).await
+}
+
+/// Same as [`read_frame_detecting`], rejecting payloads over `max_len` bytes
+/// instead of [`MAX_MESSAGE_SIZE`].
+pub async fn read_frame_detecting_limited<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    mode: &mut Option<FramingMode>,
+    max_len: usize,
+    log_prefix: &str,
+) -> io::Result<Option<Frame>> {
+    let mut decoder = Decoder::for_mode(*mode, max_len);
+    let frame = read_decoded(reader, &mut decoder, log_prefix).await;
+    *mode = decoder.mode();
+    frame?.map(|frame| decompress_frame(frame, max_len, log_prefix)).transpose()
+}
+
+/// Writes an IPC frame using the given framing mode.
+/// Flags and channel id are dropped for [`FramingMode::Legacy`] peers.
+pub async fn write_frame_as<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mode: FramingMode,
+    flags: FrameFlags,
+    channel_id: u16,
+    payload: &[u8],
+    log_prefix: &str,
+) -> io::Result<()> {
+    match mode {
+        FramingMode::Header => write_frame(writer, flags, channel_id, payload, log_prefix).await,
+        FramingMode::Legacy => write_length_prefixed(writer, ByteOrder::Little, payload, log_prefix).await,
+    }
+}
+
+/// Largest read while decoding, so that a skipped message isn't held in memory.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Reads the next message through `decoder`, never past its end, so a later
+/// read can go on with the stream. `None` on a clean disconnect.
+async fn read_decoded<R: AsyncRead + Unpin>(reader: &mut R, decoder: &mut Decoder, log_prefix: &str) -> io::Result<Option<Frame>> {
+    let mut chunk = Vec::new();
+    loop {
+        match decoder.decode().inspect_err(|e| log_decode_error(e, log_prefix))? {
+            Some((header, payload)) => return Ok(Some(Frame { header, payload })),
+            None if !feed_decoder(reader, decoder, &mut chunk, log_prefix).await? => return Ok(None),
+            None => {}
+        }
+    }
+}
+
+/// Same as [`read_decoded`], reading the body into `buffer` instead of the
+/// decoder's own, without zeroing it first. `buffer`'s allocation is reused
+/// once earlier bodies are dropped.
+async fn read_decoded_into<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    decoder: &mut Decoder,
+    buffer: &mut BytesMut,
+    log_prefix: &str,
+) -> io::Result<Option<(FrameHeader, Bytes)>> {
+    let mut chunk = Vec::new();
+    let header = loop {
+        match decoder.decode_header().inspect_err(|e| log_decode_error(e, log_prefix))? {
+            Some(header) => break header,
+            None if !feed_decoder(reader, decoder, &mut chunk, log_prefix).await? => return Ok(None),
+            None => {}
+        }
+    };
+    let len = header.length as usize;
+    buffer.clear();
+    buffer.reserve(len);
+    buffer.extend_from_slice(&decoder.take_fed(len));
+    let mut body = (&mut *reader).take((len - buffer.len()) as u64);
+    while buffer.len() < len {
+        match body.read_buf(buffer).await {
+            Ok(0) => {
+                log::error!("{}: Connection closed unexpectedly while reading message body (expected {} bytes).", log_prefix, len);
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                log::error!("{}: Error reading message body: {}", log_prefix, e);
+                return Err(e);
+            }
+        }
+    }
+    Ok(Some((header, buffer.split().freeze())))
+}
+
+/// Feeds `decoder` what it still wants of the current prefix or message,
+/// reading through `chunk`. `false` on a clean disconnect between messages.
+async fn feed_decoder<R: AsyncRead + Unpin>(reader: &mut R, decoder: &mut Decoder, chunk: &mut Vec<u8>, log_prefix: &str) -> io::Result<bool> {
+    chunk.resize(decoder.wanted().min(READ_CHUNK), 0);
+    let n = reader.read(chunk).await.inspect_err(|e| {
+        log::error!("{}: Error reading message: {}", log_prefix, e);
+    })?;
+    if n == 0 {
+        // If EOF is encountered between messages, it's a clean disconnect.
+        if decoder.is_between_messages() {
+            log::debug!("{}: Connection closed cleanly between messages.", log_prefix);
+            return Ok(false);
+        }
+        log::error!("{}: Connection closed unexpectedly in the middle of a message.", log_prefix);
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    decoder.feed(&chunk[..n]);
+    Ok(true)
+}
+
+fn log_decode_error(error: &io::Error, log_prefix: &str) {
+    if MessageTooLarge::of(error).is_some() {
+        log_too_large(error, log_prefix);
+    } else {
+        log::error!("{}: {}", log_prefix, error);
+    }
+}
+
+fn log_too_large(error: &io::Error, log_prefix: &str) {
+    if let Some(too_large) = MessageTooLarge::of(error) {
+        log::error!("{}: {} (action: {}, task_id: {}), skipped.", log_prefix, too_large,
+                   too_large.action.as_deref().unwrap_or("N/A"), too_large.task_id.as_deref().unwrap_or("N/A"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn native_messages_use_the_machine_byte_order() {
+        let mut written = Vec::new();
+        write_message_bytes(&mut written, b"{}", "test").await.unwrap();
+        assert_eq!(written[..4], 2u32.to_ne_bytes());
+
+        let mut big_endian: &[u8] = &[0, 0, 0, 2, b'{', b'}'];
+        let message = read_length_prefixed(&mut big_endian, ByteOrder::Big, MAX_MESSAGE_SIZE, "test").await.unwrap();
+        assert_eq!(message.as_deref(), Some(&b"{}"[..]));
+    }
+
+    #[tokio::test]
+    async fn ipc_frames_are_little_endian_on_every_machine() {
+        let mut written = Vec::new();
+        write_frame(&mut written, FrameFlags::NONE, 0x0102, b"{}", "test").await.unwrap();
+        assert_eq!(written[..FRAME_HEADER_LEN], [b'R', b'Z', b'N', b'B', FRAME_VERSION, 0, 0x02, 0x01, 2, 0, 0, 0]);
+
+        let mut legacy = Vec::new();
+        write_frame_as(&mut legacy, FramingMode::Legacy, FrameFlags::NONE, 0, b"{}", "test").await.unwrap();
+        assert_eq!(legacy, [2, 0, 0, 0, b'{', b'}']);
+        let mut mode = None;
+        let frame = read_frame_detecting(&mut &legacy[..], &mut mode, "test").await.unwrap().unwrap();
+        assert_eq!((mode, frame.payload), (Some(FramingMode::Legacy), b"{}".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn reuses_the_read_buffer() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, FrameFlags::NONE, 0, &[7; 4096], "test").await.unwrap();
+        write_frame(&mut stream, FrameFlags::NONE, 0, b"{}", "test").await.unwrap();
+        let mut reader = &stream[..];
+        let mut buffer = BytesMut::new();
+
+        let (_, first) = read_frame_into(&mut reader, MAX_MESSAGE_SIZE, &mut buffer, "test").await.unwrap().unwrap();
+        let allocation = first.as_ptr();
+        assert_eq!(first.len(), 4096);
+        drop(first);
+        let (_, second) = read_frame_into(&mut reader, MAX_MESSAGE_SIZE, &mut buffer, "test").await.unwrap().unwrap();
+        assert_eq!((&second[..], second.as_ptr()), (&b"{}"[..], allocation));
+        assert!(read_frame_into(&mut reader, MAX_MESSAGE_SIZE, &mut buffer, "test").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_oversized_messages() {
+        let mut stream = Vec::new();
+        let big = format!(r#"{{"action":"task_result","task_id":"t1","result":"{}"}}"#, "x".repeat(10_000));
+        write_message_bytes(&mut stream, big.as_bytes(), "test").await.unwrap();
+        write_message_bytes(&mut stream, b"{}", "test").await.unwrap();
+        let mut reader = &stream[..];
+        let mut buffer = BytesMut::new();
+
+        let error = read_message_into(&mut reader, 1024, &mut buffer, "test").await.unwrap_err();
+        let too_large = MessageTooLarge::of(&error).unwrap();
+        assert_eq!((too_large.len, too_large.limit), (big.len(), 1024));
+        assert_eq!((too_large.action.as_deref(), too_large.task_id.as_deref()), (Some("task_result"), Some("t1")));
+        let next = read_message_into(&mut reader, 1024, &mut buffer, "test").await.unwrap().unwrap();
+        assert_eq!(&next[..], b"{}");
+    }
+
+    /// Takes at most 5 bytes per write and counts the writes.
+    #[derive(Default)]
+    struct Trickle {
+        written: Vec<u8>,
+        writes: usize,
+        vectored: bool,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>, buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+            let n = buf.len().min(5);
+            self.written.extend_from_slice(&buf[..n]);
+            self.writes += 1;
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_write_vectored(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>, bufs: &[IoSlice<'_>]) -> std::task::Poll<io::Result<usize>> {
+            let buf = bufs.iter().find(|b| !b.is_empty()).map_or(&[][..], |b| &b[..]);
+            self.poll_write(cx, buf)
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.vectored
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_the_prefix_and_body_together() {
+        let mut expected = Vec::new();
+        write_message_bytes(&mut expected, b"0123456789", "test").await.unwrap();
+
+        // Short writes split the prefix and the body anywhere
+        for vectored in [false, true] {
+            let mut writer = Trickle { vectored, ..Trickle::default() };
+            write_message_unflushed(&mut writer, b"0123456789", "test").await.unwrap();
+            assert_eq!((writer.written.as_slice(), writer.writes), (expected.as_slice(), 3));
+        }
+
+        assert!(!FlushPolicy::OnIdle.should_flush(FlushPolicy::COALESCE_BYTES, false));
+        assert!(FlushPolicy::Coalesced.should_flush(FlushPolicy::COALESCE_BYTES, false));
+        assert_eq!(FlushPolicy::parse("on-idle"), Some(FlushPolicy::OnIdle));
+    }
+
+    /// Property tests of the native messaging framing, read back through a
+    /// reader that hands out the stream in arbitrary pieces.
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        /// Returns at most the next chunk size per read, and `Pending` before
+        /// every other read, like a pipe that is slower than its reader.
+        struct Chunked {
+            data: Vec<u8>,
+            pos: usize,
+            chunks: Vec<usize>,
+            reads: usize,
+        }
+
+        impl Chunked {
+            fn new(data: Vec<u8>, chunks: Vec<usize>) -> Self {
+                Chunked { data, pos: 0, chunks, reads: 0 }
+            }
+        }
+
+        impl AsyncRead for Chunked {
+            fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
+                self.reads += 1;
+                if self.reads % 2 == 1 {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                let chunk = self.chunks[(self.reads / 2) % self.chunks.len()];
+                let n = chunk.min(buf.remaining()).min(self.data.len() - self.pos);
+                let start = self.pos;
+                buf.put_slice(&self.data[start..start + n]);
+                self.pos += n;
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn block_on<F: std::future::Future>(future: F) -> F::Output {
+            tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(future)
+        }
+
+        async fn stream_of(payloads: &[Vec<u8>]) -> Vec<u8> {
+            let mut stream = Vec::new();
+            for payload in payloads {
+                write_message_bytes(&mut stream, payload, "test").await.unwrap();
+            }
+            stream
+        }
+
+        fn chunks() -> impl Strategy<Value = Vec<usize>> {
+            prop::collection::vec(1usize..64, 1..8)
+        }
+
+        proptest! {
+            #[test]
+            fn payloads_within_the_limit_round_trip(
+                payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..2048), 1..6),
+                slack in 0usize..16,
+                chunks in chunks(),
+            ) {
+                let limit = payloads.iter().map(Vec::len).max().unwrap_or_default() + slack;
+                block_on(async {
+                    let stream = stream_of(&payloads).await;
+                    let mut reader = Chunked::new(stream.clone(), chunks.clone());
+                    for payload in &payloads {
+                        let read = read_message_bytes_limited(&mut reader, limit, "test").await.unwrap();
+                        prop_assert_eq!(read.as_ref(), Some(payload));
+                    }
+                    prop_assert!(read_message_bytes(&mut reader, "test").await.unwrap().is_none());
+
+                    // The buffer-reusing read agrees
+                    let mut reader = Chunked::new(stream, chunks);
+                    let mut buffer = BytesMut::new();
+                    for payload in &payloads {
+                        let read = read_message_into(&mut reader, limit, &mut buffer, "test").await.unwrap();
+                        prop_assert_eq!(read.as_deref(), Some(&payload[..]));
+                    }
+                    prop_assert!(read_message_into(&mut reader, limit, &mut buffer, "test").await.unwrap().is_none());
+                    Ok(())
+                })?;
+            }
+
+            #[test]
+            fn oversized_payloads_are_skipped_with_message_too_large(
+                len in 1usize..8192,
+                limit in 0usize..4096,
+                chunks in chunks(),
+            ) {
+                prop_assume!(len > limit);
+                block_on(async {
+                    let stream = stream_of(&[vec![b'x'; len], b"{}".to_vec()]).await;
+                    let mut reader = Chunked::new(stream, chunks);
+                    let error = read_message_bytes_limited(&mut reader, limit, "test").await.unwrap_err();
+                    prop_assert_eq!(error.kind(), ErrorKind::InvalidData);
+                    let too_large = MessageTooLarge::of(&error).cloned();
+                    prop_assert_eq!(too_large.map(|t| (t.len, t.limit)), Some((len, limit)));
+                    // The stream stays in step
+                    let next = read_message_bytes_limited(&mut reader, limit.max(2), "test").await.unwrap();
+                    prop_assert_eq!(next.as_deref(), Some(&b"{}"[..]));
+                    Ok(())
+                })?;
+            }
+
+            #[test]
+            fn truncated_streams_end_cleanly_only_between_messages(
+                payload in prop::collection::vec(any::<u8>(), 1..2048),
+                cut in any::<prop::sample::Index>(),
+                limit in 0usize..4096,
+                chunks in chunks(),
+            ) {
+                block_on(async {
+                    let stream = stream_of(std::slice::from_ref(&payload)).await;
+                    let cut = cut.index(stream.len());
+                    let mut reader = Chunked::new(stream[..cut].to_vec(), chunks);
+                    let read = read_message_bytes_limited(&mut reader, limit, "test").await;
+                    if cut < 4 {
+                        // Nothing but part of a length: the peer went away between messages
+                        prop_assert!(matches!(read, Ok(None)));
+                    } else {
+                        // Cut in the body, whether it was to be read or skipped
+                        prop_assert_eq!(read.map_err(|e| e.kind()).err(), Some(ErrorKind::UnexpectedEof));
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+    }
+}
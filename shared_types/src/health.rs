@@ -0,0 +1,133 @@
+//! Health policy for a Main App's broker sessions.
+//!
+//! Instead of someone watching the logs for a stuck broker, a Main App checks
+//! each session every [`HealthPolicy::interval_ms`]: whether the broker still
+//! answers a probe, how many of its messages are waiting to be handled, and
+//! how many tasks failed since the last check. When a threshold is crossed the
+//! policy's [`Remediation`]s are performed. Checking is pure; performing the
+//! remediations is left to the Main App.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::alerts::AlertAction;
+
+/// When a session counts as unhealthy, and what to do about it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HealthPolicy {
+    /// Time between checks; 0 turns the monitor off.
+    pub interval_ms: u64,
+    /// Messages from the broker waiting to be handled.
+    pub max_queue_depth: usize,
+    /// Share of failed task results, from 0 to 1.
+    pub max_error_rate: f64,
+    /// Task results a check needs before the error rate is judged.
+    pub min_results: u64,
+    /// Performed in order on every check that finds a problem.
+    pub remediations: Vec<Remediation>,
+}
+
+impl Default for HealthPolicy {
+    /// A check every 30 s; more than 12 waiting messages or more than half of
+    /// at least 10 tasks failing is a problem, which is only logged.
+    fn default() -> Self {
+        HealthPolicy { interval_ms: 30_000, max_queue_depth: 12, max_error_rate: 0.5, min_results: 10, remediations: Vec::new() }
+    }
+}
+
+/// What a Main App does about an unhealthy session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Remediation {
+    /// Close the session; a broker with a reconnect policy connects again.
+    Reconnect,
+    /// Perform alert actions, as for a matching [`AlertRule`](crate::AlertRule).
+    Alert { actions: Vec<AlertAction> },
+}
+
+/// What a Main App measured about a session since the last check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionHealth {
+    /// Whether the broker answered the probe sent at the last check. `None`
+    /// if no probe was sent yet.
+    pub probe_answered: Option<bool>,
+    pub queue_depth: usize,
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// A threshold a session crossed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthIssue {
+    /// The broker didn't answer a probe within a whole interval.
+    Unresponsive,
+    /// More messages than [`HealthPolicy::max_queue_depth`] were waiting.
+    Backlog { depth: usize },
+    /// More tasks than [`HealthPolicy::max_error_rate`] failed.
+    Errors { rate: f64 },
+}
+
+impl fmt::Display for HealthIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthIssue::Unresponsive => write!(f, "broker did not answer the health probe"),
+            HealthIssue::Backlog { depth } => write!(f, "{} messages waiting", depth),
+            HealthIssue::Errors { rate } => write!(f, "{:.0}% of tasks failed", rate * 100.0),
+        }
+    }
+}
+
+impl HealthPolicy {
+    /// The policy in `RZN_HEALTH_POLICY` (JSON; unset fields keep their
+    /// defaults), else the default. `None` if the interval is 0.
+    pub fn from_env() -> Option<Self> {
+        let policy = match std::env::var("RZN_HEALTH_POLICY") {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                log::error!("Ignoring invalid RZN_HEALTH_POLICY: {}", e);
+                HealthPolicy::default()
+            }),
+            Err(_) => HealthPolicy::default(),
+        };
+        (policy.interval_ms > 0).then_some(policy)
+    }
+
+    /// The thresholds `health` crosses.
+    pub fn check(&self, health: &SessionHealth) -> Vec<HealthIssue> {
+        let mut issues = Vec::new();
+        if health.probe_answered == Some(false) {
+            issues.push(HealthIssue::Unresponsive);
+        }
+        if health.queue_depth > self.max_queue_depth {
+            issues.push(HealthIssue::Backlog { depth: health.queue_depth });
+        }
+        let results = health.succeeded + health.failed;
+        if results > 0 && results >= self.min_results {
+            let rate = health.failed as f64 / results as f64;
+            if rate > self.max_error_rate {
+                issues.push(HealthIssue::Errors { rate });
+            }
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_each_crossed_threshold() {
+        let policy: HealthPolicy = serde_json::from_str(r#"{"min_results": 4, "remediations": [{"type": "reconnect"}]}"#).unwrap();
+        assert_eq!(policy.interval_ms, 30_000);
+        assert_eq!(policy.remediations, [Remediation::Reconnect]);
+
+        let healthy = SessionHealth { probe_answered: Some(true), queue_depth: 3, succeeded: 1, failed: 2 };
+        // Too few results to judge
+        assert!(policy.check(&healthy).is_empty());
+
+        let sick = SessionHealth { probe_answered: Some(false), queue_depth: 13, succeeded: 1, failed: 3 };
+        assert_eq!(policy.check(&sick), [HealthIssue::Unresponsive, HealthIssue::Backlog { depth: 13 }, HealthIssue::Errors { rate: 0.75 }]);
+    }
+}
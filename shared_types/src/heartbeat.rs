@@ -0,0 +1,68 @@
+//! Keepalive on the IPC link between the broker and the Main App.
+//!
+//! A half-open connection (e.g. a Main App frozen in a debugger, or a peer
+//! whose machine went to sleep) used to go unnoticed until a write failed. The
+//! broker now sends a `ping` every [`Heartbeat::interval`] and gives up on the
+//! connection if nothing at all comes back within [`Heartbeat::timeout`];
+//! a Main App that has seen heartbeats closes a connection that stays silent
+//! for longer than [`Heartbeat::reap_after`]. Both sides read the same
+//! settings from the environment.
+
+use std::time::Duration;
+
+/// Task IDs of heartbeat pings start with this; the Main App's `pong` echoes it.
+pub const HEARTBEAT_TASK_PREFIX: &str = "broker-heartbeat-";
+
+/// How often the broker pings the Main App and how long it waits for a sign of life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Time between pings.
+    pub interval: Duration,
+    /// Wait after a ping for any frame from the Main App.
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    /// A ping every 15 s, answered within 10 s.
+    fn default() -> Self {
+        Heartbeat { interval: Duration::from_secs(15), timeout: Duration::from_secs(10) }
+    }
+}
+
+impl Heartbeat {
+    /// The defaults adjusted by `RZN_HEARTBEAT_INTERVAL_MS` and
+    /// `RZN_HEARTBEAT_TIMEOUT_MS`. `None` if the interval is 0.
+    pub fn from_env() -> Option<Self> {
+        fn var(key: &str, default: Duration) -> Duration {
+            match std::env::var(key) {
+                Ok(value) => value.trim().parse().map(Duration::from_millis).unwrap_or_else(|_| {
+                    log::warn!("Ignoring invalid {}={:?}", key, value);
+                    default
+                }),
+                Err(_) => default,
+            }
+        }
+        let defaults = Heartbeat::default();
+        let heartbeat = Heartbeat {
+            interval: var("RZN_HEARTBEAT_INTERVAL_MS", defaults.interval),
+            timeout: var("RZN_HEARTBEAT_TIMEOUT_MS", defaults.timeout),
+        };
+        (!heartbeat.interval.is_zero()).then_some(heartbeat)
+    }
+
+    /// Silence after which the Main App may consider the broker gone: a whole
+    /// interval plus the time the broker itself would wait.
+    pub fn reap_after(&self) -> Duration {
+        self.interval + self.timeout
+    }
+
+    /// Task ID of the `seq`-th ping.
+    pub fn task_id(seq: u64) -> String {
+        format!("{}{}", HEARTBEAT_TASK_PREFIX, seq)
+    }
+
+    /// Whether `task_id` belongs to a heartbeat ping or its `pong`.
+    pub fn is_heartbeat(task_id: &str) -> bool {
+        task_id.starts_with(HEARTBEAT_TASK_PREFIX)
+    }
+}
@@ -0,0 +1,416 @@
+//! Registering the broker as a native messaging host.
+//!
+//! Browsers find native hosts through a JSON manifest in a per-browser,
+//! per-OS directory (on Windows, through a registry key pointing at it). The
+//! manifest has to carry the broker's absolute path and the extensions allowed
+//! to connect. `rzn_broker install` writes it from the command line; a Main App
+//! can use the same [`Registration`] to check the installation and repair it
+//! from its own UI, and [`Browser::open`] to send the user to the browser's
+//! extension page or restart prompt afterwards.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Native messaging host name, as registered in the host manifest.
+pub const HOST_NAME: &str = "com.yourcompany.projectagentis.broker";
+
+/// A browser the broker can be registered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Browser {
+    Chrome,
+    Chromium,
+    Edge,
+    Firefox,
+}
+
+impl Browser {
+    pub const ALL: [Browser; 4] = [Browser::Chrome, Browser::Chromium, Browser::Edge, Browser::Firefox];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "chrome" => Some(Browser::Chrome),
+            "chromium" => Some(Browser::Chromium),
+            "edge" => Some(Browser::Edge),
+            "firefox" => Some(Browser::Firefox),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Browser::Chrome => "Chrome",
+            Browser::Chromium => "Chromium",
+            Browser::Edge => "Edge",
+            Browser::Firefox => "Firefox",
+        }
+    }
+
+    /// The browser's per-user data directory.
+    pub fn data_dir(self) -> Option<PathBuf> {
+        let env = |key: &str| std::env::var_os(key).filter(|v| !v.is_empty()).map(PathBuf::from);
+        if cfg!(target_os = "macos") {
+            let support = env("HOME")?.join("Library/Application Support");
+            Some(support.join(match self {
+                Browser::Chrome => "Google/Chrome",
+                Browser::Chromium => "Chromium",
+                Browser::Edge => "Microsoft Edge",
+                Browser::Firefox => "Mozilla",
+            }))
+        } else if cfg!(windows) {
+            // Only the registry is read there; the manifests just need a home
+            Some(env("APPDATA")?.join(match self {
+                Browser::Chrome => "Google/Chrome",
+                Browser::Chromium => "Chromium",
+                Browser::Edge => "Microsoft/Edge",
+                Browser::Firefox => "Mozilla",
+            }))
+        } else {
+            let home = env("HOME")?;
+            Some(match self {
+                Browser::Chrome => home.join(".config/google-chrome"),
+                Browser::Chromium => home.join(".config/chromium"),
+                Browser::Edge => home.join(".config/microsoft-edge"),
+                Browser::Firefox => home.join(".mozilla"),
+            })
+        }
+    }
+
+    /// Whether the browser's data directory exists, i.e. it has been run by
+    /// this user.
+    pub fn is_installed(self) -> bool {
+        self.data_dir().is_some_and(|dir| dir.is_dir())
+    }
+
+    /// Directory the browser reads host manifests from.
+    pub fn manifest_dir(self) -> Option<PathBuf> {
+        let dir = self.data_dir()?;
+        Some(match self {
+            Browser::Firefox if !cfg!(target_os = "macos") && !cfg!(windows) => dir.join("native-messaging-hosts"),
+            _ => dir.join("NativeMessagingHosts"),
+        })
+    }
+
+    /// Where the broker's host manifest for this browser goes.
+    pub fn manifest_path(self) -> Option<PathBuf> {
+        Some(self.manifest_dir()?.join(format!("{}.json", HOST_NAME)))
+    }
+
+    /// Registry key (under `HKEY_CURRENT_USER`) that points Windows browsers at the manifest.
+    fn registry_key(self) -> String {
+        let vendor = match self {
+            Browser::Chrome => r"Google\Chrome",
+            Browser::Chromium => "Chromium",
+            Browser::Edge => r"Microsoft\Edge",
+            Browser::Firefox => "Mozilla",
+        };
+        format!(r"HKCU\Software\{}\NativeMessagingHosts\{}", vendor, HOST_NAME)
+    }
+
+    /// The page listing the browser's extensions, where the user can enable
+    /// or reload the bridge extension.
+    pub fn extensions_url(self) -> &'static str {
+        match self {
+            Browser::Chrome | Browser::Chromium => "chrome://extensions",
+            Browser::Edge => "edge://extensions",
+            Browser::Firefox => "about:addons",
+        }
+    }
+
+    /// The page that restarts the browser, so it picks up a new manifest.
+    /// Firefox has none; it reads manifests when the extension connects.
+    pub fn restart_url(self) -> Option<&'static str> {
+        match self {
+            Browser::Chrome | Browser::Chromium => Some("chrome://restart"),
+            Browser::Edge => Some("edge://restart"),
+            Browser::Firefox => None,
+        }
+    }
+
+    /// Opens `url` in this browser. Internal pages like `chrome://extensions`
+    /// aren't handled by the OS, so the browser itself is launched with the
+    /// URL; a running browser opens it in a new tab.
+    pub fn open(self, url: &str) -> io::Result<()> {
+        let mut command = if cfg!(target_os = "macos") {
+            let app = match self {
+                Browser::Chrome => "Google Chrome",
+                Browser::Chromium => "Chromium",
+                Browser::Edge => "Microsoft Edge",
+                Browser::Firefox => "Firefox",
+            };
+            let mut command = Command::new("open");
+            command.args(["-a", app, url]);
+            command
+        } else if cfg!(windows) {
+            let exe = match self {
+                Browser::Chrome => "chrome",
+                Browser::Chromium => "chromium",
+                Browser::Edge => "msedge",
+                Browser::Firefox => "firefox",
+            };
+            // `start` looks the executable up in App Paths, which PATH doesn't cover
+            let mut command = Command::new("cmd");
+            command.args(["/C", "start", "", exe, url]);
+            command
+        } else {
+            let candidates: &[&str] = match self {
+                Browser::Chrome => &["google-chrome", "google-chrome-stable"],
+                Browser::Chromium => &["chromium", "chromium-browser"],
+                Browser::Edge => &["microsoft-edge", "microsoft-edge-stable"],
+                Browser::Firefox => &["firefox"],
+            };
+            let exe = candidates.iter().find(|exe| on_path(exe)).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no {} executable on PATH", self.name()))
+            })?;
+            let mut command = Command::new(exe);
+            command.arg(url);
+            command
+        };
+        let mut child = command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+        // A browser launched directly keeps running; reap it whenever it exits
+        std::thread::spawn(move || child.wait());
+        Ok(())
+    }
+
+    /// Opens the browser's extensions page.
+    pub fn open_extensions_page(self) -> io::Result<()> {
+        self.open(self.extensions_url())
+    }
+}
+
+impl fmt::Display for Browser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What the broker is registered as: its executable and the extensions
+/// allowed to start it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    /// Absolute path of the `rzn_broker` executable.
+    pub broker: PathBuf,
+    /// Chromium extension IDs (Chrome, Chromium and Edge).
+    pub extension_ids: Vec<String>,
+    /// Firefox add-on ID.
+    pub firefox_id: Option<String>,
+}
+
+/// How a browser's registration compares to a [`Registration`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstallStatus {
+    /// The broker is registered as expected.
+    Registered,
+    /// The browser doesn't seem to be installed.
+    BrowserNotFound,
+    /// There is no host manifest, or on Windows no registry key for it.
+    NotRegistered,
+    /// The manifest exists but can't be read.
+    Unreadable(String),
+    /// The manifest points at another executable, or at one that's gone.
+    WrongBroker(PathBuf),
+    /// The manifest doesn't allow this extension to connect.
+    ExtensionNotAllowed(String),
+}
+
+impl InstallStatus {
+    pub fn is_registered(&self) -> bool {
+        *self == InstallStatus::Registered
+    }
+}
+
+impl fmt::Display for InstallStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstallStatus::Registered => write!(f, "registered"),
+            InstallStatus::BrowserNotFound => write!(f, "browser not found"),
+            InstallStatus::NotRegistered => write!(f, "not registered"),
+            InstallStatus::Unreadable(e) => write!(f, "manifest unreadable: {}", e),
+            InstallStatus::WrongBroker(path) => write!(f, "manifest points at {}", path.display()),
+            InstallStatus::ExtensionNotAllowed(id) => write!(f, "extension {} not allowed", id),
+        }
+    }
+}
+
+impl Registration {
+    /// The registration a Main App expects: the broker in `RZN_BROKER_PATH`
+    /// (else `rzn_broker` next to the running executable), the
+    /// comma-separated IDs in `RZN_EXTENSION_IDS` and the add-on ID in
+    /// `RZN_FIREFOX_ID`.
+    pub fn from_env() -> io::Result<Self> {
+        let broker = match std::env::var_os("RZN_BROKER_PATH").filter(|v| !v.is_empty()) {
+            Some(path) => PathBuf::from(path),
+            None => std::env::current_exe()?.with_file_name(format!("rzn_broker{}", std::env::consts::EXE_SUFFIX)),
+        };
+        let extension_ids = std::env::var("RZN_EXTENSION_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_string)
+            .collect();
+        let firefox_id = std::env::var("RZN_FIREFOX_ID").ok().filter(|id| !id.is_empty());
+        Ok(Registration { broker, extension_ids, firefox_id })
+    }
+
+    /// Whether there is an ID to register for `browser`.
+    pub fn covers(&self, browser: Browser) -> bool {
+        match browser {
+            Browser::Firefox => self.firefox_id.is_some(),
+            _ => !self.extension_ids.is_empty(),
+        }
+    }
+
+    /// Builds the manifest; Firefox lists extension IDs instead of origins.
+    pub fn manifest(&self, browser: Browser) -> serde_json::Value {
+        let mut manifest = serde_json::json!({
+            "name": HOST_NAME,
+            "description": "Rzn:Browser Bridge Broker",
+            "path": self.broker,
+            "type": "stdio",
+        });
+        if browser == Browser::Firefox {
+            manifest["allowed_extensions"] = serde_json::json!([self.firefox_id]);
+        } else {
+            let origins: Vec<String> = self.extension_ids.iter().map(|id| format!("chrome-extension://{}/", id)).collect();
+            manifest["allowed_origins"] = serde_json::json!(origins);
+        }
+        manifest
+    }
+
+    /// Writes the manifest for `browser`, replacing any earlier one, and
+    /// returns its path. The browser has to be restarted to pick it up.
+    pub fn register(&self, browser: Browser) -> io::Result<PathBuf> {
+        let path = browser.manifest_path().ok_or_else(|| io::Error::other("could not determine the home directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let manifest = self.manifest(browser);
+        fs::write(&path, serde_json::to_string_pretty(&manifest).map_err(io::Error::other)? + "\n")?;
+        if cfg!(windows) {
+            registry(&["add", &browser.registry_key(), "/ve", "/t", "REG_SZ", "/d", &path.to_string_lossy(), "/f"])?;
+        }
+        Ok(path)
+    }
+
+    /// Checks the manifest `browser` would read against this registration.
+    pub fn verify(&self, browser: Browser) -> InstallStatus {
+        let Some(path) = browser.manifest_path() else {
+            return InstallStatus::BrowserNotFound;
+        };
+        if cfg!(windows) && registry(&["query", &browser.registry_key(), "/ve"]).is_err() {
+            return InstallStatus::NotRegistered;
+        }
+        if !path.exists() {
+            return if browser.is_installed() { InstallStatus::NotRegistered } else { InstallStatus::BrowserNotFound };
+        }
+        self.check_manifest(browser, &path)
+    }
+
+    fn check_manifest(&self, browser: Browser, path: &Path) -> InstallStatus {
+        let manifest = match read_manifest(path) {
+            Ok(manifest) => manifest,
+            Err(e) => return InstallStatus::Unreadable(e.to_string()),
+        };
+        let Some(registered) = manifest.get("path").and_then(|v| v.as_str()).map(PathBuf::from) else {
+            return InstallStatus::Unreadable("manifest has no \"path\" field".to_string());
+        };
+        let same = match (fs::canonicalize(&registered), fs::canonicalize(&self.broker)) {
+            (Ok(registered), Ok(broker)) => registered == broker,
+            _ => false,
+        };
+        if !same {
+            return InstallStatus::WrongBroker(registered);
+        }
+
+        let allowed = |key: &str| -> Vec<String> {
+            manifest.get(key).and_then(|v| v.as_array()).into_iter().flatten().filter_map(|v| v.as_str().map(str::to_string)).collect()
+        };
+        let missing = if browser == Browser::Firefox {
+            let allowed = allowed("allowed_extensions");
+            self.firefox_id.iter().find(|id| !allowed.contains(id)).cloned()
+        } else {
+            let allowed = allowed("allowed_origins");
+            self.extension_ids.iter().find(|id| !allowed.contains(&format!("chrome-extension://{}/", id))).cloned()
+        };
+        match missing {
+            Some(id) => InstallStatus::ExtensionNotAllowed(id),
+            None => InstallStatus::Registered,
+        }
+    }
+}
+
+/// Removes the broker's manifest (and registry key) for `browser`. Returns
+/// the removed manifest's path, `None` if there was none.
+pub fn unregister(browser: Browser) -> io::Result<Option<PathBuf>> {
+    let Some(path) = browser.manifest_path() else {
+        return Ok(None);
+    };
+    if cfg!(windows) {
+        // The key is absent if the browser never had the host installed
+        let _ = registry(&["delete", &browser.registry_key(), "/f"]);
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(Some(path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Reads the `path` field of a host manifest, canonicalized.
+pub fn manifest_broker_path(manifest: &Path) -> io::Result<PathBuf> {
+    let value = read_manifest(manifest)?;
+    let path = value
+        .get("path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| io::Error::other("manifest has no \"path\" field"))?;
+    fs::canonicalize(path)
+}
+
+fn read_manifest(path: &Path) -> io::Result<serde_json::Value> {
+    let contents = fs::read_to_string(path)?;
+    serde_json::from_str(&contents).map_err(io::Error::other)
+}
+
+/// Whether `exe` is an executable file in a `PATH` directory.
+fn on_path(exe: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else { return false };
+    std::env::split_paths(&path).any(|dir| dir.join(exe).is_file())
+}
+
+/// Runs `reg.exe` with `args`.
+fn registry(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("reg").args(args).stdout(Stdio::null()).stderr(Stdio::null()).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("reg {} failed ({})", args[0], status)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_a_written_manifest() {
+        let dir = std::env::temp_dir().join(format!("rzn-install-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let broker = dir.join("rzn_broker");
+        fs::write(&broker, b"").unwrap();
+        let registration = Registration { broker: broker.clone(), extension_ids: vec!["abc".to_string()], firefox_id: None };
+
+        let manifest = dir.join("manifest.json");
+        fs::write(&manifest, registration.manifest(Browser::Chrome).to_string()).unwrap();
+        assert_eq!(registration.check_manifest(Browser::Chrome, &manifest), InstallStatus::Registered);
+
+        let other = Registration { extension_ids: vec!["abc".to_string(), "def".to_string()], ..registration.clone() };
+        assert_eq!(other.check_manifest(Browser::Chrome, &manifest), InstallStatus::ExtensionNotAllowed("def".to_string()));
+
+        let moved = Registration { broker: dir.join("elsewhere"), ..registration };
+        assert_eq!(moved.check_manifest(Browser::Chrome, &manifest), InstallStatus::WrongBroker(broker));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
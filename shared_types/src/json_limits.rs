@@ -0,0 +1,199 @@
+//! Guards against pathological JSON payloads.
+//!
+//! Before a message is deserialized, [`JsonLimits::check`] scans the raw bytes
+//! once, without recursion or allocation per value, and rejects payloads that
+//! nest too deeply or carry oversized strings or arrays. A hostile or buggy
+//! peer therefore can't exhaust the stack or stall the parser for seconds.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+
+/// Limits applied to incoming JSON. The defaults fit every message the bridge
+/// sends today, including full-page HTML captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonLimits {
+    /// Deepest allowed nesting of arrays and objects. Values above serde_json's
+    /// own recursion limit (128) still fail there, as a parse error.
+    pub max_depth: usize,
+    /// Longest allowed string (including object keys), in raw bytes.
+    pub max_string_len: usize,
+    /// Most elements allowed in a single array.
+    pub max_array_len: usize,
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        JsonLimits {
+            max_depth: 64,
+            max_string_len: 8 * 1024 * 1024,
+            max_array_len: 100_000,
+        }
+    }
+}
+
+/// A payload exceeded one of the [`JsonLimits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLimitError {
+    TooDeep { limit: usize },
+    StringTooLong { limit: usize },
+    ArrayTooLong { limit: usize },
+}
+
+impl fmt::Display for JsonLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonLimitError::TooDeep { limit } => write!(f, "JSON nesting exceeds {} levels", limit),
+            JsonLimitError::StringTooLong { limit } => write!(f, "JSON string exceeds {} bytes", limit),
+            JsonLimitError::ArrayTooLong { limit } => write!(f, "JSON array exceeds {} elements", limit),
+        }
+    }
+}
+
+impl std::error::Error for JsonLimitError {}
+
+/// Error of [`JsonLimits::from_slice`].
+#[derive(Debug)]
+pub enum JsonError {
+    /// The payload was rejected before parsing.
+    Limit(JsonLimitError),
+    /// The payload is within limits but not valid for the target type.
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Limit(e) => write!(f, "payload rejected: {}", e),
+            JsonError::Parse(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonError::Limit(e) => Some(e),
+            JsonError::Parse(e) => Some(e),
+        }
+    }
+}
+
+// Open container while scanning: arrays track their comma count
+enum Open {
+    Array { commas: usize },
+    Object,
+}
+
+impl JsonLimits {
+    /// Default limits, overridden by `RZN_JSON_MAX_DEPTH`, `RZN_JSON_MAX_STRING_LEN`
+    /// and `RZN_JSON_MAX_ARRAY_LEN` when set.
+    pub fn from_env() -> Self {
+        fn var(key: &str, default: usize) -> usize {
+            match std::env::var(key) {
+                Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+                    log::warn!("Ignoring invalid {}={:?}", key, value);
+                    default
+                }),
+                Err(_) => default,
+            }
+        }
+        let defaults = JsonLimits::default();
+        JsonLimits {
+            max_depth: var("RZN_JSON_MAX_DEPTH", defaults.max_depth),
+            max_string_len: var("RZN_JSON_MAX_STRING_LEN", defaults.max_string_len),
+            max_array_len: var("RZN_JSON_MAX_ARRAY_LEN", defaults.max_array_len),
+        }
+    }
+
+    /// Scans `bytes` and returns the first limit it exceeds. Malformed JSON is
+    /// not reported here; that is left to the parser.
+    pub fn check(&self, bytes: &[u8]) -> Result<(), JsonLimitError> {
+        let mut open: Vec<Open> = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    let start = i + 1;
+                    i += 1;
+                    while i < bytes.len() && bytes[i] != b'"' {
+                        // Skip the escaped character (which may be a quote)
+                        i += if bytes[i] == b'\\' { 2 } else { 1 };
+                    }
+                    if i.min(bytes.len()) - start > self.max_string_len {
+                        return Err(JsonLimitError::StringTooLong { limit: self.max_string_len });
+                    }
+                }
+                b'[' | b'{' => {
+                    if open.len() >= self.max_depth {
+                        return Err(JsonLimitError::TooDeep { limit: self.max_depth });
+                    }
+                    open.push(if bytes[i] == b'[' { Open::Array { commas: 0 } } else { Open::Object });
+                }
+                b']' | b'}' => {
+                    open.pop();
+                }
+                b',' => {
+                    if let Some(Open::Array { commas }) = open.last_mut() {
+                        *commas += 1;
+                        // n commas separate n + 1 elements
+                        if *commas >= self.max_array_len {
+                            return Err(JsonLimitError::ArrayTooLong { limit: self.max_array_len });
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Checks `bytes` against the limits, then deserializes them.
+    pub fn from_slice<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, JsonError> {
+        self.check(bytes).map_err(JsonError::Limit)?;
+        serde_json::from_slice(bytes).map_err(JsonError::Parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> JsonLimits {
+        JsonLimits { max_depth: 3, max_string_len: 5, max_array_len: 3 }
+    }
+
+    #[test]
+    fn accepts_payloads_within_limits() {
+        let ok = br#"{"a":[1,2,{"b":"12345"}],"c":"x\"y"}"#;
+        assert_eq!(limits().check(ok), Ok(()));
+        assert!(limits().from_slice::<serde_json::Value>(ok).is_ok());
+    }
+
+    #[test]
+    fn rejects_deep_nesting() {
+        assert_eq!(limits().check(b"[[[1]]]"), Ok(()));
+        assert_eq!(limits().check(b"[[[[1]]]]"), Err(JsonLimitError::TooDeep { limit: 3 }));
+        // Brackets inside strings don't count
+        assert_eq!(limits().check(br#"["[[[[[["]"#), Err(JsonLimitError::StringTooLong { limit: 5 }));
+        assert_eq!(limits().check(br#"{"k":"[[[["}"#), Ok(()));
+    }
+
+    #[test]
+    fn rejects_long_strings_and_arrays() {
+        assert_eq!(limits().check(br#"{"k":"123456"}"#), Err(JsonLimitError::StringTooLong { limit: 5 }));
+        assert_eq!(limits().check(b"[1,2,3]"), Ok(()));
+        assert_eq!(limits().check(b"[1,2,3,4]"), Err(JsonLimitError::ArrayTooLong { limit: 3 }));
+        // Object members are not array elements
+        assert_eq!(limits().check(br#"{"a":1,"b":2,"c":3,"d":4}"#), Ok(()));
+    }
+
+    #[test]
+    fn parse_errors_are_distinct_from_limit_errors() {
+        match limits().from_slice::<serde_json::Value>(b"[1,") {
+            Err(JsonError::Parse(_)) => {}
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Key chords for `keyboard` steps.
+//!
+//! A [`KeyChord`] is a key and the modifiers held while it is pressed, written
+//! as their names joined by `+`:
+//!
+//! ```json
+//! ["Ctrl+A", "Backspace", "h", "i", "Shift+Tab", "Enter"]
+//! ```
+//!
+//! A key is one character (`"a"`, `"A"`, `"+"`) or one of [`NAMED_KEYS`], with
+//! `Space` for `" "`. The modifiers are `Ctrl`, `Alt`, `Shift` and `Meta`;
+//! `Control`, `Option`, `Cmd` and `Command` are read too. Names are matched
+//! regardless of case.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Keys written by name, as the DOM's `KeyboardEvent.key` calls them.
+pub const NAMED_KEYS: &[&str] = &[
+    "Enter", "Tab", "Escape", "Backspace", "Delete", "Insert", "ArrowUp", "ArrowDown", "ArrowLeft", "ArrowRight", "Home", "End", "PageUp", "PageDown", "F1",
+    "F2", "F3", "F4", "F5", "F6", "F7", "F8", "F9", "F10", "F11", "F12",
+];
+
+/// A key pressed with modifiers, e.g. `Ctrl+A` or `Shift+Tab`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChord {
+    /// One character, or one of [`NAMED_KEYS`].
+    pub key: String,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub meta: bool,
+}
+
+impl KeyChord {
+    /// `key` without modifiers.
+    pub fn key(key: &str) -> Result<Self, KeyError> {
+        Ok(KeyChord { key: parse_key(key)?, ctrl: false, alt: false, shift: false, meta: false })
+    }
+
+    /// The chords typing `text`, one per character; line breaks and tabs
+    /// press Enter and Tab.
+    pub fn typing(text: &str) -> Vec<KeyChord> {
+        text.chars()
+            .filter(|&c| c != '\r')
+            .map(|c| {
+                let key = match c {
+                    '\n' => "Enter".to_string(),
+                    '\t' => "Tab".to_string(),
+                    c => c.to_string(),
+                };
+                KeyChord { key, ctrl: false, alt: false, shift: false, meta: false }
+            })
+            .collect()
+    }
+}
+
+/// Why a key chord could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The chord, or a part of it between `+`s, is empty.
+    Empty,
+    /// Neither one character nor a named key.
+    UnknownKey(String),
+    /// Comes before the key but is no modifier.
+    UnknownModifier(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Empty => write!(f, "key chord is empty"),
+            KeyError::UnknownKey(key) => write!(f, "unknown key {:?}", key),
+            KeyError::UnknownModifier(modifier) => write!(f, "unknown modifier {:?} (expected Ctrl, Alt, Shift or Meta)", modifier),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+fn parse_key(key: &str) -> Result<String, KeyError> {
+    if key.chars().count() == 1 {
+        return Ok(key.to_string());
+    }
+    let name = key.trim();
+    if name.is_empty() {
+        return Err(KeyError::Empty);
+    }
+    if name.eq_ignore_ascii_case("Space") {
+        return Ok(" ".to_string());
+    }
+    NAMED_KEYS.iter().find(|named| named.eq_ignore_ascii_case(name)).map(|named| named.to_string()).ok_or_else(|| KeyError::UnknownKey(name.to_string()))
+}
+
+impl FromStr for KeyChord {
+    type Err = KeyError;
+
+    fn from_str(chord: &str) -> Result<Self, KeyError> {
+        // "+" is a key too: "+", "Ctrl++"
+        let (modifiers, key) = match chord {
+            "+" => ("", "+"),
+            _ if chord.ends_with("++") => (chord[..chord.len() - 2].trim_end(), "+"),
+            _ => chord.rsplit_once('+').map_or(("", chord), |(modifiers, key)| (modifiers, key.trim())),
+        };
+        let mut parsed = KeyChord::key(key)?;
+        for modifier in modifiers.split('+').map(str::trim).filter(|_| !modifiers.is_empty()) {
+            let held = match modifier.to_ascii_lowercase().as_str() {
+                "" => return Err(KeyError::Empty),
+                "ctrl" | "control" => &mut parsed.ctrl,
+                "alt" | "option" => &mut parsed.alt,
+                "shift" => &mut parsed.shift,
+                "meta" | "cmd" | "command" => &mut parsed.meta,
+                _ => return Err(KeyError::UnknownModifier(modifier.to_string())),
+            };
+            *held = true;
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (held, name) in [(self.ctrl, "Ctrl"), (self.alt, "Alt"), (self.shift, "Shift"), (self.meta, "Meta")] {
+            if held {
+                write!(f, "{}+", name)?;
+            }
+        }
+        match self.key.as_str() {
+            " " => write!(f, "Space"),
+            key => write!(f, "{}", key),
+        }
+    }
+}
+
+impl Serialize for KeyChord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyChord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_keys_and_modifiers() {
+        let select_all: KeyChord = "ctrl+a".parse().unwrap();
+        assert_eq!(select_all, KeyChord { key: "a".to_string(), ctrl: true, alt: false, shift: false, meta: false });
+        assert_eq!("Cmd + Shift + arrowleft".parse::<KeyChord>().unwrap().to_string(), "Shift+Meta+ArrowLeft");
+        assert_eq!("Ctrl++".parse::<KeyChord>().unwrap().to_string(), "Ctrl++");
+        assert_eq!("+".parse::<KeyChord>().unwrap().key, "+");
+        assert_eq!(" ".parse::<KeyChord>().unwrap().to_string(), "Space");
+        assert_eq!("Enter".parse::<KeyChord>().unwrap(), KeyChord::key("enter").unwrap());
+
+        assert_eq!("".parse::<KeyChord>(), Err(KeyError::Empty));
+        assert_eq!("Ctrl+".parse::<KeyChord>(), Err(KeyError::Empty));
+        assert_eq!("Return".parse::<KeyChord>(), Err(KeyError::UnknownKey("Return".to_string())));
+        assert_eq!("Hyper+A".parse::<KeyChord>(), Err(KeyError::UnknownModifier("Hyper".to_string())));
+    }
+
+    #[test]
+    fn chords_are_strings_on_the_wire() {
+        let keys: Vec<KeyChord> = serde_json::from_str(r#"["Ctrl+A", "Backspace", "Shift+Tab"]"#).unwrap();
+        assert_eq!(serde_json::to_string(&keys).unwrap(), r#"["Ctrl+A","Backspace","Shift+Tab"]"#);
+        assert!(serde_json::from_str::<KeyChord>(r#""Ctrl+Nope""#).is_err());
+
+        let typed: Vec<String> = KeyChord::typing("a b\r\n").iter().map(KeyChord::to_string).collect();
+        assert_eq!(typed, ["a", "Space", "b", "Enter"]);
+    }
+}
@@ -0,0 +1,72 @@
+//! Types and helpers shared by the broker (`rzn_broker`) and the Main App (`example_app`).
+//!
+//! Keeping the protocol structs and the framing code in one place ensures both
+//! sides of the IPC link agree on the wire format. The framing, handshake and
+//! chunking themselves are sans-IO, in `rzn_protocol`; this crate adds the
+//! tokio adapters, the payload encodings and everything that touches the OS.
+
+pub mod action;
+pub mod alerts;
+pub mod compress;
+pub mod config;
+pub mod delivery;
+pub mod diff;
+pub mod encoding;
+pub mod endpoint;
+pub mod exit;
+pub mod frame;
+pub mod health;
+pub mod heartbeat;
+pub mod install;
+pub mod json_limits;
+pub mod keys;
+pub mod lifecycle;
+pub mod locale;
+pub mod logging;
+pub mod messages;
+pub mod pairing;
+pub mod profile;
+pub mod residency;
+pub mod runtime;
+pub mod sealed;
+pub mod selector;
+
+// Sans-IO parts of the protocol, under their old paths
+pub use rzn_protocol::{chunk, peek};
+
+pub use action::Action;
+pub use alerts::{Alert, AlertAction, AlertRule, AlertRules, ConditionError};
+pub use chunk::{chunk_message, is_chunk, ChunkError, Reassembler, CHUNK_DATA_ACTION, CHUNK_END_ACTION, CHUNK_START_ACTION, E_CHUNK};
+pub use compress::{Codec, Compression, COMPRESSION_CAPABILITIES};
+pub use config::{BridgeConfig, ConfigError, Overrides, CONFIG_ENV_VAR, DEFAULT_SOCKET_BASE, SOCKET_ENV_VAR};
+pub use delivery::{msg_id, with_msg_id, Ack, ProcessedKeys, ACK_ACTION, ACK_CAPABILITY, AT_LEAST_ONCE_CAPABILITY};
+pub use diff::{diff_results, ChangeEvent};
+pub use encoding::{Encoding, ENCODING_CAPABILITIES};
+pub use endpoint::{EndpointSpec, DEFAULT_SOCKET_NAME};
+pub use exit::{ExitDetail, ExitReason, LastExit};
+pub use frame::{FlushPolicy, MessageLimits, MessageTooLarge, MAX_MESSAGE_SIZE};
+pub use health::{HealthIssue, HealthPolicy, Remediation, SessionHealth};
+pub use heartbeat::{Heartbeat, HEARTBEAT_TASK_PREFIX};
+pub use install::{Browser, InstallStatus, Registration};
+pub use json_limits::{JsonError, JsonLimitError, JsonLimits};
+pub use keys::{KeyChord, KeyError, NAMED_KEYS};
+pub use lifecycle::{BrokerState, BrokerStateChange, ConnectionState, ConnectionStateChange, ConnectionStatus, BROKER_STATE_ACTION};
+pub use logging::{LogSink, LOG_SINK_ENV_VAR};
+pub use locale::{Date, DateOrder, Locale, Money};
+pub use messages::{
+    url_origin, AttributeChange, BridgeStats, CancelRequest, CommitDecision, CommitRequest, DeadLetter, DurationSummary, ExtensionConfig, ExtensionLog, ExtensionResponse, Hello, HistoryPage, HistoryQuery, ImageFormat, InvalidTask, LogLevel,
+    Message, MutationSummary, OriginStats, PauseRequest, ResolveSelectors, ResolvedSelectors, Screenshot, ScrollBehavior, ScrollTarget, SelectOption, SelectorDegradation, SelectorMatches, ShutdownNotice, StatsQuery, Step, StepCompleted, StepErrorKind, StepProgress, StepResult, StepStarted, Task, TaskCancelled, TaskRecord, TaskResult, TaskStatus, ValueType, VersionMismatch,
+    ABORT_ACTION, BRIDGE_ERROR_ACTION, CANCEL_TASK_ACTION, COMMIT_ACTION, COMMIT_REQUEST_ACTION, CONFIGURE_ACK_ACTION, CONFIGURE_ACTION,
+    CONFIGURE_REQUEST_ACTION, DEAD_LETTER_ACTION, E_INVALID_JSON, E_PAUSED, MESSAGE_TOO_LARGE_ACTION, E_PROTOCOL_VERSION, E_TRANSCODE, E_UNKNOWN_ACTION, E_UNSUPPORTED_FRAME, HELLO_ACK_ACTION, HELLO_ACTION, HISTORY_ACTION, HISTORY_RESULT_ACTION, LOG_ACTION,
+    PAUSE_ALL_ACTION, PERFORM_TASK_ACTION, PROTOCOL_VERSION, RESOLVE_SELECTORS_ACTION, RESOLVE_SELECTORS_RESULT_ACTION, RESUME_ALL_ACTION, SELECTOR_DEGRADED_ACTION, SHUTDOWN_ACTION,
+    STATS_ACTION, STATS_RESULT_ACTION, STEP_COMPLETED_ACTION, STEP_PROGRESS_ACTION, STEP_STARTED_ACTION, TASK_CANCELLED_ACTION, TASK_RESULT_ACTION, TASK_TIMEOUT_ERROR,
+};
+pub use pairing::{AuditEntry, AuditEvent, Confirmed, PairRequest, PairedIdentity, Pairings, PairingStatus, E_NOT_PAIRED, E_REVOKED, PAIR_ACTION, PAIR_RESULT_ACTION};
+pub use peek::{peek_envelope, peek_str, peek_u64, Envelope};
+pub use profile::{Profile, ProfileError, DEFAULT_PROFILE, PROFILE_ENV_VAR};
+pub use residency::{PolicyError, ResidencyFilter, ResidencyPolicy, SensitivePattern};
+pub use runtime::{RuntimeFlavor, MAX_BLOCKING_THREADS_ENV_VAR, RUNTIME_ENV_VAR, WORKER_THREADS_ENV_VAR};
+#[cfg(feature = "runtime")]
+pub use runtime::RuntimeOptions;
+pub use sealed::{is_sealed, may_be_unsealed, KeyExchange, SealKey, SealedChannel, Side, REPLAY_WINDOW, SEALED_ACTION, UNSEALED_ACTIONS};
+pub use selector::{Selector, SelectorError, SHADOW_PIERCE};
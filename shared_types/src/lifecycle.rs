@@ -0,0 +1,175 @@
+//! The broker's lifecycle as seen by the two sides it connects.
+//!
+//! A task sent while the Main App is down used to just time out in the
+//! extension. The broker now reports each change of its [`BrokerState`] in a
+//! `broker_state` message, so the extension can show the backend as offline
+//! and the Main App learns when the broker is about to go away.
+//!
+//! Each connection between the broker and a Main App also has a
+//! [`ConnectionState`] of its own, which both ends track and report through
+//! their status APIs.
+
+use serde::{Deserialize, Serialize};
+
+/// Action of the broker's lifecycle notifications; `result` is a
+/// [`BrokerStateChange`].
+pub const BROKER_STATE_ACTION: &str = "broker_state";
+
+/// Where the broker is in its lifecycle.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BrokerState {
+    /// The extension is connected; the Main App connection isn't up yet.
+    ExtensionConnected,
+    /// Connecting, or reconnecting, to the Main App.
+    IpcConnecting,
+    /// Relaying between the extension and the Main App.
+    IpcConnected,
+    /// The Main App connection dropped; tasks can't be run until it is back.
+    IpcLost,
+    /// Shutting down: no new messages are read, queued ones are still written.
+    Draining,
+    /// About to exit.
+    ShuttingDown,
+}
+
+impl BrokerState {
+    /// Whether the broker may go from `self` to `next`.
+    pub fn can_become(self, next: BrokerState) -> bool {
+        use BrokerState::*;
+        match (self, next) {
+            (ShuttingDown, _) => false,
+            (Draining, next) => next == ShuttingDown,
+            (_, Draining | ShuttingDown) => true,
+            (ExtensionConnected | IpcLost, IpcConnecting | IpcConnected) => true,
+            (IpcConnecting, IpcConnected | IpcLost) => true,
+            (IpcConnected, IpcLost) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the Main App can be reached in this state.
+    pub fn is_online(self) -> bool {
+        matches!(self, BrokerState::IpcConnected)
+    }
+}
+
+/// Payload of a `broker_state`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokerStateChange {
+    pub state: BrokerState,
+    /// The state before, `None` for the first notification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<BrokerState>,
+}
+
+/// Where one connection between the broker and a Main App is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Opening the connection.
+    Connecting,
+    /// Connected, exchanging `hello` and `hello_ack`.
+    Handshaking,
+    /// Relaying messages.
+    Ready,
+    /// Closing: no new messages are read, queued ones are still written.
+    Draining,
+    /// The connection dropped and is being opened again.
+    Reconnecting,
+    /// Gone for good.
+    Closed,
+}
+
+impl ConnectionState {
+    /// Whether a connection may go from `self` to `next`.
+    pub fn can_become(self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+        match (self, next) {
+            (Closed, _) => false,
+            (_, Closed) => true,
+            (Draining, _) => false,
+            (_, Draining) => true,
+            (Connecting | Reconnecting, Handshaking) => true,
+            (Handshaking, Ready | Reconnecting) => true,
+            (Ready, Reconnecting) => true,
+            _ => false,
+        }
+    }
+
+    /// Moves to `next` if that is allowed, returning the change.
+    pub fn advance(&mut self, next: ConnectionState) -> Option<ConnectionStateChange> {
+        if !self.can_become(next) {
+            return None;
+        }
+        let previous = std::mem::replace(self, next);
+        Some(ConnectionStateChange { state: next, previous })
+    }
+
+    /// Whether messages are relayed in this state.
+    pub fn is_ready(self) -> bool {
+        matches!(self, ConnectionState::Ready)
+    }
+}
+
+/// A connection going from one [`ConnectionState`] to another.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStateChange {
+    pub state: ConnectionState,
+    pub previous: ConnectionState,
+}
+
+/// One of the broker's Main App connections, as its status APIs list it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStatus {
+    /// Unique for the broker process; kept across reconnects.
+    pub id: u64,
+    /// Which Main App: 0 is the primary, others are peers.
+    pub peer: usize,
+    pub state: ConnectionState,
+    /// When the connection entered `state`, in ms since the epoch.
+    pub since_ms: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use BrokerState::*;
+
+    #[test]
+    fn allows_only_forward_transitions() {
+        assert!(ExtensionConnected.can_become(IpcConnected));
+        assert!(IpcConnected.can_become(IpcLost));
+        assert!(IpcLost.can_become(IpcConnecting));
+        assert!(IpcConnecting.can_become(IpcConnected));
+        assert!(IpcLost.can_become(Draining));
+        assert!(Draining.can_become(ShuttingDown));
+
+        assert!(!IpcConnected.can_become(IpcConnected));
+        assert!(!IpcConnected.can_become(ExtensionConnected));
+        assert!(!Draining.can_become(IpcConnected));
+        assert!(!ShuttingDown.can_become(Draining));
+    }
+
+    #[test]
+    fn moves_connections_along_allowed_transitions() {
+        use ConnectionState::*;
+        let mut state = Connecting;
+        assert!(state.advance(Ready).is_none());
+        assert_eq!(state.advance(Handshaking), Some(ConnectionStateChange { state: Handshaking, previous: Connecting }));
+        assert!(state.advance(Ready).is_some());
+        assert!(state.advance(Reconnecting).is_some());
+        assert!(state.advance(Handshaking).is_some());
+        assert!(state.advance(Draining).is_some());
+        assert!(state.advance(Ready).is_none());
+        assert!(state.advance(Closed).is_some());
+        assert!(state.advance(Connecting).is_none());
+        assert_eq!(state, Closed);
+    }
+
+    #[test]
+    fn serializes_as_snake_case() {
+        let change = BrokerStateChange { state: IpcLost, previous: Some(IpcConnected) };
+        assert_eq!(serde_json::to_string(&change).unwrap(), r#"{"state":"ipc_lost","previous":"ipc_connected"}"#);
+    }
+}
@@ -0,0 +1,244 @@
+//! Locale-aware parsing of scraped numbers, prices and dates.
+//!
+//! Extracted values arrive as the page displays them: "1.299,00 €" on a German
+//! shop, "1,299.00" on a US one, "05/04/2024" meaning April or May depending on
+//! the country. A [`Locale`], usually taken from the task's `locale` tag, knows
+//! which separators and date order to expect:
+//!
+//! ```
+//! use shared_types::Locale;
+//!
+//! let de = Locale::from_tag("de-DE").unwrap();
+//! assert_eq!(de.parse_number("1.299,00"), Some(1299.0));
+//! let price = de.parse_money("1.299,00 €").unwrap();
+//! assert_eq!((price.amount, price.currency.as_deref()), (1299.0, Some("EUR")));
+//! assert_eq!(de.parse_date("05.04.2024").unwrap().to_string(), "2024-04-05");
+//! ```
+
+use std::fmt;
+
+/// Order of day, month and year in numeric dates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateOrder {
+    /// 31/12/2024
+    Dmy,
+    /// 12/31/2024
+    Mdy,
+    /// 2024/12/31
+    Ymd,
+}
+
+/// Number and date conventions of a locale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    /// Decimal separator.
+    pub decimal: char,
+    /// Digit group separator (spaces of any width are always accepted too).
+    pub grouping: char,
+    /// Order of numeric dates.
+    pub date_order: DateOrder,
+}
+
+impl Default for Locale {
+    /// `en-US`.
+    fn default() -> Self {
+        Locale { decimal: '.', grouping: ',', date_order: DateOrder::Mdy }
+    }
+}
+
+/// A parsed price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Money {
+    pub amount: f64,
+    /// ISO 4217 code, if the text carried a symbol or code.
+    pub currency: Option<String>,
+}
+
+/// A calendar date without time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl fmt::Display for Date {
+    /// ISO 8601 (`YYYY-MM-DD`).
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+// Currency symbols, longest first so "US$" wins over "$"
+const CURRENCY_SYMBOLS: &[(&str, &str)] = &[
+    ("US$", "USD"),
+    ("CA$", "CAD"),
+    ("A$", "AUD"),
+    ("R$", "BRL"),
+    ("zł", "PLN"),
+    ("Kč", "CZK"),
+    ("€", "EUR"),
+    ("£", "GBP"),
+    ("¥", "JPY"),
+    ("₹", "INR"),
+    ("₩", "KRW"),
+    ("₽", "RUB"),
+    ("₺", "TRY"),
+    ("$", "USD"),
+];
+
+// Characters that separate digit groups besides the locale's own separator
+const SPACES: &[char] = &[' ', '\u{a0}', '\u{202f}', '\u{2009}'];
+
+impl Locale {
+    /// Conventions for a BCP 47 tag such as `de-DE`, `fr` or `en_GB`, or `None`
+    /// if the language is not known.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let tag = tag.trim().replace('_', "-").to_ascii_lowercase();
+        let (language, region) = match tag.split_once('-') {
+            Some((language, rest)) => (language, rest.rsplit('-').next().unwrap_or("")),
+            None => (tag.as_str(), ""),
+        };
+        let (decimal, grouping, date_order) = match (language, region) {
+            ("en", "us" | "") => ('.', ',', DateOrder::Mdy),
+            ("en", "ca") => ('.', ',', DateOrder::Ymd),
+            ("en", _) => ('.', ',', DateOrder::Dmy),
+            ("de" | "it", "ch") => ('.', '\'', DateOrder::Dmy),
+            ("de" | "it" | "es" | "pt" | "nl" | "da" | "tr" | "id" | "el" | "ro", _) => (',', '.', DateOrder::Dmy),
+            ("fr" | "ru" | "uk" | "pl" | "cs" | "sk" | "fi" | "nb" | "no" | "bg", _) => (',', ' ', DateOrder::Dmy),
+            ("sv" | "lt" | "hu", _) => (',', ' ', DateOrder::Ymd),
+            ("ja" | "zh" | "ko", _) => ('.', ',', DateOrder::Ymd),
+            ("hi" | "he" | "th", _) => ('.', ',', DateOrder::Dmy),
+            _ => return None,
+        };
+        Some(Locale { decimal, grouping, date_order })
+    }
+
+    /// Parses a number such as "1.299,50", "-3 000" or "(12.00)" (negative).
+    /// Surrounding text without digits (units, currency symbols) is ignored.
+    pub fn parse_number(&self, text: &str) -> Option<f64> {
+        let text = text.trim();
+        // Accounting notation for negatives
+        let (text, negative) = match text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+            Some(inner) => (inner, true),
+            None => (text, false),
+        };
+        let start = text.find(|c: char| c.is_ascii_digit() || c == self.decimal)?;
+        let end = text.rfind(|c: char| c.is_ascii_digit())? + 1;
+        let negative = negative || text[..start].trim_end().ends_with(['-', '\u{2212}']) || text[end..].trim() == "-";
+        let mut number = String::from(if negative { "-" } else { "" });
+        let mut seen_decimal = false;
+        for c in text[start..end].chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+            } else if c == self.decimal && !seen_decimal {
+                seen_decimal = true;
+                number.push('.');
+            } else if c != self.grouping && !SPACES.contains(&c) {
+                return None;
+            }
+        }
+        number.parse().ok()
+    }
+
+    /// Parses a price such as "1.299,00 €", "US$ 12.50" or "CHF 1'000".
+    pub fn parse_money(&self, text: &str) -> Option<Money> {
+        let amount = self.parse_number(text)?;
+        let currency = CURRENCY_SYMBOLS
+            .iter()
+            .find(|(symbol, _)| text.contains(symbol))
+            .map(|(_, code)| code.to_string())
+            .or_else(|| {
+                // An ISO code written out ("EUR 12", "12 CHF")
+                text.split(|c: char| !c.is_ascii_alphabetic())
+                    .find(|word| word.len() == 3 && word.chars().all(|c| c.is_ascii_uppercase()))
+                    .map(str::to_string)
+            });
+        Some(Money { amount, currency })
+    }
+
+    /// Parses a numeric date in this locale's order ("31.12.2024", "12/31/24").
+    /// ISO dates (`2024-12-31`) are accepted in every locale. Two-digit years
+    /// are taken as 1970-2069.
+    pub fn parse_date(&self, text: &str) -> Option<Date> {
+        let parts: Vec<&str> = text
+            .trim()
+            .trim_end_matches('.')
+            .split(['.', '/', '-', ' '])
+            .filter(|part| !part.is_empty())
+            .collect();
+        if parts.len() != 3 || parts.iter().any(|part| !part.chars().all(|c| c.is_ascii_digit())) {
+            return None;
+        }
+        let order = if parts[0].len() == 4 { DateOrder::Ymd } else { self.date_order };
+        let (year, month, day) = match order {
+            DateOrder::Dmy => (parts[2], parts[1], parts[0]),
+            DateOrder::Mdy => (parts[2], parts[0], parts[1]),
+            DateOrder::Ymd => (parts[0], parts[1], parts[2]),
+        };
+        let year: i32 = match (year.len(), year.parse().ok()?) {
+            (2, year) if year < 70 => 2000 + year,
+            (2, year) => 1900 + year,
+            (4, year) => year,
+            _ => return None,
+        };
+        let date = Date { year, month: month.parse().ok()?, day: day.parse().ok()? };
+        let valid = (1..=12).contains(&date.month) && (1..=days_in_month(date.year, date.month)).contains(&date.day);
+        valid.then_some(date)
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_follow_the_locale_separators() {
+        let us = Locale::default();
+        let de = Locale::from_tag("de-DE").unwrap();
+        let fr = Locale::from_tag("fr_FR").unwrap();
+        let ch = Locale::from_tag("de-CH").unwrap();
+        assert_eq!(us.parse_number("1,299.50"), Some(1299.5));
+        assert_eq!(de.parse_number("1.299,50"), Some(1299.5));
+        assert_eq!(fr.parse_number("1\u{202f}299,50 kg"), Some(1299.5));
+        assert_eq!(ch.parse_number("1'299.50"), Some(1299.5));
+        assert_eq!(us.parse_number("(12.00)"), Some(-12.0));
+        assert_eq!(de.parse_number("- 3,5"), Some(-3.5));
+        // A second decimal separator means the locale is wrong
+        assert_eq!(us.parse_number("1.299.50"), None);
+        assert_eq!(us.parse_number("n/a"), None);
+    }
+
+    #[test]
+    fn money_detects_symbols_and_codes() {
+        let us = Locale::default();
+        let price = |text| us.parse_money(text).map(|m| (m.amount, m.currency));
+        assert_eq!(price("US$ 12.50"), Some((12.5, Some("USD".to_string()))));
+        assert_eq!(price("£1,000"), Some((1000.0, Some("GBP".to_string()))));
+        assert_eq!(price("12.00 CHF"), Some((12.0, Some("CHF".to_string()))));
+        assert_eq!(price("12.00"), Some((12.0, None)));
+    }
+
+    #[test]
+    fn dates_follow_the_locale_order() {
+        let date = |locale: Locale, text| locale.parse_date(text).map(|d| d.to_string());
+        let us = Locale::default();
+        let gb = Locale::from_tag("en-GB").unwrap();
+        assert_eq!(date(us, "04/05/2024"), Some("2024-04-05".to_string()));
+        assert_eq!(date(gb, "04/05/2024"), Some("2024-05-04".to_string()));
+        assert_eq!(date(gb, "2024-05-04"), Some("2024-05-04".to_string()));
+        assert_eq!(date(gb, "29.02.23"), None);
+        assert_eq!(date(gb, "29.02.24"), Some("2024-02-29".to_string()));
+        assert_eq!(date(us, "13/01/2024"), None);
+        assert_eq!(Locale::from_tag("xx"), None);
+    }
+}
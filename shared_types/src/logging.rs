@@ -0,0 +1,289 @@
+//! Where the broker and the Main App log.
+//!
+//! By default both log to stderr, filtered by `RUST_LOG` as usual. Enterprise
+//! IT tends to watch the platform's own log rather than app-private output,
+//! so the config file's `log_sink` (or `RZN_LOG_SINK`) can send the log
+//! there instead, with the same `RUST_LOG` filter:
+//!
+//! * `journald`: the systemd journal (Linux), with `SYSLOG_IDENTIFIER` set
+//!   to the binary's name;
+//! * `oslog`: the unified log (macOS), under the subsystem
+//!   `com.rzn.<binary>`;
+//! * `eventlog`: the Windows Event Log, with the binary's name as the
+//!   source. Without an installed message file, Event Viewer shows the text
+//!   under a "description not found" preamble;
+//! * `native`: whichever of these the platform has.
+//!
+//! A sink that isn't available falls back to stderr.
+
+use std::fmt;
+use std::io;
+
+/// Environment variable choosing the log sink, winning over the config file.
+pub const LOG_SINK_ENV_VAR: &str = "RZN_LOG_SINK";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogSink {
+    #[default]
+    Stderr,
+    /// The platform's own log, see [`LogSink::resolve`].
+    Native,
+    Journald,
+    OsLog,
+    EventLog,
+}
+
+impl LogSink {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "stderr" => Some(LogSink::Stderr),
+            "native" => Some(LogSink::Native),
+            "journald" => Some(LogSink::Journald),
+            "oslog" => Some(LogSink::OsLog),
+            "eventlog" => Some(LogSink::EventLog),
+            _ => None,
+        }
+    }
+
+    /// [`LogSink::Native`] as the sink of this platform; others as they are.
+    pub fn resolve(self) -> Self {
+        match self {
+            LogSink::Native if cfg!(target_os = "macos") => LogSink::OsLog,
+            LogSink::Native if cfg!(windows) => LogSink::EventLog,
+            LogSink::Native if cfg!(target_os = "linux") => LogSink::Journald,
+            LogSink::Native => LogSink::Stderr,
+            sink => sink,
+        }
+    }
+}
+
+impl fmt::Display for LogSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogSink::Stderr => "stderr",
+            LogSink::Native => "native",
+            LogSink::Journald => "journald",
+            LogSink::OsLog => "oslog",
+            LogSink::EventLog => "eventlog",
+        })
+    }
+}
+
+/// Installs the logger for the binary `name`, logging to the sink in the
+/// bridge config ([`BridgeConfig::log_sink`](crate::BridgeConfig::log_sink)).
+/// Call it once `--config` is installed, so the right file is read.
+pub fn init(name: &str) {
+    let sink = crate::BridgeConfig::load().unwrap_or_default().log_sink().resolve();
+    let backend = match Backend::open(sink, name) {
+        Ok(Some(backend)) => backend,
+        Ok(None) => return env_logger::init(),
+        Err(e) => {
+            env_logger::init();
+            log::warn!("Logging to stderr, {} is not available: {}", sink, e);
+            return;
+        }
+    };
+    let filter = env_filter::Builder::from_env("RUST_LOG").build();
+    log::set_max_level(filter.filter());
+    if let Err(e) = log::set_boxed_logger(Box::new(NativeLogger { filter, backend })) {
+        eprintln!("Could not install the {} logger: {}", sink, e);
+    }
+}
+
+/// A logger writing to a platform log.
+struct NativeLogger {
+    filter: env_filter::Filter,
+    backend: Backend,
+}
+
+impl log::Log for NativeLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.filter.matches(record) {
+            self.backend.write(record.level(), record.target(), &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+enum Backend {
+    #[cfg(target_os = "linux")]
+    Journald(journald::Journal),
+    #[cfg(target_os = "macos")]
+    OsLog(oslog::OsLog),
+    #[cfg(windows)]
+    EventLog(eventlog::EventSource),
+}
+
+impl Backend {
+    /// The backend of `sink`, `None` for stderr.
+    fn open(sink: LogSink, name: &str) -> io::Result<Option<Self>> {
+        let _ = name;
+        match sink {
+            LogSink::Stderr | LogSink::Native => Ok(None),
+            #[cfg(target_os = "linux")]
+            LogSink::Journald => journald::Journal::open(name).map(|journal| Some(Backend::Journald(journal))),
+            #[cfg(target_os = "macos")]
+            LogSink::OsLog => Ok(Some(Backend::OsLog(oslog::OsLog::new(&format!("com.rzn.{}", name), "default")))),
+            #[cfg(windows)]
+            LogSink::EventLog => eventlog::EventSource::register(name).map(|source| Some(Backend::EventLog(source))),
+            #[allow(unreachable_patterns)]
+            sink => Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} is not available on this platform", sink))),
+        }
+    }
+
+    fn write(&self, level: log::Level, target: &str, message: &str) {
+        let _ = (level, target, message);
+        match self {
+            #[cfg(target_os = "linux")]
+            Backend::Journald(journal) => journal.send(level, target, message),
+            #[cfg(target_os = "macos")]
+            Backend::OsLog(log) => {
+                let level = match level {
+                    log::Level::Error => oslog::Level::Error,
+                    log::Level::Warn => oslog::Level::Default,
+                    log::Level::Info => oslog::Level::Info,
+                    log::Level::Debug | log::Level::Trace => oslog::Level::Debug,
+                };
+                log.with_level(level, &format!("[{}] {}", target, message));
+            }
+            #[cfg(windows)]
+            Backend::EventLog(source) => source.report(level, &format!("[{}] {}", target, message)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod journald {
+    use std::io;
+    use std::os::unix::net::UnixDatagram;
+
+    const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+    /// A connection to journald's native protocol socket.
+    pub(super) struct Journal {
+        socket: UnixDatagram,
+        identifier: String,
+    }
+
+    impl Journal {
+        pub(super) fn open(identifier: &str) -> io::Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNAL_SOCKET)?;
+            Ok(Journal { socket, identifier: identifier.to_string() })
+        }
+
+        pub(super) fn send(&self, level: log::Level, target: &str, message: &str) {
+            let priority = match level {
+                log::Level::Error => "3",
+                log::Level::Warn => "4",
+                log::Level::Info => "6",
+                log::Level::Debug | log::Level::Trace => "7",
+            };
+            let mut entry = Vec::new();
+            for (field, value) in [("PRIORITY", priority), ("SYSLOG_IDENTIFIER", self.identifier.as_str()), ("TARGET", target), ("MESSAGE", message)] {
+                append_field(&mut entry, field, value);
+            }
+            // Nowhere left to report a failure to
+            let _ = self.socket.send(&entry);
+        }
+    }
+
+    /// Appends `field=value`, length-prefixed if the value spans lines.
+    pub(super) fn append_field(entry: &mut Vec<u8>, field: &str, value: &str) {
+        entry.extend_from_slice(field.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+}
+
+#[cfg(windows)]
+mod eventlog {
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+
+    use windows_sys::Win32::Foundation::HANDLE;
+    use windows_sys::Win32::System::EventLog::{
+        DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+    };
+
+    /// A registered Event Log source.
+    pub(super) struct EventSource(HANDLE);
+
+    // The handle is only passed to ReportEventW, which may be called from any thread
+    unsafe impl Send for EventSource {}
+    unsafe impl Sync for EventSource {}
+
+    fn wide(text: &str) -> Vec<u16> {
+        std::ffi::OsStr::new(text).encode_wide().chain(Some(0)).collect()
+    }
+
+    impl EventSource {
+        pub(super) fn register(name: &str) -> io::Result<Self> {
+            let name = wide(name);
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+            if handle.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(EventSource(handle))
+        }
+
+        pub(super) fn report(&self, level: log::Level, message: &str) {
+            let kind = match level {
+                log::Level::Error => EVENTLOG_ERROR_TYPE,
+                log::Level::Warn => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let message = wide(message);
+            let strings = [message.as_ptr()];
+            unsafe {
+                ReportEventW(self.0, kind, 0, 0, std::ptr::null_mut(), 1, 0, strings.as_ptr(), std::ptr::null());
+            }
+        }
+    }
+
+    impl Drop for EventSource {
+        fn drop(&mut self) {
+            unsafe {
+                DeregisterEventSource(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_resolves_sinks() {
+        for sink in [LogSink::Stderr, LogSink::Native, LogSink::Journald, LogSink::OsLog, LogSink::EventLog] {
+            assert_eq!(LogSink::parse(&sink.to_string()), Some(sink));
+        }
+        assert_eq!(LogSink::parse("syslog"), None);
+        assert_ne!(LogSink::Native.resolve(), LogSink::Native);
+        assert_eq!(LogSink::Journald.resolve(), LogSink::Journald);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn frames_journal_fields() {
+        let mut entry = Vec::new();
+        journald::append_field(&mut entry, "PRIORITY", "6");
+        journald::append_field(&mut entry, "MESSAGE", "two\nlines");
+        let mut expected = b"PRIORITY=6\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&9u64.to_le_bytes());
+        expected.extend_from_slice(b"two\nlines\n");
+        assert_eq!(entry, expected);
+    }
+}
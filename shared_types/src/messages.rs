@@ -0,0 +1,1455 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::action::Action;
+use crate::chunk::{ChunkError, E_CHUNK};
+use crate::frame::MessageTooLarge;
+use crate::keys::KeyChord;
+use crate::lifecycle::ConnectionStatus;
+use crate::selector::Selector;
+
+// --- Shared Message Structures ---
+// These structs define the communication protocol between the extension,
+// the broker and the Main App.
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub action: Action,
+    pub task_id: String,
+    // Optional so simple messages (e.g. ping) don't need to carry a task
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task: Option<Task>,
+    // Free-form payload for non-task messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+    // How long the message stays deliverable once the broker has read it (ms).
+    // Messages still queued when it runs out are dropped instead of delivered late.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<u64>,
+    // Identifies the message for acks and duplicate detection (see `delivery`).
+    // The broker assigns one to messages from the Main App that don't carry it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_id: Option<String>,
+}
+
+/// Action of a task sent by the host for the extension to run.
+pub const PERFORM_TASK_ACTION: &str = "perform_task";
+/// Asks for the task `task_id` to be stopped; `data` is a [`CancelRequest`].
+/// Acknowledged with a `task_cancelled`.
+pub const CANCEL_TASK_ACTION: &str = "cancel_task";
+/// Acknowledgment of a `cancel_task`; `result` is a [`TaskCancelled`].
+pub use rzn_protocol::correlate::TASK_CANCELLED_ACTION;
+/// `error` of a task failed for running past its `timeout_ms`.
+pub const TASK_TIMEOUT_ERROR: &str = "timeout";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Task {
+    pub steps: Vec<Step>,
+    // Largest serialized `TaskResult` the caller accepts (bytes). Bigger results
+    // are truncated and flagged with `result_truncated` instead of being dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_result_bytes: Option<u64>,
+    // BCP 47 tag (e.g. "de-DE") of the scraped site, for parsing its numbers
+    // and dates with `Locale::from_tag`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    // Free-form labels, e.g. the product feature that started the task. The
+    // bridge relays them untouched; broker statistics can be filtered by tag
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // Free-form caller data, relayed untouched
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    // How long the task may run once sent (ms). Past it the task fails with
+    // a `timeout` error and is cancelled, see `ExtensionResponse::task_timed_out`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    // Have each step's result summarize the changes it made to the page, see
+    // `StepResult::mutations`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub record_mutations: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Step {
+    #[serde(rename = "navigate")]
+    Navigate {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destructive: Option<bool>,
+    },
+    #[serde(rename = "scrape")]
+    Scrape { config: serde_json::Value }, // Keep config generic, the extension interprets it
+    #[serde(rename = "click")]
+    Click {
+        selector: Selector,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        wait_for_nav: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destructive: Option<bool>,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    #[serde(rename = "fill")]
+    Fill {
+        selector: Selector,
+        value: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dispatch_events: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destructive: Option<bool>,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    #[serde(rename = "wait_for_selector")]
+    WaitForSelector {
+        selector: Selector,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<String>,
+        timeout: u32,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    #[serde(rename = "wait_for_timeout")]
+    WaitForTimeout { timeout: u32 },
+    #[serde(rename = "extract")]
+    Extract {
+        selector: Selector,
+        target: String, // "text", "html", "attribute" or "attributes" (a name -> value map)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attribute_name: Option<String>,
+        // With target "attributes", only these attributes (missing ones are null)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attribute_names: Option<Vec<String>>,
+        variable_name: String,
+        // Extract from every match (as an array) instead of just the first
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        all: Option<bool>,
+        // With `all`, keep at most this many values
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<u32>,
+        // Trim surrounding whitespace from extracted values
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        trim: Option<bool>,
+        // With `all`, drop repeated values (keeping the first occurrence)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dedup: Option<bool>,
+        // Reduce each value to a match of this regex (in the syntax shared by
+        // JavaScript and the `regex` crate); values that don't match become null
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        regex: Option<String>,
+        // Capture group of `regex` to keep (default: 1 if the regex has groups, else the whole match)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<usize>,
+        // Coerce extracted strings to this type; failures fail the step with `error_kind: coercion`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value_type: Option<ValueType>,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    // Remembers the matching element as `handle_name` for later steps' `within`
+    #[serde(rename = "locate")]
+    Locate {
+        selector: Selector,
+        handle_name: String,
+        // Which match to take (0-based), e.g. the n-th card of a results grid
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        index: Option<u32>,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    // Captures the visible part of the page, the whole page or one element. The
+    // image comes back in the step's data, see `Screenshot`
+    #[serde(rename = "screenshot")]
+    Screenshot {
+        // Element to capture, scrolled into view first
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        selector: Option<Selector>,
+        // The whole scrollable page instead of the viewport
+        #[serde(default)]
+        full_page: bool,
+        #[serde(default)]
+        format: ImageFormat,
+        // JPEG quality from 0 to 100 (default 90)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quality: Option<u8>,
+    },
+    // Chooses an option of a <select>, by exactly one of `value`, `label` or `index`
+    #[serde(rename = "select")]
+    Select {
+        selector: Selector,
+        #[serde(flatten)]
+        option: SelectOption,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destructive: Option<bool>,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    // Presses `keys` one after the other, e.g. ["Ctrl+A", "Backspace", "Enter"].
+    // The extension emulates what the page would do with them (typing into
+    // inputs, Tab moving the focus, Enter submitting a form)
+    #[serde(rename = "keyboard")]
+    Keyboard {
+        // Element to focus first; without one the keys go to the focused element
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        selector: Option<Selector>,
+        keys: Vec<KeyChord>,
+        // Pause after each key (ms)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delay_ms: Option<u32>,
+        // Enter may submit a form
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destructive: Option<bool>,
+        // Handle (from a `locate` step) to search `selector` under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    // Scrolls the page. With `until`, scrolls again (waiting for content to
+    // load in between) until that element appears or `max_iterations` scrolls
+    // were made, e.g. to advance an infinite-scroll feed
+    #[serde(rename = "scroll")]
+    Scroll {
+        target: ScrollTarget,
+        #[serde(default)]
+        behavior: ScrollBehavior,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until: Option<Selector>,
+        // Most scrolls made for `until` (default 10)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_iterations: Option<u32>,
+    },
+    // Moves the pointer over the element, e.g. to open a menu that shows on hover
+    #[serde(rename = "hover")]
+    Hover {
+        selector: Selector,
+        // Handle (from a `locate` step) to search under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    // Drags one element onto another, with HTML5 drag events if the source is
+    // `draggable`, else with the pointer and mouse events drag libraries follow
+    #[serde(rename = "drag_and_drop")]
+    DragAndDrop {
+        source_selector: Selector,
+        target_selector: Selector,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destructive: Option<bool>,
+        // Handle (from a `locate` step) to search both selectors under instead of the whole page
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        within: Option<String>,
+    },
+    // Add other step types as needed, ensuring they match the extension's content script
+}
+
+/// Where a `scroll` step scrolls to: `"top"`, `"bottom"`, `{"selector": ...}`
+/// (the element into view) or `{"pixels": {"x": 0, "y": 800}}` (by that much
+/// from where the page is).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrollTarget {
+    Top,
+    Bottom,
+    Selector(Selector),
+    Pixels {
+        #[serde(default)]
+        x: i32,
+        #[serde(default)]
+        y: i32,
+    },
+}
+
+/// How a `scroll` step moves the page, as `ScrollToOptions.behavior`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrollBehavior {
+    #[default]
+    Instant,
+    Smooth,
+}
+
+/// Which option a `select` step chooses: `{"value": "bug"}`, `{"label": "Bug
+/// report"}` (its text, ignoring surrounding whitespace) or `{"index": 1}`
+/// (0-based).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", try_from = "SelectOptionFields")]
+pub enum SelectOption {
+    Value(String),
+    Label(String),
+    Index(u32),
+}
+
+// What a `select` step says, so one naming two options is rejected instead
+// of quietly choosing by either
+#[derive(Deserialize)]
+struct SelectOptionFields {
+    value: Option<String>,
+    label: Option<String>,
+    index: Option<u32>,
+}
+
+impl TryFrom<SelectOptionFields> for SelectOption {
+    type Error = String;
+
+    fn try_from(fields: SelectOptionFields) -> Result<Self, String> {
+        match (fields.value, fields.label, fields.index) {
+            (Some(value), None, None) => Ok(SelectOption::Value(value)),
+            (None, Some(label), None) => Ok(SelectOption::Label(label)),
+            (None, None, Some(index)) => Ok(SelectOption::Index(index)),
+            (None, None, None) => Err("select needs one of value, label or index".to_string()),
+            _ => Err("select takes only one of value, label or index".to_string()),
+        }
+    }
+}
+
+/// Encoding of a screenshot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+}
+
+impl ImageFormat {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Expected type of an extracted value, coerced by the extension.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueType {
+    /// Trimmed string.
+    String,
+    /// JSON number. Currency symbols, spaces and thousands separators are
+    /// ignored, so "$1,299.00" becomes 1299.
+    Number,
+    /// true/yes/on/1 or false/no/off/0 (case-insensitive).
+    Boolean,
+    /// Date or date-time, returned as an ISO 8601 string. `format` uses the
+    /// tokens YYYY, MM, DD, HH, mm and ss (e.g. "DD.MM.YYYY"); without it the
+    /// browser's date parser is used.
+    Date {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<String>,
+    },
+}
+
+impl Task {
+    /// Checks the task before it is sent to the extension, so malformed steps
+    /// fail fast instead of halfway through a run.
+    pub fn validate(&self) -> Result<(), InvalidTask> {
+        // Handles located so far on the current page
+        let mut handles: Vec<&str> = Vec::new();
+        for (step_index, step) in self.steps.iter().enumerate() {
+            let invalid = |reason: String| InvalidTask { step_index, reason };
+            if let Some(selector) = step.selector() {
+                selector.validate().map_err(|e| invalid(e.to_string()))?;
+            }
+            if let Some(handle) = step.within() {
+                if !handles.contains(&handle) {
+                    return Err(invalid(format!("handle {:?} is not located by an earlier step on this page", handle)));
+                }
+            }
+            if let Step::Extract { target, attribute_name, attribute_names, all, limit, dedup, .. } = step {
+                match target.as_str() {
+                    "text" | "html" => {}
+                    "attribute" if attribute_name.is_none() => {
+                        return Err(invalid("target \"attribute\" requires attribute_name".to_string()));
+                    }
+                    "attribute" | "attributes" => {}
+                    other => return Err(invalid(format!("unknown extract target {:?}", other))),
+                }
+                if attribute_names.is_some() && target != "attributes" {
+                    return Err(invalid("attribute_names requires target \"attributes\"".to_string()));
+                }
+                let all = all.unwrap_or(false);
+                if !all && (limit.is_some() || dedup.is_some()) {
+                    return Err(invalid("limit and dedup require all: true".to_string()));
+                }
+                if *limit == Some(0) {
+                    return Err(invalid("limit must be at least 1".to_string()));
+                }
+            }
+            if let Step::Extract { regex, group, .. } = step {
+                match (regex, group) {
+                    (None, Some(_)) => return Err(invalid("group requires regex".to_string())),
+                    (Some(pattern), group) => {
+                        let compiled = regex::Regex::new(pattern)
+                            .map_err(|e| invalid(format!("invalid regex {:?}: {}", pattern, e)))?;
+                        let groups = compiled.captures_len() - 1;
+                        if let Some(group) = group.filter(|&group| group > groups) {
+                            return Err(invalid(format!("regex {:?} has no capture group {}", pattern, group)));
+                        }
+                    }
+                    (None, None) => {}
+                }
+            }
+            if let Step::Extract { value_type: Some(ValueType::Date { format: Some(format) }), .. } = step {
+                if !["YYYY", "MM", "DD"].iter().all(|token| format.contains(token)) {
+                    return Err(invalid(format!("date format {:?} needs at least YYYY, MM and DD", format)));
+                }
+            }
+            if let Step::Screenshot { selector, full_page, format, quality } = step {
+                if selector.is_some() && *full_page {
+                    return Err(invalid("selector and full_page can't be combined".to_string()));
+                }
+                match quality {
+                    Some(_) if *format != ImageFormat::Jpeg => return Err(invalid("quality requires format \"jpeg\"".to_string())),
+                    Some(quality) if *quality > 100 => return Err(invalid(format!("quality {} is over 100", quality))),
+                    _ => {}
+                }
+            }
+            if let Step::Scroll { target, until, max_iterations, .. } = step {
+                match until {
+                    Some(_) if !matches!(target, ScrollTarget::Bottom | ScrollTarget::Pixels { .. }) => {
+                        return Err(invalid("until requires target \"bottom\" or pixels".to_string()));
+                    }
+                    Some(until) => until.validate().map_err(|e| invalid(format!("until: {}", e)))?,
+                    None if max_iterations.is_some() => return Err(invalid("max_iterations requires until".to_string())),
+                    None => {}
+                }
+                if *max_iterations == Some(0) {
+                    return Err(invalid("max_iterations must be at least 1".to_string()));
+                }
+            }
+            if let Step::DragAndDrop { target_selector, .. } = step {
+                target_selector.validate().map_err(|e| invalid(format!("target_selector: {}", e)))?;
+            }
+            if let Step::Keyboard { keys, selector, within, .. } = step {
+                if keys.is_empty() {
+                    return Err(invalid("keys is empty".to_string()));
+                }
+                if within.is_some() && selector.is_none() {
+                    return Err(invalid("within requires selector".to_string()));
+                }
+            }
+            match step {
+                // A new page starts without handles
+                Step::Navigate { .. } => handles.clear(),
+                Step::Locate { handle_name, .. } if handle_name.trim().is_empty() => {
+                    return Err(invalid("handle_name is empty".to_string()));
+                }
+                Step::Locate { handle_name, .. } => handles.push(handle_name),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Task {
+    /// Whether the task carries `tag`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Origin (`scheme://host[:port]`) of the first page the task navigates to.
+    pub fn origin(&self) -> Option<String> {
+        self.steps.iter().find_map(|step| match step {
+            Step::Navigate { url, .. } => url_origin(url),
+            _ => None,
+        })
+    }
+}
+
+/// Origin (`scheme://host[:port]`, lowercased) of an absolute URL.
+pub fn url_origin(url: &str) -> Option<String> {
+    let (scheme, rest) = url.trim().split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    // Credentials are not part of the origin
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if scheme.is_empty() || host.is_empty() {
+        return None;
+    }
+    Some(format!("{}://{}", scheme, host).to_ascii_lowercase())
+}
+
+/// Why [`Task::validate`] rejected a task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTask {
+    pub step_index: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid step {}: {}", self.step_index, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidTask {}
+
+impl Step {
+    /// The element selector of steps that target an element.
+    pub fn selector(&self) -> Option<&Selector> {
+        match self {
+            Step::Click { selector, .. }
+            | Step::Fill { selector, .. }
+            | Step::WaitForSelector { selector, .. }
+            | Step::Extract { selector, .. }
+            | Step::Locate { selector, .. }
+            | Step::Select { selector, .. }
+            | Step::Hover { selector, .. } => Some(selector),
+            Step::DragAndDrop { source_selector, .. } => Some(source_selector),
+            Step::Screenshot { selector, .. } | Step::Keyboard { selector, .. } => selector.as_ref(),
+            Step::Scroll { target: ScrollTarget::Selector(selector), .. } => Some(selector),
+            _ => None,
+        }
+    }
+
+    /// The handle the step is scoped to, if any.
+    pub fn within(&self) -> Option<&str> {
+        match self {
+            Step::Click { within, .. }
+            | Step::Fill { within, .. }
+            | Step::WaitForSelector { within, .. }
+            | Step::Extract { within, .. }
+            | Step::Locate { within, .. }
+            | Step::Select { within, .. }
+            | Step::Keyboard { within, .. }
+            | Step::Hover { within, .. }
+            | Step::DragAndDrop { within, .. } => within.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// True if the step is flagged `destructive` (submits, deletes, purchases...).
+    /// The extension asks the host to commit before running such steps.
+    pub fn is_destructive(&self) -> bool {
+        match self {
+            Step::Navigate { destructive, .. }
+            | Step::Click { destructive, .. }
+            | Step::Fill { destructive, .. }
+            | Step::Select { destructive, .. }
+            | Step::Keyboard { destructive, .. }
+            | Step::DragAndDrop { destructive, .. } => {
+                destructive.unwrap_or(false)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ExtensionResponse {
+    pub action: Action, // e.g., "pong", "task_result"
+    pub task_id: String, // Echo task_id if available, else use placeholder
+    pub success: bool,
+    // Use serde_json::Value for flexibility, or define specific result structs
+    pub result: Option<serde_json::Value>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Sent back instead of a message that was over the receiver's size limit;
+/// `result` has its `len`, the `limit` and its `action`, if that could be read.
+pub use rzn_protocol::correlate::MESSAGE_TOO_LARGE_ACTION;
+
+impl ExtensionResponse {
+    /// The `message_too_large` answer to a skipped message, under the
+    /// message's own `task_id` so its sender can fail the task.
+    pub fn message_too_large(too_large: &MessageTooLarge) -> Self {
+        ExtensionResponse {
+            action: Action::MessageTooLarge,
+            task_id: too_large.task_id.clone().unwrap_or_else(|| "N/A".to_string()),
+            success: false,
+            result: Some(serde_json::json!({
+                "len": too_large.len,
+                "limit": too_large.limit,
+                "action": too_large.action,
+            })),
+            error: Some(too_large.to_string()),
+        }
+    }
+
+    /// The `bridge_error` with code [`E_CHUNK`] for a chunked transfer that
+    /// couldn't be put back together.
+    pub fn chunk_error(task_id: &str, error: &ChunkError) -> Self {
+        ExtensionResponse::bridge_error(task_id, E_CHUNK, &error.to_string())
+    }
+
+    /// A `bridge_error`: `result` is the structured error, `{code, message,
+    /// task_id}`, and `error` reads "[code] message".
+    pub fn bridge_error(task_id: &str, code: &str, message: &str) -> Self {
+        ExtensionResponse {
+            action: Action::BridgeError,
+            task_id: task_id.to_string(),
+            success: false,
+            result: Some(serde_json::json!({ "code": code, "message": message, "task_id": task_id })),
+            error: Some(format!("[{}] {}", code, message)),
+        }
+    }
+
+    /// Adds the fields of `details` to an object `result`, keeping the ones
+    /// it has.
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        if let (serde_json::Value::Object(details), Some(serde_json::Value::Object(result))) = (details, self.result.as_mut()) {
+            for (key, value) in details {
+                result.entry(key).or_insert(value);
+            }
+        }
+        self
+    }
+}
+
+// --- Task Results ---
+
+/// Action of the response the extension sends when a task finishes.
+pub use rzn_protocol::correlate::TASK_RESULT_ACTION;
+
+/// `result` payload of a `task_result` response.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TaskResult {
+    pub steps: Vec<StepResult>,
+    /// Set when step data was cut to fit the task's `max_result_bytes`.
+    #[serde(default)]
+    pub result_truncated: bool,
+}
+
+/// Outcome of a single step.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StepResult {
+    #[serde(rename = "type")]
+    pub step_type: String,
+    pub success: bool,
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Machine-readable category of `error`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<StepErrorKind>,
+    /// What the step changed in the page, for tasks with `record_mutations`.
+    /// `None` for navigations and steps that left the page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mutations: Option<MutationSummary>,
+}
+
+impl StepResult {
+    /// The image a screenshot step captured. `None` for other steps, failed
+    /// screenshots and ones whose data was cut by `max_result_bytes`.
+    pub fn screenshot(&self) -> Option<Screenshot> {
+        if self.step_type != "screenshot" {
+            return None;
+        }
+        serde_json::from_value(self.data.clone()?).ok()
+    }
+}
+
+/// Changes to the page while a step ran and until it settled (up to a second
+/// after), counted by a `MutationObserver`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MutationSummary {
+    /// Nodes added to and removed from the document.
+    pub added: u32,
+    pub removed: u32,
+    /// Changed attributes and text nodes.
+    pub attributes: u32,
+    pub text: u32,
+    /// The first 20 changes of attributes that tend to tell what happened:
+    /// `class`, `hidden`, `disabled`, `open`, `checked`, `selected`, `value`,
+    /// `src`, `href` and `aria-*`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notable: Vec<AttributeChange>,
+}
+
+impl MutationSummary {
+    /// True if the step left the page as it was, e.g. a click nothing
+    /// listened to.
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.attributes == 0 && self.text == 0
+    }
+}
+
+/// One attribute change in a [`MutationSummary`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttributeChange {
+    /// The element, as `tag#id.class`.
+    pub element: String,
+    pub attribute: String,
+    /// `None` where the attribute was missing before or removed.
+    #[serde(default)]
+    pub old_value: Option<String>,
+    #[serde(default)]
+    pub new_value: Option<String>,
+}
+
+/// `data` of a screenshot step. In JSON the image is base64, which large
+/// images cross in chunks like any large result (see [`crate::chunk`]).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Screenshot {
+    pub format: ImageFormat,
+    /// Size of the image in pixels.
+    pub width: u32,
+    pub height: u32,
+    /// The encoded image.
+    #[serde(with = "base64_bytes")]
+    pub image: Vec<u8>,
+    /// Set when a full-page capture was cut to the largest image the browser
+    /// draws.
+    #[serde(default)]
+    pub clipped: bool,
+}
+
+mod base64_bytes {
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        BASE64.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Category of a step failure.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StepErrorKind {
+    /// The element did not appear in time.
+    Timeout,
+    /// An extracted value could not be coerced to the declared `value_type`.
+    Coercion,
+    /// The host aborted a destructive step (or didn't commit it in time).
+    Aborted,
+    /// The host cancelled the task with `cancel_task` before this step.
+    Cancelled,
+    /// Anything else, including kinds added by newer extensions.
+    #[serde(other)]
+    Other,
+}
+
+impl TaskResult {
+    /// Serialized size in bytes.
+    pub fn encoded_len(&self) -> usize {
+        serde_json::to_vec(self).map(|bytes| bytes.len()).unwrap_or(0)
+    }
+
+    /// Cuts step data until the result serializes to at most `max_bytes`.
+    /// Array data loses trailing items, other data is dropped, largest step first.
+    /// Returns true if anything was cut. Step types, flags and errors are kept,
+    /// so a tiny budget can still be exceeded.
+    pub fn truncate_to(&mut self, max_bytes: usize) -> bool {
+        let mut truncated = false;
+        while self.encoded_len() > max_bytes {
+            let sizes = self.steps.iter().map(|step| {
+                step.data.as_ref().and_then(|data| serde_json::to_vec(data).ok()).map_or(0, |bytes| bytes.len())
+            });
+            let Some((index, size)) = sizes.enumerate().max_by_key(|&(_, size)| size) else {
+                break;
+            };
+            if size <= "null".len() {
+                break; // Nothing left to cut
+            }
+            let excess = self.encoded_len() - max_bytes;
+            let data = self.steps[index].data.take();
+            self.steps[index].data = match data {
+                Some(serde_json::Value::Array(mut items)) if items.len() > 1 => {
+                    // Drop trailing items worth at least the excess
+                    let mut freed = 0;
+                    while freed < excess && items.len() > 1 {
+                        freed += items.pop().and_then(|item| serde_json::to_vec(&item).ok()).map_or(0, |b| b.len()) + 1;
+                    }
+                    Some(serde_json::Value::Array(items))
+                }
+                _ => None,
+            };
+            truncated = true;
+        }
+        self.result_truncated |= truncated;
+        truncated
+    }
+}
+
+// --- Step Progress ---
+
+/// Sent by the extension as a step starts; `data` is a [`StepStarted`].
+pub const STEP_STARTED_ACTION: &str = "step_started";
+/// Sent by the extension while a step waits or works; `data` is a [`StepProgress`].
+pub const STEP_PROGRESS_ACTION: &str = "step_progress";
+/// Sent by the extension as a step ends, ahead of the `task_result`; `data`
+/// is a [`StepCompleted`].
+pub const STEP_COMPLETED_ACTION: &str = "step_completed";
+
+/// Payload of a `step_started`. The task is the envelope's `task_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StepStarted {
+    /// Index of the step in the task.
+    pub step_index: usize,
+    #[serde(rename = "type")]
+    pub step_type: String,
+}
+
+/// Payload of a `step_progress`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StepProgress {
+    pub step_index: usize,
+    /// What the step is doing, e.g. "waiting for commit".
+    pub message: String,
+    /// How far along the step is, from 0 to 1, when the extension can tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fraction: Option<f64>,
+}
+
+/// Payload of a `step_completed`: the step's entry of the final
+/// [`TaskResult`], before any truncation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StepCompleted {
+    pub step_index: usize,
+    pub result: StepResult,
+}
+
+// --- Two-Phase Commit ---
+
+/// Sent by the extension before a destructive step; `data` is a [`CommitRequest`].
+pub const COMMIT_REQUEST_ACTION: &str = "commit_request";
+/// Host reply letting the paused step run; `data` is a [`CommitDecision`].
+pub const COMMIT_ACTION: &str = "commit";
+/// Host reply cancelling the paused step and the rest of the task.
+pub const ABORT_ACTION: &str = "abort";
+
+/// Payload of a `commit_request`. The task is the envelope's `task_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitRequest {
+    /// Index of the paused step in the task.
+    pub step_index: usize,
+    pub step: Step,
+}
+
+/// Payload of a `commit` or `abort` reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitDecision {
+    pub step_index: usize,
+    /// Why the step was aborted, shown in the step error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+// --- Extension Log Forwarding ---
+
+/// Action of log records sent by the extension (fire-and-forget, no response).
+pub const LOG_ACTION: &str = "log";
+
+/// Severity of a forwarded extension log record.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+/// Payload (`data`) of a `log` message from the extension.
+/// The related task, if any, is the envelope's `task_id`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtensionLog {
+    pub level: LogLevel,
+    /// Where in the extension the record came from (e.g. "handleTask").
+    pub scope: String,
+    pub message: String,
+}
+
+// --- Extension Configuration ---
+
+/// Action of the settings pushed by the host to the extension (`data` is an [`ExtensionConfig`]).
+pub const CONFIGURE_ACTION: &str = "configure";
+
+/// Action of the extension's acknowledgment; `result` carries the config it applied.
+pub const CONFIGURE_ACK_ACTION: &str = "configure_ack";
+
+/// Action the extension sends right after connecting to ask for its settings.
+pub const CONFIGURE_REQUEST_ACTION: &str = "configure_request";
+
+/// Extension settings pushed by the host. Unset fields keep the extension's
+/// current value, so a runtime push only needs to carry what changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionConfig {
+    /// Pause between task steps (ms), to be polite to scraped sites.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub step_delay_ms: Option<u32>,
+    /// Timeout for element waits when a step doesn't set its own (ms).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_timeout_ms: Option<u32>,
+    /// Feature toggles by name. Unknown names are kept but ignored by the extension.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, bool>,
+}
+
+// --- Handshake ---
+
+// The handshake itself is in the protocol crate (`rzn_protocol::handshake`)
+pub use rzn_protocol::handshake::{Hello, VersionMismatch, HELLO_ACK_ACTION, HELLO_ACTION, PROTOCOL_VERSION};
+
+/// Action of the structured errors the broker sends to the extension; `result`
+/// carries a `code` such as [`E_PROTOCOL_VERSION`].
+pub use rzn_protocol::correlate::BRIDGE_ERROR_ACTION;
+/// Error code of a handshake between incompatible protocol versions.
+pub const E_PROTOCOL_VERSION: &str = "E_PROTOCOL_VERSION";
+/// Error code of a request whose action the receiving side has no handler for.
+pub const E_UNKNOWN_ACTION: &str = "E_UNKNOWN_ACTION";
+/// Error code of a message over the JSON limits (nesting, string or array
+/// sizes), which the broker drops.
+pub const E_INVALID_JSON: &str = "E_INVALID_JSON";
+/// Error code of an IPC frame with flags the broker can't handle, such as
+/// encryption, which isn't negotiated.
+pub const E_UNSUPPORTED_FRAME: &str = "E_UNSUPPORTED_FRAME";
+/// Error code of a MessagePack or CBOR message the broker couldn't turn into
+/// JSON for the extension.
+pub const E_TRANSCODE: &str = "E_TRANSCODE";
+
+// --- Pause / Resume ---
+
+/// Host broadcast stopping all automation: the broker holds new tasks and the
+/// extension stops before its next step. `data` is a [`PauseRequest`].
+pub const PAUSE_ALL_ACTION: &str = "pause_all";
+/// Host broadcast lifting a `pause_all`; held tasks then run in order.
+pub const RESUME_ALL_ACTION: &str = "resume_all";
+/// Error code of a task the broker refused to hold while paused.
+pub const E_PAUSED: &str = "E_PAUSED";
+
+/// Payload of a `pause_all`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PauseRequest {
+    /// Why automation was paused, shown in the extension's log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Message {
+    /// A `pause_all` broadcast.
+    pub fn pause_all(task_id: impl Into<String>, reason: Option<String>) -> Self {
+        Message::control(PAUSE_ALL_ACTION, task_id.into(), serde_json::to_value(PauseRequest { reason }).ok())
+    }
+
+    /// A `resume_all` broadcast.
+    pub fn resume_all(task_id: impl Into<String>) -> Self {
+        Message::control(RESUME_ALL_ACTION, task_id.into(), None)
+    }
+
+    fn control(action: &str, task_id: String, data: Option<serde_json::Value>) -> Self {
+        Message { action: action.into(), task_id, task: None, data, ttl_ms: None, msg_id: None }
+    }
+}
+
+// --- Shutdown ---
+
+/// Sent before exiting on a signal: by the broker to the extension and the Main
+/// App, and by a Main App to the extension. `data` is a [`ShutdownNotice`].
+pub const SHUTDOWN_ACTION: &str = "shutdown";
+
+/// Payload of a `shutdown`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShutdownNotice {
+    /// What ended the process, e.g. `SIGTERM`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Message {
+    /// A `shutdown` notice.
+    pub fn shutdown(task_id: impl Into<String>, reason: Option<String>) -> Self {
+        Message::control(SHUTDOWN_ACTION, task_id.into(), serde_json::to_value(ShutdownNotice { reason }).ok())
+    }
+}
+
+// --- Task Cancellation ---
+
+/// Payload of a `cancel_task`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CancelRequest {
+    /// Why the task is cancelled, shown in the step error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Result of a `task_cancelled`. A cancelled task still sends its
+/// `task_result`, failed at the step it stopped before with
+/// [`StepErrorKind::Cancelled`]. The step running when the cancellation
+/// comes finishes first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskCancelled {
+    /// `false` if the task wasn't running, e.g. because it already finished.
+    pub cancelled: bool,
+}
+
+impl Message {
+    /// A `cancel_task` for the task `task_id`.
+    pub fn cancel_task(task_id: impl Into<String>, reason: Option<String>) -> Self {
+        Message::control(CANCEL_TASK_ACTION, task_id.into(), serde_json::to_value(CancelRequest { reason }).ok())
+    }
+}
+
+impl ExtensionResponse {
+    /// The `task_cancelled` acknowledging a `cancel_task` for `task_id`.
+    pub fn task_cancelled(task_id: impl Into<String>, cancelled: bool) -> Self {
+        ExtensionResponse {
+            action: Action::TaskCancelled,
+            task_id: task_id.into(),
+            success: true,
+            result: serde_json::to_value(TaskCancelled { cancelled }).ok(),
+            error: None,
+        }
+    }
+
+    /// The failed `task_result` standing in for the result of a task that
+    /// ran past its [`Task::timeout_ms`], with error [`TASK_TIMEOUT_ERROR`].
+    pub fn task_timed_out(task_id: impl Into<String>) -> Self {
+        ExtensionResponse {
+            action: Action::TaskResult,
+            task_id: task_id.into(),
+            success: false,
+            result: None,
+            error: Some(TASK_TIMEOUT_ERROR.to_string()),
+        }
+    }
+}
+
+// --- Dead Letters ---
+
+/// Sent by the broker to the extension in place of one of its messages that
+/// expired while held for a Main App that was away; `result` is a
+/// [`DeadLetter`] and `task_id` the message's.
+pub const DEAD_LETTER_ACTION: &str = "dead_letter";
+
+/// A message of the extension that never reached the Main App.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// `action` of the message, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    /// How long the message was held (ms).
+    pub held_ms: u64,
+}
+
+impl ExtensionResponse {
+    /// The `dead_letter` of a message of task `task_id`.
+    pub fn dead_letter(task_id: impl Into<String>, letter: &DeadLetter) -> Self {
+        ExtensionResponse {
+            action: Action::DeadLetter,
+            task_id: task_id.into(),
+            success: false,
+            result: serde_json::to_value(letter).ok(),
+            error: Some("Message expired before the Main App was back".to_string()),
+        }
+    }
+}
+
+// --- Selector Preview ---
+
+/// Asks the extension to try selectors on a page, without running any step;
+/// `data` is a [`ResolveSelectors`]. For task-authoring UIs to show while a
+/// selector is typed what it matches.
+pub const RESOLVE_SELECTORS_ACTION: &str = "resolve_selectors";
+/// The extension's answer to a `resolve_selectors`; `result` is a
+/// [`ResolvedSelectors`].
+pub use rzn_protocol::correlate::RESOLVE_SELECTORS_RESULT_ACTION;
+
+/// Payload of a `resolve_selectors`. The extension opens `url` in a
+/// background tab, resolves each selector once the page has loaded and
+/// closes the tab again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResolveSelectors {
+    pub url: String,
+    pub selectors: Vec<Selector>,
+    /// How many matches to show the text of, per selector (default 3).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<u32>,
+}
+
+/// Result of a `resolve_selectors_result`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedSelectors {
+    /// Where the page ended up, after redirects.
+    pub url: String,
+    /// One per requested selector, in order.
+    pub matches: Vec<SelectorMatches>,
+}
+
+/// What one selector matched on the page.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SelectorMatches {
+    pub selector: Selector,
+    pub count: u32,
+    /// Visible text of the first matches, trimmed and cut to 200 characters.
+    #[serde(default)]
+    pub samples: Vec<String>,
+    /// Why the selector couldn't be resolved, e.g. a malformed XPath.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Message {
+    /// A `resolve_selectors` with the ID `task_id`.
+    pub fn resolve_selectors(task_id: impl Into<String>, request: &ResolveSelectors) -> Self {
+        Message::control(RESOLVE_SELECTORS_ACTION, task_id.into(), serde_json::to_value(request).ok())
+    }
+}
+
+// --- Bridge Statistics ---
+
+/// Sent by a Main App to ask the broker for its task statistics; `data` is
+/// an optional [`StatsQuery`].
+pub const STATS_ACTION: &str = "bridge_stats";
+/// The broker's answer to a `bridge_stats`; `result` is a [`BridgeStats`].
+pub const STATS_RESULT_ACTION: &str = "bridge_stats_result";
+
+/// Payload of a `bridge_stats`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsQuery {
+    /// Only count tasks carrying this tag (see [`Task::tags`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+/// Task statistics the broker collected since it started.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BridgeStats {
+    /// One entry per origin, most tasks first.
+    pub origins: Vec<OriginStats>,
+    /// The broker's open Main App connections, oldest first.
+    #[serde(default)]
+    pub connections: Vec<ConnectionStatus>,
+}
+
+/// Outcomes of the finished tasks for one origin (see [`Task::origin`]).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OriginStats {
+    /// `scheme://host[:port]`, or "(none)" for tasks without a `navigate` step.
+    pub origin: String,
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Mean time from the task reaching the extension to its result.
+    pub average_duration_ms: u64,
+    /// Failure codes (a [`StepErrorKind`] or a bridge error code) and how
+    /// often each occurred, most common first.
+    pub failure_codes: Vec<(String, u64)>,
+}
+
+impl OriginStats {
+    /// Share of finished tasks that succeeded, from 0 to 1.
+    pub fn success_rate(&self) -> f64 {
+        match self.succeeded + self.failed {
+            0 => 0.0,
+            total => self.succeeded as f64 / total as f64,
+        }
+    }
+}
+
+/// Sent by a Main App to look up recently finished tasks; `data` is an
+/// optional [`HistoryQuery`].
+pub const HISTORY_ACTION: &str = "bridge_history";
+/// The broker's answer to a `bridge_history`; `result` is a [`HistoryPage`].
+pub const HISTORY_RESULT_ACTION: &str = "bridge_history_result";
+
+/// How a finished task ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Succeeded,
+    Failed,
+}
+
+/// Payload of a `bridge_history`. Every filter that is set must match; times
+/// are milliseconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryQuery {
+    /// Only tasks carrying this tag (see [`Task::tags`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Only tasks for this origin (see [`Task::origin`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<TaskStatus>,
+    /// Only tasks that finished at or after this time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since_ms: Option<u64>,
+    /// Only tasks that finished before this time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_duration_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_duration_ms: Option<u64>,
+    /// Matching records to skip, newest first.
+    #[serde(default)]
+    pub offset: usize,
+    /// Records per page; the broker caps it (100 if unset).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    /// Whether `record` passes every filter.
+    pub fn matches(&self, record: &TaskRecord) -> bool {
+        self.tag.as_ref().is_none_or(|tag| record.tags.contains(tag))
+            && self.origin.as_ref().is_none_or(|origin| *origin == record.origin)
+            && self.status.is_none_or(|status| status == record.status)
+            && self.since_ms.is_none_or(|since| record.finished_ms >= since)
+            && self.until_ms.is_none_or(|until| record.finished_ms < until)
+            && self.min_duration_ms.is_none_or(|min| record.duration_ms >= min)
+            && self.max_duration_ms.is_none_or(|max| record.duration_ms <= max)
+    }
+}
+
+/// A finished task, as kept in the broker's history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TaskRecord {
+    pub task_id: String,
+    /// `scheme://host[:port]`, or "(none)" for tasks without a `navigate` step.
+    pub origin: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub status: TaskStatus,
+    /// Failure code of a failed task, as in [`OriginStats::failure_codes`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_code: Option<String>,
+    /// When the result came back.
+    pub finished_ms: u64,
+    /// Time from the task reaching the extension to its result.
+    pub duration_ms: u64,
+}
+
+/// One page of the tasks matching a [`HistoryQuery`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryPage {
+    /// Number of matching records, across all pages.
+    pub total: usize,
+    /// The requested page, newest first.
+    pub records: Vec<TaskRecord>,
+    /// Durations of all matching records, not just this page.
+    pub durations: DurationSummary,
+}
+
+/// Duration percentiles (nearest rank) of a set of tasks, all 0 if empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DurationSummary {
+    pub count: usize,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+}
+
+impl DurationSummary {
+    /// Summarizes `durations`, in milliseconds, in any order.
+    pub fn from_durations(mut durations: Vec<u64>) -> Self {
+        durations.sort_unstable();
+        let percentile = |p: usize| match durations.len() {
+            0 => 0,
+            n => durations[(n * p).div_ceil(100).max(1) - 1],
+        };
+        DurationSummary {
+            count: durations.len(),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: durations.last().copied().unwrap_or(0),
+        }
+    }
+}
+
+/// Sent by the broker to a Main App when a selector that kept working on an
+/// origin fails; `result` is a [`SelectorDegradation`]. It arrives just
+/// before the failed `task_result`.
+pub const SELECTOR_DEGRADED_ACTION: &str = "selector_degraded";
+
+/// A selector that used to work on a site and has started failing, which
+/// usually means the site changed and the task needs updating.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SelectorDegradation {
+    /// Origin of the task (see [`Task::origin`]).
+    pub origin: String,
+    /// Index of the failed step in the task.
+    pub step_index: usize,
+    /// Type of the failed step, e.g. `click`.
+    pub step_type: String,
+    /// The step's selector, as the task gave it.
+    pub selector: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<StepErrorKind>,
+    /// Runs in a row the selector succeeded in before this failure.
+    pub successes: u64,
+    /// When it last succeeded, in milliseconds since the Unix epoch.
+    pub last_success_ms: u64,
+}
+
+// --- End of Shared Message Structures ---
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn select_steps_choose_by_value_label_or_index() {
+        for (option, expected) in [
+            (json!({ "value": "bug" }), SelectOption::Value("bug".to_string())),
+            (json!({ "label": "Bug report" }), SelectOption::Label("Bug report".to_string())),
+            (json!({ "index": 2 }), SelectOption::Index(2)),
+        ] {
+            let mut json = json!({ "type": "select", "selector": "#topic" });
+            json.as_object_mut().unwrap().extend(option.as_object().unwrap().clone());
+            let step: Step = serde_json::from_value(json.clone()).unwrap();
+            assert!(matches!(&step, Step::Select { option, destructive: None, within: None, .. } if *option == expected));
+            assert_eq!(serde_json::to_value(&step).unwrap(), json);
+        }
+
+        let step: Step = serde_json::from_value(json!({ "type": "select", "selector": "#topic", "index": 0, "destructive": true, "within": "form" })).unwrap();
+        assert!(step.is_destructive());
+        assert_eq!(step.within(), Some("form"));
+
+        for invalid in [json!({ "type": "select", "selector": "#topic" }), json!({ "type": "select", "selector": "#topic", "value": "bug", "index": 1 })] {
+            assert!(serde_json::from_value::<Step>(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn step_results_carry_mutation_summaries() {
+        let task: Task = serde_json::from_value(json!({ "steps": [], "record_mutations": true })).unwrap();
+        assert!(task.record_mutations);
+        assert_eq!(serde_json::to_value(Task::default()).unwrap(), json!({ "steps": [] }));
+
+        let result: StepResult = serde_json::from_value(json!({
+            "type": "click",
+            "success": true,
+            "mutations": { "added": 3, "removed": 0, "attributes": 1, "text": 0, "notable": [{ "element": "button#save", "attribute": "disabled", "old_value": null, "new_value": "" }] },
+        }))
+        .unwrap();
+        let mutations = result.mutations.unwrap();
+        assert!(!mutations.is_empty());
+        assert_eq!(mutations.notable[0], AttributeChange { element: "button#save".to_string(), attribute: "disabled".to_string(), old_value: None, new_value: Some(String::new()) });
+        assert!(MutationSummary::default().is_empty());
+    }
+
+    #[test]
+    fn scroll_targets_are_names_or_objects() {
+        for (json, target) in [
+            (json!("bottom"), ScrollTarget::Bottom),
+            (json!({ "selector": "#feed li:last-child" }), ScrollTarget::Selector(Selector::from("#feed li:last-child"))),
+            (json!({ "pixels": { "x": 0, "y": 800 } }), ScrollTarget::Pixels { x: 0, y: 800 }),
+        ] {
+            assert_eq!(serde_json::from_value::<ScrollTarget>(json.clone()).unwrap(), target);
+            assert_eq!(serde_json::to_value(&target).unwrap(), json);
+        }
+        let step: Step = serde_json::from_value(json!({ "type": "scroll", "target": { "pixels": { "y": -200 } } })).unwrap();
+        assert!(matches!(step, Step::Scroll { target: ScrollTarget::Pixels { x: 0, y: -200 }, behavior: ScrollBehavior::Instant, until: None, .. }));
+
+        let task = |scroll: serde_json::Value| serde_json::from_value::<Task>(json!({ "steps": [scroll] })).unwrap().validate();
+        assert!(task(json!({ "type": "scroll", "target": "bottom", "behavior": "smooth", "until": "#end", "max_iterations": 20 })).is_ok());
+        assert!(task(json!({ "type": "scroll", "target": "top", "until": "#end" })).is_err());
+        assert!(task(json!({ "type": "scroll", "target": "bottom", "max_iterations": 3 })).is_err());
+        assert!(task(json!({ "type": "scroll", "target": "bottom", "until": " " })).is_err());
+    }
+
+    #[test]
+    fn keyboard_steps_may_be_scoped_and_destructive() {
+        let submit = json!({ "type": "keyboard", "selector": "input[name=q]", "keys": ["Enter"], "destructive": true, "within": "search" });
+        let step: Step = serde_json::from_value(submit.clone()).unwrap();
+        assert!(step.is_destructive());
+        assert_eq!(step.within(), Some("search"));
+        assert_eq!(serde_json::to_value(&step).unwrap(), submit);
+
+        let task = |steps: serde_json::Value| serde_json::from_value::<Task>(json!({ "steps": steps })).unwrap().validate();
+        assert!(task(json!([{ "type": "locate", "selector": "form", "handle_name": "search" }, submit])).is_ok());
+        assert!(task(json!([submit])).is_err());
+        let unfocused = task(json!([{ "type": "locate", "selector": "form", "handle_name": "search" }, { "type": "keyboard", "keys": ["Tab"], "within": "search" }]));
+        assert_eq!(unfocused.unwrap_err().reason, "within requires selector");
+    }
+
+    #[test]
+    fn drag_and_drop_checks_both_selectors() {
+        let drag = json!({ "type": "drag_and_drop", "source_selector": "#todo .card", "target_selector": { "text": "Done" }, "destructive": true });
+        let step: Step = serde_json::from_value(drag.clone()).unwrap();
+        assert_eq!(step.selector(), Some(&Selector::from("#todo .card")));
+        assert!(step.is_destructive());
+        assert_eq!(serde_json::to_value(&step).unwrap(), drag);
+
+        let task = |step: serde_json::Value| serde_json::from_value::<Task>(json!({ "steps": [step] })).unwrap().validate();
+        assert!(task(json!({ "type": "hover", "selector": "nav .menu" })).is_ok());
+        let invalid = task(json!({ "type": "drag_and_drop", "source_selector": ".card", "target_selector": "a >>> " })).unwrap_err();
+        assert!(invalid.reason.starts_with("target_selector:"), "{}", invalid);
+
+        let board = json!({ "type": "locate", "selector": "#board", "handle_name": "board" });
+        let scoped = json!({ "type": "drag_and_drop", "source_selector": ".card", "target_selector": ".done", "within": "board" });
+        assert_eq!(serde_json::from_value::<Step>(scoped.clone()).unwrap().within(), Some("board"));
+        let steps = |steps: serde_json::Value| serde_json::from_value::<Task>(json!({ "steps": steps })).unwrap().validate();
+        assert!(steps(json!([board, scoped])).is_ok());
+        assert!(steps(json!([scoped])).is_err());
+    }
+
+    #[test]
+    fn truncate_to_cuts_the_largest_step_data() {
+        let rows: Vec<String> = (0..100).map(|i| format!("row {}", i)).collect();
+        let result = || -> TaskResult {
+            serde_json::from_value(json!({ "steps": [
+                { "type": "extract", "success": true, "data": rows },
+                { "type": "extract", "success": true, "data": "x".repeat(500) },
+                { "type": "click", "success": false, "error": "not found" },
+            ] }))
+            .unwrap()
+        };
+        let full = result().encoded_len();
+        assert!(!result().truncate_to(full));
+
+        // An array loses trailing items and stays ahead of the smaller string
+        let mut trimmed = result();
+        assert!(trimmed.truncate_to(full - 100));
+        assert!(trimmed.encoded_len() <= full - 100 && trimmed.result_truncated);
+        let items = trimmed.steps[0].data.as_ref().and_then(|data| data.as_array()).unwrap();
+        assert!(!items.is_empty() && items.len() < rows.len());
+        assert_eq!(items[0], "row 0");
+        assert!(trimmed.steps[1].data.is_some());
+
+        // Data that isn't an array is dropped whole
+        let mut dropped = result();
+        dropped.steps[0].data = Some(json!(["row 0"]));
+        assert!(dropped.truncate_to(dropped.encoded_len() - 100));
+        assert_eq!(dropped.steps[1].data, None);
+        assert_eq!(dropped.steps[0].data, Some(json!(["row 0"])));
+
+        // Types, flags and errors stay, even past a budget too small to meet
+        let mut tiny = result();
+        assert!(tiny.truncate_to(10));
+        assert!(tiny.encoded_len() > 10 && tiny.result_truncated);
+        assert!(tiny.steps.iter().all(|step| step.data.is_none()));
+        assert_eq!(tiny.steps.iter().map(|step| step.step_type.as_str()).collect::<Vec<_>>(), ["extract", "extract", "click"]);
+        assert_eq!(tiny.steps[2].error.as_deref(), Some("not found"));
+    }
+}
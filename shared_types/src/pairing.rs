@@ -0,0 +1,573 @@
+//! First-run pairing between an extension instance and a Main App.
+//!
+//! Any extension allowed by the host manifest (or a copied manifest) can make
+//! the broker connect to the Main App. A Main App that requires pairing talks
+//! to an extension only once it has been paired with it:
+//!
+//! 1. On connect the extension sends `pair` ([`PairRequest`]) with the token
+//!    it was given earlier, if any.
+//! 2. A known token is answered with [`PairingStatus::Paired`]. Otherwise the
+//!    Main App answers [`PairingStatus::Pending`] with a short one-time code,
+//!    which the extension shows to the user.
+//! 3. The user enters that code in the Main App, which calls
+//!    [`Pairings::confirm_pairing`] and sends the extension its new token in a
+//!    [`PairingStatus::Paired`].
+//!
+//! Until then the Main App answers the extension's messages with a
+//! `bridge_error` carrying [`E_NOT_PAIRED`].
+//!
+//! A Main App that seals payloads ([`Pairings::set_sealing`]) also agrees on a
+//! [`SealKey`] with the extension while pairing; see [`crate::sealed`]. The
+//! broker relays the public keys, so they are bound to the code the user
+//! confirms:
+//!
+//! 1. The extension's `pair` carries only a commitment to its public key, the
+//!    key's SHA-256.
+//! 2. The Main App answers [`PairingStatus::KeyOffered`] with its own key.
+//! 3. The extension reveals its key in a second `pair`, which the Main App
+//!    checks against the commitment. Both ends derive the code from the two
+//!    keys, and the extension shows the code it derived.
+//!
+//! A broker that swapped the keys had to choose its own before seeing the
+//! ones they replace, so the two ends derive different codes (but for a one
+//! in a million chance) and the code the user enters pairs nothing. Seal keys are kept in a file of their own, which the
+//! broker never reads.
+//!
+//! Paired extensions are listed by [`Pairings::list`] and can be revoked by
+//! their [`PairedIdentity::id`]. A revoked token is refused with
+//! [`E_REVOKED`]: by the broker when the extension says hello with it, and by
+//! the Main App when it pairs with it. Pairings, revocations and refusals are
+//! appended to an audit log next to the pairings file.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::BridgeConfig;
+use crate::sealed::{KeyExchange, SealKey};
+
+/// Sent by the extension to pair or to show it is paired; `data` is a [`PairRequest`].
+pub const PAIR_ACTION: &str = "pair";
+/// The Main App's answer to a `pair`; `result` is a [`PairingStatus`].
+pub const PAIR_RESULT_ACTION: &str = "pair_result";
+/// Error code for messages from an extension that isn't paired yet.
+pub const E_NOT_PAIRED: &str = "E_NOT_PAIRED";
+/// Error code for an extension whose pairing was revoked.
+pub const E_REVOKED: &str = "E_REVOKED";
+
+/// How long a pairing code can be confirmed.
+const CODE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Hashed ahead of the public keys a pairing code is derived from; the
+/// extension uses the same.
+const CODE_INFO: &[u8] = b"rzn-browser-bridge pairing code v1";
+
+/// `data` of a `pair`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PairRequest {
+    /// Token from an earlier pairing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// The browser's ID for the extension, kept with a new pairing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension_id: Option<String>,
+    /// SHA-256 of the extension's P-256 public key for a seal key, in
+    /// base64, sent ahead of the key itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_commitment: Option<String>,
+    /// The extension's P-256 public key, in base64, revealed once the Main
+    /// App offered its own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+/// `result` of a `pair_result`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PairingStatus {
+    /// The extension is paired; it keeps `token` for its next connections.
+    /// `sealed` tells whether payloads are sealed.
+    Paired {
+        token: String,
+        #[serde(default)]
+        sealed: bool,
+    },
+    /// The Main App's half of a seal key, answering a `key_commitment`. The
+    /// extension reveals its own next.
+    KeyOffered { public_key: String },
+    /// The user has to enter `code`, as shown by the extension, in the Main App.
+    Pending { code: String },
+    /// The Main App talks to any extension.
+    NotRequired,
+}
+
+/// An extension paired with the Main App. Its token is not exposed.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PairedIdentity {
+    /// Short name the pairing is listed and revoked by.
+    #[serde(default)]
+    pub id: String,
+    /// The browser's ID for the extension, as reported when pairing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension_id: Option<String>,
+    pub paired_at_ms: u64,
+    /// Set once the pairing is revoked; its token is refused from then on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at_ms: Option<u64>,
+    token: String,
+}
+
+impl PairedIdentity {
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at_ms.is_some()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct PairingsFile {
+    paired: Vec<PairedIdentity>,
+}
+
+/// Seal keys in base64 by [`PairedIdentity::id`].
+#[derive(Serialize, Deserialize, Default)]
+struct SealKeysFile {
+    keys: BTreeMap<String, String>,
+}
+
+struct PendingCode {
+    /// `None` while the extension's key is yet to be revealed.
+    code: Option<String>,
+    session: u64,
+    extension_id: Option<String>,
+    /// Our half of a seal key, offered for the extension's commitment.
+    offer: Option<KeyOffer>,
+    /// The seal key agreed once the extension revealed its key.
+    key: Option<SealKey>,
+    expires_at: Instant,
+}
+
+struct KeyOffer {
+    commitment: String,
+    ours: KeyExchange,
+}
+
+impl KeyOffer {
+    /// The code and the seal key agreed with the extension's key `theirs`,
+    /// if it is the one it committed to.
+    fn reveal(self, theirs: &str) -> io::Result<(String, SealKey)> {
+        if !constant_time_eq(key_commitment(theirs)?.as_bytes(), self.commitment.as_bytes()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the extension's public key doesn't match its commitment"));
+        }
+        let code = pairing_code(theirs, &self.ours.public_key())?;
+        Ok((code, self.ours.agree(theirs)?))
+    }
+}
+
+/// A pairing confirmed by [`Pairings::confirm_pairing`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Confirmed {
+    /// Session of the extension that showed the code.
+    pub session: u64,
+    pub token: String,
+    /// Whether a seal key was agreed.
+    pub sealed: bool,
+}
+
+impl Confirmed {
+    /// The `pair_result` telling the extension it is paired.
+    pub fn status(&self) -> PairingStatus {
+        PairingStatus::Paired { token: self.token.clone(), sealed: self.sealed }
+    }
+}
+
+/// What the audit log records.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Paired,
+    Revoked,
+    /// A revoked identity tried to connect.
+    Refused,
+}
+
+/// A line of the audit log, in JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub at_ms: u64,
+    pub event: AuditEvent,
+    /// [`PairedIdentity::id`] of the pairing concerned.
+    pub id: String,
+    /// Who refused the identity, e.g. `broker`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+}
+
+/// A Main App's paired extensions and the codes waiting to be confirmed.
+#[derive(Default)]
+pub struct Pairings {
+    /// Where tokens are kept; `None` keeps them in memory only.
+    path: Option<PathBuf>,
+    paired: Vec<PairedIdentity>,
+    pending: Vec<PendingCode>,
+    /// Whether new pairings agree on a seal key.
+    sealing: bool,
+    /// Read from their file on first use, so loading pairings doesn't load keys.
+    seal_keys: Option<SealKeysFile>,
+}
+
+impl Pairings {
+    /// `pairings.json` next to the bridge config file.
+    pub fn default_path() -> Option<PathBuf> {
+        BridgeConfig::path().map(|path| path.with_file_name("pairings.json"))
+    }
+
+    /// Reads the paired extensions from `path`, where new pairings are saved
+    /// too. A missing file reads as no pairings.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let file = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => PairingsFile::default(),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+        };
+        let mut paired = file.paired;
+        // Files written before pairings had IDs
+        for identity in paired.iter_mut().filter(|identity| identity.id.is_empty()) {
+            identity.id = random_hex(4)?;
+        }
+        Ok(Pairings { path: Some(path), paired, ..Pairings::default() })
+    }
+
+    /// Makes new pairings agree on a seal key with extensions that offer one.
+    pub fn set_sealing(&mut self, sealing: bool) {
+        self.sealing = sealing;
+    }
+
+    /// Every pairing made, revoked ones included.
+    pub fn list(&self) -> &[PairedIdentity] {
+        &self.paired
+    }
+
+    /// The pairing that handed out `token`, revoked or not.
+    pub fn identity(&self, token: &str) -> Option<&PairedIdentity> {
+        self.paired.iter().find(|paired| constant_time_eq(paired.token.as_bytes(), token.as_bytes()))
+    }
+
+    /// Whether `token` was handed out by an earlier pairing that wasn't revoked.
+    pub fn is_paired(&self, token: &str) -> bool {
+        self.identity(token).is_some_and(|identity| !identity.is_revoked())
+    }
+
+    /// Answers the `pair` of the extension on `session`, which isn't paired
+    /// yet: with the code it has to show, or, if sealing and it committed to
+    /// a key, with our half of the seal key. Its second `pair`, revealing
+    /// its key, is answered with the code derived from both keys; a key that
+    /// isn't the one it committed to is an error. Any other `pair` replaces
+    /// the session's earlier code.
+    pub fn request(&mut self, session: u64, request: PairRequest) -> io::Result<PairingStatus> {
+        let offered = self.pending.iter_mut().filter(|pending| pending.session == session).find_map(|pending| {
+            let offer = pending.offer.take()?;
+            Some((pending, offer))
+        });
+        if let (Some((pending, offer)), Some(theirs)) = (offered, &request.public_key) {
+            match offer.reveal(theirs) {
+                Ok((code, key)) => {
+                    pending.code = Some(code.clone());
+                    pending.key = Some(key);
+                    return Ok(PairingStatus::Pending { code });
+                }
+                Err(e) => {
+                    self.cancel(session);
+                    return Err(e);
+                }
+            }
+        }
+        self.cancel(session);
+        let expires_at = Instant::now() + CODE_TTL;
+        let extension_id = request.extension_id;
+        if let Some(commitment) = request.key_commitment.filter(|_| self.sealing) {
+            let ours = KeyExchange::new()?;
+            let public_key = ours.public_key();
+            let offer = Some(KeyOffer { commitment, ours });
+            self.pending.push(PendingCode { code: None, session, extension_id, offer, key: None, expires_at });
+            return Ok(PairingStatus::KeyOffered { public_key });
+        }
+        let mut bytes = [0u8; 4];
+        random(&mut bytes)?;
+        let code = format_code(u32::from_le_bytes(bytes));
+        self.pending.push(PendingCode { code: Some(code.clone()), session, extension_id, offer: None, key: None, expires_at });
+        Ok(PairingStatus::Pending { code })
+    }
+
+    /// Forgets the session's code, e.g. when its connection ends.
+    pub fn cancel(&mut self, session: u64) {
+        self.pending.retain(|pending| pending.session != session);
+    }
+
+    /// Pairs the extension that shows `code` (dashes and spaces don't matter)
+    /// and saves its new token, and the seal key agreed with it, if any.
+    /// `None` if no unexpired code matches. Each code works once.
+    pub fn confirm_pairing(&mut self, code: &str) -> io::Result<Option<Confirmed>> {
+        let normalize = |code: &str| code.chars().filter(char::is_ascii_digit).collect::<String>();
+        let code = normalize(code);
+        let now = Instant::now();
+        self.pending.retain(|pending| pending.expires_at > now);
+        let Some(index) = self.pending.iter().position(|pending| pending.code.as_deref().map(normalize) == Some(code.clone())) else {
+            return Ok(None);
+        };
+        let pending = self.pending.remove(index);
+        let token = random_hex(32)?;
+        let identity = PairedIdentity {
+            id: random_hex(4)?,
+            extension_id: pending.extension_id,
+            paired_at_ms: now_ms(),
+            revoked_at_ms: None,
+            token: token.clone(),
+        };
+        let id = identity.id.clone();
+        // The key comes first, so no pairing is saved without the key it was made with
+        if let Some(key) = &pending.key {
+            self.seal_keys()?.keys.insert(id.clone(), key.to_base64());
+            self.save_seal_keys()?;
+        }
+        self.paired.push(identity);
+        if let Err(e) = self.save() {
+            self.paired.pop();
+            return Err(e);
+        }
+        self.audit(AuditEvent::Paired, &id, None);
+        Ok(Some(Confirmed { session: pending.session, token, sealed: pending.key.is_some() }))
+    }
+
+    /// The seal key agreed with the pairing that handed out `token`, if it
+    /// isn't revoked.
+    pub fn seal_key(&mut self, token: &str) -> io::Result<Option<SealKey>> {
+        let Some(id) = self.identity(token).filter(|identity| !identity.is_revoked()).map(|identity| identity.id.clone()) else {
+            return Ok(None);
+        };
+        self.seal_keys()?.keys.get(&id).map(|key| SealKey::from_base64(key)).transpose()
+    }
+
+    /// `seal-keys.json` next to the pairings file.
+    pub fn seal_keys_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|path| path.with_file_name("seal-keys.json"))
+    }
+
+    /// Revokes the pairing `id`, so its token is refused from now on.
+    /// Returns `false` if there is no such pairing or it was revoked already.
+    pub fn revoke(&mut self, id: &str) -> io::Result<bool> {
+        let Some(identity) = self.paired.iter_mut().find(|identity| identity.id == id && !identity.is_revoked()) else {
+            return Ok(false);
+        };
+        identity.revoked_at_ms = Some(now_ms());
+        self.save()?;
+        if self.seal_keys()?.keys.remove(id).is_some() {
+            self.save_seal_keys()?;
+        }
+        self.audit(AuditEvent::Revoked, id, None);
+        Ok(true)
+    }
+
+    /// Records that `by` refused the revoked `identity`.
+    pub fn record_refusal(&self, identity: &PairedIdentity, by: &str) {
+        self.audit(AuditEvent::Refused, &identity.id, Some(by));
+    }
+
+    /// `pairing-audit.log` next to the pairings file.
+    pub fn audit_path(&self) -> Option<PathBuf> {
+        self.path.as_ref().map(|path| path.with_file_name("pairing-audit.log"))
+    }
+
+    /// Logs `event` and appends it to the audit log. Failing to write the log
+    /// doesn't undo the event.
+    fn audit(&self, event: AuditEvent, id: &str, by: Option<&str>) {
+        let entry = AuditEntry { at_ms: now_ms(), event, id: id.to_string(), by: by.map(str::to_string) };
+        log::warn!("Pairing audit: {:?} {}{}", event, id, by.map(|by| format!(" by {}", by)).unwrap_or_default());
+        let Some(path) = self.audit_path() else { return };
+        let line = serde_json::to_string(&entry).unwrap_or_default() + "\n";
+        let appended = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| io::Write::write_all(&mut file, line.as_bytes()));
+        if let Err(e) = appended {
+            log::error!("Pairing audit: Could not write {}: {}", path.display(), e);
+        }
+    }
+
+    /// Writes the tokens to the file, readable by the current user only.
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else { return Ok(()) };
+        write_private(path, &PairingsFile { paired: self.paired.clone() })
+    }
+
+    /// The seal keys, read from their file the first time.
+    fn seal_keys(&mut self) -> io::Result<&mut SealKeysFile> {
+        if self.seal_keys.is_none() {
+            let file = match self.seal_keys_path().map(|path| (fs::read_to_string(&path), path)) {
+                Some((Ok(contents), path)) => serde_json::from_str(&contents)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?,
+                Some((Err(e), path)) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(io::Error::new(e.kind(), format!("{}: {}", path.display(), e)));
+                }
+                _ => SealKeysFile::default(),
+            };
+            self.seal_keys = Some(file);
+        }
+        Ok(self.seal_keys.get_or_insert_with(SealKeysFile::default))
+    }
+
+    /// Writes the seal keys to their file, readable by the current user only.
+    fn save_seal_keys(&self) -> io::Result<()> {
+        let (Some(path), Some(keys)) = (self.seal_keys_path(), &self.seal_keys) else { return Ok(()) };
+        write_private(&path, keys)
+    }
+}
+
+/// Writes `value` as JSON to `path`, readable by the current user only.
+fn write_private(path: &Path, value: &impl Serialize) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let contents = serde_json::to_string_pretty(value).map_err(io::Error::other)?;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, contents.as_bytes())
+}
+
+/// SHA-256 of the base64 `public_key`, in base64.
+fn key_commitment(public_key: &str) -> io::Result<String> {
+    let bytes = BASE64.decode(public_key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("public key: {}", e)))?;
+    Ok(BASE64.encode(Sha256::digest(bytes)))
+}
+
+/// The code derived from the extension's and the Main App's public keys.
+fn pairing_code(extension_key: &str, main_app_key: &str) -> io::Result<String> {
+    let mut hash = Sha256::new_with_prefix(CODE_INFO);
+    for key in [extension_key, main_app_key] {
+        hash.update(BASE64.decode(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("public key: {}", e)))?);
+    }
+    let digest = hash.finalize();
+    Ok(format_code(u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])))
+}
+
+/// Six digits of `n`, e.g. `042-917`.
+fn format_code(n: u32) -> String {
+    let digits = n % 1_000_000;
+    format!("{:03}-{:03}", digits / 1000, digits % 1000)
+}
+
+fn random(bytes: &mut [u8]) -> io::Result<()> {
+    getrandom::getrandom(bytes).map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// `len` random bytes in hex.
+fn random_hex(len: usize) -> io::Result<String> {
+    let mut bytes = vec![0u8; len];
+    random(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Compares without returning early, so timing doesn't reveal how much of a
+/// guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pairs_once_per_code_and_remembers_the_token() {
+        let dir = std::env::temp_dir().join(format!("rzn-pairings-test-{}", std::process::id()));
+        let path = dir.join("pairings.json");
+        let mut pairings = Pairings::load(path.clone()).unwrap();
+        let PairingStatus::Pending { code } = pairings.request(7, PairRequest::default()).unwrap() else { panic!("no code") };
+        assert_eq!((code.len(), &code[3..4]), (7, "-"));
+
+        let wrong = if code == "000-000" { "000-001" } else { "000-000" };
+        assert_eq!(pairings.confirm_pairing(wrong).unwrap(), None);
+        let Confirmed { session, token, sealed } = pairings.confirm_pairing(&code.replace('-', " ")).unwrap().unwrap();
+        assert_eq!((session, sealed), (7, false));
+        assert_eq!(pairings.confirm_pairing(&code).unwrap(), None);
+
+        let reloaded = Pairings::load(path.clone()).unwrap();
+        assert!(reloaded.is_paired(&token));
+        assert!(!reloaded.is_paired(&token[1..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revoked_tokens_are_no_longer_paired() {
+        let dir = std::env::temp_dir().join(format!("rzn-revoke-test-{}", std::process::id()));
+        let path = dir.join("pairings.json");
+        let mut pairings = Pairings::load(path.clone()).unwrap();
+        let request = PairRequest { extension_id: Some("abcdef".to_string()), ..PairRequest::default() };
+        let PairingStatus::Pending { code } = pairings.request(3, request).unwrap() else { panic!("no code") };
+        let token = pairings.confirm_pairing(&code).unwrap().unwrap().token;
+        let id = pairings.list()[0].id.clone();
+        assert_eq!(pairings.list()[0].extension_id.as_deref(), Some("abcdef"));
+
+        assert!(pairings.revoke(&id).unwrap());
+        assert!(!pairings.revoke(&id).unwrap());
+        let reloaded = Pairings::load(path.clone()).unwrap();
+        assert!(!reloaded.is_paired(&token));
+        let identity = reloaded.identity(&token).unwrap();
+        assert!(identity.is_revoked());
+        reloaded.record_refusal(identity, "broker");
+
+        let log = fs::read_to_string(reloaded.audit_path().unwrap()).unwrap();
+        let events: Vec<AuditEntry> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.iter().map(|e| e.event).collect::<Vec<_>>(), [AuditEvent::Paired, AuditEvent::Revoked, AuditEvent::Refused]);
+        assert_eq!(events[2].by.as_deref(), Some("broker"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sealing_pairings_agree_on_a_key() {
+        let dir = std::env::temp_dir().join(format!("rzn-sealing-test-{}", std::process::id()));
+        let path = dir.join("pairings.json");
+        let mut pairings = Pairings::load(path.clone()).unwrap();
+        pairings.set_sealing(true);
+        let extension = KeyExchange::new().unwrap();
+        let public_key = extension.public_key();
+        let committed = PairRequest { key_commitment: Some(key_commitment(&public_key).unwrap()), ..PairRequest::default() };
+        let PairingStatus::KeyOffered { public_key: ours } = pairings.request(5, committed.clone()).unwrap() else { panic!("no key offered") };
+        let revealed = PairRequest { public_key: Some(public_key.clone()), ..PairRequest::default() };
+        let code = pairing_code(&public_key, &ours).unwrap();
+        assert_eq!(pairings.request(5, revealed).unwrap(), PairingStatus::Pending { code: code.clone() });
+        let confirmed = pairings.confirm_pairing(&code).unwrap().unwrap();
+        assert!(matches!(confirmed.status(), PairingStatus::Paired { sealed: true, .. }));
+        let key = extension.agree(&ours).unwrap();
+
+        // The keys aren't in the pairings file, and go with the pairing
+        assert!(!fs::read_to_string(&path).unwrap().contains(&key.to_base64()));
+        let mut reloaded = Pairings::load(path.clone()).unwrap();
+        assert_eq!(reloaded.seal_key(&confirmed.token).unwrap(), Some(key));
+        let id = reloaded.list()[0].id.clone();
+        reloaded.revoke(&id).unwrap();
+        assert_eq!(Pairings::load(path.clone()).unwrap().seal_key(&confirmed.token).unwrap(), None);
+
+        // A key other than the one committed to pairs nothing
+        reloaded.set_sealing(true);
+        let PairingStatus::KeyOffered { public_key: ours } = reloaded.request(6, committed).unwrap() else { panic!("no key offered") };
+        let swapped = KeyExchange::new().unwrap().public_key();
+        assert!(reloaded.request(6, PairRequest { public_key: Some(swapped.clone()), ..PairRequest::default() }).is_err());
+        assert!(reloaded.confirm_pairing(&pairing_code(&swapped, &ours).unwrap()).unwrap().is_none());
+        assert_eq!(Pairings::load(path).unwrap().list().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Per-user, per-profile namespacing of the IPC endpoint.
+//!
+//! Abstract sockets, Windows named pipes and the `/tmp` fallback are shared by
+//! every user on a machine, so the endpoint name carries the OS user and a
+//! profile name:
+//!
+//! ```text
+//! <socket>.<user>.<profile>.sock
+//! ```
+//!
+//! The socket base name (default `com.yourcompany.projectagentis.broker`) and
+//! the profile (default `default`) come from the [bridge settings](crate::config),
+//! which the broker and the Main App must agree on. Running several profiles
+//! side by side lets one user keep e.g. a work and a personal browser bridged
+//! to different apps.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use interprocess::local_socket::{GenericNamespaced, NameType};
+
+use crate::config::BridgeConfig;
+use crate::endpoint::{EndpointSpec, DEFAULT_SOCKET_NAME};
+
+/// Environment variable selecting the profile.
+pub const PROFILE_ENV_VAR: &str = "RZN_PROFILE";
+
+/// Profile used when no setting names one.
+pub const DEFAULT_PROFILE: &str = "default";
+
+// Keeps socket names well within the socket path limit
+const MAX_PROFILE_LEN: usize = 32;
+
+/// The OS user and profile an endpoint belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    socket: String,
+    user: String,
+    name: String,
+}
+
+impl Profile {
+    /// Profile selected by `--profile`, `RZN_PROFILE` or the config file, for
+    /// the current user.
+    pub fn current() -> Result<Self, ProfileError> {
+        let config = BridgeConfig::load_or_default();
+        Profile::with_socket(&config.socket(), &config.profile())
+    }
+
+    /// Profile `name` for the current user, on the configured socket base name.
+    pub fn named(name: &str) -> Result<Self, ProfileError> {
+        Profile::with_socket(&BridgeConfig::load_or_default().socket(), name)
+    }
+
+    /// Profile `name` for the current user, on the socket base name `socket`.
+    pub fn with_socket(socket: &str, name: &str) -> Result<Self, ProfileError> {
+        validate_profile_name(name)?;
+        Ok(Profile { socket: socket.to_string(), user: current_user(), name: name.to_string() })
+    }
+
+    /// Profile name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// OS user (the uid on Unix) the profile belongs to.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Endpoint name, unique per user and profile.
+    pub fn socket_name(&self) -> String {
+        format!("{}.{}.sock", self.socket_name_prefix(), self.name)
+    }
+
+    /// Namespaced endpoint if the platform supports it, else the filesystem endpoint.
+    pub fn endpoint(&self) -> EndpointSpec {
+        EndpointSpec::resolve(&self.socket_name())
+    }
+
+    /// Names of the profiles of this user with a listening Main App.
+    pub fn list_running(&self) -> io::Result<Vec<String>> {
+        let prefix = format!("{}.", self.socket_name_prefix());
+        let mut profiles: Vec<String> = endpoint_names()?
+            .iter()
+            .filter_map(|name| profile_from_socket_name(&prefix, name))
+            .map(str::to_string)
+            .collect();
+        profiles.sort();
+        profiles.dedup();
+        Ok(profiles)
+    }
+
+    fn socket_name_prefix(&self) -> String {
+        format!("{}.{}", self.socket, self.user)
+    }
+}
+
+/// A profile name that can't be used in an endpoint name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileError {
+    pub name: String,
+}
+
+impl fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid profile name {:?}: use 1-{} ASCII letters, digits, '-' or '_'",
+            self.name, MAX_PROFILE_LEN
+        )
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+impl From<ProfileError> for io::Error {
+    fn from(e: ProfileError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+fn validate_profile_name(name: &str) -> Result<(), ProfileError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_PROFILE_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ProfileError { name: name.to_string() })
+    }
+}
+
+#[cfg(unix)]
+fn current_user() -> String {
+    // SAFETY: getuid has no preconditions and cannot fail
+    unsafe { libc::getuid() }.to_string()
+}
+
+#[cfg(not(unix))]
+fn current_user() -> String {
+    let user = std::env::var("USERNAME").unwrap_or_default();
+    let user: String = user.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_').collect();
+    if user.is_empty() {
+        "user".to_string()
+    } else {
+        user
+    }
+}
+
+/// Extracts the profile from `name` if it is one of `prefix`'s endpoints.
+fn profile_from_socket_name<'a>(prefix: &str, name: &'a str) -> Option<&'a str> {
+    let profile = name.strip_prefix(prefix)?.strip_suffix(".sock")?;
+    validate_profile_name(profile).ok().map(|_| profile)
+}
+
+/// Names of the endpoints currently bound on this machine, as far as they
+/// can be listed: abstract sockets on Linux, pipes on Windows, and the
+/// socket files in the runtime directory.
+fn endpoint_names() -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    if cfg!(target_os = "linux") && GenericNamespaced::is_supported() {
+        // Abstract socket paths are listed with a leading '@'
+        let table = std::fs::read_to_string("/proc/net/unix")?;
+        names.extend(
+            table
+                .lines()
+                .filter_map(|line| line.split_whitespace().nth(7))
+                .filter_map(|path| path.strip_prefix('@'))
+                .map(str::to_string),
+        );
+    }
+    let dir = match EndpointSpec::filesystem(DEFAULT_SOCKET_NAME) {
+        EndpointSpec::Path(path) => path.parent().map(Path::to_path_buf),
+        EndpointSpec::Namespaced(_) => None,
+    };
+    if let Some(dir) = dir {
+        for entry in std::fs::read_dir(dir)? {
+            names.extend(entry?.file_name().to_str().map(str::to_string));
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_names_are_namespaced_by_user_and_profile() {
+        let profile = Profile {
+            socket: "com.yourcompany.projectagentis.broker".to_string(),
+            user: "1000".to_string(),
+            name: "work".to_string(),
+        };
+        assert_eq!(profile.socket_name(), "com.yourcompany.projectagentis.broker.1000.work.sock");
+        let prefix = "com.yourcompany.projectagentis.broker.1000.";
+        assert_eq!(profile_from_socket_name(prefix, &profile.socket_name()), Some("work"));
+        // Other users' endpoints and unrelated sockets are not profiles
+        assert_eq!(profile_from_socket_name(prefix, "com.yourcompany.projectagentis.broker.1001.work.sock"), None);
+        assert_eq!(profile_from_socket_name(prefix, "com.yourcompany.projectagentis.broker.sock"), None);
+    }
+
+    #[test]
+    fn rejects_unsafe_profile_names() {
+        assert!(Profile::with_socket("s", "work_2-b").is_ok());
+        for name in ["", "a.b", "../x", "a b", &"x".repeat(MAX_PROFILE_LEN + 1)] {
+            assert_eq!(Profile::with_socket("s", name), Err(ProfileError { name: name.to_string() }));
+        }
+    }
+}
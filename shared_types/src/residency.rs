@@ -0,0 +1,184 @@
+//! Data residency filter for task results.
+//!
+//! Some deployments must never store or export certain data, such as card
+//! numbers or national IDs, whatever a task happens to extract. A Main App
+//! runs each result through a [`ResidencyFilter`] before it logs, keeps or
+//! exports it: every string matching a configured pattern is replaced with a
+//! `[REDACTED:<name>]` marker.
+//!
+//! Patterns come from built-in sets ([`BUILTIN_SETS`]) and from regexes of
+//! the policy's own, e.g. for the national IDs of the countries it serves:
+//!
+//! ```json
+//! {"sets": ["credit_card", "us_ssn"], "patterns": [{"name": "nl_bsn", "regex": "\\b\\d{9}\\b"}]}
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Names of the built-in pattern sets.
+pub const BUILTIN_SETS: &[&str] = &["credit_card", "us_ssn", "uk_nino"];
+
+/// What a filter redacts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ResidencyPolicy {
+    /// Built-in sets, from [`BUILTIN_SETS`].
+    pub sets: Vec<String>,
+    pub patterns: Vec<SensitivePattern>,
+}
+
+/// A pattern of the policy's own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SensitivePattern {
+    /// Shown in the redaction marker.
+    pub name: String,
+    pub regex: String,
+}
+
+/// A policy that can't be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyError {
+    UnknownSet(String),
+    Regex { name: String, error: String },
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::UnknownSet(set) => write!(f, "unknown pattern set {:?} (known: {})", set, BUILTIN_SETS.join(", ")),
+            PolicyError::Regex { name, error } => write!(f, "pattern {}: {}", name, error),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+/// Further check of a match, e.g. a checksum.
+type Check = fn(&str) -> bool;
+
+struct Pattern {
+    name: String,
+    regex: Regex,
+    /// `None` accepts every match.
+    check: Option<Check>,
+}
+
+/// Replaces sensitive data in task results with redaction markers.
+pub struct ResidencyFilter {
+    patterns: Vec<Pattern>,
+}
+
+impl ResidencyFilter {
+    pub fn new(policy: &ResidencyPolicy) -> Result<Self, PolicyError> {
+        let mut patterns = Vec::new();
+        for set in &policy.sets {
+            let (regex, check): (&str, Option<Check>) = match set.as_str() {
+                // 13 to 19 digits, optionally grouped, that pass the Luhn check
+                "credit_card" => (r"\b\d(?:[ -]?\d){12,18}\b", Some(luhn_valid)),
+                "us_ssn" => (r"\b\d{3}-\d{2}-\d{4}\b", None),
+                "uk_nino" => (r"\b[A-CEGHJ-PR-TW-Z]{2} ?\d{2} ?\d{2} ?\d{2} ?[A-D]\b", None),
+                _ => return Err(PolicyError::UnknownSet(set.clone())),
+            };
+            patterns.push(Pattern { name: set.clone(), regex: Regex::new(regex).expect("built-in pattern"), check });
+        }
+        for pattern in &policy.patterns {
+            let regex = Regex::new(&pattern.regex).map_err(|e| PolicyError::Regex { name: pattern.name.clone(), error: e.to_string() })?;
+            patterns.push(Pattern { name: pattern.name.clone(), regex, check: None });
+        }
+        Ok(ResidencyFilter { patterns })
+    }
+
+    /// The filter for the policy in `RZN_RESIDENCY_POLICY` (JSON), `None` if
+    /// it isn't set. An invalid policy redacts every built-in set rather than
+    /// nothing.
+    pub fn from_env() -> Option<Self> {
+        let json = std::env::var("RZN_RESIDENCY_POLICY").ok()?;
+        let filter = serde_json::from_str::<ResidencyPolicy>(&json)
+            .map_err(|e| e.to_string())
+            .and_then(|policy| ResidencyFilter::new(&policy).map_err(|e| e.to_string()));
+        Some(filter.unwrap_or_else(|e| {
+            log::error!("Invalid RZN_RESIDENCY_POLICY, redacting all built-in sets instead: {}", e);
+            let sets = BUILTIN_SETS.iter().map(|set| set.to_string()).collect();
+            ResidencyFilter::new(&ResidencyPolicy { sets, patterns: Vec::new() }).expect("built-in sets")
+        }))
+    }
+
+    /// Redacts every string in `value`, object keys aside. Returns how many
+    /// matches of each pattern were replaced.
+    pub fn redact(&self, value: &mut Value) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        self.redact_into(value, &mut counts);
+        counts
+    }
+
+    /// Redacts `text`, adding to `counts`.
+    pub fn redact_str(&self, text: &str, counts: &mut BTreeMap<String, usize>) -> String {
+        let mut text = text.to_string();
+        for pattern in &self.patterns {
+            let mut count = 0;
+            let replaced = pattern.regex.replace_all(&text, |caps: &Captures| {
+                let found = &caps[0];
+                if pattern.check.is_some_and(|check| !check(found)) {
+                    return found.to_string();
+                }
+                count += 1;
+                format!("[REDACTED:{}]", pattern.name)
+            });
+            if count > 0 {
+                text = replaced.into_owned();
+                *counts.entry(pattern.name.clone()).or_default() += count;
+            }
+        }
+        text
+    }
+
+    fn redact_into(&self, value: &mut Value, counts: &mut BTreeMap<String, usize>) {
+        match value {
+            Value::String(text) => *text = self.redact_str(text, counts),
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_into(item, counts)),
+            Value::Object(fields) => fields.values_mut().for_each(|field| self.redact_into(field, counts)),
+            _ => {}
+        }
+    }
+}
+
+/// Whether the digits in `text` pass the Luhn checksum of card numbers.
+fn luhn_valid(text: &str) -> bool {
+    let digits: Vec<u32> = text.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| if i % 2 == 1 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_configured_patterns_everywhere() {
+        let policy: ResidencyPolicy = serde_json::from_str(r#"{"sets": ["credit_card", "us_ssn"], "patterns": [{"name": "nl_bsn", "regex": "\\bBSN \\d{9}\\b"}]}"#).unwrap();
+        let filter = ResidencyFilter::new(&policy).unwrap();
+        let mut result = serde_json::json!({
+            "steps": [
+                { "data": "Card 4111 1111 1111 1111, order 1234567890123" },
+                { "data": ["SSN 078-05-1120", "BSN 123456782"] },
+            ],
+        });
+        let counts = filter.redact(&mut result);
+        assert_eq!(result["steps"][0]["data"], "Card [REDACTED:credit_card], order 1234567890123");
+        assert_eq!(result["steps"][1]["data"], serde_json::json!(["SSN [REDACTED:us_ssn]", "[REDACTED:nl_bsn]"]));
+        assert_eq!(counts.into_iter().collect::<Vec<_>>(), [("credit_card".to_string(), 1), ("nl_bsn".to_string(), 1), ("us_ssn".to_string(), 1)]);
+
+        let unknown = ResidencyPolicy { sets: vec!["passport".to_string()], patterns: Vec::new() };
+        assert!(matches!(ResidencyFilter::new(&unknown), Err(PolicyError::UnknownSet(_))));
+    }
+}
@@ -0,0 +1,126 @@
+//! Tokio runtime settings of the binaries and of embedders that let the
+//! bridge own its runtime.
+//!
+//! An app that already runs tokio embeds the bridge in its own runtime and
+//! ignores these. Otherwise [`RuntimeOptions::build`] makes the runtime the
+//! config asks for, see [`BridgeConfig::runtime`](crate::BridgeConfig::runtime).
+//! Both are only built with the `runtime` feature; the settings themselves
+//! are read from the config file either way.
+
+use std::fmt;
+#[cfg(feature = "runtime")]
+use std::future::Future;
+#[cfg(feature = "runtime")]
+use std::io;
+
+#[cfg(feature = "runtime")]
+use tokio::runtime::{Builder, Runtime};
+
+/// Environment variable choosing the runtime flavor, `multi_thread` or
+/// `current_thread`.
+pub const RUNTIME_ENV_VAR: &str = "RZN_RUNTIME";
+
+/// Environment variable setting the worker threads of a multi-thread runtime.
+pub const WORKER_THREADS_ENV_VAR: &str = "RZN_WORKER_THREADS";
+
+/// Environment variable capping the threads for blocking work, such as stdin.
+pub const MAX_BLOCKING_THREADS_ENV_VAR: &str = "RZN_MAX_BLOCKING_THREADS";
+
+/// Which scheduler runs the tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RuntimeFlavor {
+    /// Everything on the thread that blocks on the runtime. Fewest threads,
+    /// enough for a single relay.
+    CurrentThread,
+    /// A pool of worker threads, one per core unless set.
+    #[default]
+    MultiThread,
+}
+
+impl RuntimeFlavor {
+    /// Parses `current_thread` or `multi_thread` (also with a dash).
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "current_thread" => Some(RuntimeFlavor::CurrentThread),
+            "multi_thread" => Some(RuntimeFlavor::MultiThread),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeFlavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RuntimeFlavor::CurrentThread => "current_thread",
+            RuntimeFlavor::MultiThread => "multi_thread",
+        })
+    }
+}
+
+/// How to build a runtime. Unset counts keep tokio's defaults.
+#[cfg(feature = "runtime")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuntimeOptions {
+    pub flavor: RuntimeFlavor,
+    /// Worker threads of a multi-thread runtime; a current-thread one has none.
+    pub worker_threads: Option<usize>,
+    /// Most threads kept for blocking work (tokio's default is 512).
+    pub max_blocking_threads: Option<usize>,
+}
+
+#[cfg(feature = "runtime")]
+impl RuntimeOptions {
+    /// A runtime with these options and every driver enabled.
+    pub fn build(&self) -> io::Result<Runtime> {
+        if self.worker_threads == Some(0) || self.max_blocking_threads == Some(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "runtime thread counts must be at least 1"));
+        }
+        let mut builder = match self.flavor {
+            RuntimeFlavor::CurrentThread => Builder::new_current_thread(),
+            RuntimeFlavor::MultiThread => Builder::new_multi_thread(),
+        };
+        if let (RuntimeFlavor::MultiThread, Some(threads)) = (self.flavor, self.worker_threads) {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(threads);
+        }
+        builder.enable_all().build()
+    }
+
+    /// Builds a runtime and runs `future` to completion on it.
+    pub fn block_on<F: Future>(&self, future: F) -> io::Result<F::Output> {
+        Ok(self.build()?.block_on(future))
+    }
+}
+
+/// Parses a thread count, which must be at least 1.
+#[cfg(feature = "runtime")]
+pub(crate) fn parse_threads(value: &str) -> Option<usize> {
+    value.trim().parse().ok().filter(|&threads| threads > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_runtime_settings() {
+        assert_eq!(RuntimeFlavor::parse("current-thread"), Some(RuntimeFlavor::CurrentThread));
+        assert_eq!(RuntimeFlavor::parse("Multi_Thread"), Some(RuntimeFlavor::MultiThread));
+        assert_eq!(RuntimeFlavor::parse("work_stealing"), None);
+    }
+
+    #[cfg(feature = "runtime")]
+    #[test]
+    fn builds_the_configured_runtime() {
+        assert_eq!((parse_threads("4"), parse_threads("0"), parse_threads("many")), (Some(4), None, None));
+
+        let options = RuntimeOptions { flavor: RuntimeFlavor::CurrentThread, worker_threads: None, max_blocking_threads: Some(2) };
+        let sum = options.block_on(async { tokio::task::spawn_blocking(|| 1 + 1).await.unwrap() }).unwrap();
+        assert_eq!(sum, 2);
+        let options = RuntimeOptions { worker_threads: Some(2), ..RuntimeOptions::default() };
+        assert_eq!(options.build().unwrap().metrics().num_workers(), 2);
+        assert!(RuntimeOptions { max_blocking_threads: Some(0), ..options }.build().is_err());
+    }
+}
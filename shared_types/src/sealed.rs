@@ -0,0 +1,358 @@
+//! Sealed payloads: messages encrypted by the extension and opened only by
+//! the Main App, and the other way round.
+//!
+//! The broker relays whatever it is given, so for the most sensitive
+//! deployments the payloads it relays can be kept from it altogether. The key
+//! is agreed while pairing, from P-256 public keys that the extension and a
+//! Main App that seals exchange through the broker and bind to the code the
+//! user confirms (see [`crate::pairing`]). Both derive the same AES-256-GCM
+//! key ([`SealKey`]) from the ECDH secret with HKDF-SHA256; the broker only
+//! ever sees the public keys.
+//!
+//! A sealed message keeps its `task_id` in the clear, for routing and logs,
+//! next to a sequence number that only grows for each end:
+//!
+//! ```json
+//! {"action": "sealed", "task_id": "t1", "data": {"seq": 1760623823000000, "nonce": "<base64>", "ciphertext": "<base64>"}}
+//! ```
+//!
+//! Both are bound to the ciphertext as associated data, with the [`Side`]
+//! that sealed it, so the broker can't move a sealed message to another task
+//! or reflect it back to its sender. A message whose sequence number was
+//! already opened, or lies more than [`REPLAY_WINDOW`] from the time it is
+//! opened, is rejected as a replay, so the broker can't deliver a captured
+//! message twice either. Both ends run on the same machine, so their clocks
+//! agree well within the window.
+//!
+//! The broker's own features that read payloads (statistics, notifications,
+//! result budgets) don't see into sealed messages.
+//!
+//! Once a key is agreed, both ends drop what comes unsealed, except for
+//! [`UNSEALED_ACTIONS`]: the broker has no key to seal its own messages
+//! with, and the pairing is what agrees on one.
+//!
+//! Those are not authenticated, which leaves a gap in integrity: the broker
+//! can forge them. A forged `bridge_error` or `message_too_large` fails a
+//! sealed task, an `ack` confirms a delivery that wasn't made, a
+//! `dead_letter` reports a message expired and a `selector_degraded` warns
+//! of a selector that works. None of them carries a result, so the broker
+//! can fail or confirm what it relays but not answer it, which it could as
+//! well by dropping the message. Only a sealed `task_result` shows what a
+//! task did.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hkdf::Hkdf;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::action::Action;
+use crate::peek::peek_envelope;
+
+pub const SEALED_ACTION: &str = "sealed";
+
+/// HKDF `info` of the derived key; the extension uses the same.
+const KEY_INFO: &[u8] = b"rzn-browser-bridge sealed v1";
+const NONCE_LEN: usize = 12;
+
+/// How far from the time it is opened a sealed message's `seq` may lie;
+/// the sequence numbers within it are remembered to reject replays.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(120);
+
+/// `data` of a `sealed` message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SealedPayload {
+    /// Microseconds since the Unix epoch when sealed, or one past the
+    /// sender's last `seq` if that is later.
+    pub seq: u64,
+    /// 12 random bytes, in base64.
+    pub nonce: String,
+    /// The message's JSON text encrypted with AES-256-GCM, tag appended, in base64.
+    pub ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SealedMessage {
+    action: Action,
+    task_id: String,
+    data: SealedPayload,
+}
+
+/// What may still come unsealed once a key is agreed: the broker's own
+/// control messages, heartbeats, what the Main App asks the broker for and
+/// the pairing.
+pub const UNSEALED_ACTIONS: &[Action] = &[
+    Action::Hello,
+    Action::HelloAck,
+    Action::Ping,
+    Action::Pong,
+    Action::BridgeError,
+    Action::Ack,
+    Action::BrokerState,
+    Action::BridgeState,
+    Action::Shutdown,
+    Action::MessageTooLarge,
+    Action::DeadLetter,
+    Action::Stats,
+    Action::StatsResult,
+    Action::History,
+    Action::HistoryResult,
+    Action::SelectorDegraded,
+    Action::SelftestResult,
+    Action::Pair,
+    Action::PairResult,
+];
+
+/// Whether `message` is sealed, judged by its `action`.
+pub fn is_sealed(message: &[u8]) -> bool {
+    peek_envelope(message).action.as_deref() == Some(SEALED_ACTION)
+}
+
+/// Whether `message` may come unsealed although a key was agreed, see
+/// [`UNSEALED_ACTIONS`].
+pub fn may_be_unsealed(message: &[u8]) -> bool {
+    peek_envelope(message).action.is_some_and(|action| UNSEALED_ACTIONS.contains(&Action::parse(&action)))
+}
+
+/// The end that sealed a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Extension,
+    MainApp,
+}
+
+impl Side {
+    fn label(self) -> &'static str {
+        match self {
+            Side::Extension => "extension",
+            Side::MainApp => "main_app",
+        }
+    }
+
+    fn other(self) -> Side {
+        match self {
+            Side::Extension => Side::MainApp,
+            Side::MainApp => Side::Extension,
+        }
+    }
+}
+
+/// The key a Main App and an extension share. Not shown by `Debug`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SealKey([u8; 32]);
+
+impl fmt::Debug for SealKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SealKey(..)")
+    }
+}
+
+impl SealKey {
+    pub fn from_base64(key: &str) -> io::Result<Self> {
+        let bytes = BASE64.decode(key).map_err(|e| invalid(format!("seal key: {}", e)))?;
+        let key = bytes.try_into().map_err(|_| invalid("seal key: not 32 bytes"))?;
+        Ok(SealKey(key))
+    }
+
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(self.0)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+/// One end's use of a [`SealKey`]: seals what it sends as its [`Side`] and
+/// opens what the other end sent, once each.
+#[derive(Debug)]
+pub struct SealedChannel {
+    key: SealKey,
+    side: Side,
+    last_seq: u64,
+    /// Sequence numbers opened within the [`REPLAY_WINDOW`].
+    seen: BTreeSet<u64>,
+    replays_rejected: u64,
+}
+
+impl SealedChannel {
+    pub fn new(key: SealKey, side: Side) -> Self {
+        SealedChannel { key, side, last_seq: 0, seen: BTreeSet::new(), replays_rejected: 0 }
+    }
+
+    /// How many messages [`open`](Self::open) rejected as replays: opened
+    /// before, or sealed outside the [`REPLAY_WINDOW`].
+    pub fn replays_rejected(&self) -> u64 {
+        self.replays_rejected
+    }
+
+    /// Encrypts the serialized `message` into a `sealed` message under the
+    /// same `task_id` and the next sequence number.
+    pub fn seal(&mut self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let task_id = peek_envelope(message).task_id.map_or_else(|| "N/A".to_string(), |id| id.into_owned());
+        self.last_seq = now_micros().max(self.last_seq + 1);
+        let seq = self.last_seq;
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+        let aad = associated_data(self.side, seq, &task_id);
+        let ciphertext = self
+            .key
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: message, aad: aad.as_bytes() })
+            .map_err(|_| io::Error::other("sealing failed"))?;
+        let sealed = SealedMessage {
+            action: Action::Sealed,
+            task_id,
+            data: SealedPayload { seq, nonce: BASE64.encode(nonce), ciphertext: BASE64.encode(ciphertext) },
+        };
+        serde_json::to_vec(&sealed).map_err(io::Error::other)
+    }
+
+    /// Decrypts a `sealed` message from the other end into the message it
+    /// wraps. Fails if it was sealed with another key or by this end,
+    /// tampered with on the way, or is a replay.
+    pub fn open(&mut self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let sealed: SealedMessage = serde_json::from_slice(sealed).map_err(|e| invalid(format!("sealed message: {}", e)))?;
+        let nonce = BASE64.decode(&sealed.data.nonce).map_err(|e| invalid(format!("sealed nonce: {}", e)))?;
+        if nonce.len() != NONCE_LEN {
+            return Err(invalid("sealed nonce: not 12 bytes"));
+        }
+        let ciphertext = BASE64.decode(&sealed.data.ciphertext).map_err(|e| invalid(format!("sealed ciphertext: {}", e)))?;
+        let aad = associated_data(self.side.other(), sealed.data.seq, &sealed.task_id);
+        let opened = self
+            .key
+            .cipher()
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: aad.as_bytes() })
+            .map_err(|_| invalid(format!("sealed message for task {} could not be opened", sealed.task_id)))?;
+        // Judged once authentic, so only the other end's own messages count as replays
+        let (now, window) = (now_micros(), REPLAY_WINDOW.as_micros() as u64);
+        let seq = sealed.data.seq;
+        if seq.abs_diff(now) > window || !self.seen.insert(seq) {
+            self.replays_rejected += 1;
+            return Err(invalid(format!("sealed message for task {} is a replay (seq {})", sealed.task_id, seq)));
+        }
+        // Older ones are rejected for their age alone
+        self.seen = self.seen.split_off(&now.saturating_sub(window));
+        Ok(opened)
+    }
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_micros() as u64)
+}
+
+/// What a sealed message binds besides its text; the extension builds the same.
+fn associated_data(sealed_by: Side, seq: u64, task_id: &str) -> String {
+    format!("{}:{}:{}", sealed_by.label(), seq, task_id)
+}
+
+/// One side of the ECDH key agreement made while pairing.
+pub struct KeyExchange(SecretKey);
+
+impl KeyExchange {
+    pub fn new() -> io::Result<Self> {
+        loop {
+            let mut bytes = [0u8; 32];
+            getrandom::getrandom(&mut bytes).map_err(|e| io::Error::other(e.to_string()))?;
+            // Rejects the few values that aren't a valid scalar
+            if let Ok(secret) = SecretKey::from_slice(&bytes) {
+                return Ok(KeyExchange(secret));
+            }
+        }
+    }
+
+    /// Our public key for the other side: an uncompressed SEC1 point in
+    /// base64, as WebCrypto exports a `raw` P-256 key.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.0.public_key().to_encoded_point(false).as_bytes())
+    }
+
+    /// Derives the shared key from the other side's public key.
+    pub fn agree(self, their_public_key: &str) -> io::Result<SealKey> {
+        let bytes = BASE64.decode(their_public_key).map_err(|e| invalid(format!("public key: {}", e)))?;
+        let theirs = PublicKey::from_sec1_bytes(&bytes).map_err(|_| invalid("public key: not a P-256 point"))?;
+        let shared = p256::ecdh::diffie_hellman(self.0.to_nonzero_scalar(), theirs.as_affine());
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.raw_secret_bytes())
+            .expand(KEY_INFO, &mut key)
+            .map_err(|_| io::Error::other("key derivation failed"))?;
+        Ok(SealKey(key))
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_messages_open_with_the_agreed_key_only() {
+        let (app, extension) = (KeyExchange::new().unwrap(), KeyExchange::new().unwrap());
+        let (app_public, extension_public) = (app.public_key(), extension.public_key());
+        let key = app.agree(&extension_public).unwrap();
+        assert_eq!(extension.agree(&app_public).unwrap(), key);
+
+        let (mut app, mut extension) = (SealedChannel::new(key.clone(), Side::MainApp), SealedChannel::new(key, Side::Extension));
+        let message = br#"{"action":"task_result","task_id":"t1","result":{"password":"hunter2"}}"#;
+        let sealed = extension.seal(message).unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(peek_envelope(&sealed).task_id.as_deref(), Some("t1"));
+        assert!(!sealed.windows(7).any(|w| w == b"hunter2"));
+        assert_eq!(app.open(&sealed).unwrap(), message);
+
+        // Another key, a task ID or sequence number changed, or a message reflected to its sender doesn't open
+        let other = KeyExchange::new().unwrap().agree(&extension_public).unwrap();
+        assert!(SealedChannel::new(other, Side::MainApp).open(&sealed).is_err());
+        let moved = String::from_utf8(sealed.clone()).unwrap().replace(r#""task_id":"t1""#, r#""task_id":"t2""#);
+        assert!(app.open(moved.as_bytes()).is_err());
+        let seq = serde_json::from_slice::<SealedMessage>(&sealed).unwrap().data.seq;
+        let renumbered = String::from_utf8(sealed.clone()).unwrap().replace(&seq.to_string(), &(seq + 1).to_string());
+        assert!(app.open(renumbered.as_bytes()).is_err());
+        assert!(extension.open(&sealed).is_err());
+
+        // Sequence numbers only grow
+        let next = extension.seal(message).unwrap();
+        assert!(serde_json::from_slice::<SealedMessage>(&next).unwrap().data.seq > seq);
+    }
+
+    #[test]
+    fn replays_are_rejected_and_counted() {
+        let key = KeyExchange::new().unwrap().agree(&KeyExchange::new().unwrap().public_key()).unwrap();
+        let (mut app, mut extension) = (SealedChannel::new(key.clone(), Side::MainApp), SealedChannel::new(key, Side::Extension));
+        let message = br#"{"action":"task_result","task_id":"t1","success":true}"#;
+        let (first, second) = (extension.seal(message).unwrap(), extension.seal(message).unwrap());
+        assert!(app.open(&second).is_ok() && app.open(&first).is_ok());
+        assert!(app.open(&first).is_err());
+        assert_eq!(app.replays_rejected(), 1);
+
+        // Sealed outside the window
+        extension.last_seq = now_micros() + 2 * REPLAY_WINDOW.as_micros() as u64;
+        assert!(app.open(&extension.seal(message).unwrap()).is_err());
+        assert_eq!(app.replays_rejected(), 2);
+
+        // Failing to open isn't a replay
+        let mut other = SealedChannel::new(KeyExchange::new().unwrap().agree(&KeyExchange::new().unwrap().public_key()).unwrap(), Side::MainApp);
+        assert!(other.open(&first).is_err());
+        assert_eq!(other.replays_rejected(), 0);
+    }
+
+    #[test]
+    fn only_control_messages_may_come_unsealed() {
+        assert!(may_be_unsealed(br#"{"action":"ping","task_id":"heartbeat-1"}"#));
+        assert!(may_be_unsealed(br#"{"action":"pair","task_id":"p1","data":{"extension_id":"x"}}"#));
+        assert!(!may_be_unsealed(br#"{"action":"perform_task","task_id":"t1"}"#));
+        assert!(!may_be_unsealed(br#"{"task_id":"t1"}"#));
+    }
+}
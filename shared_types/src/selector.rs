@@ -0,0 +1,196 @@
+//! Element selectors shared by the host and the extension.
+//!
+//! A [`Selector`] is CSS, XPath, visible text or an ARIA role. CSS is written
+//! as a plain JSON string (the original wire form); the other kinds are objects:
+//!
+//! ```json
+//! "form#login button.submit"
+//! {"xpath": "//table/tbody/tr[2]/td[3]"}
+//! {"text": "Add to cart", "exact": true}
+//! {"role": "button", "name": "Save"}
+//! ```
+//!
+//! CSS selectors may use the `>>>` combinator to reach into open shadow roots:
+//! `my-app >>> settings-panel >>> button.save` matches `button.save` inside the
+//! shadow root of `settings-panel`, which itself lives inside the shadow root
+//! of `my-app`.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Combinator that continues the match inside the previous element's shadow root.
+pub const SHADOW_PIERCE: &str = ">>>";
+
+/// How a step finds its element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selector {
+    /// CSS selector, optionally with `>>>` shadow-root combinators.
+    Css(String),
+    /// XPath expression evaluated against the document.
+    XPath(String),
+    /// Innermost element whose visible text contains `text` (or equals it, if `exact`).
+    Text { text: String, exact: bool },
+    /// Element with the given ARIA role (explicit or implicit), optionally
+    /// matching its accessible name.
+    Role { role: String, name: Option<String> },
+}
+
+// Wire forms, tried in order. CSS keeps the plain string form.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SelectorRepr {
+    Css(String),
+    CssObject { css: String },
+    XPath { xpath: String },
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        exact: bool,
+    },
+    Role {
+        role: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+}
+
+impl Serialize for Selector {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self.clone() {
+            Selector::Css(css) => SelectorRepr::Css(css),
+            Selector::XPath(xpath) => SelectorRepr::XPath { xpath },
+            Selector::Text { text, exact } => SelectorRepr::Text { text, exact },
+            Selector::Role { role, name } => SelectorRepr::Role { role, name },
+        };
+        repr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Selector {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match SelectorRepr::deserialize(deserializer)? {
+            SelectorRepr::Css(css) | SelectorRepr::CssObject { css } => Selector::Css(css),
+            SelectorRepr::XPath { xpath } => Selector::XPath(xpath),
+            SelectorRepr::Text { text, exact } => Selector::Text { text, exact },
+            SelectorRepr::Role { role, name } => Selector::Role { role, name },
+        })
+    }
+}
+
+impl From<&str> for Selector {
+    fn from(css: &str) -> Self {
+        Selector::Css(css.to_string())
+    }
+}
+
+impl From<String> for Selector {
+    fn from(css: String) -> Self {
+        Selector::Css(css)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Css(css) => write!(f, "{}", css),
+            Selector::XPath(xpath) => write!(f, "xpath={}", xpath),
+            Selector::Text { text, exact: true } => write!(f, "text={:?}", text),
+            Selector::Text { text, exact: false } => write!(f, "text~={:?}", text),
+            Selector::Role { role, name: Some(name) } => write!(f, "role={}[name={:?}]", role, name),
+            Selector::Role { role, name: None } => write!(f, "role={}", role),
+        }
+    }
+}
+
+impl Selector {
+    /// Checks that the selector is well-formed.
+    pub fn validate(&self) -> Result<(), SelectorError> {
+        match self {
+            Selector::Css(css) => validate_selector(css),
+            Selector::XPath(value) | Selector::Text { text: value, .. } | Selector::Role { role: value, .. } => {
+                if value.trim().is_empty() {
+                    Err(SelectorError::Empty)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// A selector the extension could not resolve.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectorError {
+    /// The selector (or its XPath, text or role) is empty or whitespace.
+    Empty,
+    /// A `>>>` has nothing on one of its sides.
+    EmptyShadowSegment { selector: String },
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectorError::Empty => write!(f, "selector is empty"),
+            SelectorError::EmptyShadowSegment { selector } => {
+                write!(f, "selector {:?} has an empty part around '{}'", selector, SHADOW_PIERCE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+/// Splits `selector` at each `>>>` into the CSS selectors applied from the
+/// document down through each shadow root.
+pub fn shadow_segments(selector: &str) -> Result<Vec<&str>, SelectorError> {
+    if selector.trim().is_empty() {
+        return Err(SelectorError::Empty);
+    }
+    let segments: Vec<&str> = selector.split(SHADOW_PIERCE).map(str::trim).collect();
+    if segments.iter().any(|segment| segment.is_empty()) {
+        return Err(SelectorError::EmptyShadowSegment { selector: selector.to_string() });
+    }
+    Ok(segments)
+}
+
+/// Checks that the CSS `selector` is well-formed.
+pub fn validate_selector(selector: &str) -> Result<(), SelectorError> {
+    shadow_segments(selector).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_strings_stay_css() {
+        let selector: Selector = serde_json::from_str(r#""div.card > a""#).unwrap();
+        assert_eq!(selector, Selector::Css("div.card > a".to_string()));
+        assert_eq!(serde_json::to_string(&selector).unwrap(), r#""div.card > a""#);
+        let object: Selector = serde_json::from_str(r#"{"css": "a"}"#).unwrap();
+        assert_eq!(object, Selector::from("a"));
+    }
+
+    #[test]
+    fn typed_forms_round_trip() {
+        for json in [
+            r#"{"xpath":"//tr[2]/td"}"#,
+            r#"{"text":"Add to cart","exact":true}"#,
+            r#"{"text":"Add"}"#,
+            r#"{"role":"button","name":"Save"}"#,
+            r#"{"role":"link"}"#,
+        ] {
+            let selector: Selector = serde_json::from_str(json).unwrap();
+            assert_eq!(serde_json::to_string(&selector).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn validates_each_kind() {
+        let shadow = Selector::from("a >>> ").validate();
+        assert_eq!(shadow, Err(SelectorError::EmptyShadowSegment { selector: "a >>> ".to_string() }));
+        assert_eq!(Selector::XPath(" ".to_string()).validate(), Err(SelectorError::Empty));
+        assert_eq!(Selector::Role { role: "button".to_string(), name: None }.validate(), Ok(()));
+    }
+}